@@ -0,0 +1,102 @@
+//! Compares the traversal cost of union hierarchies built with each split method.
+//!
+//! The scene is deliberately unbalanced: a single huge "terrain" sphere plus a lot of small props
+//! scattered over its surface, which is the case median splitting handles worst. Run with
+//! `cargo bench --bench split_cost`.
+
+use std::time::Instant;
+use rand::{prelude::*, rngs::StdRng};
+use sdf::{
+    math::*,
+    node::*,
+    elements::*,
+    obb::SdfSplitMethod,
+    faux_shader,
+};
+
+const PROP_COUNT: usize = 1000;
+const QUERY_COUNT: usize = 5000;
+const TERRAIN_RADIUS: f32 = 2000.0;
+
+fn build_scene(split_method: SdfSplitMethod) -> SdfNode {
+    let mut rng = StdRng::seed_from_u64(0x5df);
+    (0..PROP_COUNT)
+        .fold(
+            SdfBuilder::primitive(SdfSphere {
+                radius: TERRAIN_RADIUS,
+            })
+            .transform(Transform::from_xyz(0.0, -TERRAIN_RADIUS, 0.0))
+            .operation(SdfUnion::new(0.0).with_split_method(split_method)),
+            |acc, _| {
+                // Props come in small clusters, like they would in an actual level
+                let cluster = Vec3::new(
+                    (rng.gen_range(-10..10) * 20) as f32,
+                    0.0,
+                    (rng.gen_range(-10..10) * 20) as f32,
+                );
+                acc.with(
+                    SdfBuilder::primitive(SdfSphere {
+                        radius: rng.gen_range(0.2..1.5),
+                    })
+                    .transform(Transform::from_translation(cluster + Vec3::new(
+                        rng.gen_range(-5.0..5.0),
+                        rng.gen_range(0.0..3.0),
+                        rng.gen_range(-5.0..5.0),
+                    )))
+                )
+            }
+        )
+        .finalize()
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(0xbe7c);
+    let queries = (0..QUERY_COUNT)
+        .map(|_| Vec4::new(
+            rng.gen_range(-220.0..220.0),
+            rng.gen_range(-5.0..30.0),
+            rng.gen_range(-220.0..220.0),
+            1.0,
+        ))
+        .collect::<Vec<Vec4>>();
+
+    let mut reference: Option<Vec<f32>> = None;
+    println!("{:<12} {:>10} {:>12} {:>12} {:>12} {:>12}",
+        "method", "build", "blocks/query", "prims/query", "pruned/query", "time/query");
+    for method in [SdfSplitMethod::Median, SdfSplitMethod::SurfaceArea, SdfSplitMethod::Volume] {
+        let build_start = Instant::now();
+        let buffer = build_scene(method).expanded().make_buffer();
+        let build_time = build_start.elapsed();
+
+        let mut blocks = 0;
+        let mut prims = 0;
+        let mut pruned = 0;
+        let query_start = Instant::now();
+        let dists = queries.iter()
+            .map(|query| {
+                let (dist, stats) = faux_shader::nearest_neighbor_stats(&buffer, *query);
+                blocks += stats.blocks_visited;
+                prims += stats.primitives_evaluated;
+                pruned += stats.blocks_pruned;
+                dist
+            })
+            .collect::<Vec<f32>>();
+        let query_time = query_start.elapsed();
+
+        // Every hierarchy has to give the same answers, otherwise the comparison is meaningless
+        match &reference {
+            Some(ref_dists) => assert!(
+                ref_dists.iter().zip(dists.iter()).all(|(a, b)| (a - b).abs() <= 1e-3 * a.abs().max(1.0)),
+                "{:?} hierarchy gives different distances than the median one!", method),
+            None => reference = Some(dists),
+        }
+
+        println!("{:<12} {:>10.2?} {:>12.1} {:>12.1} {:>12.1} {:>12.2?}",
+            format!("{:?}", method),
+            build_time,
+            blocks as f32 / QUERY_COUNT as f32,
+            prims as f32 / QUERY_COUNT as f32,
+            pruned as f32 / QUERY_COUNT as f32,
+            query_time / QUERY_COUNT as u32);
+    }
+}
@@ -0,0 +1,519 @@
+use super::math::*;
+use super::{
+    obb::*,
+    tree::*,
+    buffer::*,
+};
+
+// How a keyframe moves on to the next one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SdfInterpolation {
+    // Hold the value until the next keyframe
+    Step,
+    Linear,
+    // Easing curve through (0, 0), the two control points and (1, 1), like CSS cubic-bezier()
+    Bezier(Vec2, Vec2),
+}
+
+// Cubic bezier from 0 to 1 with the inner control values a and b
+fn bezier(a: f32, b: f32, s: f32) -> f32 {
+    let r = 1.0 - s;
+    3.0 * r * r * s * a + 3.0 * r * s * s * b + s * s * s
+}
+
+// Control value of the part of a cubic bezier between the parameters in u, by de Casteljau steps
+fn blossom(points: [f32; 4], u: [f32; 3]) -> f32 {
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let first = [lerp(points[0], points[1], u[0]), lerp(points[1], points[2], u[0]), lerp(points[2], points[3], u[0])];
+    let second = [lerp(first[0], first[1], u[1]), lerp(first[1], first[2], u[1])];
+    lerp(second[0], second[1], u[2])
+}
+
+impl SdfInterpolation {
+    // Map the linear progress between two keyframes to the interpolation weight
+    pub fn ease(&self, progress: f32) -> f32 {
+        match self {
+            SdfInterpolation::Step => 0.0,
+            SdfInterpolation::Linear => progress,
+            SdfInterpolation::Bezier(p1, p2) => {
+                let (lo, hi) = Self::solve_bezier(*p1, *p2, progress);
+                bezier(p1.y, p2.y, (lo + hi) / 2.0)
+            },
+        }
+    }
+
+    /**
+     * Range of the interpolation weight while the progress goes from `start` to `end`. Bezier
+     * curves can overshoot their keyframes, so they are bounded by the control points of the part
+     * of the curve in between.
+     */
+    pub fn ease_range(&self, start: f32, end: f32) -> (f32, f32) {
+        match self {
+            SdfInterpolation::Step => (0.0, 0.0),
+            SdfInterpolation::Linear => (start, end),
+            SdfInterpolation::Bezier(p1, p2) => {
+                let (s0, s1) = (Self::solve_bezier(*p1, *p2, start).0, Self::solve_bezier(*p1, *p2, end).1);
+                let points = [0.0, p1.y, p2.y, 1.0];
+                [[s0, s0, s0], [s0, s0, s1], [s0, s1, s1], [s1, s1, s1]].iter()
+                    .map(|u| blossom(points, *u))
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), y| (lo.min(y), hi.max(y)))
+            },
+        }
+    }
+
+    // Bracket around the curve parameter where x(s) reaches the progress
+    fn solve_bezier(p1: Vec2, p2: Vec2, progress: f32) -> (f32, f32) {
+        // x(s) is monotonic as long as the control points stay in the unit square
+        let (mut lo, mut hi) = (0.0_f32, 1.0_f32);
+        for _ in 0..24 {
+            let mid = (lo + hi) / 2.0;
+            if bezier(p1.x, p2.x, mid) < progress {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        (lo, hi)
+    }
+}
+
+pub trait SdfAnimatable: Copy {
+    fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl SdfAnimatable for f32 {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl SdfAnimatable for Transform {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        Transform {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SdfKeyframe<T: SdfAnimatable> {
+    pub time: f32,
+    pub value: T,
+    // Interpolation towards the next keyframe
+    pub interpolation: SdfInterpolation,
+}
+
+#[derive(Debug, Clone)]
+pub struct SdfTrack<T: SdfAnimatable> {
+    keyframes: Vec<SdfKeyframe<T>>,
+}
+
+impl<T: SdfAnimatable> SdfTrack<T> {
+    pub fn new() -> Self {
+        SdfTrack {
+            keyframes: Vec::new(),
+        }
+    }
+
+    // Add a keyframe, replacing any keyframe at the same time
+    pub fn with_key(mut self, time: f32, value: T, interpolation: SdfInterpolation) -> Self {
+        let key = SdfKeyframe {
+            time,
+            value,
+            interpolation,
+        };
+        let index = self.keyframes.partition_point(|other| other.time < time);
+        match self.keyframes.get(index) {
+            Some(other) if other.time == time => self.keyframes[index] = key,
+            _ => self.keyframes.insert(index, key),
+        }
+        self
+    }
+
+    pub fn keyframes(&self) -> &[SdfKeyframe<T>] {
+        self.keyframes.as_slice()
+    }
+
+    pub fn time_range(&self) -> Option<(f32, f32)> {
+        Some((self.keyframes.first()?.time, self.keyframes.last()?.time))
+    }
+
+    // Value of the track at a time, holding the first and last keyframes outside of the track
+    pub fn sample(&self, time: f32) -> Option<T> {
+        let first = self.keyframes.first()?;
+        let next = self.keyframes.partition_point(|key| key.time <= time);
+        if next == 0 {
+            return Some(first.value);
+        } else if next == self.keyframes.len() {
+            return Some(self.keyframes[next - 1].value);
+        }
+        let (key, next_key) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        let progress = (time - key.time) / (next_key.time - key.time);
+        Some(key.value.interpolate(&next_key.value, key.interpolation.ease(progress)))
+    }
+
+    /**
+     * Values at the two ends of the range of interpolation weights between two times, which
+     * mustn't have a keyframe between them. Every value in between is an interpolation of the two.
+     */
+    pub fn sample_range(&self, start: f32, end: f32) -> Option<(T, T)> {
+        let first = self.keyframes.first()?;
+        let next = self.keyframes.partition_point(|key| key.time <= (start + end) / 2.0);
+        if next == 0 {
+            return Some((first.value, first.value));
+        } else if next == self.keyframes.len() {
+            let value = self.keyframes[next - 1].value;
+            return Some((value, value));
+        }
+        let (key, next_key) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        let progress = |time: f32| ((time - key.time) / (next_key.time - key.time)).clamp(0.0, 1.0);
+        let (lo, hi) = key.interpolation.ease_range(progress(start), progress(end));
+        Some((key.value.interpolate(&next_key.value, lo), key.value.interpolate(&next_key.value, hi)))
+    }
+}
+
+impl<T: SdfAnimatable> Default for SdfTrack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SdfChannel {
+    Transform(SdfTrack<Transform>),
+    // Numeric field of the node's element, see SdfElement::set_param()
+    Param(String, SdfTrack<f32>),
+}
+
+impl SdfChannel {
+    fn times(&self) -> Vec<f32> {
+        match self {
+            SdfChannel::Transform(track) => track.keyframes().iter().map(|key| key.time).collect(),
+            SdfChannel::Param(_, track) => track.keyframes().iter().map(|key| key.time).collect(),
+        }
+    }
+}
+
+/**
+ * Keyframed transforms and element parameters of the nodes of an [`SdfTree`].
+ *
+ * [`SdfAnimation::sweep()`] gives every animated node a sweep covering all of its poses, so that
+ * playing the animation back only refits and patches the animated nodes themselves.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct SdfAnimation {
+    pub channels: Vec<(NodeId, SdfChannel)>,
+}
+
+impl SdfAnimation {
+    pub fn new() -> Self {
+        SdfAnimation {
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn with_transform(mut self, node: NodeId, track: SdfTrack<Transform>) -> Self {
+        self.channels.push((node, SdfChannel::Transform(track)));
+        self
+    }
+
+    pub fn with_param(mut self, node: NodeId, name: &str, track: SdfTrack<f32>) -> Self {
+        self.channels.push((node, SdfChannel::Param(name.to_string(), track)));
+        self
+    }
+
+    pub fn time_range(&self) -> Option<(f32, f32)> {
+        let times = self.channels.iter()
+            .flat_map(|(_, channel)| channel.times())
+            .collect::<Vec<f32>>();
+        Some((
+            times.iter().copied().reduce(f32::min)?,
+            times.iter().copied().reduce(f32::max)?,
+        ))
+    }
+
+    // Pose the tree at a time. The tree is left dirty, to be refit or used to update a buffer.
+    pub fn evaluate_at(&self, tree: &mut SdfTree, time: f32) -> Result<(), &'static str> {
+        for (node, channel) in self.channels.iter() {
+            match channel {
+                SdfChannel::Transform(track) => if let Some(transform) = track.sample(time) {
+                    tree.set_transform(*node, transform)?;
+                },
+                SdfChannel::Param(name, track) => if let Some(value) = track.sample(time) {
+                    tree.set_param(*node, name, value)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub fn update_buffer(
+        &self,
+        tree: &mut SdfTree,
+        buffer: &mut SdfPersistentBuffer,
+        time: f32,
+    ) -> Result<SdfBufferUpdate, &'static str> {
+        self.evaluate_at(tree, time)?;
+        buffer.update(tree)
+    }
+
+    /**
+     * Set the sweep of every animated node to a box that covers all of its poses, and leave the
+     * tree posed at the start of the animation.
+     *
+     * The animation is cut at the keyframes and at `samples` evenly spaced times, which only makes
+     * the sweep tighter. Within a piece, translations and scales are linear in the interpolation
+     * weight, so the poses at the ends of its weight range cover the ones in between. Rotations
+     * are covered by a sphere around the pivot instead. Element boxes are assumed to grow
+     * monotonically with their parameters, so those are only posed at the ends of their ranges.
+     */
+    pub fn sweep(&self, tree: &mut SdfTree, samples: usize) -> Result<(), &'static str> {
+        let (start, end) = match self.time_range() {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        let mut times = self.channels.iter()
+            .flat_map(|(_, channel)| channel.times())
+            .chain((0..=samples).map(|i| start + (end - start) * i as f32 / samples.max(1) as f32))
+            .collect::<Vec<f32>>();
+        times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        times.dedup();
+
+        let mut nodes = self.channels.iter()
+            .map(|(node, _)| *node)
+            .collect::<Vec<NodeId>>();
+        nodes.sort();
+        nodes.dedup();
+        for node in nodes.iter() {
+            tree.set_sweep(*node, None)?;
+        }
+
+        // Children are swept before their parents, so that parents are fit around the final
+        // sweeps of their children
+        let depth = |tree: &SdfTree, node: NodeId| std::iter::successors(Some(node), |node| tree.parent(*node)).count();
+        nodes.sort_by_key(|node| std::cmp::Reverse(depth(tree, *node)));
+        for node in nodes.iter() {
+            let mut poses = Vec::new();
+            for time in times.iter() {
+                self.evaluate_at(tree, *time)?;
+                tree.refit();
+                poses.push(tree.bbox(*node));
+            }
+            for piece in times.windows(2) {
+                self.evaluate_at(tree, piece[0])?;
+                poses.extend(self.sweep_piece(tree, *node, piece[0], piece[1])?);
+            }
+            tree.set_sweep(*node, Some(SdfBoundingBox::merge(poses.as_slice())))?;
+        }
+        self.evaluate_at(tree, start)
+    }
+
+    // Boxes covering the poses of a node between two times without a keyframe between them
+    fn sweep_piece(&self, tree: &mut SdfTree, node: NodeId, start: f32, end: f32) -> Result<Vec<SdfBoundingBox>, &'static str> {
+        let mut transforms = (tree.transform(node), tree.transform(node));
+        let mut params: Vec<(&str, (f32, f32))> = Vec::new();
+        for (_, channel) in self.channels.iter().filter(|(other, _)| *other == node) {
+            match channel {
+                SdfChannel::Transform(track) => if let Some(range) = track.sample_range(start, end) {
+                    transforms = range;
+                },
+                SdfChannel::Param(name, track) => if let Some(range) = track.sample_range(start, end) {
+                    params.push((name.as_str(), range));
+                },
+            }
+        }
+        let (first, last) = transforms;
+        let rotates = first.rotation.angle_between(last.rotation) > 0.0;
+        let max_scale = first.scale.abs().max(last.scale.abs());
+        let mut boxes = Vec::new();
+        // Every combination of the ends of the parameter ranges
+        for corner in 0..1_usize << params.len() {
+            for (i, (name, (lo, hi))) in params.iter().enumerate() {
+                tree.set_param(node, name, if corner & (1 << i) == 0 { *lo } else { *hi })?;
+            }
+            for transform in [first, last] {
+                tree.set_transform(node, transform)?;
+                tree.refit();
+                boxes.push(tree.bbox(node));
+            }
+            if rotates {
+                // Any rotation of the largest scaled box stays within its farthest vertex from the pivot,
+                // which is measured without the offset that comes after the transform
+                let offset = tree.offset(node);
+                tree.set_transform(node, Transform::from_scale(max_scale))?;
+                tree.set_offset(node, DVec3::ZERO)?;
+                tree.refit();
+                let radius = tree.bbox(node).verts().iter()
+                    .map(|vert| vert.truncate().length())
+                    .fold(0.0, f32::max);
+                tree.set_offset(node, offset)?;
+                let center = (first.translation + last.translation) / 2.0;
+                let half_extents = (last.translation - first.translation).abs() / 2.0 + Vec3::splat(radius);
+                boxes.push(SdfBoundingBox::from_extents(center, half_extents).apply_translation(offset));
+            }
+        }
+        Ok(boxes)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::math::*;
+    use float_cmp::approx_eq;
+    use crate::{
+        anim::*,
+        node::*,
+        elements::*,
+        faux_shader,
+    };
+
+    fn sphere_at(center: Vec3) -> SdfBuilder {
+        SdfBuilder::primitive(SdfSphere {
+            radius: 1.0,
+        })
+        .transform(Transform::from_translation(center))
+    }
+
+    fn union() -> SdfUnion {
+        SdfUnion::new(0.0)
+    }
+
+    #[test]
+    fn test_track_interpolation() {
+        let track = SdfTrack::new()
+            .with_key(1.0, 10.0, SdfInterpolation::Step)
+            .with_key(0.0, 0.0, SdfInterpolation::Linear)
+            .with_key(2.0, 20.0, SdfInterpolation::Bezier(Vec2::new(0.42, 0.0), Vec2::new(0.58, 1.0)))
+            .with_key(3.0, 30.0, SdfInterpolation::Linear);
+        assert_eq!(track.time_range(), Some((0.0, 3.0)));
+        assert_eq!(track.sample(-1.0), Some(0.0));
+        assert_eq!(track.sample(0.25), Some(2.5));
+        assert_eq!(track.sample(1.5), Some(10.0));
+        assert_eq!(track.sample(2.0), Some(20.0));
+        assert_eq!(track.sample(4.0), Some(30.0));
+
+        // Ease-in-out is symmetric around its midpoint, and slow at both ends
+        assert!(approx_eq!(f32, track.sample(2.5).unwrap(), 25.0, epsilon = 1e-3));
+        assert!(track.sample(2.1).unwrap() < 21.0);
+        assert!(track.sample(2.9).unwrap() > 29.0);
+
+        // Keys at the same time replace each other
+        let track = track.with_key(3.0, 40.0, SdfInterpolation::Linear);
+        assert_eq!(track.keyframes().len(), 4);
+        assert_eq!(track.sample(3.0), Some(40.0));
+        assert_eq!(SdfTrack::<f32>::new().sample(0.0), None);
+
+        let turn = SdfTrack::new()
+            .with_key(0.0, Transform::identity(), SdfInterpolation::Linear)
+            .with_key(1.0, Transform::from_xyz(2.0, 0.0, 0.0).with_rotation(Quat::from_rotation_y(1.0)), SdfInterpolation::Linear);
+        let half = turn.sample(0.5).unwrap();
+        assert!((half.translation - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-6);
+        assert!(half.rotation.angle_between(Quat::from_rotation_y(0.5)) < 1e-4);
+    }
+
+    #[test]
+    fn test_animated_buffer() {
+        let mut tree = SdfTree::new(
+            sphere_at(Vec3::ZERO)
+                .operation(union())
+                .with(sphere_at(Vec3::new(0.0, 8.0, 0.0)))
+                .with(sphere_at(Vec3::new(0.0, -8.0, 0.0)))
+                .with(sphere_at(Vec3::new(-3.0, 0.0, 10.0))
+                    .operation(union())
+                    .with(sphere_at(Vec3::new(3.0, 0.0, 10.0))))
+                .finalize()
+        );
+        let root = tree.root();
+        let prop = tree.children(root)[0];
+        let anim = SdfAnimation::new()
+            .with_transform(prop, SdfTrack::new()
+                .with_key(0.0, Transform::identity(), SdfInterpolation::Linear)
+                .with_key(1.0, Transform::from_xyz(10.0, 0.0, 0.0), SdfInterpolation::Linear))
+            .with_param(prop, "radius", SdfTrack::new()
+                .with_key(0.0, 1.0, SdfInterpolation::Linear)
+                .with_key(1.0, 2.0, SdfInterpolation::Step));
+        assert!(SdfAnimation::new().with_param(prop, "height", SdfTrack::new()
+            .with_key(0.0, 1.0, SdfInterpolation::Linear))
+            .evaluate_at(&mut tree, 0.0)
+            .is_err());
+
+        let mut buffer = SdfPersistentBuffer::new(&mut tree).unwrap();
+        anim.sweep(&mut tree, 8).unwrap();
+        let update = buffer.update(&mut tree).unwrap();
+        assert!(!update.rebuilt);
+        let sweep = tree.sweep(prop).unwrap();
+
+        for frame in 1..=10 {
+            let time = frame as f32 / 10.0;
+            let update = anim.update_buffer(&mut tree, &mut buffer, time).unwrap();
+
+            // Only the prop and the split boxes it's grouped into are touched
+            assert!(!update.rebuilt);
+            assert!((1..=3).contains(&update.patched_blocks));
+            assert!(update.downtree_ranges.iter().all(|range| range.start > 0));
+            for vert in tree.bbox(prop).verts() {
+                let center = sweep.center();
+                assert!(sweep.contains(center + (vert.truncate() - center) * 0.999));
+            }
+
+            let prop_center = Vec3::new(time * 10.0, 0.0, 0.0);
+            let centers = [
+                Vec3::new(0.0, 8.0, 0.0),
+                Vec3::new(0.0, -8.0, 0.0),
+                Vec3::new(-3.0, 0.0, 10.0),
+                Vec3::new(3.0, 0.0, 10.0),
+            ];
+            for point in [Vec3::new(5.0, 1.0, 0.0), Vec3::new(12.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 10.0), Vec3::new(-4.0, -2.0, 1.0)] {
+                let ground_truth = centers.iter()
+                    .map(|center| (point - *center).length() - 1.0)
+                    .fold((point - prop_center).length() - (1.0 + time), f32::min);
+                let buffer_result = faux_shader::nearest_neighbor(buffer.buffer(), point.extend(1.0)).0;
+                assert!(approx_eq!(f32, ground_truth, buffer_result, epsilon = 1e-4),
+                    "Animated Buffer Failed! Ground Truth: {}, Buffer Result: {}", ground_truth, buffer_result);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sweep_bounds() {
+        let mut tree = SdfTree::new(
+            sphere_at(Vec3::new(0.0, 20.0, 0.0))
+                .operation(union())
+                .with(sphere_at(Vec3::new(2.5, 0.0, 0.0)))
+                .finalize()
+        );
+        let prop = tree.children(tree.root())[1];
+        // A half turn of the sphere's box, and a bezier that overshoots both of its keyframes
+        let anim = SdfAnimation::new()
+            .with_transform(prop, SdfTrack::new()
+                .with_key(0.0, Transform::from_xyz(2.5, 0.0, 0.0), SdfInterpolation::Linear)
+                .with_key(1.0, Transform::from_xyz(2.5, 0.0, 0.0).with_rotation(Quat::from_rotation_z(3.0)), SdfInterpolation::Bezier(Vec2::new(0.3, -1.5), Vec2::new(0.7, 2.5)))
+                .with_key(2.0, Transform::from_xyz(0.0, 0.0, 2.0), SdfInterpolation::Linear))
+            .with_param(prop, "radius", SdfTrack::new()
+                .with_key(0.0, 0.5, SdfInterpolation::Bezier(Vec2::new(0.2, 3.0), Vec2::new(0.8, -2.0)))
+                .with_key(2.0, 1.0, SdfInterpolation::Linear));
+        assert!(SdfInterpolation::Bezier(Vec2::new(0.3, -1.5), Vec2::new(0.7, 2.5)).ease(0.2) < 0.0);
+
+        // Even without any samples between the keyframes, every pose is inside of the sweep
+        anim.sweep(&mut tree, 0).unwrap();
+        let sweep = tree.sweep(prop).unwrap();
+        let center = sweep.center();
+        for step in 0..=400 {
+            anim.evaluate_at(&mut tree, step as f32 / 200.0).unwrap();
+            tree.refit();
+            for vert in tree.bbox(prop).verts() {
+                assert!(sweep.contains(center + (vert.truncate() - center) * 0.999),
+                    "Pose at {} leaves the sweep at {}", step as f32 / 200.0, vert);
+            }
+        }
+
+        // Pieces with linear interpolation and without rotation only need their ends
+        let track = SdfTrack::new()
+            .with_key(0.0, 1.0, SdfInterpolation::Linear)
+            .with_key(1.0, 3.0, SdfInterpolation::Step);
+        assert_eq!(track.sample_range(0.25, 0.5), Some((1.5, 2.0)));
+        assert_eq!(track.sample_range(1.0, 2.0), Some((3.0, 3.0)));
+    }
+}
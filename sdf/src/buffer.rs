@@ -0,0 +1,708 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    mem::size_of,
+    ops::Range,
+};
+use super::math::DVec3;
+use super::{
+    obb::*,
+    node::*,
+    tree::*,
+    component::*,
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct SdfBufferUpdate {
+    // Whether the topology of the tree changed, so the whole buffer had to be rebuilt
+    pub rebuilt: bool,
+    pub patched_blocks: usize,
+    // Byte ranges of the downtree and uptree buffers that have to be uploaded again
+    pub downtree_ranges: Vec<Range<usize>>,
+    pub uptree_ranges: Vec<Range<usize>>,
+    // Byte ranges of the side buffer that have to be uploaded again
+    pub side_ranges: Vec<Range<usize>>,
+}
+
+/**
+ * SdfTreeBuffer that's kept in sync with an [`SdfTree`].
+ *
+ * Every block remembers the tree node it was expanded from and how its bounding box was made, so
+ * that changed bounding boxes, elements and materials only rewrite the blocks that depend on them. The tree is
+ * only expanded again when its topology changes. Like [`ExpandedSdfNode::make_relative_buffer()`], the
+ * buffer can be relative to an origin, which only rewrites the bounding boxes when it moves.
+ */
+pub struct SdfPersistentBuffer {
+    buffer: SdfTreeBuffer,
+    sources: Vec<SdfBlockSource>,
+    boxes: Vec<SdfBoundingBox>,
+    origin: DVec3,
+    // Origins that the bounding boxes of the blocks are rebased to
+    origins: Vec<DVec3>,
+    // Downtree indices of the blocks that have to be rewritten when a node changes
+    dependents: HashMap<NodeId, Vec<usize>>,
+    // Number of side buffer floats that each block owns
+    side_lens: Vec<usize>,
+    topology_version: u64,
+}
+
+impl SdfPersistentBuffer {
+    pub fn new(tree: &mut SdfTree) -> Result<Self, &'static str> {
+        Self::new_relative(tree, DVec3::ZERO)
+    }
+
+    pub fn new_relative(tree: &mut SdfTree, origin: DVec3) -> Result<Self, &'static str> {
+        let mut persistent = SdfPersistentBuffer {
+            buffer: SdfTreeBuffer::make_empty(),
+            sources: Vec::new(),
+            boxes: Vec::new(),
+            origin,
+            origins: Vec::new(),
+            dependents: HashMap::new(),
+            side_lens: Vec::new(),
+            topology_version: 0,
+        };
+        persistent.rebuild(tree)?;
+        Ok(persistent)
+    }
+
+    pub fn buffer(&self) -> &SdfTreeBuffer {
+        &self.buffer
+    }
+
+    pub fn origin(&self) -> DVec3 {
+        self.origin
+    }
+
+    // Fails without touching the buffer if the tree has an element that can't be encoded
+    fn rebuild(&mut self, tree: &mut SdfTree) -> Result<(), &'static str> {
+        tree.refit();
+        let expanded = tree.expanded();
+        self.buffer = expanded.try_make_relative_buffer(self.origin)?;
+        tree.take_changed();
+        (self.sources, self.boxes) = expanded.block_sources().into_iter().unzip();
+        self.origins = expanded.block_origins(self.origin);
+        self.topology_version = tree.topology_version();
+        self.side_lens = self.sources.iter()
+            .map(|source| tree.element(source.node.unwrap()).side_data().len())
+            .collect();
+        self.dependents.clear();
+        for index in 0..self.sources.len() {
+            for node in self.dependencies(tree, index) {
+                self.dependents.entry(node).or_default().push(index);
+            }
+        }
+        Ok(())
+    }
+
+    // Tree nodes that the contents of a block are made from
+    fn dependencies(&self, tree: &SdfTree, index: usize) -> Vec<NodeId> {
+        let source = &self.sources[index];
+        let node = source.node.expect("Tried tracking a block that wasn't expanded from an SDF tree!");
+        let mut nodes = vec![node];
+        match &source.bounds {
+            SdfBoundsRule::Source => (),
+            SdfBoundsRule::FirstChild => nodes.extend(self.dependencies(tree, index + 1)),
+            SdfBoundsRule::Merge(slot_inds) => nodes.extend(slot_inds.iter()
+                .map(|i| tree.children(node)[*i])),
+        }
+        let mut frame = node;
+        for _ in 0..source.collapsed {
+            frame = tree.parent(frame).unwrap();
+            nodes.push(frame);
+        }
+        nodes
+    }
+
+    /**
+     * Bring the buffer up to date with the tree, refitting it first.
+     *
+     * Blocks are rewritten from the last block to the first, so the children of a block are always
+     * up to date before it copies their bounding box. Side buffer data is rewritten in place, unless
+     * its size changed, which moves everything after it and takes a rebuild.
+     */
+    pub fn update(&mut self, tree: &mut SdfTree) -> Result<SdfBufferUpdate, &'static str> {
+        self.update_with(tree, false)
+    }
+
+    // Move the origin that the buffer is relative to, and bring it up to date with the tree
+    pub fn set_origin(&mut self, tree: &mut SdfTree, origin: DVec3) -> Result<SdfBufferUpdate, &'static str> {
+        let moved = origin != self.origin;
+        self.origin = origin;
+        self.update_with(tree, moved)
+    }
+
+    fn update_with(&mut self, tree: &mut SdfTree, moved: bool) -> Result<SdfBufferUpdate, &'static str> {
+        tree.refit();
+        if tree.topology_version() != self.topology_version {
+            return self.rebuild_update(tree);
+        }
+
+        let mut dirty = tree.take_changed().iter()
+            .filter_map(|node| self.dependents.get(node))
+            .flatten()
+            .copied()
+            .collect::<BTreeSet<usize>>();
+        let resized = dirty.iter()
+            .any(|index| tree.element(self.sources[*index].node.unwrap()).side_data().len() != self.side_lens[*index]);
+        // Replacing an element with one that can't be encoded is reported by the rebuild
+        let unencodable = dirty.iter()
+            .any(|index| check_gpu_encoding(tree.element(self.sources[*index].node.unwrap())).is_err());
+        if resized || unencodable {
+            return self.rebuild_update(tree);
+        }
+
+        let mut side_ranges = Vec::new();
+        let mut dirty_uptree = dirty.iter()
+            .rev()
+            .filter_map(|index| self.patch_block(tree, *index, &mut side_ranges))
+            .collect::<BTreeSet<usize>>();
+        side_ranges.sort_by_key(|range| range.start);
+        if moved || self.origin != DVec3::ZERO {
+            dirty.extend(self.rebase_blocks(tree));
+        }
+        // Parameters like displacement amplitudes change the factors of the ancestors too
+        for index in self.refactor_blocks(tree) {
+            dirty.insert(index);
+            dirty_uptree.insert(self.uptree_index(index));
+        }
+        Ok(SdfBufferUpdate {
+            rebuilt: false,
+            patched_blocks: dirty.len(),
+            downtree_ranges: byte_ranges(dirty.into_iter(), size_of::<SdfOperationBlock>()),
+            uptree_ranges: byte_ranges(dirty_uptree.into_iter(), size_of::<SdfOperationUptreeBlock>()),
+            side_ranges,
+        })
+    }
+
+    fn rebuild_update(&mut self, tree: &mut SdfTree) -> Result<SdfBufferUpdate, &'static str> {
+        self.rebuild(tree)?;
+        let len = self.buffer.buffer_len as usize;
+        Ok(SdfBufferUpdate {
+            rebuilt: true,
+            patched_blocks: len,
+            downtree_ranges: byte_ranges(0..len, size_of::<SdfOperationBlock>()),
+            uptree_ranges: byte_ranges(0..len, size_of::<SdfOperationUptreeBlock>()),
+            side_ranges: byte_ranges(0..self.buffer.side_buffer.len(), size_of::<f32>()),
+        })
+    }
+
+    /**
+     * Rewrite a downtree block, returning the index of its uptree block if that changed too.
+     * Side buffer data is only copied if it differs, and its byte range is added to `side_ranges`.
+     */
+    fn patch_block(&mut self, tree: &SdfTree, index: usize, side_ranges: &mut Vec<Range<usize>>) -> Option<usize> {
+        let source = &self.sources[index];
+        let node = source.node.unwrap();
+        let mut bbox = match &source.bounds {
+            SdfBoundsRule::Source => tree.bbox(node),
+            SdfBoundsRule::FirstChild => self.boxes[index + 1],
+            SdfBoundsRule::Merge(slot_inds) => SdfBoundingBox::merge(
+                slot_inds.iter()
+                    .map(|i| tree.outer_bbox(tree.children(node)[*i]))
+                    .collect::<Vec<SdfBoundingBox>>()
+                    .as_slice()
+            ),
+        };
+        let mut frame = node;
+        for _ in 0..source.collapsed {
+            frame = tree.parent(frame).unwrap();
+            bbox = bbox.apply_frame(&tree.bbox(frame));
+        }
+        self.boxes[index] = bbox;
+
+        let intern = tree.element(node);
+        let intern_info = intern.get_info();
+        let rebased = bbox.rebased(self.origins[index], intern_info.is_union).0;
+        let dt_block = &mut self.buffer.downtree_buffer[index];
+        dt_block.op_code = intern_info.op_id;
+        dt_block.is_primitive = intern_info.is_primitive;
+        dt_block.material = if intern_info.is_primitive { tree.material(node) } else { 0 };
+        let side_offset = dt_block.op_specific.side_offset;
+        dt_block.op_specific = intern.get_dt_specific_block();
+        dt_block.op_specific.side_offset = side_offset;
+        let side_data = intern.side_data();
+        let side = (side_offset as usize)..(side_offset as usize + side_data.len());
+        // Compared by bits, since quantized data is packed into floats that may well be NaN
+        let stale = self.buffer.side_buffer[side.clone()].iter()
+            .zip(side_data)
+            .any(|(old, new)| old.to_bits() != new.to_bits());
+        if stale {
+            self.buffer.side_buffer[side.clone()].copy_from_slice(side_data);
+            side_ranges.push((side.start * size_of::<f32>())..(side.end * size_of::<f32>()));
+        }
+        self.write_bbox(index, &rebased);
+
+        let ut_index = self.uptree_index(index);
+        let ut_block = &mut self.buffer.uptree_buffer[ut_index];
+        let ut_block_spec = intern.get_ut_specific_block();
+        if ut_block.op_code != intern_info.op_id || ut_block.op_specific != ut_block_spec {
+            ut_block.op_code = intern_info.op_id;
+            ut_block.op_specific = ut_block_spec;
+            Some(ut_index)
+        } else {
+            None
+        }
+    }
+
+    // The uptree buffer is in post-order, so a block's uptree index is shifted by the size of its
+    // subtree and its depth
+    fn uptree_index(&self, index: usize) -> usize {
+        let dt_block = &self.buffer.downtree_buffer[index];
+        index + dt_block.len as usize + 1 - dt_block.level as usize
+    }
+
+    /**
+     * Rewrite the Lipschitz factors of the blocks, returning the indices of the ones that changed.
+     * Factors are accumulated from the last block to the first like ExpandedSdfNode::lipschitz
+     * does, over the blocks that the tree was expanded into.
+     */
+    fn refactor_blocks(&mut self, tree: &SdfTree) -> Vec<usize> {
+        let len = self.buffer.buffer_len as usize;
+        let mut changed = Vec::new();
+        for index in (0..len).rev() {
+            let dt_block = &self.buffer.downtree_buffer[index];
+            let end = index + 1 + dt_block.len as usize;
+            // Both slots of an operation, if they were written
+            let mut slots = Vec::new();
+            if !dt_block.is_primitive && index + 1 < end {
+                slots.push(index + 1);
+                let second = index + 2 + self.buffer.downtree_buffer[index + 1].len as usize;
+                if second < end {
+                    slots.push(second);
+                }
+            }
+            let intern = tree.element(self.sources[index].node.unwrap());
+            let own = intern.lipschitz();
+            let lipschitz = if intern.get_info().is_union && slots.len() > 1 {
+                own
+            } else {
+                own * slots.iter()
+                    .map(|slot| self.buffer.downtree_buffer[*slot].lipschitz)
+                    .fold(1.0, f32::max)
+            };
+            if lipschitz != dt_block.lipschitz {
+                let ut_index = self.uptree_index(index);
+                self.buffer.downtree_buffer[index].lipschitz = lipschitz;
+                self.buffer.uptree_buffer[ut_index].lipschitz = lipschitz;
+                changed.push(index);
+            }
+        }
+        changed
+    }
+
+    fn write_bbox(&mut self, index: usize, bbox: &SdfBoundingBox) {
+        let dt_block = &mut self.buffer.downtree_buffer[index];
+        dt_block.bounding_box = bbox.get_bbox_block();
+        // The root has no parent union to be compared against
+        if index != 0 {
+            dt_block.other_box = dt_block.bounding_box;
+        }
+    }
+
+    /**
+     * Rewrite the bounding boxes of the blocks whose origin changed, returning their indices.
+     * Besides the origin itself moving, a union's frame can change the origin of its children.
+     */
+    fn rebase_blocks(&mut self, tree: &SdfTree) -> Vec<usize> {
+        // Origins inside of the frames of the current block's ancestors, by level
+        let mut inner_origins: Vec<DVec3> = Vec::new();
+        let mut changed = Vec::new();
+        for index in 0..self.boxes.len() {
+            let level = self.buffer.downtree_buffer[index].level as usize;
+            inner_origins.truncate(level - 1);
+            let origin = inner_origins.last().copied().unwrap_or(self.origin);
+            let is_union = tree.element(self.sources[index].node.unwrap()).get_info().is_union;
+            let (bbox, inner_origin) = self.boxes[index].rebased(origin, is_union);
+            inner_origins.push(inner_origin);
+            if origin != self.origins[index] {
+                self.origins[index] = origin;
+                self.write_bbox(index, &bbox);
+                changed.push(index);
+            }
+        }
+        changed
+    }
+}
+
+// Coalesce sorted block indices into byte ranges
+fn byte_ranges(indices: impl Iterator<Item = usize>, block_size: usize) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for index in indices {
+        let start = index * block_size;
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end += block_size,
+            _ => ranges.push(start..(start + block_size)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Arc;
+    use rand::prelude::*;
+    use crate::math::*;
+    use float_cmp::approx_eq;
+    use crate::{
+        buffer::*,
+        elements::*,
+        heightfield::{*, tests::ridge_grid},
+        noise::{SdfNoise, SdfNoiseBasis},
+        faux_shader,
+    };
+
+    type SdfEdit = Box<dyn Fn(&mut SdfTree)>;
+
+    fn sphere_at(center: Vec3, radius: f32) -> SdfBuilder {
+        SdfBuilder::primitive(SdfSphere {
+            radius,
+        })
+        .transform(Transform::from_translation(center))
+    }
+
+    fn union() -> SdfUnion {
+        SdfUnion::new(0.0)
+    }
+
+    fn balanced(depth: u32, center: Vec3, spread: f32) -> SdfBuilder {
+        if depth == 0 {
+            return sphere_at(center, 1.0);
+        }
+        let axis = [Vec3::X, Vec3::Y, Vec3::Z][depth as usize % 3] * spread;
+        balanced(depth - 1, center - axis, spread / 2.0)
+            .operation(union())
+            .with(balanced(depth - 1, center + axis, spread / 2.0))
+    }
+
+    fn copy_buffer(buffer: &SdfTreeBuffer) -> SdfTreeBuffer {
+        SdfTreeBuffer {
+            downtree_buffer: buffer.downtree_buffer.clone(),
+            uptree_buffer: buffer.uptree_buffer.clone(),
+            side_buffer: buffer.side_buffer.clone(),
+            buffer_len: buffer.buffer_len,
+        }
+    }
+
+    // Check that the reported byte ranges cover every block that changed in an update
+    fn check_ranges(before: &SdfTreeBuffer, after: &SdfTreeBuffer, update: &SdfBufferUpdate) {
+        let covered = |ranges: &Vec<Range<usize>>, index: usize, block_size: usize| ranges.iter()
+            .any(|range| range.start <= index * block_size && (index + 1) * block_size <= range.end);
+        for index in 0..after.buffer_len as usize {
+            if before.downtree_buffer[index] != after.downtree_buffer[index] {
+                assert!(covered(&update.downtree_ranges, index, size_of::<SdfOperationBlock>()),
+                    "Downtree block {} changed outside of the dirty ranges!", index);
+            }
+            if before.uptree_buffer[index] != after.uptree_buffer[index] {
+                assert!(covered(&update.uptree_ranges, index, size_of::<SdfOperationUptreeBlock>()),
+                    "Uptree block {} changed outside of the dirty ranges!", index);
+            }
+        }
+    }
+
+    // Compare the faux shader on the patched buffer against the CPU search on the tree itself
+    fn check_distances(tree: &SdfTree, buffer: &SdfTreeBuffer) {
+        let mut rng = thread_rng();
+        let node = tree.to_node(tree.root());
+        for _ in 0..200 {
+            let point = Vec3::new(
+                rng.gen_range(-30.0..30.0),
+                rng.gen_range(-30.0..30.0),
+                rng.gen_range(-30.0..30.0),
+            );
+            let tree_result = node.nearest_neighbor(point).distance;
+            let buffer_result = faux_shader::nearest_neighbor(buffer, point.extend(1.0)).0;
+            assert!(approx_eq!(f32, tree_result, buffer_result, epsilon = 1e-3),
+                "Patched buffer failed! Tree Result: {}, Buffer Result: {}", tree_result, buffer_result);
+        }
+    }
+
+    #[test]
+    fn test_patch_chain() {
+        // Without unions of several children, the expanded tree can't change shape, so patching
+        // has to give exactly the same buffer as a rebuild
+        let mut tree = SdfTree::new(
+            sphere_at(Vec3::new(0.0, 1.0, 0.0), 1.0)
+                .operation(SdfCaaClone {
+                    displacement: Vec3::splat(3.0),
+                    neg_limit: Vec3::splat(-1.0),
+                    pos_limit: Vec3::splat(1.0),
+                })
+                .operation(union())
+                .transform(Transform::from_rotation(Quat::from_rotation_y(0.3)))
+                .finalize()
+        );
+        let mut persistent = SdfPersistentBuffer::new(&mut tree).unwrap();
+        let root = tree.root();
+        let clone = tree.children(root)[0];
+        let sphere = tree.children(clone)[0];
+        let edits: Vec<SdfEdit> = vec![
+            Box::new(move |tree| tree.set_transform(sphere, Transform::from_xyz(0.5, -0.5, 0.0)).unwrap()),
+            Box::new(move |tree| { tree.replace_element(sphere, Box::new(SdfSphere {
+                radius: 1.5,
+            })).unwrap(); }),
+            Box::new(move |tree| { tree.replace_element(clone, Box::new(SdfCaaClone {
+                displacement: Vec3::splat(4.0),
+                neg_limit: Vec3::splat(-2.0),
+                pos_limit: Vec3::splat(2.0),
+            })).unwrap(); }),
+            Box::new(move |tree| tree.set_transform(root, Transform::from_xyz(1.0, 2.0, 3.0)).unwrap()),
+            Box::new(move |tree| tree.set_material(sphere, 3).unwrap()),
+        ];
+        for edit in edits.iter() {
+            let before = copy_buffer(persistent.buffer());
+            edit(&mut tree);
+            let update = persistent.update(&mut tree).unwrap();
+            assert!(!update.rebuilt);
+            assert!(update.patched_blocks > 0);
+            let fresh = tree.expanded().make_buffer();
+            assert_eq!(persistent.buffer().downtree_buffer, fresh.downtree_buffer);
+            assert_eq!(persistent.buffer().uptree_buffer, fresh.uptree_buffer);
+            check_ranges(&before, persistent.buffer(), &update);
+        }
+
+        // Nothing changed, nothing to do
+        let update = persistent.update(&mut tree).unwrap();
+        assert_eq!(update.patched_blocks, 0);
+        assert!(update.downtree_ranges.is_empty() && update.uptree_ranges.is_empty());
+
+        // Displacement amplitudes change the Lipschitz factors of the blocks above them too
+        tree.replace_element(clone, Box::new(SdfDisplace {
+            amplitude: 0.2,
+            noise: SdfNoise::new(SdfNoiseBasis::Simplex, 4),
+        })).unwrap();
+        persistent.update(&mut tree).unwrap();
+        let before = copy_buffer(persistent.buffer());
+        tree.set_param(clone, "amplitude", 0.4).unwrap();
+        let update = persistent.update(&mut tree).unwrap();
+        assert!(!update.rebuilt);
+        let fresh = tree.expanded().make_buffer();
+        assert_eq!(persistent.buffer().downtree_buffer, fresh.downtree_buffer);
+        assert_eq!(persistent.buffer().uptree_buffer, fresh.uptree_buffer);
+        assert!(fresh.downtree_buffer[0].lipschitz > before.downtree_buffer[0].lipschitz);
+        check_ranges(&before, persistent.buffer(), &update);
+    }
+
+    #[test]
+    fn test_patch_balanced() {
+        let mut tree = SdfTree::new(
+            balanced(5, Vec3::ZERO, 10.0)
+                .transform(Transform::from_rotation(Quat::from_rotation_y(0.3)))
+                .finalize()
+        );
+        let mut persistent = SdfPersistentBuffer::new(&mut tree).unwrap();
+        let total = persistent.buffer().buffer_len as usize;
+        check_distances(&tree, persistent.buffer());
+        let root = tree.root();
+        let mut leaf = root;
+        while !tree.children(leaf).is_empty() {
+            leaf = tree.children(leaf)[1];
+        }
+        let inner = tree.children(tree.children(root)[0])[0];
+
+        let edits: Vec<SdfEdit> = vec![
+            Box::new(move |tree| tree.set_transform(leaf, Transform::from_xyz(0.5, -2.0, 1.0)).unwrap()),
+            Box::new(move |tree| tree.set_transform(inner, Transform::from_xyz(0.0, 3.0, 0.0)).unwrap()),
+            Box::new(move |tree| tree.set_transform(root, Transform::from_xyz(1.0, 2.0, 3.0)).unwrap()),
+        ];
+        for edit in edits.iter() {
+            let before = copy_buffer(persistent.buffer());
+            edit(&mut tree);
+            let update = persistent.update(&mut tree).unwrap();
+            assert!(!update.rebuilt);
+            assert!(update.patched_blocks > 0 && update.patched_blocks < total / 4,
+                "Patched {} of {} blocks!", update.patched_blocks, total);
+            check_ranges(&before, persistent.buffer(), &update);
+            check_distances(&tree, persistent.buffer());
+        }
+
+        // Spheres only have downtree data, so changing one can't dirty the uptree buffer
+        tree.replace_element(leaf, Box::new(SdfSphere {
+            radius: 2.0,
+        })).unwrap();
+        let update = persistent.update(&mut tree).unwrap();
+        assert!(update.uptree_ranges.is_empty());
+        assert!(!update.downtree_ranges.is_empty());
+        check_distances(&tree, persistent.buffer());
+
+        // ...but unions keep their smoothing radius in the uptree buffer
+        let before = copy_buffer(persistent.buffer());
+        tree.replace_element(inner, Box::new(SdfUnion::new(0.5))).unwrap();
+        let update = persistent.update(&mut tree).unwrap();
+        assert_eq!(update.uptree_ranges.len(), 1);
+        check_ranges(&before, persistent.buffer(), &update);
+
+        // Elements that can't be encoded are reported, and leave the buffer as it was
+        let closure = SdfClosure {
+            min: Vec3::splat(-1.0),
+            max: Vec3::splat(1.0),
+            distance: Arc::new(|point: Vec3| point.length() - 1.0),
+            lipschitz: 1.0,
+        };
+        tree.replace_element(leaf, Box::new(closure.clone())).unwrap();
+        let before = copy_buffer(persistent.buffer());
+        assert!(persistent.update(&mut tree).is_err());
+        assert!(persistent.buffer().downtree_buffer == before.downtree_buffer);
+        assert!(SdfPersistentBuffer::new(&mut SdfTree::new(SdfBuilder::primitive(closure).finalize())).is_err());
+    }
+
+    #[test]
+    fn test_patch_wide_union() {
+        // Moving an interior sphere keeps the fitted frames of the wide union, so only the blocks
+        // along its path through the split hierarchy need patching
+        let centers = (0..48)
+            .map(|i| Vec3::new((i % 4) as f32, (i / 4 % 4) as f32, (i / 16) as f32) * 5.0)
+            .collect::<Vec<Vec3>>();
+        let wide = centers[1..].iter()
+            .fold(
+                sphere_at(centers[0], 1.0).operation(union()),
+                |acc, center| acc.with(sphere_at(*center, 1.0))
+            );
+        // Single child unions are collapsed into their child when expanded
+        let frame = Transform::from_xyz(0.0, 1.0, 0.0).with_rotation(Quat::from_rotation_z(0.2));
+        let mut tree = SdfTree::new(wide.operation(union()).transform(frame).finalize());
+        let mut persistent = SdfPersistentBuffer::new(&mut tree).unwrap();
+        let total = persistent.buffer().buffer_len as usize;
+        check_distances(&tree, persistent.buffer());
+
+        let root = tree.root();
+        let wide = tree.children(root)[0];
+        let prop = tree.children(wide)[21];
+        tree.set_transform(prop, Transform::from_translation(centers[21] + Vec3::new(0.5, 0.0, -0.5))).unwrap();
+        let update = persistent.update(&mut tree).unwrap();
+        assert!(!update.rebuilt);
+        assert!(update.patched_blocks < total / 4, "Patched {} of {} blocks!", update.patched_blocks, total);
+        check_distances(&tree, persistent.buffer());
+
+        // Only the collapsed top block depends on the single child union
+        tree.set_transform(root, Transform::from_xyz(3.0, 0.0, -2.0)).unwrap();
+        let update = persistent.update(&mut tree).unwrap();
+        assert_eq!(update.patched_blocks, 1);
+        assert_eq!(update.downtree_ranges, vec![0..size_of::<SdfOperationBlock>()]);
+        check_distances(&tree, persistent.buffer());
+    }
+
+    #[test]
+    fn test_patch_side_data() {
+        let heightfield = |grid: SdfHeightGrid| SdfHeightfield {
+            half_size: Vec2::new(8.0, 6.0),
+            height: 4.0,
+            depth: 1.0,
+            grid: Arc::new(grid),
+        };
+        let mut tree = SdfTree::new(
+            sphere_at(Vec3::new(0.0, 6.0, 0.0), 2.0)
+                .operation(union())
+                .with(SdfBuilder::primitive(heightfield(ridge_grid(9, 7))))
+                .finalize()
+        );
+        let mut persistent = SdfPersistentBuffer::new(&mut tree).unwrap();
+        assert_eq!(persistent.buffer().side_buffer, ridge_grid(9, 7).packed());
+        check_distances(&tree, persistent.buffer());
+        let terrain = tree.children(tree.root())[1];
+
+        // Scaling the heights only touches the op-specific block
+        tree.set_param(terrain, "height", 6.0).unwrap();
+        let update = persistent.update(&mut tree).unwrap();
+        assert!(!update.rebuilt && update.side_ranges.is_empty());
+        check_distances(&tree, persistent.buffer());
+
+        // A new grid of the same size is copied over the old one
+        let before = copy_buffer(persistent.buffer());
+        let flat = SdfHeightGrid::new(9, 7, vec![0.25; 63]).unwrap();
+        tree.replace_element(terrain, Box::new(heightfield(flat))).unwrap();
+        let update = persistent.update(&mut tree).unwrap();
+        assert!(!update.rebuilt);
+        let side_bytes = before.side_buffer.len() * size_of::<f32>();
+        assert_eq!(update.side_ranges, vec![0..side_bytes]);
+        check_ranges(&before, persistent.buffer(), &update);
+        check_distances(&tree, persistent.buffer());
+
+        // ...but a bigger one doesn't fit
+        tree.replace_element(terrain, Box::new(heightfield(ridge_grid(17, 9)))).unwrap();
+        let update = persistent.update(&mut tree).unwrap();
+        assert!(update.rebuilt);
+        assert_eq!(persistent.buffer().side_buffer, ridge_grid(17, 9).packed());
+        check_distances(&tree, persistent.buffer());
+    }
+
+    #[test]
+    fn test_topology_rebuild() {
+        let mut tree = SdfTree::new(balanced(2, Vec3::ZERO, 4.0).finalize());
+        let mut persistent = SdfPersistentBuffer::new(&mut tree).unwrap();
+        let root = tree.root();
+        let new_sphere = tree.insert_child(root, sphere_at(Vec3::new(0.0, 0.0, 10.0), 2.0).finalize()).unwrap();
+        let update = persistent.update(&mut tree).unwrap();
+        assert!(update.rebuilt);
+        let len = persistent.buffer().buffer_len as usize;
+        assert_eq!(len, 9);
+        assert_eq!(update.downtree_ranges, vec![0..(len * size_of::<SdfOperationBlock>())]);
+        assert_eq!(update.uptree_ranges, vec![0..(len * size_of::<SdfOperationUptreeBlock>())]);
+        check_distances(&tree, persistent.buffer());
+
+        tree.remove(new_sphere).unwrap();
+        let update = persistent.update(&mut tree).unwrap();
+        assert!(update.rebuilt);
+        assert_eq!(persistent.buffer().buffer_len, 7);
+        check_distances(&tree, persistent.buffer());
+    }
+
+    #[test]
+    fn test_relative_origin() {
+        let builder = |center: Vec3| balanced(3, center, 8.0)
+            .with(sphere_at(center + Vec3::new(0.0, 12.0, 0.0), 0.5));
+        let near_tree = SdfTree::new(builder(Vec3::ZERO).finalize());
+        let near_node = near_tree.to_node(near_tree.root());
+        // Far enough out that f32 positions are only good to a few millimetres. Whole origins can be
+        // reached with the transforms of the leaves, the others need an f64 offset.
+        let cases = [
+            (DVec3::new(20000.0, 0.0, -30000.0), false),
+            (DVec3::new(20000.37, 0.0, -30000.81), true),
+        ];
+        let tolerance = if cfg!(feature = "f64") { 1e-4 } else { 1e-2 };
+        let mut rng = StdRng::seed_from_u64(49);
+        for (far, through_offset) in cases {
+            let mut far_tree = SdfTree::new(match through_offset {
+                true => builder(Vec3::ZERO).translate(far),
+                false => builder(far.as_vec3()),
+            }.finalize());
+
+            // Evaluated relative to a camera, the far tree matches the same tree near the origin
+            let camera = Vec3::new(0.3, 0.1, 0.2);
+            let buffer = far_tree.expanded().make_relative_buffer(far + camera.as_dvec3());
+            for _ in 0..200 {
+                let point = Vec3::new(rng.gen_range(-30.0..30.0), rng.gen_range(-30.0..30.0), rng.gen_range(-30.0..30.0));
+                let near_result = near_node.nearest_neighbor(camera + point).distance;
+                let buffer_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+                assert!(approx_eq!(f32, near_result, buffer_result, epsilon = tolerance),
+                    "Relative buffer failed! Near Result: {}, Buffer Result: {}", near_result, buffer_result);
+            }
+
+            // Moving the origin or the frames of unions gives the same blocks as making the buffer again
+            let mut persistent = SdfPersistentBuffer::new_relative(&mut far_tree, far).unwrap();
+            let root = far_tree.root();
+            let edits: Vec<SdfEdit> = vec![
+                Box::new(move |tree| tree.set_transform(root, Transform::from_rotation(Quat::from_rotation_y(0.3))).unwrap()),
+                Box::new(move |tree| tree.set_transform(root, Transform::from_xyz(1.0, 2.0, 3.0)).unwrap()),
+                Box::new(move |tree| tree.set_transform(root, Transform::identity()).unwrap()),
+            ];
+            for (i, edit) in edits.iter().enumerate() {
+                let origin = far + DVec3::new(i as f64 * 10.5, 0.25, -3.0);
+                let before = copy_buffer(persistent.buffer());
+                let update = persistent.set_origin(&mut far_tree, origin).unwrap();
+                assert!(!update.rebuilt);
+                check_ranges(&before, persistent.buffer(), &update);
+                let fresh = far_tree.expanded().make_relative_buffer(origin);
+                assert_eq!(persistent.buffer().downtree_buffer, fresh.downtree_buffer);
+
+                let before = copy_buffer(persistent.buffer());
+                edit(&mut far_tree);
+                let update = persistent.update(&mut far_tree).unwrap();
+                assert!(!update.rebuilt);
+                check_ranges(&before, persistent.buffer(), &update);
+                let fresh = far_tree.expanded().make_relative_buffer(origin);
+                assert_eq!(persistent.buffer().downtree_buffer, fresh.downtree_buffer);
+                assert_eq!(persistent.buffer().uptree_buffer, fresh.uptree_buffer);
+            }
+        }
+    }
+}
@@ -3,17 +3,18 @@ use super::{
     node::*,
     faux_shader::*,
 };
-use bevy::{
-    prelude::*,
-    reflect::TypeUuid,
-};
+use super::math::*;
+#[cfg(feature = "bevy")]
+use bevy::reflect::TypeUuid;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub struct SdfOpSpecificBlock {
     pub mat4s: [Mat4; 2],
     pub vec4s: [Vec4; 3],
     pub floats: [f32; 2],
+    // Start of the element's data in the side buffer, for elements that have any
+    pub side_offset: u32,
 }
 
 impl SdfOpSpecificBlock {
@@ -21,10 +22,34 @@ impl SdfOpSpecificBlock {
         mat4s: [Mat4::ZERO; 2],
         vec4s: [Vec4::ZERO; 3],
         floats: [0.0; 2],
+        side_offset: 0,
     };
 }
 
-#[derive(Debug, Clone, Copy)]
+// Vectors that #[derive(SdfElement)] can pack into vec4s, padded with zeros
+pub trait SdfBlockVector {
+    fn to_vec4(self) -> Vec4;
+}
+
+impl SdfBlockVector for Vec2 {
+    fn to_vec4(self) -> Vec4 {
+        self.extend(0.0).extend(0.0)
+    }
+}
+
+impl SdfBlockVector for Vec3 {
+    fn to_vec4(self) -> Vec4 {
+        self.extend(0.0)
+    }
+}
+
+impl SdfBlockVector for Vec4 {
+    fn to_vec4(self) -> Vec4 {
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub struct SdfOperationBlock {
     pub op_code: u32,
@@ -32,6 +57,10 @@ pub struct SdfOperationBlock {
     pub parent_is_union: bool,
     pub len: u32,
     pub level: u32,
+    // Material ID of primitives, unused by operations
+    pub material: u32,
+    // Lipschitz factor of the subtree, see SdfNode::lipschitz
+    pub lipschitz: f32,
     pub op_specific: SdfOpSpecificBlock,
     pub bounding_box: SdfBoundingBoxBlock,
     pub other_box: SdfBoundingBoxBlock,
@@ -44,25 +73,30 @@ impl SdfOperationBlock {
         parent_is_union: false,
         len: 0,
         level: 0,
+        material: 0,
+        lipschitz: 0.0,
         op_specific: SdfOpSpecificBlock::ZERO,
         bounding_box: SdfBoundingBoxBlock::ZERO,
         other_box: SdfBoundingBoxBlock::ZERO,
     };
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub struct SdfOperationUptreeBlock {
     pub op_code: u32,
     pub parent_is_union: bool,
+    // Same as the downtree block's, for scaling the result into a parent union
+    pub lipschitz: f32,
     pub op_specific: SdfOpSpecificBlock,
     pub level: u32,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub struct SdfBoundingBoxBlock {
     pub matrix: Mat4,
+    // Extents of the box, with the distance scale of its evaluation frame in w
     pub scale: Vec4,
     pub full_inverse: Mat4,
     pub trans_inverse: Mat4,
@@ -77,11 +111,14 @@ impl SdfBoundingBoxBlock {
     };
 }
 
-#[derive(TypeUuid)]
-#[uuid = "b2ad9d5c-eb4e-517b-98d7-1162e78ddadb"]
+// Bevy asset type of the buffer, for uploading it with the `bevy` feature
+#[cfg_attr(feature = "bevy", derive(TypeUuid))]
+#[cfg_attr(feature = "bevy", uuid = "b2ad9d5c-eb4e-517b-98d7-1162e78ddadb")]
 pub struct SdfTreeBuffer {
     pub downtree_buffer: Vec<SdfOperationBlock>,
     pub uptree_buffer: Vec<SdfOperationUptreeBlock>,
+    // Bulk element data that doesn't fit into op-specific blocks, like heightfield grids
+    pub side_buffer: Vec<f32>,
     pub buffer_len: u32,
 }
 
@@ -90,6 +127,7 @@ impl SdfTreeBuffer {
         SdfTreeBuffer {
             downtree_buffer: Vec::new(),
             uptree_buffer: Vec::new(),
+            side_buffer: Vec::new(),
             buffer_len: 0,
         }
     }
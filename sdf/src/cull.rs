@@ -0,0 +1,235 @@
+use super::math::*;
+use super::obb::*;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SdfCullStats {
+    pub total_nodes: usize,
+    pub visited_nodes: usize,
+    pub frustum_culled: usize,
+    pub occlusion_culled: usize,
+    pub collapsed_nodes: usize,
+    pub emitted_nodes: usize,
+}
+
+impl SdfCullStats {
+    pub fn culled_nodes(&self) -> usize {
+        self.frustum_culled + self.occlusion_culled
+    }
+}
+
+pub struct SdfCuller {
+    pub frustum: SdfFrustum,
+    pub eye: Vec3,
+    pub occluders: Vec<SdfBoundingBox>,
+}
+
+impl SdfCuller {
+    pub fn new(frustum: SdfFrustum, eye: Vec3) -> Self {
+        SdfCuller {
+            frustum,
+            eye,
+            occluders: Vec::new(),
+        }
+    }
+
+    pub fn from_camera(camera: &Transform, projection: Mat4) -> Self {
+        Self::new(
+            SdfFrustum::from_view_projection(projection * camera.compute_matrix().inverse()),
+            camera.translation,
+        )
+    }
+
+    /**
+     * Add solid, world space boxes that hide whatever is behind them from the eye.
+     *
+     * Occluders should be conservative, i.e. lie entirely inside of opaque geometry. Occluders
+     * that contain the eye are ignored.
+     */
+    pub fn with_occluders(mut self, occluders: Vec<SdfBoundingBox>) -> Self {
+        self.occluders = occluders.into_iter()
+            .filter(|occluder| !occluder.contains(self.eye))
+            .collect();
+        self
+    }
+
+    pub fn is_outside(&self, world_box: &SdfBoundingBox) -> bool {
+        world_box.classify_frustum(&self.frustum) == SdfIntersection::Outside
+    }
+
+    /**
+     * Test whether a world space box is entirely hidden behind a single occluder.
+     *
+     * The set of points that can't be seen past a convex occluder is itself convex, so it's
+     * enough to check that the sight line to every vertex of the box passes through the occluder.
+     */
+    pub fn is_occluded(&self, world_box: &SdfBoundingBox) -> bool {
+        let verts = world_box.verts();
+        self.occluders.iter()
+            .any(|occluder| verts.iter()
+                .all(|vert| match occluder.ray_intersect(self.eye, vert.truncate() - self.eye) {
+                    Some((t_enter, _)) => t_enter <= 1.0,
+                    None => false,
+                }))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Arc;
+    use crate::math::*;
+    use std::f32::consts::FRAC_PI_4;
+    use crate::{
+        cull::*,
+        node::*,
+        elements::*,
+        component::*,
+    };
+
+    // Row of spheres along the x-axis, with the camera looking down -z at the first few of them
+    fn sphere_row(count: usize) -> ExpandedSdfNode {
+        (1..count)
+            .fold(
+                SdfBuilder::primitive(SdfSphere {
+                    radius: 1.0,
+                })
+                .operation(SdfUnion::new(0.0)),
+                |acc, i| acc.with(
+                    SdfBuilder::primitive(SdfSphere {
+                        radius: 1.0,
+                    })
+                    .transform(Transform::from_xyz(i as f32 * 4.0, 0.0, 0.0))
+                )
+            )
+            .finalize()
+            .expanded()
+    }
+
+    fn camera() -> (Transform, Mat4) {
+        (
+            Transform::from_xyz(0.0, 0.0, 10.0),
+            Mat4::perspective_rh(FRAC_PI_4, 1.0, 0.1, 100.0),
+        )
+    }
+
+    /**
+     * Check that the skip counts and levels of a buffer describe a proper pre-order traversal,
+     * and that the uptree buffer is the matching post-order traversal.
+     */
+    fn validate_buffer(buffer: &SdfTreeBuffer) {
+        let blocks = &buffer.downtree_buffer;
+        assert_eq!(buffer.buffer_len as usize, blocks.len());
+        assert_eq!(blocks.len(), buffer.uptree_buffer.len());
+        for (i, block) in blocks.iter().enumerate() {
+            let subtree_len = blocks[i + 1..].iter()
+                .take_while(|other| other.level > block.level)
+                .count();
+            assert_eq!(block.len as usize, subtree_len, "Wrong skip count at block {}!", i);
+            if i == 0 {
+                assert_eq!(block.level, 1);
+            } else {
+                assert!(block.level <= blocks[i - 1].level + 1, "Level jumps by more than one at block {}!", i);
+            }
+        }
+        let mut ut_levels = Vec::new();
+        fn post_order(blocks: &[SdfOperationBlock], index: usize, levels: &mut Vec<u32>) {
+            let mut child = index + 1;
+            while child <= index + blocks[index].len as usize {
+                post_order(blocks, child, levels);
+                child += 1 + blocks[child].len as usize;
+            }
+            levels.push(blocks[index].level);
+        }
+        if !blocks.is_empty() {
+            post_order(blocks, 0, &mut ut_levels);
+        }
+        assert_eq!(
+            ut_levels,
+            buffer.uptree_buffer.iter().map(|block| block.level).collect::<Vec<u32>>()
+        );
+    }
+
+    fn primitive_centers(buffer: &SdfTreeBuffer) -> Vec<f32> {
+        let mut centers = buffer.downtree_buffer.iter()
+            .filter(|block| block.is_primitive)
+            .map(|block| block.bounding_box.matrix.w_axis.x)
+            .collect::<Vec<f32>>();
+        centers.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        centers
+    }
+
+    #[test]
+    fn test_frustum_cull() {
+        let tree = sphere_row(16);
+        let (cam, proj) = camera();
+        let culler = SdfCuller::from_camera(&cam, proj);
+        let (buffer, stats) = tree.make_culled_buffer(&culler).unwrap();
+        validate_buffer(&buffer);
+        validate_buffer(&tree.make_buffer());
+
+        // Only the spheres whose boxes are in view should survive
+        let expected = (0..16)
+            .map(|i| i as f32 * 4.0)
+            .filter(|x| !culler.is_outside(&SdfBoundingBox::from_transform(Transform::from_xyz(*x, 0.0, 0.0))))
+            .collect::<Vec<f32>>();
+        let centers = primitive_centers(&buffer);
+        assert!(!expected.is_empty() && expected.len() < 16);
+        assert_eq!(centers.len(), expected.len());
+        for (center, x) in centers.iter().zip(expected.iter()) {
+            assert!((center - x).abs() < 1e-4);
+        }
+
+        assert_eq!(stats.total_nodes, tree.node_count());
+        assert_eq!(stats.emitted_nodes, buffer.buffer_len as usize);
+        assert_eq!(stats.occlusion_culled, 0);
+        assert!(stats.frustum_culled > 0);
+        assert!(stats.visited_nodes < stats.total_nodes);
+        assert_eq!(
+            stats.total_nodes,
+            stats.emitted_nodes + stats.culled_nodes() + stats.collapsed_nodes
+        );
+    }
+
+    #[test]
+    fn test_occlusion_cull() {
+        let tree = sphere_row(3);
+        let (cam, proj) = camera();
+        // Wall between the camera and the first sphere
+        let wall = SdfBoundingBox::from_transform(
+            Transform::from_xyz(0.0, 0.0, 5.0).with_scale(Vec3::new(2.0, 2.0, 0.5))
+        );
+        let culler = SdfCuller::from_camera(&cam, proj).with_occluders(vec![wall]);
+        let (buffer, stats) = tree.make_culled_buffer(&culler).unwrap();
+        validate_buffer(&buffer);
+        assert_eq!(primitive_centers(&buffer), vec![4.0]);
+        assert_eq!(stats.occlusion_culled, 1);
+        assert_eq!(
+            stats.total_nodes,
+            stats.emitted_nodes + stats.culled_nodes() + stats.collapsed_nodes
+        );
+
+        // Occluders around the eye can't hide anything
+        let culler = SdfCuller::from_camera(&cam, proj)
+            .with_occluders(vec![SdfBoundingBox::from_transform(Transform::from_xyz(0.0, 0.0, 10.0))]);
+        assert!(culler.occluders.is_empty());
+    }
+
+    #[test]
+    fn test_cull_everything() {
+        let tree = sphere_row(4);
+        let (_, proj) = camera();
+        let culler = SdfCuller::from_camera(&Transform::from_xyz(0.0, 0.0, -10.0), proj);
+        let (buffer, stats) = tree.make_culled_buffer(&culler).unwrap();
+        assert_eq!(buffer.buffer_len, 0);
+        assert_eq!(stats.frustum_culled, stats.total_nodes);
+
+        // Elements that can't be encoded are reported instead of panicking
+        let (cam, _) = camera();
+        let closure = SdfBuilder::primitive(SdfClosure {
+            min: Vec3::splat(-1.0),
+            max: Vec3::splat(1.0),
+            distance: Arc::new(|point: Vec3| point.length() - 1.0),
+            lipschitz: 1.0,
+        }).finalize().expanded();
+        assert!(closure.make_culled_buffer(&SdfCuller::from_camera(&cam, proj)).is_err());
+    }
+}
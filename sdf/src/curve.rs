@@ -0,0 +1,366 @@
+use super::math::*;
+use super::{
+    node::SdfBuilder,
+    elements::{SdfUnion, SdfCapsule, SdfQuadBezierTube, SdfCubicBezierTube},
+    dual::*,
+};
+
+// Samples taken along a cubic bezier before refining the closest one
+pub(crate) const CUBIC_SAMPLES: usize = 16;
+// Newton steps taken from the closest sample
+pub(crate) const CUBIC_ITERATIONS: usize = 4;
+// Pieces of a cubic bezier that are bounded, and how often the ones that may be closest are halved
+pub(crate) const CUBIC_PIECES: usize = 16;
+pub(crate) const CUBIC_DEPTH: usize = 8;
+// Most pieces a curve is cut into, so tiny segment lengths can't build huge unions
+const MAX_CURVE_PIECES: usize = 4096;
+
+fn dot2(v: Vec3) -> f32 {
+    v.dot(v)
+}
+
+/**
+ * Distance to the hull of two spheres, from Inigo Quilez's round cone. When one sphere holds the
+ * other, the hull is just the bigger sphere.
+ */
+pub fn capsule_distance(p: Vec3, a: Vec3, b: Vec3, radius_a: f32, radius_b: f32) -> f32 {
+    capsule_distance_scalar(SdfVec3::from_vec3(p), a, b, radius_a, radius_b)
+}
+
+// Capsule distance in any scalar, for exact derivatives
+pub fn capsule_distance_scalar<S: SdfScalar>(p: SdfVec3<S>, a: Vec3, b: Vec3, radius_a: f32, radius_b: f32) -> S {
+    let ba = b - a;
+    let l2 = dot2(ba);
+    let rr = radius_a - radius_b;
+    let a2 = l2 - rr * rr;
+    let pa = p - SdfVec3::from_vec3(a);
+    if a2 <= 0.0 {
+        return (pa.length() - S::from_f32(radius_a))
+            .min((p - SdfVec3::from_vec3(b)).length() - S::from_f32(radius_b));
+    }
+    let il2 = S::from_f32(1.0 / l2);
+    let y = pa.dot(SdfVec3::from_vec3(ba));
+    let z = y - S::from_f32(l2);
+    let x2 = (pa * S::from_f32(l2) - SdfVec3::from_vec3(ba) * y).length_squared();
+    let y2 = y * y * S::from_f32(l2);
+    let z2 = z * z * S::from_f32(l2);
+    // Which part of the hull is nearest only depends on the values
+    let k = rr.signum() * rr * rr * x2.value();
+    if z.value().signum() * a2 * z2.value() > k {
+        (x2 + z2).sqrt() * il2 - S::from_f32(radius_b)
+    } else if y.value().signum() * a2 * y2.value() < k {
+        (x2 + y2).sqrt() * il2 - S::from_f32(radius_a)
+    } else {
+        ((x2 * S::from_f32(a2) * il2).sqrt() + y * S::from_f32(rr)) * il2 - S::from_f32(radius_a)
+    }
+}
+
+// Distance to the segment from a to b
+pub fn segment_distance(p: Vec3, a: Vec3, b: Vec3) -> f32 {
+    let pa = p - a;
+    let ba = b - a;
+    (pa - ba * (pa.dot(ba) / dot2(ba).max(f32::MIN_POSITIVE)).clamp(0.0, 1.0)).length()
+}
+
+// Exact distance to a quadratic bezier curve, by solving the cubic for the closest parameter
+pub fn quad_bezier_distance(pos: Vec3, [a, b, c]: [Vec3; 3]) -> f32 {
+    let ab = b - a;
+    let curve = a - 2.0 * b + c;
+    // Evenly spaced control points on a line make the cubic degenerate
+    if dot2(curve) < 1e-12 {
+        return segment_distance(pos, a, c);
+    }
+    let lin = ab * 2.0;
+    let d = a - pos;
+    let kk = 1.0 / dot2(curve);
+    let kx = kk * ab.dot(curve);
+    let ky = kk * (2.0 * dot2(ab) + d.dot(curve)) / 3.0;
+    let kz = kk * d.dot(ab);
+    let p = ky - kx * kx;
+    let q = kx * (2.0 * kx * kx - 3.0 * ky) + kz;
+    let h = q * q + 4.0 * p * p * p;
+    let at = |t: f32| dot2(d + (lin + curve * t) * t);
+    let res = if h >= 0.0 {
+        let h = h.sqrt();
+        let x = (Vec2::new(h, -h) - Vec2::splat(q)) / 2.0;
+        let uv = Vec2::new(x.x.signum() * x.x.abs().cbrt(), x.y.signum() * x.y.abs().cbrt());
+        at((uv.x + uv.y - kx).clamp(0.0, 1.0))
+    } else {
+        // Three real roots, of which the middle one is never the closest
+        let z = (-p).sqrt();
+        let v = (q / (p * z * 2.0)).clamp(-1.0, 1.0).acos() / 3.0;
+        let m = v.cos();
+        let n = v.sin() * 3_f32.sqrt();
+        let t = (Vec3::new(m + m, -n - m, n - m) * z - Vec3::splat(kx)).clamp(Vec3::ZERO, Vec3::ONE);
+        at(t.x).min(at(t.y))
+    };
+    res.sqrt()
+}
+
+pub fn quad_bezier_point([a, b, c]: [Vec3; 3], t: f32) -> Vec3 {
+    let s = 1.0 - t;
+    a * s * s + b * 2.0 * s * t + c * t * t
+}
+
+pub fn cubic_bezier_point([a, b, c, d]: [Vec3; 4], t: f32) -> Vec3 {
+    let s = 1.0 - t;
+    a * s * s * s + b * 3.0 * s * s * t + c * 3.0 * s * t * t + d * t * t * t
+}
+
+fn cubic_bezier_derivatives([a, b, c, d]: [Vec3; 4], t: f32) -> (Vec3, Vec3) {
+    let s = 1.0 - t;
+    (
+        (b - a) * 3.0 * s * s + (c - b) * 6.0 * s * t + (d - c) * 3.0 * t * t,
+        (c - b * 2.0 + a) * 6.0 * s + (d - c * 2.0 + b) * 6.0 * t,
+    )
+}
+
+// Control points of the part of a cubic bezier between two parameters
+pub fn cubic_bezier_piece(points: [Vec3; 4], t0: f32, t1: f32) -> [Vec3; 4] {
+    let (start, end) = (cubic_bezier_point(points, t0), cubic_bezier_point(points, t1));
+    let span = (t1 - t0) / 3.0;
+    [
+        start,
+        start + cubic_bezier_derivatives(points, t0).0 * span,
+        end - cubic_bezier_derivatives(points, t1).0 * span,
+        end,
+    ]
+}
+
+/**
+ * Lower bound on the distance to a cubic bezier curve, which lies in the hull of its control
+ * points. The hull lies within the distance of the inner points from the chord, which shrinks
+ * quadratically as the curve is cut into smaller pieces.
+ */
+fn cubic_hull_bound(pos: Vec3, [a, b, c, d]: [Vec3; 4]) -> f32 {
+    let deviation = segment_distance(b, a, d).max(segment_distance(c, a, d));
+    (segment_distance(pos, a, d) - deviation).max(0.0)
+}
+
+/**
+ * Distance to a cubic bezier curve. There's no closed form, so the closest of a fixed number of
+ * samples is refined with a few Newton steps, which gives a point on the curve and so a distance
+ * that's never too small. Pieces of the curve whose hull is nearer than that are halved a fixed
+ * number of times, and the nearest of the hulls of the final pieces is returned. That never
+ * overestimates the distance, and more halving only tightens it.
+ */
+pub fn cubic_bezier_distance(pos: Vec3, points: [Vec3; 4]) -> f32 {
+    let (mut t, mut best) = (0..=CUBIC_SAMPLES)
+        .map(|i| i as f32 / CUBIC_SAMPLES as f32)
+        .map(|t| (t, dot2(cubic_bezier_point(points, t) - pos)))
+        .fold((0.0, f32::INFINITY), |acc, sample| if sample.1 < acc.1 { sample } else { acc });
+    for _ in 0..CUBIC_ITERATIONS {
+        let offset = cubic_bezier_point(points, t) - pos;
+        let (first, second) = cubic_bezier_derivatives(points, t);
+        let slope = dot2(first) + offset.dot(second);
+        if slope <= f32::EPSILON {
+            break;
+        }
+        t = (t - offset.dot(first) / slope).clamp(0.0, 1.0);
+        best = best.min(dot2(cubic_bezier_point(points, t) - pos));
+    }
+    let upper = best.sqrt();
+
+    // Every halving replaces one piece on the stack with two, so it never holds more than this
+    let mut pieces = [(0.0, 0.0, 0); CUBIC_PIECES + CUBIC_DEPTH];
+    for (i, piece) in pieces.iter_mut().take(CUBIC_PIECES).enumerate() {
+        *piece = (i as f32 / CUBIC_PIECES as f32, (i + 1) as f32 / CUBIC_PIECES as f32, 0);
+    }
+    let (mut len, mut lower) = (CUBIC_PIECES, f32::INFINITY);
+    while len > 0 {
+        len -= 1;
+        let (t0, t1, depth) = pieces[len];
+        let bound = cubic_hull_bound(pos, cubic_bezier_piece(points, t0, t1));
+        if bound >= upper || depth == CUBIC_DEPTH {
+            lower = lower.min(bound);
+        } else {
+            let middle = (t0 + t1) / 2.0;
+            pieces[len] = (t0, middle, depth + 1);
+            pieces[len + 1] = (middle, t1, depth + 1);
+            len += 2;
+        }
+    }
+    lower
+}
+
+// Length of the control polygon, which is never shorter than the curve
+fn hull_length(points: &[Vec3]) -> f32 {
+    points.windows(2).map(|pair| (pair[1] - pair[0]).length()).sum()
+}
+
+// Number of pieces to cut a curve into, so that none is much longer than the segment length
+fn piece_count(points: &[Vec3], segment_length: f32) -> Result<usize, &'static str> {
+    if segment_length.is_nan() || segment_length <= 0.0 {
+        return Err("Segment length has to be positive!");
+    }
+    let pieces = (hull_length(points) / segment_length).ceil();
+    if pieces > MAX_CURVE_PIECES as f32 {
+        return Err("Segment length is too short for the curve!");
+    }
+    Ok((pieces as usize).max(1))
+}
+
+// Union of the pieces, so its expansion can prune the ones that are far away
+fn union_of(mut pieces: impl Iterator<Item = SdfBuilder>) -> SdfBuilder {
+    let first = pieces.next().expect("Curves have at least one piece");
+    let mut pieces = pieces.peekable();
+    if pieces.peek().is_none() {
+        return first;
+    }
+    pieces.fold(
+        first.operation(SdfUnion::new(0.0)),
+        |union, piece| union.with(piece),
+    )
+}
+
+/**
+ * Chain of capsules through the points, with the radius interpolated linearly between the radii
+ * of the points. Each segment is a primitive of its own in a union.
+ */
+pub fn polyline(points: &[Vec3], radii: &[f32]) -> Result<SdfBuilder, &'static str> {
+    if points.len() < 2 {
+        return Err("Polyline needs at least two points!");
+    }
+    if points.len() != radii.len() {
+        return Err("Polyline needs a radius for every point!");
+    }
+    Ok(union_of(points.windows(2).zip(radii.windows(2)).map(|(ends, radii)| {
+        SdfBuilder::primitive(SdfCapsule {
+            a: ends[0],
+            b: ends[1],
+            radius_a: radii[0],
+            radius_b: radii[1],
+        })
+    })))
+}
+
+/**
+ * Tube of constant radius around a quadratic bezier curve. Curves longer than the segment length
+ * are cut into pieces of equal parameter range, each an exact quadratic of its own.
+ */
+pub fn quad_bezier_tube(points: [Vec3; 3], radius: f32, segment_length: f32) -> Result<SdfBuilder, &'static str> {
+    let pieces = piece_count(&points, segment_length)?;
+    // Derivative of the curve at t
+    let tangent = |t: f32| ((points[1] - points[0]) * (1.0 - t) + (points[2] - points[1]) * t) * 2.0;
+    Ok(union_of((0..pieces).map(|i| {
+        let (t0, t1) = (i as f32 / pieces as f32, (i + 1) as f32 / pieces as f32);
+        let start = quad_bezier_point(points, t0);
+        SdfBuilder::primitive(SdfQuadBezierTube {
+            points: [
+                start,
+                start + tangent(t0) * (t1 - t0) / 2.0,
+                quad_bezier_point(points, t1),
+            ],
+            radius,
+        })
+    })))
+}
+
+// Tube of constant radius around a cubic bezier curve, cut into pieces like quadratic tubes
+pub fn cubic_bezier_tube(points: [Vec3; 4], radius: f32, segment_length: f32) -> Result<SdfBuilder, &'static str> {
+    let pieces = piece_count(&points, segment_length)?;
+    Ok(union_of((0..pieces).map(|i| {
+        SdfBuilder::primitive(SdfCubicBezierTube {
+            points: cubic_bezier_piece(points, i as f32 / pieces as f32, (i + 1) as f32 / pieces as f32),
+            radius,
+        })
+    })))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use float_cmp::approx_eq;
+    use crate::curve::*;
+
+    // Distance to a curve, by densely sampling it
+    fn sampled_distance(p: Vec3, curve: impl Fn(f32) -> Vec3) -> f32 {
+        (0..=20000)
+            .map(|i| (curve(i as f32 / 20000.0) - p).length())
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    fn random_point(rng: &mut ThreadRng, extent: f32) -> Vec3 {
+        Vec3::new(
+            rng.gen_range(-extent..extent),
+            rng.gen_range(-extent..extent),
+            rng.gen_range(-extent..extent),
+        )
+    }
+
+    #[test]
+    fn test_curve_distances() {
+        let mut rng = thread_rng();
+        for _ in 0..50 {
+            let quad = [random_point(&mut rng, 2.0), random_point(&mut rng, 2.0), random_point(&mut rng, 2.0)];
+            let cubic = [quad[0], quad[1], quad[2], random_point(&mut rng, 2.0)];
+            for _ in 0..10 {
+                let p = random_point(&mut rng, 3.0);
+                let expected = sampled_distance(p, |t| quad_bezier_point(quad, t));
+                let distance = quad_bezier_distance(p, quad);
+                assert!((distance - expected).abs() < 1e-3,
+                    "Quadratic bezier: {} instead of {}", distance, expected);
+                let expected = sampled_distance(p, |t| cubic_bezier_point(cubic, t));
+                let distance = cubic_bezier_distance(p, cubic);
+                // Never more than the distance, but close to it
+                assert!(distance <= expected + 1e-5 && expected - distance < 1e-3,
+                    "Cubic bezier: {} instead of {}", distance, expected);
+            }
+        }
+
+        // Capsules of constant radius are rounded segments
+        for _ in 0..100 {
+            let (a, b, p) = (random_point(&mut rng, 2.0), random_point(&mut rng, 2.0), random_point(&mut rng, 4.0));
+            assert!(approx_eq!(f32, capsule_distance(p, a, b, 0.5, 0.5), segment_distance(p, a, b) - 0.5, epsilon = 1e-4));
+        }
+
+        // Tapered capsules touch both end spheres, and fall back to the bigger one when it holds the other
+        let (a, b) = (Vec3::ZERO, Vec3::new(4.0, 0.0, 0.0));
+        assert!(approx_eq!(f32, capsule_distance(Vec3::new(-3.0, 0.0, 0.0), a, b, 1.0, 0.5), 2.0, epsilon = 1e-5));
+        assert!(approx_eq!(f32, capsule_distance(Vec3::new(7.0, 0.0, 0.0), a, b, 1.0, 0.5), 2.5, epsilon = 1e-5));
+        assert!(approx_eq!(f32, capsule_distance(Vec3::new(0.0, 9.0, 0.0), a, b, 5.0, 0.5), 4.0, epsilon = 1e-5));
+        assert!(capsule_distance(Vec3::new(2.0, 0.74, 0.0), a, b, 1.0, 0.5) < 0.0);
+    }
+
+    #[test]
+    fn test_curve_splitting() {
+        let mut rng = thread_rng();
+        let quad = [Vec3::new(-4.0, 0.0, 0.0), Vec3::new(0.0, 6.0, 2.0), Vec3::new(4.0, 0.0, -1.0)];
+        let cubic = [quad[0], Vec3::new(-1.0, 5.0, 0.0), Vec3::new(2.0, -5.0, 0.0), quad[2]];
+        let quad_tree = quad_bezier_tube(quad, 0.25, 1.0).unwrap().finalize();
+        let cubic_tree = cubic_bezier_tube(cubic, 0.25, 1.0).unwrap().finalize();
+        let short_tree = quad_bezier_tube(quad, 0.25, 100.0).unwrap().finalize();
+        // Long curves are cut up, short ones are a single primitive
+        assert!(quad_tree.slots().len() > 10 && cubic_tree.slots().len() > 10);
+        assert!(short_tree.slots().is_empty());
+
+        for _ in 0..200 {
+            let p = random_point(&mut rng, 6.0);
+            let expected = quad_bezier_distance(p, quad) - 0.25;
+            assert!(approx_eq!(f32, quad_tree.nearest_neighbor(p).distance, expected, epsilon = 1e-3));
+            assert!(approx_eq!(f32, short_tree.nearest_neighbor(p).distance, expected, epsilon = 1e-4));
+            let expected = sampled_distance(p, |t| cubic_bezier_point(cubic, t)) - 0.25;
+            assert!((cubic_tree.nearest_neighbor(p).distance - expected).abs() < 2e-3);
+        }
+
+        let line = polyline(
+            &[Vec3::ZERO, Vec3::new(2.0, 0.0, 0.0), Vec3::new(2.0, 2.0, 0.0)],
+            &[0.5, 0.25, 0.5],
+        ).unwrap().finalize();
+        assert_eq!(line.slots().len(), 2);
+        for _ in 0..100 {
+            let p = random_point(&mut rng, 4.0);
+            let expected = capsule_distance(p, Vec3::ZERO, Vec3::new(2.0, 0.0, 0.0), 0.5, 0.25)
+                .min(capsule_distance(p, Vec3::new(2.0, 0.0, 0.0), Vec3::new(2.0, 2.0, 0.0), 0.25, 0.5));
+            assert!(approx_eq!(f32, line.nearest_neighbor(p).distance, expected, epsilon = 1e-4));
+        }
+        assert!(quad_bezier_tube(quad, 0.25, 0.0).is_err());
+        assert!(cubic_bezier_tube(cubic, 0.25, -1.0).is_err());
+        assert!(cubic_bezier_tube(cubic, 0.25, f32::NAN).is_err());
+        assert!(cubic_bezier_tube(cubic, 0.25, 1e-6).is_err());
+        assert!(quad_bezier_tube(quad, 0.25, f32::MIN_POSITIVE).is_err());
+        assert!(polyline(&[Vec3::ZERO], &[1.0]).is_err());
+        assert!(polyline(&[Vec3::ZERO, Vec3::X], &[1.0]).is_err());
+    }
+}
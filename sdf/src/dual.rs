@@ -0,0 +1,598 @@
+use std::{fmt, ops::{Add, Sub, Mul, Div, Neg}};
+use super::math::*;
+use super::elements::SdfElement;
+
+/**
+ * Number that distances can be computed in, so the same distance function gives plain values in
+ * f32 or f64 and exact derivatives in dual numbers. Branches and min/max pick by the plain value,
+ * so derivatives follow whichever side was taken.
+ */
+pub trait SdfScalar: Copy + fmt::Debug
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    fn from_f32(value: f32) -> Self;
+    fn value(self) -> f32;
+    fn sqrt(self) -> Self;
+
+    fn abs(self) -> Self {
+        if self.value() < 0.0 { -self } else { self }
+    }
+
+    fn min(self, other: Self) -> Self {
+        if other.value() < self.value() { other } else { self }
+    }
+
+    fn max(self, other: Self) -> Self {
+        if other.value() > self.value() { other } else { self }
+    }
+
+    fn clamp(self, min: f32, max: f32) -> Self {
+        self.max(Self::from_f32(min)).min(Self::from_f32(max))
+    }
+}
+
+impl SdfScalar for f32 {
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    fn value(self) -> f32 {
+        self
+    }
+
+    fn sqrt(self) -> Self {
+        f32::sqrt(self)
+    }
+}
+
+impl SdfScalar for f64 {
+    fn from_f32(value: f32) -> Self {
+        value as f64
+    }
+
+    fn value(self) -> f32 {
+        self as f32
+    }
+
+    fn sqrt(self) -> Self {
+        f64::sqrt(self)
+    }
+
+    // Compared at full precision, instead of by the f32 value()
+    fn abs(self) -> Self {
+        if self < 0.0 { -self } else { self }
+    }
+
+    fn min(self, other: Self) -> Self {
+        if other < self { other } else { self }
+    }
+
+    fn max(self, other: Self) -> Self {
+        if other > self { other } else { self }
+    }
+}
+
+// Point whose coordinates are scalars, which is what generic distance functions take
+#[derive(Debug, Clone, Copy)]
+pub struct SdfVec3<S> {
+    pub x: S,
+    pub y: S,
+    pub z: S,
+}
+
+impl<S: SdfScalar> SdfVec3<S> {
+    pub fn new(x: S, y: S, z: S) -> Self {
+        SdfVec3 { x, y, z }
+    }
+
+    // Constant point, whose derivatives are zero
+    pub fn from_vec3(point: Vec3) -> Self {
+        SdfVec3::new(S::from_f32(point.x), S::from_f32(point.y), S::from_f32(point.z))
+    }
+
+    pub fn splat(value: S) -> Self {
+        SdfVec3::new(value, value, value)
+    }
+
+    pub fn value(&self) -> Vec3 {
+        Vec3::new(self.x.value(), self.y.value(), self.z.value())
+    }
+
+    pub fn abs(self) -> Self {
+        SdfVec3::new(self.x.abs(), self.y.abs(), self.z.abs())
+    }
+
+    pub fn max(self, other: Self) -> Self {
+        SdfVec3::new(self.x.max(other.x), self.y.max(other.y), self.z.max(other.z))
+    }
+
+    pub fn max_element(self) -> S {
+        self.x.max(self.y).max(self.z)
+    }
+
+    pub fn dot(self, other: Self) -> S {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn length_squared(self) -> S {
+        self.dot(self)
+    }
+
+    pub fn length(self) -> S {
+        self.length_squared().sqrt()
+    }
+
+    // Move the point by an affine matrix
+    pub fn transform_point(self, mat: Mat4) -> Self {
+        let row = |i: usize| {
+            let row = mat.row(i);
+            self.x * S::from_f32(row.x) + self.y * S::from_f32(row.y) + self.z * S::from_f32(row.z) + S::from_f32(row.w)
+        };
+        SdfVec3::new(row(0), row(1), row(2))
+    }
+}
+
+impl<S: SdfScalar> Add for SdfVec3<S> {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        SdfVec3::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl<S: SdfScalar> Sub for SdfVec3<S> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        SdfVec3::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl<S: SdfScalar> Mul<S> for SdfVec3<S> {
+    type Output = Self;
+
+    fn mul(self, other: S) -> Self {
+        SdfVec3::new(self.x * other, self.y * other, self.z * other)
+    }
+}
+
+// Value with its gradient with respect to a point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dual3 {
+    pub value: f32,
+    pub gradient: Vec3,
+}
+
+impl Dual3 {
+    // Coordinates of a point as the variables that gradients are taken with respect to
+    pub fn variables(point: Vec3) -> SdfVec3<Self> {
+        let var = |value: f32, gradient: Vec3| Dual3 { value, gradient };
+        SdfVec3::new(var(point.x, Vec3::X), var(point.y, Vec3::Y), var(point.z, Vec3::Z))
+    }
+
+    // Apply a function given its value and derivative at this value
+    fn chain(self, value: f32, derivative: f32) -> Self {
+        Dual3 {
+            value,
+            gradient: self.gradient * derivative,
+        }
+    }
+
+    fn recip(self) -> Self {
+        self.chain(1.0 / self.value, -1.0 / (self.value * self.value))
+    }
+}
+
+impl SdfScalar for Dual3 {
+    fn from_f32(value: f32) -> Self {
+        Dual3 {
+            value,
+            gradient: Vec3::ZERO,
+        }
+    }
+
+    fn value(self) -> f32 {
+        self.value
+    }
+
+    fn sqrt(self) -> Self {
+        let root = self.value.sqrt();
+        self.chain(root, 0.5 / root)
+    }
+}
+
+impl Add for Dual3 {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Dual3 {
+            value: self.value + other.value,
+            gradient: self.gradient + other.gradient,
+        }
+    }
+}
+
+impl Sub for Dual3 {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self + -other
+    }
+}
+
+impl Mul for Dual3 {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Dual3 {
+            value: self.value * other.value,
+            gradient: self.gradient * other.value + other.gradient * self.value,
+        }
+    }
+}
+
+impl Div for Dual3 {
+    type Output = Self;
+
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn div(self, other: Self) -> Self {
+        self * other.recip()
+    }
+}
+
+impl Neg for Dual3 {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Dual3 {
+            value: -self.value,
+            gradient: -self.gradient,
+        }
+    }
+}
+
+// Value with its gradient and Hessian with respect to a point, for curvature
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HyperDual3 {
+    pub value: f32,
+    pub gradient: Vec3,
+    pub hessian: Mat3,
+}
+
+fn outer(a: Vec3, b: Vec3) -> Mat3 {
+    Mat3::from_cols(a * b.x, a * b.y, a * b.z)
+}
+
+impl HyperDual3 {
+    pub fn variables(point: Vec3) -> SdfVec3<Self> {
+        let var = |value: f32, gradient: Vec3| HyperDual3 { value, gradient, hessian: Mat3::ZERO };
+        SdfVec3::new(var(point.x, Vec3::X), var(point.y, Vec3::Y), var(point.z, Vec3::Z))
+    }
+
+    // Apply a function given its value and first and second derivatives at this value
+    fn chain(self, value: f32, derivative: f32, second: f32) -> Self {
+        HyperDual3 {
+            value,
+            gradient: self.gradient * derivative,
+            hessian: self.hessian * derivative + outer(self.gradient, self.gradient) * second,
+        }
+    }
+
+    fn recip(self) -> Self {
+        let recip = 1.0 / self.value;
+        self.chain(recip, -recip * recip, 2.0 * recip * recip * recip)
+    }
+
+    /**
+     * Sum of the principal curvatures of the level set through the point, the divergence of the
+     * unit gradient. Positive where the surface bends away from its normal, 2/r on a sphere.
+     */
+    pub fn curvature(&self) -> f32 {
+        let (g, h) = (self.gradient, self.hessian);
+        let trace = h.x_axis.x + h.y_axis.y + h.z_axis.z;
+        let length = g.length();
+        (length * length * trace - g.dot(h * g)) / (length * length * length)
+    }
+}
+
+impl SdfScalar for HyperDual3 {
+    fn from_f32(value: f32) -> Self {
+        HyperDual3 {
+            value,
+            gradient: Vec3::ZERO,
+            hessian: Mat3::ZERO,
+        }
+    }
+
+    fn value(self) -> f32 {
+        self.value
+    }
+
+    fn sqrt(self) -> Self {
+        let root = self.value.sqrt();
+        self.chain(root, 0.5 / root, -0.25 / (root * self.value))
+    }
+}
+
+impl Add for HyperDual3 {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        HyperDual3 {
+            value: self.value + other.value,
+            gradient: self.gradient + other.gradient,
+            hessian: self.hessian + other.hessian,
+        }
+    }
+}
+
+impl Sub for HyperDual3 {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self + -other
+    }
+}
+
+impl Mul for HyperDual3 {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        HyperDual3 {
+            value: self.value * other.value,
+            gradient: self.gradient * other.value + other.gradient * self.value,
+            hessian: self.hessian * other.value + other.hessian * self.value
+                + outer(self.gradient, other.gradient) + outer(other.gradient, self.gradient),
+        }
+    }
+}
+
+impl Div for HyperDual3 {
+    type Output = Self;
+
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn div(self, other: Self) -> Self {
+        self * other.recip()
+    }
+}
+
+impl Neg for HyperDual3 {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        HyperDual3 {
+            value: -self.value,
+            gradient: -self.gradient,
+            hessian: self.hessian * -1.0,
+        }
+    }
+}
+
+// Dual numbers that trees can be evaluated in, through the object-safe methods of SdfElement
+pub trait SdfDualScalar: SdfScalar {
+    fn variables(point: Vec3) -> SdfVec3<Self>;
+    fn element_distance(element: &dyn SdfElement, point: SdfVec3<Self>) -> Option<Self>;
+    fn element_combine(element: &dyn SdfElement, point: SdfVec3<Self>, slots: &[Self]) -> Option<Self>;
+}
+
+impl SdfDualScalar for Dual3 {
+    fn variables(point: Vec3) -> SdfVec3<Self> {
+        Dual3::variables(point)
+    }
+
+    fn element_distance(element: &dyn SdfElement, point: SdfVec3<Self>) -> Option<Self> {
+        element.distance_dual(point)
+    }
+
+    fn element_combine(element: &dyn SdfElement, point: SdfVec3<Self>, slots: &[Self]) -> Option<Self> {
+        element.combine_dual(point, slots)
+    }
+}
+
+impl SdfDualScalar for HyperDual3 {
+    fn variables(point: Vec3) -> SdfVec3<Self> {
+        HyperDual3::variables(point)
+    }
+
+    fn element_distance(element: &dyn SdfElement, point: SdfVec3<Self>) -> Option<Self> {
+        element.distance_hyper_dual(point)
+    }
+
+    fn element_combine(element: &dyn SdfElement, point: SdfVec3<Self>, slots: &[Self]) -> Option<Self> {
+        element.combine_hyper_dual(point, slots)
+    }
+}
+
+/**
+ * Distance to a box from how far a point is past each of its faces, |p| - half extents. Inside the
+ * box the outside part is flat, and its length has no derivative there, so it's left out.
+ */
+pub fn box_distance_scalar<S: SdfScalar>(q: SdfVec3<S>) -> S {
+    let zero = S::from_f32(0.0);
+    let outside = q.max(SdfVec3::splat(zero));
+    let outside = if outside.length_squared().value() > 0.0 { outside.length() } else { zero };
+    outside + q.max_element().min(zero)
+}
+
+// Same blend as material::smooth_min, in any scalar
+pub fn smooth_min_scalar<S: SdfScalar>(a: S, b: S, radius: f32) -> S {
+    if radius <= 0.0 || !a.value().is_finite() || !b.value().is_finite() {
+        return a.min(b);
+    }
+    let one = S::from_f32(1.0);
+    let radius = S::from_f32(radius);
+    let h = (S::from_f32(0.5) + S::from_f32(0.5) * (a - b) / radius).clamp(0.0, 1.0);
+    a + (b - a) * h - radius * h * (one - h)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use crate::{
+        dual::*,
+        node::*,
+        elements::*,
+        noise::*,
+    };
+
+    const STEP: f32 = 2e-4;
+
+    fn central_gradient<F: Fn(Vec3) -> f32>(distance: F, point: Vec3) -> Vec3 {
+        Vec3::new(
+            distance(point + Vec3::X * STEP) - distance(point - Vec3::X * STEP),
+            distance(point + Vec3::Y * STEP) - distance(point - Vec3::Y * STEP),
+            distance(point + Vec3::Z * STEP) - distance(point - Vec3::Z * STEP),
+        ) / (2.0 * STEP)
+    }
+
+    #[test]
+    fn test_dual_distance() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let sdf_tree = SdfBuilder::primitive(SdfSphere {
+                radius: 1.0,
+            })
+            .transform(Transform::from_xyz(1.5, 0.0, 0.0))
+            .operation(SdfUnion::new(0.5))
+            .with(SdfBuilder::primitive(SdfCapsule {
+                    a: Vec3::new(0.0, -1.0, 0.0),
+                    b: Vec3::new(0.0, 1.5, 0.0),
+                    radius_a: 0.6,
+                    radius_b: 0.3,
+                })
+                .transform(Transform::from_rotation(Quat::from_rotation_z(0.4)).with_scale(Vec3::splat(1.5))))
+            .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 0.4,
+                })
+                .operation(SdfMorph {
+                    t: 0.3,
+                })
+                .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 0.8,
+                }))
+                .transform(Transform::from_xyz(-1.5, 1.0, 0.5)))
+            .finalize();
+
+        for _ in 0..200 {
+            let point = Vec3::new(rng.gen_range(-3.0..3.0), rng.gen_range(-3.0..3.0), rng.gen_range(-3.0..3.0));
+            // Duals carry the same values as nearest_neighbor, with gradients that match its slope
+            let dual = sdf_tree.dual_distance::<Dual3>(point).unwrap();
+            let distance = sdf_tree.nearest_neighbor(point).distance;
+            assert!((dual.value - distance).abs() < 1e-5, "Dual value {} instead of {}", dual.value, distance);
+            let numeric = central_gradient(|point| sdf_tree.nearest_neighbor(point).distance, point);
+            assert!((dual.gradient - numeric).length() < 1e-2, "Gradient {} instead of {} at {}", dual.gradient, numeric, point);
+
+            // Second order duals agree with the first order ones, and their Hessian with its slope
+            let hyper = sdf_tree.dual_distance::<HyperDual3>(point).unwrap();
+            assert!((hyper.value - dual.value).abs() < 1e-6 && (hyper.gradient - dual.gradient).length() < 1e-5);
+            let numeric = [Vec3::X, Vec3::Y, Vec3::Z].map(|axis| central_gradient(
+                |point| sdf_tree.dual_distance::<Dual3>(point).unwrap().gradient.dot(axis),
+                point,
+            ));
+            let numeric = Mat3::from_cols(numeric[0], numeric[1], numeric[2]);
+            // Differences that straddle a seam between pieces of a distance aren't symmetric
+            if (numeric - numeric.transpose()).to_cols_array().iter().any(|x| x.abs() > 1e-2) {
+                continue;
+            }
+            for (exact, approx) in hyper.hessian.to_cols_array().iter().zip(numeric.to_cols_array()) {
+                assert!((exact - approx).abs() < 2e-2 * (1.0 + exact.abs()), "Hessian {} instead of {} at {}", hyper.hessian, numeric, point);
+            }
+        }
+
+        // Curvature of a sphere is 2/r anywhere around it, and f64 gives the same distances
+        let sphere = SdfSphere {
+            radius: 2.0,
+        };
+        let sphere_tree = SdfBuilder::primitive(sphere.clone())
+            .transform(Transform::from_xyz(1.0, 2.0, 3.0))
+            .finalize();
+        for _ in 0..20 {
+            let dir = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)).normalize();
+            let point = Vec3::new(1.0, 2.0, 3.0) + dir * 2.0;
+            assert!((sphere_tree.curvature(point).unwrap() - 1.0).abs() < 1e-3);
+            assert!((sphere_tree.normal(point) - dir).length() < 1e-4);
+            let wide = sphere.distance_scalar(SdfVec3::<f64>::from_vec3(dir * 5.0));
+            assert!((wide - 3.0).abs() < 1e-6);
+        }
+
+        // Trees with an element without dual distances still get normals, from differences
+        let tube = SdfBuilder::primitive(SdfQuadBezierTube {
+                points: [Vec3::new(-1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0)],
+                radius: 0.2,
+            })
+            .finalize();
+        assert!(tube.dual_distance::<Dual3>(Vec3::Y).is_none());
+        assert!((tube.normal(Vec3::new(0.0, 1.0, 0.0)).length() - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_dual_elements() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let box_frame = SdfBoxFrame {
+            dimension: Vec3::new(1.0, 0.8, 0.6),
+            thickness: 0.1,
+        };
+        let terrain = SdfTerrain {
+            half_size: Vec2::new(3.0, 2.0),
+            amplitude: 0.5,
+            depth: 0.5,
+            noise: SdfNoise::new(SdfNoiseBasis::Simplex, 3).with_octaves(3, 2.0, 0.5),
+        };
+        let trees = [SdfNoiseBasis::Value, SdfNoiseBasis::Perlin, SdfNoiseBasis::Simplex]
+            .map(|basis| (format!("{:?} displacement", basis), SdfBuilder::primitive(SdfSphere {
+                    radius: 1.5,
+                })
+                .operation(SdfDisplace {
+                    amplitude: 0.2,
+                    noise: SdfNoise::new(basis, 1).with_frequency(1.3).with_octaves(2, 2.0, 0.5),
+                })
+                .finalize()))
+            .into_iter()
+            .chain([
+                ("Box frame".to_string(), SdfBuilder::primitive(box_frame.clone())
+                    .transform(Transform::from_rotation(Quat::from_rotation_y(0.3)))
+                    .finalize()),
+                ("Terrain".to_string(), SdfBuilder::primitive(terrain.clone()).finalize()),
+            ]);
+
+        for (name, sdf_tree) in trees {
+            let mut checked = 0;
+            for _ in 0..200 {
+                let point = Vec3::new(rng.gen_range(-2.5..2.5), rng.gen_range(-2.0..2.0), rng.gen_range(-2.0..2.0));
+                let dual = sdf_tree.dual_distance::<Dual3>(point).unwrap();
+                let distance = sdf_tree.nearest_neighbor(point).distance;
+                assert!((dual.value - distance).abs() < 1e-5, "{} dual value {} instead of {}", name, dual.value, distance);
+                let hyper = sdf_tree.dual_distance::<HyperDual3>(point).unwrap();
+                assert!((hyper.value - dual.value).abs() < 1e-6 && (hyper.gradient - dual.gradient).length() < 1e-5);
+
+                // Seams between the pieces of a distance, like the edges of the box frame, have no slope
+                let numeric = central_gradient(|point| sdf_tree.nearest_neighbor(point).distance, point);
+                let second = central_gradient(|point| sdf_tree.nearest_neighbor(point).distance, point + Vec3::splat(STEP));
+                if (numeric - second).length() > 0.05 {
+                    continue;
+                }
+                assert!((dual.gradient - numeric).length() < 1e-2, "{} gradient {} instead of {} at {}", name, dual.gradient, numeric, point);
+                checked += 1;
+            }
+            assert!(checked > 150, "{} only checked at {} points", name, checked);
+        }
+
+        // Distances in f64 agree with the f32 ones
+        for _ in 0..50 {
+            let point = Vec3::new(rng.gen_range(-2.5..2.5), rng.gen_range(-2.0..2.0), rng.gen_range(-2.0..2.0));
+            let wide_point = SdfVec3::<f64>::from_vec3(point);
+            assert!((box_frame.distance_scalar(wide_point) - box_frame.distance_to(point) as f64).abs() < 1e-5);
+            assert!((terrain.distance_scalar(wide_point) - terrain.distance_to(point) as f64).abs() < 1e-5);
+            let noise = terrain.noise;
+            assert!((noise.sample_scalar(wide_point) - noise.sample(point) as f64).abs() < 1e-5);
+        }
+        // Values that only differ beyond f32 precision are still told apart
+        let (low, high) = (1.0_f64, 1.0 + 1e-12);
+        assert_eq!((SdfScalar::min(high, low), SdfScalar::max(low, high)), (low, high));
+        assert_eq!(SdfScalar::abs(-1e-60_f64), 1e-60);
+    }
+}
@@ -1,9 +1,19 @@
-use std::fmt;
-use bevy::prelude::*;
+use std::{fmt, sync::Arc};
+use super::math::*;
+pub use sdf_derive::SdfElement;
 use super::{
     node::*, 
     obb::*,
+    material::SdfMaterialMix,
+    noise::SdfNoise,
+    heightfield::*,
+    mesh::SdfTriangleMesh,
+    grid::*,
+    shape2d::SdfShape2d,
+    curve::*,
     component::*,
+    interval::*,
+    dual::*,
 };
 
 pub struct SdfElementInfo {
@@ -60,8 +70,18 @@ impl PartialEq for SdfElementInfo {
     }
 }
 
-pub trait SdfElement: fmt::Debug {
+/**
+ * The parts of an element that #[derive(SdfElement)] writes: its op info, cloning it into a box,
+ * and packing it into op-specific blocks.
+ */
+pub trait SdfElementCore {
     fn get_info(&self) -> SdfElementInfo;
+    fn box_clone(&self) -> Box<dyn SdfElement>;
+    fn get_dt_specific_block(&self) -> SdfOpSpecificBlock;
+    fn get_ut_specific_block(&self) -> SdfOpSpecificBlock;
+}
+
+pub trait SdfElement: SdfElementCore + fmt::Debug {
     fn get_bbox(&self, slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox;
     fn downtree_transform(&self, point: Vec3) -> Vec3 {
         point
@@ -69,161 +89,973 @@ pub trait SdfElement: fmt::Debug {
     fn uptree_transform(&self, point: Vec3) -> Vec3 {
         point
     }
+    // Region that downtree_transform moves the points of a region into, so override them together
+    fn downtree_interval(&self, region: SdfAabb) -> SdfAabb {
+        region
+    }
     fn distance_to(&self, point: Vec3) -> f32 {
         point.length()
     }
-    fn clone(&self) -> Box<dyn SdfElement>;
-    fn get_dt_specific_block(&self) -> SdfOpSpecificBlock {
-        SdfOpSpecificBlock::ZERO
+    // Bounds on distance_to over a region, for primitives that can map intervals
+    fn distance_interval(&self, _region: SdfAabb) -> Option<SdfInterval> {
+        None
     }
-    fn get_ut_specific_block(&self) -> SdfOpSpecificBlock {
-        SdfOpSpecificBlock::ZERO
+    // distance_to in dual numbers, for primitives whose distance is generic over SdfScalar
+    fn distance_dual(&self, _point: SdfVec3<Dual3>) -> Option<Dual3> {
+        None
+    }
+    fn distance_hyper_dual(&self, _point: SdfVec3<HyperDual3>) -> Option<HyperDual3> {
+        None
+    }
+    // Distance over which a union blends its children; only meaningful for unions
+    fn blend_radius(&self) -> f32 {
+        0.0
+    }
+    // How a union splits its slots into a binary hierarchy, see SdfNode::split_slots()
+    fn split_method(&self) -> SdfSplitMethod {
+        SdfSplitMethod::default()
+    }
+    // Numeric fields that animation tracks can target, by name
+    fn get_param(&self, _name: &str) -> Option<f32> {
+        None
+    }
+    fn set_param(&mut self, _name: &str, _value: f32) -> Result<(), &'static str> {
+        Err("Unknown element parameter!")
+    }
+    // Bulk data that's packed into the side buffer, with its offset stored in the downtree block
+    fn side_data(&self) -> &[f32] {
+        &[]
+    }
+    /**
+     * Bound on how much faster than the distance to the surface the value of this element can
+     * change, given children whose values change at most as fast as theirs. The distances of a
+     * subtree are divided by the product of these factors along its steepest path, up to the
+     * nearest union, so sphere tracing and pruning never overshoot. See SdfNode::lipschitz.
+     */
+    fn lipschitz(&self) -> f32 {
+        1.0
+    }
+    // Point that the slots of an operation that isn't a union are evaluated at, like a projection
+    fn slot_point(&self, point: Vec3) -> Vec3 {
+        point
+    }
+    // Combine the results of the slots of an operation that isn't a union, at a point in its frame
+    fn combine(&self, _point: Vec3, slots: &[(f32, SdfMaterialMix)]) -> (f32, SdfMaterialMix) {
+        slots[0]
+    }
+    // Bounds on what combine gives over a region, for operations that can map intervals
+    fn combine_interval(&self, _region: SdfAabb, _slots: &[SdfInterval]) -> Option<SdfInterval> {
+        None
+    }
+    // combine in dual numbers, for operations whose combine is generic over SdfScalar
+    fn combine_dual(&self, _point: SdfVec3<Dual3>, _slots: &[Dual3]) -> Option<Dual3> {
+        None
+    }
+    fn combine_hyper_dual(&self, _point: SdfVec3<HyperDual3>, _slots: &[HyperDual3]) -> Option<HyperDual3> {
+        None
+    }
+    // Primitives expand to themselves, operations have to expand their slots
+    fn expand(&self, this_node: &SdfNode) -> ExpandedSdfNode {
+        ExpandedSdfNode::primitive(this_node.bbox.unwrap(), self.box_clone())
     }
-    fn expand(&self, this_node: &SdfNode) -> ExpandedSdfNode;
 }
 
-#[derive(Debug)]
+impl Clone for Box<dyn SdfElement> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// Field and component index of a vector parameter, named like "displacement.x"
+fn vec3_param(name: &str) -> Option<(&str, usize)> {
+    let (field, component) = name.split_once('.')?;
+    Some((field, ["x", "y", "z"].iter().position(|other| *other == component)?))
+}
+
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 0, primitive)]
 pub struct SdfSphere {
+    #[sdf(floats = 0)]
     pub radius: f32,
 }
 
-impl SdfElement for SdfSphere {
-    fn get_info(&self) -> SdfElementInfo {
-        SdfElementInfo::primitive_info(0)
+impl SdfSphere {
+    pub fn distance_scalar<S: SdfScalar>(&self, point: SdfVec3<S>) -> S {
+        point.length() - S::from_f32(self.radius)
     }
+}
 
+impl SdfElement for SdfSphere {
     fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
         SdfBoundingBox::from_transform(Transform::from_scale(Vec3::splat(self.radius)))
     }
 
-    fn clone(&self) -> Box<dyn SdfElement> {
-        Box::new(SdfSphere {
-            radius: self.radius
-        })
+    fn distance_to(&self, point: Vec3) -> f32 {
+        self.distance_scalar(SdfVec3::from_vec3(point))
     }
 
-    fn distance_to(&self, point: Vec3) -> f32 {
-        point.length() - self.radius
+    fn distance_dual(&self, point: SdfVec3<Dual3>) -> Option<Dual3> {
+        Some(self.distance_scalar(point))
     }
 
-    fn get_dt_specific_block(&self) -> SdfOpSpecificBlock {
-        let mut ret = SdfOpSpecificBlock::ZERO;
-        ret.floats[0] = self.radius;
-        ret
+    fn distance_hyper_dual(&self, point: SdfVec3<HyperDual3>) -> Option<HyperDual3> {
+        Some(self.distance_scalar(point))
     }
 
-    fn expand(&self, this_node: &SdfNode) -> ExpandedSdfNode {
-        ExpandedSdfNode::primitive(this_node.bbox.unwrap(), self.clone())
+    fn distance_interval(&self, region: SdfAabb) -> Option<SdfInterval> {
+        Some(region.norm() - self.radius)
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "radius" => Some(self.radius),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match name {
+            "radius" => self.radius = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug)]
-struct SdfBoxFrame {
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 5, primitive)]
+pub(crate) struct SdfBoxFrame {
+    #[sdf(vec4s = 0)]
     pub dimension: Vec3,
+    #[sdf(floats = 0)]
     pub thickness: f32,
 }
 
+impl SdfBoxFrame {
+    pub fn from_block(block: &SdfOpSpecificBlock) -> Self {
+        SdfBoxFrame {
+            dimension: block.vec4s[0].truncate(),
+            thickness: block.floats[0],
+        }
+    }
+
+    // Edges of the box, `thickness` thick, from Inigo Quilez's box frame
+    pub fn distance_scalar<S: SdfScalar>(&self, point: SdfVec3<S>) -> S {
+        let thickness = SdfVec3::splat(S::from_f32(self.thickness));
+        let p = point.abs() - SdfVec3::from_vec3(self.dimension);
+        let q = (p + thickness).abs() - thickness;
+        box_distance_scalar(SdfVec3::new(p.x, q.y, q.z))
+            .min(box_distance_scalar(SdfVec3::new(q.x, p.y, q.z)))
+            .min(box_distance_scalar(SdfVec3::new(q.x, q.y, p.z)))
+    }
+}
+
 impl SdfElement for SdfBoxFrame {
+    fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        SdfBoundingBox::from_transform(Transform::from_scale(self.dimension))
+    }
+
+    fn distance_to(&self, point: Vec3) -> f32 {
+        self.distance_scalar(SdfVec3::from_vec3(point))
+    }
+
+    fn distance_dual(&self, point: SdfVec3<Dual3>) -> Option<Dual3> {
+        Some(self.distance_scalar(point))
+    }
+
+    fn distance_hyper_dual(&self, point: SdfVec3<HyperDual3>) -> Option<HyperDual3> {
+        Some(self.distance_scalar(point))
+    }
+}
+
+/**
+ * Heightfield terrain of fBm noise over a rectangle of the xz-plane, `amplitude` high at most.
+ *
+ * The terrain is solid below its surface, down to `depth` below its lowest possible point. Its
+ * distance is scaled by the steepest slope of the noise, so it never overestimates.
+ */
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 6, primitive)]
+pub struct SdfTerrain {
+    #[sdf(vec4s = 2)]
+    pub half_size: Vec2,
+    #[sdf(vec4s = 2, component = 2)]
+    pub amplitude: f32,
+    #[sdf(vec4s = 2, component = 3)]
+    pub depth: f32,
+    #[sdf(with = "SdfNoise::write_block", vec4s = 0, count = 2)]
+    pub noise: SdfNoise,
+}
+
+impl SdfTerrain {
+    pub fn from_block(block: &SdfOpSpecificBlock) -> Self {
+        SdfTerrain {
+            half_size: block.vec4s[2].truncate().truncate(),
+            amplitude: block.vec4s[2].z,
+            depth: block.vec4s[2].w,
+            noise: SdfNoise::from_vec4s(&block.vec4s[0..2]),
+        }
+    }
+
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        self.height_scalar(x, z)
+    }
+
+    fn height_scalar<S: SdfScalar>(&self, x: S, z: S) -> S {
+        S::from_f32(self.amplitude) * self.noise.sample_scalar(SdfVec3::new(x, S::from_f32(0.0), z))
+    }
+
+    pub fn distance_scalar<S: SdfScalar>(&self, point: SdfVec3<S>) -> S {
+        let slope = self.amplitude * self.noise.lipschitz();
+        let surface = (point.y - self.height_scalar(point.x, point.z)) / S::from_f32((1.0 + slope * slope).sqrt());
+        let (center, half_extents) = self.slab();
+        let slab = box_distance_scalar((point - SdfVec3::from_vec3(center)).abs() - SdfVec3::from_vec3(half_extents));
+        surface.max(slab)
+    }
+
+    fn slab(&self) -> (Vec3, Vec3) {
+        (
+            Vec3::new(0.0, -self.depth / 2.0, 0.0),
+            Vec3::new(self.half_size.x, self.amplitude + self.depth / 2.0, self.half_size.y),
+        )
+    }
+}
+
+impl SdfElement for SdfTerrain {
+    fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        let (center, half_extents) = self.slab();
+        SdfBoundingBox::from_extents(center, half_extents)
+    }
+
+    fn distance_to(&self, point: Vec3) -> f32 {
+        self.distance_scalar(SdfVec3::from_vec3(point))
+    }
+
+    fn distance_dual(&self, point: SdfVec3<Dual3>) -> Option<Dual3> {
+        Some(self.distance_scalar(point))
+    }
+
+    fn distance_hyper_dual(&self, point: SdfVec3<HyperDual3>) -> Option<HyperDual3> {
+        Some(self.distance_scalar(point))
+    }
+
+    fn distance_interval(&self, region: SdfAabb) -> Option<SdfInterval> {
+        let slope = self.amplitude * self.noise.lipschitz();
+        // Heights are sampled on the xz-plane
+        let ground = SdfAabb::new(region.min * Vec3::new(1.0, 0.0, 1.0), region.max * Vec3::new(1.0, 0.0, 1.0));
+        let heights = self.noise.interval(ground) * self.amplitude;
+        let surface = (region.axis(1) - heights) * (1.0 + slope * slope).sqrt().recip();
+        let (center, half_extents) = self.slab();
+        let slab = region.map_corners(|corner| corner - center).box_distance(half_extents);
+        Some(surface.max(slab))
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "amplitude" => Some(self.amplitude),
+            "depth" => Some(self.depth),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match name {
+            "amplitude" => self.amplitude = value,
+            "depth" => self.depth = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Terrain sampled from a grid of heights over a rectangle of the xz-plane.
+ *
+ * The grid is shared between clones and goes into the side buffer, since it's far too big for an
+ * op-specific block.
+ */
+#[derive(Debug)]
+pub struct SdfHeightfield {
+    pub half_size: Vec2,
+    pub height: f32,
+    pub depth: f32,
+    pub grid: Arc<SdfHeightGrid>,
+}
+
+impl SdfHeightfield {
+    pub const OP_CODE: u32 = 7;
+
+    pub fn view(&self) -> SdfHeightfieldView<'_> {
+        SdfHeightfieldView::new(&self.grid, self.half_size, self.height, self.depth)
+    }
+}
+
+impl SdfElementCore for SdfHeightfield {
     fn get_info(&self) -> SdfElementInfo {
-        SdfElementInfo::primitive_info(5)
+        SdfElementInfo::primitive_info(Self::OP_CODE)
+    }
+
+    fn box_clone(&self) -> Box<dyn SdfElement> {
+        Box::new(SdfHeightfield {
+            half_size: self.half_size,
+            height: self.height,
+            depth: self.depth,
+            grid: self.grid.clone(),
+        })
     }
 
+    fn get_dt_specific_block(&self) -> SdfOpSpecificBlock {
+        self.view().to_block()
+    }
+
+    fn get_ut_specific_block(&self) -> SdfOpSpecificBlock {
+        SdfOpSpecificBlock::ZERO
+    }
+}
+
+impl SdfElement for SdfHeightfield {
     fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
-        SdfBoundingBox::from_transform(Transform::from_scale(self.dimension))
+        let (center, half_extents) = self.view().slab();
+        SdfBoundingBox::from_extents(center, half_extents)
+    }
+
+    fn distance_to(&self, point: Vec3) -> f32 {
+        self.view().distance_to(point)
+    }
+
+    fn side_data(&self) -> &[f32] {
+        self.grid.packed()
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "height" => Some(self.height),
+            "depth" => Some(self.depth),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match name {
+            "height" => self.height = value,
+            "depth" => self.depth = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Closed triangle mesh, with exact signed distance.
+ *
+ * Meshes are only evaluated on the CPU. To draw one, bake it into an SdfGrid.
+ */
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 8, primitive)]
+pub struct SdfMesh {
+    pub mesh: Arc<SdfTriangleMesh>,
+}
+
+impl SdfMesh {
+    pub fn baked(&self, voxel_size: f32) -> SdfGrid {
+        SdfGrid {
+            grid: Arc::new(self.mesh.bake(voxel_size)),
+        }
+    }
+}
+
+impl SdfElement for SdfMesh {
+    fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        let (min, max) = self.mesh.bounds();
+        SdfBoundingBox::from_extents((min + max) / 2.0, (max - min) / 2.0)
+    }
+
+    fn distance_to(&self, point: Vec3) -> f32 {
+        self.mesh.signed_distance(point)
+    }
+}
+
+/**
+ * Shape given by a distance function, for trying things out without writing an element. The
+ * function gets points in the element's own frame, and has to stay inside of the bounds.
+ *
+ * Closures are only evaluated on the CPU, since there's no way to encode them for the GPU.
+ */
+#[derive(Clone, SdfElement)]
+#[sdf(op = 15, primitive)]
+pub struct SdfClosure {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub distance: Arc<dyn Fn(Vec3) -> f32 + Send + Sync>,
+    // How much faster than the distance to its surface the function may change
+    pub lipschitz: f32,
+}
+
+impl fmt::Debug for SdfClosure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SdfClosure")
+            .field("min", &self.min)
+            .field("max", &self.max)
+            .field("lipschitz", &self.lipschitz)
+            .finish()
+    }
+}
+
+impl SdfElement for SdfClosure {
+    fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        SdfBoundingBox::from_extents((self.min + self.max) / 2.0, (self.max - self.min) / 2.0)
+    }
+
+    fn distance_to(&self, point: Vec3) -> f32 {
+        (self.distance)(point)
+    }
+
+    fn lipschitz(&self) -> f32 {
+        self.lipschitz
+    }
+}
+
+// Distances sampled on a dense grid or in sparse bricks, which go into the side buffer
+#[derive(Debug)]
+pub struct SdfGrid {
+    pub grid: Arc<SdfGridData>,
+}
+
+impl SdfGrid {
+    pub const OP_CODE: u32 = 9;
+}
+
+impl SdfElementCore for SdfGrid {
+    fn get_info(&self) -> SdfElementInfo {
+        SdfElementInfo::primitive_info(Self::OP_CODE)
     }
 
-    fn clone(&self) -> Box<dyn SdfElement> {
-        Box::new(SdfBoxFrame {
-            dimension: self.dimension,
-            thickness: self.thickness,
+    fn box_clone(&self) -> Box<dyn SdfElement> {
+        Box::new(SdfGrid {
+            grid: self.grid.clone(),
         })
     }
-    
+
     fn get_dt_specific_block(&self) -> SdfOpSpecificBlock {
-        let mut ret = SdfOpSpecificBlock::ZERO;
-        ret.vec4s[0] = self.dimension.extend(0.0);
-        ret.floats[0] = self.thickness;
-        ret
+        SdfGridView::new(&self.grid).to_block()
+    }
+
+    fn get_ut_specific_block(&self) -> SdfOpSpecificBlock {
+        SdfOpSpecificBlock::ZERO
+    }
+}
+
+impl SdfElement for SdfGrid {
+    fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        let (center, half_extents) = SdfGridView::new(&self.grid).extents();
+        SdfBoundingBox::from_extents(center, half_extents)
+    }
+
+    fn distance_to(&self, point: Vec3) -> f32 {
+        SdfGridView::new(&self.grid).distance_to(point)
+    }
+
+    fn side_data(&self) -> &[f32] {
+        self.grid.packed()
+    }
+}
+
+/**
+ * 2D shape in the xy plane, for the profiles that SdfExtrude and SdfRevolve turn into solids.
+ *
+ * The distance ignores z, so a profile only makes sense as the child of one of those, which
+ * evaluate it at z = 0. Flat boxes can't be inverted, so its box is as thick as the shape's
+ * smaller half extent.
+ *
+ * The shape goes into vec4s[0..3] of the block.
+ */
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 10, primitive)]
+pub struct SdfProfile {
+    #[sdf(with = "SdfShape2d::write_block", vec4s = 0, count = 3)]
+    pub shape: SdfShape2d,
+}
+
+impl SdfProfile {
+    pub fn from_block(block: &SdfOpSpecificBlock, side_buffer: &[f32]) -> Self {
+        SdfProfile {
+            shape: SdfShape2d::from_block(block, side_buffer),
+        }
+    }
+}
+
+impl SdfElement for SdfProfile {
+    fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        let (min, max) = self.shape.bounds();
+        let half_size = (max - min) / 2.0;
+        SdfBoundingBox::from_extents(((min + max) / 2.0).extend(0.0), half_size.extend(half_size.min_element()))
     }
-    
+
+    fn distance_to(&self, point: Vec3) -> f32 {
+        self.shape.distance(point.truncate())
+    }
+
+    fn side_data(&self) -> &[f32] {
+        self.shape.side_data()
+    }
+}
+
+// Extents in the xy plane of the boxes of a profile, which is all that extrusions and revolutions use
+fn profile_bounds(slots_bboxes: &[SdfBoundingBox]) -> Option<(Vec2, Vec2)> {
+    let verts = slots_bboxes.first()?.verts();
+    Some(verts.iter().fold((Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY)), |(min, max), vert| {
+        (min.min(vert.truncate().truncate()), max.max(vert.truncate().truncate()))
+    }))
+}
+
+/**
+ * Extrude a 2D child along z over `height`, centered on its plane. The child is only evaluated
+ * at z = 0, so it's usually an SdfProfile, or a union of them.
+ *
+ * The height goes into floats[0] of the uptree block.
+ */
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 4, strict(acc = 0, drawn = 1), blocks = "uptree")]
+pub struct SdfExtrude {
+    #[sdf(floats = 0)]
+    pub height: f32,
+}
+
+impl SdfExtrude {
+    pub fn from_block(block: &SdfOpSpecificBlock) -> Self {
+        SdfExtrude {
+            height: block.floats[0],
+        }
+    }
+
+    // Distance of the extrusion from the child's distance in the plane, at a point in its frame
+    pub fn extrude(&self, point: Vec3, distance: f32) -> f32 {
+        let w = Vec2::new(distance, point.z.abs() - self.height / 2.0);
+        w.x.max(w.y).min(0.0) + w.max(Vec2::ZERO).length()
+    }
+}
+
+impl SdfElement for SdfExtrude {
+    fn get_bbox(&self, slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        match profile_bounds(slots_bboxes) {
+            Some((min, max)) => SdfBoundingBox::from_extents(
+                ((min + max) / 2.0).extend(0.0),
+                ((max - min) / 2.0).extend(self.height / 2.0),
+            ),
+            None => SdfBoundingBox::zero(),
+        }
+    }
+
+    fn slot_point(&self, point: Vec3) -> Vec3 {
+        point.truncate().extend(0.0)
+    }
+
+    fn combine(&self, point: Vec3, slots: &[(f32, SdfMaterialMix)]) -> (f32, SdfMaterialMix) {
+        (self.extrude(point, slots[0].0), slots[0].1)
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "height" => Some(self.height),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match name {
+            "height" => self.height = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
+    }
+
     fn expand(&self, this_node: &SdfNode) -> ExpandedSdfNode {
-        ExpandedSdfNode::primitive(this_node.bbox.unwrap(), self.clone())
+        expand_in_own_frame(this_node, self.box_clone())
+    }
+}
+
+/**
+ * Revolve a 2D child around the y axis, with the child's x measured from `offset` away from the
+ * axis. Like for SdfExtrude, the child is only evaluated at z = 0. Parts of the child that would
+ * end up behind the axis, at x < -offset, are cut off.
+ *
+ * The offset goes into floats[0] of the downtree block.
+ */
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 5, strict(acc = 0, drawn = 1))]
+pub struct SdfRevolve {
+    #[sdf(floats = 0)]
+    pub offset: f32,
+}
+
+impl SdfRevolve {
+    pub fn from_block(block: &SdfOpSpecificBlock) -> Self {
+        SdfRevolve {
+            offset: block.floats[0],
+        }
+    }
+
+    // Point in the plane of the child that a point around the axis is swept from
+    pub fn unrevolve(&self, point: Vec3) -> Vec3 {
+        Vec3::new(Vec2::new(point.x, point.z).length() - self.offset, point.y, 0.0)
+    }
+}
+
+impl SdfElement for SdfRevolve {
+    fn get_bbox(&self, slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        match profile_bounds(slots_bboxes) {
+            Some((min, max)) => {
+                let radius = (self.offset + max.x).max(0.0);
+                SdfBoundingBox::from_extents(
+                    Vec3::new(0.0, (min.y + max.y) / 2.0, 0.0),
+                    Vec3::new(radius, (max.y - min.y) / 2.0, radius),
+                )
+            },
+            None => SdfBoundingBox::zero(),
+        }
+    }
+
+    fn slot_point(&self, point: Vec3) -> Vec3 {
+        self.unrevolve(point)
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "offset" => Some(self.offset),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match name {
+            "offset" => self.offset = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
+    }
+
+    fn expand(&self, this_node: &SdfNode) -> ExpandedSdfNode {
+        expand_in_own_frame(this_node, self.box_clone())
+    }
+}
+
+/**
+ * Capsule between two points, tapering linearly from one radius to the other.
+ *
+ * Chains of capsules are built with curve::polyline.
+ */
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 12, primitive)]
+pub struct SdfCapsule {
+    #[sdf(vec4s = 0)]
+    pub a: Vec3,
+    #[sdf(vec4s = 1)]
+    pub b: Vec3,
+    #[sdf(vec4s = 0, component = 3)]
+    pub radius_a: f32,
+    #[sdf(vec4s = 1, component = 3)]
+    pub radius_b: f32,
+}
+
+impl SdfCapsule {
+    pub fn from_block(block: &SdfOpSpecificBlock) -> Self {
+        SdfCapsule {
+            a: block.vec4s[0].truncate(),
+            b: block.vec4s[1].truncate(),
+            radius_a: block.vec4s[0].w,
+            radius_b: block.vec4s[1].w,
+        }
+    }
+}
+
+impl SdfElement for SdfCapsule {
+    // Box along the segment, which stays tight for thin diagonal capsules
+    fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        let axis = self.b - self.a;
+        let radius = self.radius_a.max(self.radius_b);
+        let rotation = if axis.length_squared() > 0.0 {
+            Quat::from_rotation_arc(Vec3::X, axis.normalize())
+        } else {
+            Quat::IDENTITY
+        };
+        SdfBoundingBox::from_oriented_extents(
+            (self.a + self.b) / 2.0,
+            rotation,
+            Vec3::new(axis.length() / 2.0 + radius, radius, radius),
+        )
+    }
+
+    fn distance_to(&self, point: Vec3) -> f32 {
+        capsule_distance(point, self.a, self.b, self.radius_a, self.radius_b)
+    }
+
+    fn distance_dual(&self, point: SdfVec3<Dual3>) -> Option<Dual3> {
+        Some(capsule_distance_scalar(point, self.a, self.b, self.radius_a, self.radius_b))
+    }
+
+    fn distance_hyper_dual(&self, point: SdfVec3<HyperDual3>) -> Option<HyperDual3> {
+        Some(capsule_distance_scalar(point, self.a, self.b, self.radius_a, self.radius_b))
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "radius_a" => Some(self.radius_a),
+            "radius_b" => Some(self.radius_b),
+            _ => match vec3_param(name)? {
+                ("a", i) => Some(self.a[i]),
+                ("b", i) => Some(self.b[i]),
+                _ => None,
+            },
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match (name, vec3_param(name)) {
+            ("radius_a", _) => self.radius_a = value,
+            ("radius_b", _) => self.radius_b = value,
+            (_, Some(("a", i))) => self.a[i] = value,
+            (_, Some(("b", i))) => self.b[i] = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
+    }
+}
+
+// Bezier control points go into vec4s, with a fourth point spread over their w components
+fn write_bezier_points<const N: usize>(points: &[Vec3; N], block: &mut SdfOpSpecificBlock) {
+    for (i, point) in points.iter().enumerate() {
+        match i {
+            0..=2 => block.vec4s[i] = point.extend(block.vec4s[i].w),
+            _ => (0..3).for_each(|axis| block.vec4s[axis].w = point[axis]),
+        }
+    }
+}
+
+// Box around the control points of a bezier curve, which hold the curve between them
+fn bezier_bbox(points: &[Vec3], radius: f32) -> SdfBoundingBox {
+    let (min, max) = points.iter()
+        .fold((Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)), |(min, max), point| {
+            (min.min(*point), max.max(*point))
+        });
+    SdfBoundingBox::from_extents((min + max) / 2.0, (max - min) / 2.0 + Vec3::splat(radius))
+}
+
+/**
+ * Tube around a quadratic bezier curve, with exact distance.
+ *
+ * Long curves are cut into a union of tubes with curve::quad_bezier_tube.
+ */
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 13, primitive)]
+pub struct SdfQuadBezierTube {
+    #[sdf(with = "write_bezier_points", vec4s = 0, count = 3)]
+    pub points: [Vec3; 3],
+    #[sdf(floats = 0)]
+    pub radius: f32,
+}
+
+impl SdfQuadBezierTube {
+    pub fn from_block(block: &SdfOpSpecificBlock) -> Self {
+        SdfQuadBezierTube {
+            points: [0, 1, 2].map(|i| block.vec4s[i].truncate()),
+            radius: block.floats[0],
+        }
+    }
+}
+
+impl SdfElement for SdfQuadBezierTube {
+    fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        bezier_bbox(&self.points, self.radius)
+    }
+
+    fn distance_to(&self, point: Vec3) -> f32 {
+        quad_bezier_distance(point, self.points) - self.radius
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "radius" => Some(self.radius),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match name {
+            "radius" => self.radius = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Tube around a cubic bezier curve, with the distance found by a bounded number of refinement
+ * steps.
+ *
+ * The fourth point goes into the w components of the block, since it doesn't have room for it.
+ */
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 14, primitive)]
+pub struct SdfCubicBezierTube {
+    #[sdf(with = "write_bezier_points", vec4s = 0, count = 3)]
+    pub points: [Vec3; 4],
+    #[sdf(floats = 0)]
+    pub radius: f32,
+}
+
+impl SdfCubicBezierTube {
+    pub fn from_block(block: &SdfOpSpecificBlock) -> Self {
+        let [a, b, c] = block.vec4s;
+        SdfCubicBezierTube {
+            points: [a.truncate(), b.truncate(), c.truncate(), Vec3::new(a.w, b.w, c.w)],
+            radius: block.floats[0],
+        }
+    }
+}
+
+impl SdfElement for SdfCubicBezierTube {
+    fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        bezier_bbox(&self.points, self.radius)
+    }
+
+    fn distance_to(&self, point: Vec3) -> f32 {
+        cubic_bezier_distance(point, self.points) - self.radius
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "radius" => Some(self.radius),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match name {
+            "radius" => self.radius = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
     }
 }
 
 // Operations
 
+/**
+ * Expansion of an operation with up to two slots that live in the frame of the operation's own box,
+ * like the children of a union. A missing second slot is left null.
+ */
+fn expand_in_own_frame(this_node: &SdfNode, intern: Box<dyn SdfElement>) -> ExpandedSdfNode {
+    let slot = |i: usize| Box::new(this_node.slots().get(i).map_or_else(ExpandedSdfNode::null, |slot| slot.expanded()));
+    ExpandedSdfNode::operation([slot(0), slot(1)], this_node.bbox.unwrap(), intern)
+}
+
 // Basic smooth union
-#[derive(Debug)]
+// The downtree copy of the radius widens the pruning test of the children
+#[derive(Debug, Clone, Default, SdfElement)]
+#[sdf(op = 0, union, blocks = "both")]
 pub struct SdfUnion {
+    #[sdf(floats = 0)]
     pub smooth_radius: f32,
+    // Private, so unions are built with new() and don't break when fields are added
+    split_method: SdfSplitMethod,
 }
 
-impl SdfElement for SdfUnion {
-    fn get_info(&self) -> SdfElementInfo {
-        SdfElementInfo::union_info(0)
+impl SdfUnion {
+    // Union split by the default method, see SdfSplitMethod
+    pub fn new(smooth_radius: f32) -> Self {
+        SdfUnion {
+            smooth_radius,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_split_method(mut self, split_method: SdfSplitMethod) -> Self {
+        self.split_method = split_method;
+        self
     }
+}
 
+impl SdfElement for SdfUnion {
     fn get_bbox(&self, slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
         SdfBoundingBox::merge(slots_bboxes)
     }
 
     fn expand(&self, this_node: &SdfNode) -> ExpandedSdfNode {
-        match this_node.slots.len() {
-            1 => this_node.slots.get(0).unwrap().expanded(),
+        match this_node.slots().len() {
+            // The union's own transform has to be kept when it's replaced by its only child
+            1 => {
+                let mut only = this_node.slots()[0].expanded();
+                only.collapse_into(&this_node.bbox.unwrap());
+                only
+            },
             0 => ExpandedSdfNode::null(),
             _ => {
                 fn recurse(this_intern: &SdfUnion, this_node: &SdfNode, index_vec: Vec<usize>) -> ExpandedSdfNode {
                     if index_vec.len() == 1 {
-                        return this_node.slots[index_vec[0]].expanded();
+                        return this_node.slots()[index_vec[0]].expanded();
                     }
 
-                    let bboxes = index_vec.iter()
-                        .map(|i| this_node.slots[*i].bbox.unwrap())
-                        .collect::<Vec<SdfBoundingBox>>();
-                    let merged_box = SdfBoundingBox::merge(bboxes.as_slice());
-                    let (left_child_inds, right_child_inds) = merged_box.split(bboxes.as_slice());
+                    let (merged_box, left_child_inds, right_child_inds) = this_node.split_slots(
+                        index_vec.as_slice(),
+                        this_intern.split_method);
+                    // The top of the split tree carries the union's own box, which moves points
+                    // into the frame the children live in
+                    let (node_box, bounds) = match index_vec.len() == this_node.slots().len() {
+                        true => (this_node.bbox.unwrap(), SdfBoundsRule::Source),
+                        false => (merged_box, SdfBoundsRule::Merge(index_vec.clone())),
+                    };
                     
                     ExpandedSdfNode::operation(
                         [
                             Box::new(recurse(this_intern, this_node, left_child_inds)),
                             Box::new(recurse(this_intern, this_node, right_child_inds)),
                         ],
-                        merged_box,
-                        this_intern.clone(),
-                    )
+                        node_box,
+                        this_intern.box_clone(),
+                    ).with_bounds(bounds)
                 }
-                recurse(self, this_node, (0..this_node.slots.len()).collect())
+                recurse(self, this_node, (0..this_node.slots().len()).collect())
             },
         }
     }
 
-    fn clone(&self) -> Box<dyn SdfElement> {
-        Box::new(SdfUnion {
-            smooth_radius: self.smooth_radius,
-        })
+    fn blend_radius(&self) -> f32 {
+        self.smooth_radius
     }
 
-    fn get_ut_specific_block(&self) -> SdfOpSpecificBlock {
-        let mut ret = SdfOpSpecificBlock::ZERO;
-        ret.floats[0] = self.smooth_radius;
-        ret
+    fn split_method(&self) -> SdfSplitMethod {
+        self.split_method
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "smooth_radius" => Some(self.smooth_radius),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match name {
+            "smooth_radius" => self.smooth_radius = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
     }
 }
 
 // Continuous, Axis Aligned clone operation
-#[derive(Debug)]
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 1, strict(acc = 0, drawn = 1))]
 pub struct SdfCaaClone {
+    #[sdf(vec4s = 0)]
     pub displacement: Vec3,
+    #[sdf(vec4s = 1)]
     pub neg_limit: Vec3,
+    #[sdf(vec4s = 2)]
     pub pos_limit: Vec3,
 }
 
 impl SdfElement for SdfCaaClone {
-    fn get_info(&self) -> SdfElementInfo {
-        SdfElementInfo::strict_info(1, 0, 1)
-    }
-
     fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
         SdfBoundingBox::from_transform(
             Transform::from_translation((self.neg_limit + self.pos_limit) / 2_f32 * self.displacement)
@@ -240,24 +1072,203 @@ impl SdfElement for SdfCaaClone {
         )
     }
 
-    fn clone(&self) -> Box<dyn SdfElement> {
-        Box::new(SdfCaaClone {
-            displacement: self.displacement,
-            neg_limit: self.neg_limit,
-            pos_limit: self.pos_limit,
-        })
+    // Regions that stay inside of one cell are shifted, and the others can land anywhere in one
+    fn downtree_interval(&self, region: SdfAabb) -> SdfAabb {
+        let lattice_region = region.map_corners(|corner| corner - self.displacement / 2.0);
+        let (mut min, mut max) = (lattice_region.min, lattice_region.max);
+        for axis in 0..3 {
+            let period = self.displacement[axis].abs();
+            let (cell_min, cell_max) = ((min[axis] / period).trunc(), (max[axis] / period).trunc());
+            let one_side = min[axis] >= 0.0 || max[axis] <= 0.0;
+            if cell_min == cell_max && one_side {
+                min[axis] -= cell_min * period;
+                max[axis] -= cell_max * period;
+            } else {
+                min[axis] = if min[axis] >= 0.0 { 0.0 } else { -period };
+                max[axis] = if max[axis] <= 0.0 { 0.0 } else { period };
+            }
+        }
+        SdfAabb::new(min, max)
     }
 
-    fn get_dt_specific_block(&self) -> SdfOpSpecificBlock {
-        let mut ret = SdfOpSpecificBlock::ZERO;
-        ret.vec4s[0] = self.displacement.extend(0.0);
-        ret.vec4s[1] = self.neg_limit.extend(0.0);
-        ret.vec4s[2] = self.pos_limit.extend(0.0);
-        ret
+    fn combine_interval(&self, _region: SdfAabb, slots: &[SdfInterval]) -> Option<SdfInterval> {
+        Some(slots[0])
+    }
+
+    fn combine_dual(&self, _point: SdfVec3<Dual3>, slots: &[Dual3]) -> Option<Dual3> {
+        Some(slots[0])
+    }
+
+    fn combine_hyper_dual(&self, _point: SdfVec3<HyperDual3>, slots: &[HyperDual3]) -> Option<HyperDual3> {
+        Some(slots[0])
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match vec3_param(name)? {
+            ("displacement", i) => Some(self.displacement[i]),
+            ("neg_limit", i) => Some(self.neg_limit[i]),
+            ("pos_limit", i) => Some(self.pos_limit[i]),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match vec3_param(name) {
+            Some(("displacement", i)) => self.displacement[i] = value,
+            Some(("neg_limit", i)) => self.neg_limit[i] = value,
+            Some(("pos_limit", i)) => self.pos_limit[i] = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
     }
 
     fn expand(&self, this_node: &SdfNode) -> ExpandedSdfNode {
-        ExpandedSdfNode::simple_operation(this_node.slots.get(0).unwrap().expanded(), self.clone())
+        ExpandedSdfNode::simple_operation(this_node.slots().first().unwrap().expanded(), self.box_clone())
+    }
+}
+
+// Interpolation between the distances of two shapes
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 2, strict(acc = 0, drawn = 2), blocks = "uptree")]
+pub struct SdfMorph {
+    #[sdf(floats = 0)]
+    pub t: f32,
+}
+
+impl SdfMorph {
+    pub fn blend_scalar<S: SdfScalar>(&self, left: S, right: S) -> S {
+        left + (right - left) * S::from_f32(self.t)
+    }
+}
+
+impl SdfElement for SdfMorph {
+    fn get_bbox(&self, slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        SdfBoundingBox::merge(slots_bboxes)
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "t" => Some(self.t),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match name {
+            "t" => self.t = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
+    }
+
+    // Extrapolating past either shape makes the blend steeper than both of them
+    fn lipschitz(&self) -> f32 {
+        (1.0 - self.t).abs() + self.t.abs()
+    }
+
+    fn combine(&self, _point: Vec3, slots: &[(f32, SdfMaterialMix)]) -> (f32, SdfMaterialMix) {
+        let ((left_dist, left_mat), (right_dist, right_mat)) = (slots[0], slots[1]);
+        (self.blend_scalar(left_dist, right_dist), left_mat.blend(&right_mat, self.t))
+    }
+
+    fn combine_interval(&self, _region: SdfAabb, slots: &[SdfInterval]) -> Option<SdfInterval> {
+        Some(slots[0] * (1.0 - self.t) + slots[1] * self.t)
+    }
+
+    fn combine_dual(&self, _point: SdfVec3<Dual3>, slots: &[Dual3]) -> Option<Dual3> {
+        Some(self.blend_scalar(slots[0], slots[1]))
+    }
+
+    fn combine_hyper_dual(&self, _point: SdfVec3<HyperDual3>, slots: &[HyperDual3]) -> Option<HyperDual3> {
+        Some(self.blend_scalar(slots[0], slots[1]))
+    }
+
+    fn expand(&self, this_node: &SdfNode) -> ExpandedSdfNode {
+        expand_in_own_frame(this_node, self.box_clone())
+    }
+}
+
+/**
+ * Displace the surface of the child by up to `amplitude` along its normal with fBm noise.
+ *
+ * Noise makes the distance steeper than the child's, which the displacement reports as its
+ * Lipschitz factor, so the tree scales its distances down to keep sphere tracing from overshooting.
+ */
+#[derive(Debug, Clone, SdfElement)]
+#[sdf(op = 3, strict(acc = 0, drawn = 1), blocks = "both")]
+pub struct SdfDisplace {
+    #[sdf(floats = 0)]
+    pub amplitude: f32,
+    #[sdf(with = "SdfNoise::write_block", vec4s = 0, count = 2)]
+    pub noise: SdfNoise,
+}
+
+impl SdfDisplace {
+    pub fn from_block(block: &SdfOpSpecificBlock) -> Self {
+        SdfDisplace {
+            amplitude: block.floats[0],
+            noise: SdfNoise::from_vec4s(&block.vec4s[0..2]),
+        }
+    }
+
+    pub fn step_scale(&self) -> f32 {
+        1.0 / self.lipschitz()
+    }
+
+    // Displaced distance, before the tree scales it by the step scale
+    pub fn displace(&self, point: Vec3, distance: f32) -> f32 {
+        self.displace_scalar(SdfVec3::from_vec3(point), distance)
+    }
+
+    pub fn displace_scalar<S: SdfScalar>(&self, point: SdfVec3<S>, distance: S) -> S {
+        distance + S::from_f32(self.amplitude) * self.noise.sample_scalar(point)
+    }
+}
+
+impl SdfElement for SdfDisplace {
+    fn get_bbox(&self, slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
+        slots_bboxes.first()
+            .map(|bbox| bbox.inflated(self.amplitude))
+            .unwrap_or_else(SdfBoundingBox::zero)
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "amplitude" => Some(self.amplitude),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> Result<(), &'static str> {
+        match name {
+            "amplitude" => self.amplitude = value,
+            _ => return Err("Unknown element parameter!"),
+        }
+        Ok(())
+    }
+
+    fn lipschitz(&self) -> f32 {
+        1.0 + self.amplitude.abs() * self.noise.lipschitz()
+    }
+
+    fn combine(&self, point: Vec3, slots: &[(f32, SdfMaterialMix)]) -> (f32, SdfMaterialMix) {
+        (self.displace(point, slots[0].0), slots[0].1)
+    }
+
+    fn combine_interval(&self, region: SdfAabb, slots: &[SdfInterval]) -> Option<SdfInterval> {
+        Some(slots[0] + self.noise.interval(region) * self.amplitude)
+    }
+
+    fn combine_dual(&self, point: SdfVec3<Dual3>, slots: &[Dual3]) -> Option<Dual3> {
+        Some(self.displace_scalar(point, slots[0]))
+    }
+
+    fn combine_hyper_dual(&self, point: SdfVec3<HyperDual3>, slots: &[HyperDual3]) -> Option<HyperDual3> {
+        Some(self.displace_scalar(point, slots[0]))
+    }
+
+    fn expand(&self, this_node: &SdfNode) -> ExpandedSdfNode {
+        expand_in_own_frame(this_node, self.box_clone())
     }
 }
 
@@ -291,4 +1302,70 @@ impl SdfElement for SdfCaaClone {
 //             amplitude: self.amplitude,
 //         })
 //     }
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+pub mod tests {
+    use crate::math::*;
+    use crate::{
+        elements::*,
+        noise::*,
+    };
+
+    #[test]
+    fn test_derived_blocks() {
+        let capsule = SdfCapsule {
+            a: Vec3::new(1.0, 2.0, 3.0),
+            b: Vec3::new(4.0, 5.0, 6.0),
+            radius_a: 0.5,
+            radius_b: 0.25,
+        };
+        let block = capsule.get_dt_specific_block();
+        assert_eq!(block.vec4s[0], Vec4::new(1.0, 2.0, 3.0, 0.5));
+        assert_eq!(block.vec4s[1], Vec4::new(4.0, 5.0, 6.0, 0.25));
+        assert_eq!(capsule.get_ut_specific_block(), SdfOpSpecificBlock::ZERO);
+        assert!(capsule.get_info() == SdfElementInfo::primitive_info(12));
+
+        // Blocks read back into the same element
+        let tube = SdfCubicBezierTube {
+            points: [Vec3::X, Vec3::Y, Vec3::Z, Vec3::new(7.0, 8.0, 9.0)],
+            radius: 0.1,
+        };
+        let read = SdfCubicBezierTube::from_block(&tube.get_dt_specific_block());
+        assert_eq!((read.points, read.radius), (tube.points, tube.radius));
+        let displace = SdfDisplace {
+            amplitude: 0.3,
+            noise: SdfNoise::new(SdfNoiseBasis::Perlin, 7).with_octaves(3, 2.0, 0.5),
+        };
+        let read = SdfDisplace::from_block(&displace.get_ut_specific_block());
+        assert_eq!((read.amplitude, read.noise), (displace.amplitude, displace.noise));
+
+        // Kinds and block selection come from the struct attributes
+        let morph = SdfMorph {
+            t: 0.25,
+        };
+        assert!(morph.get_info() == SdfElementInfo::strict_info(2, 0, 2));
+        assert_eq!(morph.get_dt_specific_block(), SdfOpSpecificBlock::ZERO);
+        assert_eq!(morph.get_ut_specific_block().floats[0], 0.25);
+        let union = SdfUnion::new(0.5);
+        assert!(union.get_info().is_union);
+        assert_eq!(union.get_dt_specific_block(), union.get_ut_specific_block());
+
+        // Boxed clones keep their fields
+        let boxed: Box<dyn SdfElement> = Box::new(capsule);
+        assert_eq!(boxed.clone().get_dt_specific_block(), block);
+    }
+
+    #[test]
+    fn test_box_frame() {
+        let frame = SdfBoxFrame { dimension: Vec3::new(1.0, 0.5, 0.8), thickness: 0.1 };
+        let read = SdfBoxFrame::from_block(&frame.get_dt_specific_block());
+        assert_eq!((read.dimension, read.thickness), (frame.dimension, frame.thickness));
+
+        // The frame is hollow, with only its edges inside
+        let frame = SdfBoxFrame { dimension: Vec3::ONE, thickness: 0.1 };
+        assert!(frame.distance_to(Vec3::ZERO) > 0.5);
+        assert!(frame.distance_to(Vec3::new(0.0, 1.0, 0.0)) > 0.0);
+        assert!(frame.distance_to(Vec3::splat(0.95)) < 0.0);
+        assert!(frame.distance_to(Vec3::new(0.0, 0.95, 0.95)) < 0.0);
+    }
+}
@@ -1,8 +1,10 @@
 use super::{
     component::*,
+    material::*,
+    registry::registry,
     obb::CmpFloat,
 };
-use bevy::prelude::*;
+use super::math::*;
 
 #[derive(Clone, Copy)]
 struct DowntreeResult {
@@ -12,14 +14,28 @@ struct DowntreeResult {
 #[derive(Clone)]
 struct LevelStackEntry {
     branch_points: [Vec4; 2],
+    // Point of the operation that owns this level in its frame, before it was passed down
+    op_point: Vec4,
     branch_dists: [f32; 2],
+    branch_mats: [SdfMaterialMix; 2],
+    // Blend radius of the operation that owns this level, which widens union pruning
+    prune_margin: f32,
+    // Distance scale of the operation that owns this level, which its result is multiplied by
+    dist_scale: f32,
+    // Lipschitz factor of the operation that owns this level, which unions scale their slots to
+    lipschitz: f32,
     fill_idx: u32,
 }
 
 impl LevelStackEntry {
     pub const ZERO: Self = LevelStackEntry {
         branch_points: [Vec4::ZERO; 2],
+        op_point: Vec4::ZERO,
         branch_dists: [0_f32; 2],
+        branch_mats: [SdfMaterialMix { ids: [0; 2], weight: 0.0 }; 2],
+        prune_margin: 0.0,
+        dist_scale: 1.0,
+        lipschitz: 1.0,
         fill_idx: 0,
     };
 }
@@ -57,43 +73,70 @@ fn mindist(bbox: SdfBoundingBoxBlock, point: Vec4) -> f32 {
     q_local.max(Vec4::ZERO).length() + q_local.x.max(q_local.y.max(q_local.z)).min(0.0)
 }
 
-fn prim_dispatch(code: u32, op_specific: SdfOpSpecificBlock, point: Vec4) -> f32 {
-    match code {
-        // Sphere
-        0 => point.truncate().length() - op_specific.floats[0],
+// Same as SdfNode::slot_scale, for a block that's written into the level of its parent
+fn slot_scale(parent_is_union: bool, parent_lipschitz: f32, lipschitz: f32) -> f32 {
+    if parent_is_union { parent_lipschitz / lipschitz } else { 1.0 }
+}
 
-        other => panic!("Unsupported primitive op code: {}", other),
+fn prim_dispatch(code: u32, op_specific: SdfOpSpecificBlock, side_buffer: &[f32], point: Vec4) -> f32 {
+    match registry().primitive(code).and_then(|entry| entry.distance) {
+        Some(distance) => distance(&op_specific, side_buffer, point),
+        None => panic!("Unsupported primitive op code: {}", code),
     }
 }
 
 fn downtree_dispatch(code: u32, op_specific: SdfOpSpecificBlock, point: Vec4) -> [Vec4; 2] {
-    match code {
-        // Union
-        0 => [point, point],
+    match registry().operation(code) {
+        Some(entry) => (entry.downtree)(&op_specific, point),
+        None => panic!("Unsupported downtree op code: {}", code),
+    }
+}
 
-        // CAA Clone
-        1 => [
-            point - op_specific.vec4s[0] * (point / op_specific.vec4s[0]).round().clamp(op_specific.vec4s[1], op_specific.vec4s[2]),
-            Vec4::ZERO,
-        ],
+fn prune_margin(code: u32, op_specific: SdfOpSpecificBlock) -> f32 {
+    registry().operation(code).map_or(0.0, |entry| (entry.prune_margin)(&op_specific))
+}
 
-        other => panic!("Unsupported downtree op code: {}", other),
+// The point is the operation's own, like SdfElement::combine gets it
+fn uptree_dispatch(
+    code: u32,
+    op_specific: SdfOpSpecificBlock,
+    point: Vec4,
+    left: (f32, SdfMaterialMix),
+    right: (f32, SdfMaterialMix),
+) -> (f32, SdfMaterialMix) {
+    match registry().operation(code) {
+        Some(entry) => (entry.uptree)(&op_specific, point, left, right),
+        None => panic!("Unsupported uptree op code: {}", code),
     }
 }
 
-fn uptree_dispatch(code: u32, op_specific: SdfOpSpecificBlock, left_dist: f32, right_dist: f32) -> f32 {
-    match code {
-        // Union
-        0 => min(left_dist, right_dist),
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SdfTraversalStats {
+    pub blocks_visited: usize,
+    pub primitives_evaluated: usize,
+    pub subtrees_pruned: usize,
+    pub blocks_pruned: usize,
+}
 
-        // CAA Clone
-        1 => right_dist,
-        
-        other => panic!("Unsupported downtree op code: {}", other),
-    }
+// Distance to the surface and the ID of its dominant material
+pub fn nearest_neighbor(sdf_tree: &SdfTreeBuffer, point: Vec4) -> (f32, u32) {
+    let (distance, material, _) = traverse(sdf_tree, point);
+    (distance, material.dominant())
+}
+
+// Distance to the surface and the full material blend of smooth unions
+pub fn nearest_material(sdf_tree: &SdfTreeBuffer, point: Vec4) -> (f32, SdfMaterialMix) {
+    let (distance, material, _) = traverse(sdf_tree, point);
+    (distance, material)
+}
+
+pub fn nearest_neighbor_stats(sdf_tree: &SdfTreeBuffer, point: Vec4) -> (f32, SdfTraversalStats) {
+    let (distance, _, stats) = traverse(sdf_tree, point);
+    (distance, stats)
 }
 
-pub fn nearest_neighbor(sdf_tree: &SdfTreeBuffer, point: Vec4) -> f32 {
+fn traverse(sdf_tree: &SdfTreeBuffer, point: Vec4) -> (f32, SdfMaterialMix, SdfTraversalStats) {
+    let mut stats = SdfTraversalStats::default();
     let mut dt_index = 0;
     let mut ut_index = 0;
     let mut last_dt_level = 0;
@@ -101,30 +144,18 @@ pub fn nearest_neighbor(sdf_tree: &SdfTreeBuffer, point: Vec4) -> f32 {
 
     point_stack[1] = LevelStackEntry {
         branch_points: [point, Vec4::ZERO],
+        op_point: point,
         branch_dists: [f32::INFINITY, f32::INFINITY],
+        branch_mats: [SdfMaterialMix::single(0); 2],
+        prune_margin: 0.0,
+        dist_scale: 1.0,
+        lipschitz: 1.0,
         fill_idx: 0,
     };
 
     while dt_index < sdf_tree.buffer_len as usize {
         let dt_block = &sdf_tree.downtree_buffer[dt_index];
-        let (dt_point, dt_ut_prune_cmp) = {
-            let this_frame = &point_stack[dt_block.level as usize];
-            (this_frame.branch_points[this_frame.fill_idx as usize],
-                this_frame.branch_dists[0])
-        };
-
-        // Apply union pruning
-        if dt_block.parent_is_union {
-            let this_mindist = mindist(dt_block.bounding_box, dt_point);
-            if this_mindist > 0_f32
-                && (this_mindist > dt_ut_prune_cmp
-                    || this_mindist > maxdist(dt_block.other_box, dt_point)) {
-                dt_index += 1 + dt_block.len as usize;
-                ut_index += 1 + dt_block.len as usize;
-                continue;
-            }
-        }   
-
+        stats.blocks_visited += 1;
         // Apply uptree algorithm
         if dt_block.level < last_dt_level {
             let mut last_ut_level = u32::MAX;
@@ -138,16 +169,23 @@ pub fn nearest_neighbor(sdf_tree: &SdfTreeBuffer, point: Vec4) -> f32 {
                 }
 
                 // Perform uptree operation
-                let (lbranch_dist, rbranch_dist) = {
+                let (branch_point, dist_scale, lbranch, rbranch) = {
                     let child_frame = &point_stack[ut_block.level as usize + 1];
-                    (child_frame.branch_dists[0], child_frame.branch_dists[1])
+                    (child_frame.op_point,
+                        child_frame.dist_scale,
+                        (child_frame.branch_dists[0], child_frame.branch_mats[0]),
+                        (child_frame.branch_dists[1], child_frame.branch_mats[1]))
                 };
-                let ut_frame = &mut point_stack[ut_block.level as usize];
-                ut_frame.branch_dists[ut_frame.fill_idx as usize] = uptree_dispatch(
+                let (distance, material) = uptree_dispatch(
                     ut_block.op_code,
                     ut_block.op_specific,
-                    lbranch_dist,
-                    rbranch_dist);
+                    branch_point,
+                    lbranch,
+                    rbranch);
+                let ut_frame = &mut point_stack[ut_block.level as usize];
+                let fill_idx = ut_frame.fill_idx as usize;
+                let slot_scale = slot_scale(ut_block.parent_is_union, ut_frame.lipschitz, ut_block.lipschitz);
+                (ut_frame.branch_dists[fill_idx], ut_frame.branch_mats[fill_idx]) = (distance * dist_scale * slot_scale, material);
                 ut_frame.fill_idx += 1;
 
                 // Increment
@@ -156,25 +194,56 @@ pub fn nearest_neighbor(sdf_tree: &SdfTreeBuffer, point: Vec4) -> f32 {
             }
         }
 
+        let (dt_point, dt_ut_prune_cmp, dt_prune_margin) = {
+            let this_frame = &point_stack[dt_block.level as usize];
+            (this_frame.branch_points[this_frame.fill_idx as usize],
+                this_frame.branch_dists[0],
+                this_frame.prune_margin)
+        };
+
+        // Apply union pruning, now that the uptree step has filled in the sibling distances. Smooth
+        // unions only ignore subtrees that are farther than their blend radius from the sibling.
+        // Slots of unions are scaled to a factor of 1, so their distances stay within their boxes.
+        if dt_block.parent_is_union {
+            let this_mindist = mindist(dt_block.bounding_box, dt_point);
+            if this_mindist > 0_f32
+                && (this_mindist > dt_ut_prune_cmp + dt_prune_margin
+                    || this_mindist > maxdist(dt_block.other_box, dt_point) + dt_prune_margin) {
+                stats.subtrees_pruned += 1;
+                stats.blocks_pruned += 1 + dt_block.len as usize;
+                last_dt_level = dt_block.level;
+                dt_index += 1 + dt_block.len as usize;
+                ut_index += 1 + dt_block.len as usize;
+                continue;
+            }
+        }   
+
         // Primitive case
         if dt_block.is_primitive {
             let this_frame = &mut point_stack[dt_block.level as usize];
+            let slot_scale = slot_scale(dt_block.parent_is_union, this_frame.lipschitz, dt_block.lipschitz);
             this_frame.branch_dists[this_frame.fill_idx as usize] = prim_dispatch(
                 dt_block.op_code,
                 dt_block.op_specific,
-                dt_block.bounding_box.trans_inverse * dt_point);
+                &sdf_tree.side_buffer,
+                dt_block.bounding_box.trans_inverse * dt_point) * dt_block.bounding_box.scale.w * slot_scale;
+            this_frame.branch_mats[this_frame.fill_idx as usize] = SdfMaterialMix::single(dt_block.material);
             this_frame.fill_idx += 1;
             ut_index += 1;
+            stats.primitives_evaluated += 1;
         } 
         // Non-primitive case
         else {
+            let op_point = dt_block.bounding_box.trans_inverse * dt_point;
             let child_frame = &mut point_stack[dt_block.level as usize + 1];
-            child_frame.branch_points = downtree_dispatch(
-                dt_block.op_code,
-                dt_block.op_specific,
-                dt_block.bounding_box.trans_inverse * dt_point);
+            child_frame.branch_points = downtree_dispatch(dt_block.op_code, dt_block.op_specific, op_point);
+            child_frame.op_point = op_point;
             child_frame.fill_idx = 0;
             child_frame.branch_dists = [f32::INFINITY; 2];
+            child_frame.branch_mats = [SdfMaterialMix::single(0); 2];
+            child_frame.prune_margin = prune_margin(dt_block.op_code, dt_block.op_specific);
+            child_frame.dist_scale = dt_block.bounding_box.scale.w;
+            child_frame.lipschitz = dt_block.lipschitz;
         }
 
         // Increment
@@ -188,21 +257,29 @@ pub fn nearest_neighbor(sdf_tree: &SdfTreeBuffer, point: Vec4) -> f32 {
         let ut_block = &sdf_tree.uptree_buffer[ut_index];
 
         // Perform uptree operation
-        let (lbranch_dist, rbranch_dist) = {
+        let (branch_point, dist_scale, lbranch, rbranch) = {
             let child_frame = &point_stack[ut_block.level as usize + 1];
-            (child_frame.branch_dists[0], child_frame.branch_dists[1])
+            (child_frame.op_point,
+                child_frame.dist_scale,
+                (child_frame.branch_dists[0], child_frame.branch_mats[0]),
+                (child_frame.branch_dists[1], child_frame.branch_mats[1]))
         };
-        let ut_frame = &mut point_stack[ut_block.level as usize];
-        ut_frame.branch_dists[ut_frame.fill_idx as usize] = uptree_dispatch(
+        let (distance, material) = uptree_dispatch(
             ut_block.op_code,
             ut_block.op_specific,
-            lbranch_dist,
-            rbranch_dist);
+            branch_point,
+            lbranch,
+            rbranch);
+        let ut_frame = &mut point_stack[ut_block.level as usize];
+        let fill_idx = ut_frame.fill_idx as usize;
+        let slot_scale = slot_scale(ut_block.parent_is_union, ut_frame.lipschitz, ut_block.lipschitz);
+        (ut_frame.branch_dists[fill_idx], ut_frame.branch_mats[fill_idx]) = (distance * dist_scale * slot_scale, material);
         ut_frame.fill_idx += 1;
 
         // Increment
         ut_index += 1;
     }
 
-    point_stack[1].branch_dists[0]
+    let lipschitz = sdf_tree.downtree_buffer.first().map_or(1.0, |root| root.lipschitz);
+    (point_stack[1].branch_dists[0] / lipschitz, point_stack[1].branch_mats[0], stats)
 }
\ No newline at end of file
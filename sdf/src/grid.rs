@@ -0,0 +1,534 @@
+use std::{fmt, sync::Arc};
+use super::math::*;
+use super::{
+    component::SdfOpSpecificBlock,
+    node::SdfNode,
+    elements::SdfGrid,
+};
+
+// Cells along each side of a brick. Bricks store the samples on both of their faces, so
+// interpolation never has to look into a neighboring brick.
+pub const BRICK_CELLS: usize = 8;
+const BRICK_SAMPLES: usize = BRICK_CELLS + 1;
+const BRICK_LEN: usize = BRICK_SAMPLES * BRICK_SAMPLES * BRICK_SAMPLES;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdfGridLayout {
+    // Every sample of the grid
+    Dense,
+    // Bricks of samples near the surface, found through an indirection table
+    Sparse,
+}
+
+impl SdfGridLayout {
+    fn id(&self) -> u32 {
+        match self {
+            SdfGridLayout::Dense => 0,
+            SdfGridLayout::Sparse => 1,
+        }
+    }
+
+    fn from_id(id: u32) -> Self {
+        match id {
+            0 => SdfGridLayout::Dense,
+            _ => SdfGridLayout::Sparse,
+        }
+    }
+}
+
+// How samples are stored. Quantized samples are packed into the bits of side buffer floats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdfGridPrecision {
+    Float,
+    Unorm16,
+    Unorm8,
+}
+
+impl SdfGridPrecision {
+    pub fn bits(&self) -> usize {
+        match self {
+            SdfGridPrecision::Float => 32,
+            SdfGridPrecision::Unorm16 => 16,
+            SdfGridPrecision::Unorm8 => 8,
+        }
+    }
+
+    fn from_bits(bits: usize) -> Self {
+        match bits {
+            16 => SdfGridPrecision::Unorm16,
+            8 => SdfGridPrecision::Unorm8,
+            _ => SdfGridPrecision::Float,
+        }
+    }
+
+    fn max_code(&self) -> u32 {
+        ((1_u64 << self.bits()) - 1) as u32
+    }
+}
+
+/**
+ * Shape of the packed data of a grid.
+ *
+ * Samples are stored in blocks that start on a whole float: a single block for dense grids, and
+ * one per brick for sparse grids, which come after their indirection table.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SdfGridFormat {
+    // Number of cells along each axis, a multiple of BRICK_CELLS for sparse grids
+    pub cells: UVec3,
+    pub layout: SdfGridLayout,
+    pub precision: SdfGridPrecision,
+    // Quantized distances are clamped to [-range, range]
+    pub range: f32,
+}
+
+impl SdfGridFormat {
+    pub fn bricks(&self) -> UVec3 {
+        self.cells / BRICK_CELLS as u32
+    }
+
+    // Samples along each axis of a block
+    fn block_samples(&self) -> UVec3 {
+        match self.layout {
+            SdfGridLayout::Dense => self.cells + UVec3::ONE,
+            SdfGridLayout::Sparse => UVec3::splat(BRICK_SAMPLES as u32),
+        }
+    }
+
+    fn block_len(&self) -> usize {
+        let samples = self.block_samples();
+        (samples.x * samples.y * samples.z) as usize
+    }
+
+    fn block_words(&self) -> usize {
+        (self.block_len() * self.precision.bits()).div_ceil(32)
+    }
+
+    fn table_len(&self) -> usize {
+        match self.layout {
+            SdfGridLayout::Dense => 0,
+            SdfGridLayout::Sparse => {
+                let bricks = self.bricks();
+                (bricks.x * bricks.y * bricks.z) as usize * 2
+            },
+        }
+    }
+
+    fn decode(&self, data: &[f32], block: usize, index: usize) -> f32 {
+        let start = self.table_len() + block * self.block_words();
+        if self.precision == SdfGridPrecision::Float {
+            return data[start + index];
+        }
+        let bits = self.precision.bits();
+        let word = data[start + index * bits / 32].to_bits();
+        let code = (word >> (index * bits % 32)) & self.precision.max_code();
+        (code as f32 / self.precision.max_code() as f32 * 2.0 - 1.0) * self.range
+    }
+
+    fn encode(&self, samples: &[f32]) -> Vec<f32> {
+        if self.precision == SdfGridPrecision::Float {
+            return samples.to_vec();
+        }
+        let bits = self.precision.bits();
+        let mut words = vec![0_u32; self.block_words()];
+        for (index, sample) in samples.iter().enumerate() {
+            let unorm = (sample / self.range).clamp(-1.0, 1.0) * 0.5 + 0.5;
+            let code = (unorm * self.precision.max_code() as f32).round() as u32;
+            words[index * bits / 32] |= code << (index * bits % 32);
+        }
+        words.into_iter().map(f32::from_bits).collect()
+    }
+}
+
+/**
+ * Distances sampled on a grid, either densely or as bricks that are only kept near the surface.
+ *
+ * Sparse grids are packed for the side buffer as an indirection table of two floats per brick,
+ * followed by the samples of the kept bricks. An entry holds the index of its brick, or -1 with a
+ * bound on the distance everywhere in the brick if it was left out.
+ */
+pub struct SdfGridData {
+    // Corner of the grid with the lowest coordinates
+    pub origin: Vec3,
+    pub voxel_size: f32,
+    pub format: SdfGridFormat,
+    data: Vec<f32>,
+}
+
+impl SdfGridData {
+    // Sample a distance function at every corner of the cells of a box
+    pub fn bake_dense(min: Vec3, max: Vec3, voxel_size: f32, distance: impl Fn(Vec3) -> f32) -> Self {
+        let cells = ((max - min) / voxel_size).ceil().max(Vec3::ONE).as_uvec3();
+        let mut data = Vec::with_capacity(((cells.x + 1) * (cells.y + 1) * (cells.z + 1)) as usize);
+        for z in 0..=cells.z {
+            for y in 0..=cells.y {
+                for x in 0..=cells.x {
+                    data.push(distance(min + UVec3::new(x, y, z).as_vec3() * voxel_size));
+                }
+            }
+        }
+        SdfGridData {
+            origin: min,
+            voxel_size,
+            format: SdfGridFormat {
+                cells,
+                layout: SdfGridLayout::Dense,
+                precision: SdfGridPrecision::Float,
+                range: 0.0,
+            },
+            data,
+        }
+    }
+
+    /**
+     * Sample a distance function over a box, keeping only the bricks that the surface could pass
+     * through. The function has to be an actual distance, or a lower bound of one, since the bounds
+     * of left out bricks are taken from its value at their centers.
+     */
+    pub fn bake_sparse(min: Vec3, max: Vec3, voxel_size: f32, distance: impl Fn(Vec3) -> f32) -> Self {
+        let brick_size = voxel_size * BRICK_CELLS as f32;
+        let bricks = ((max - min) / brick_size).ceil().max(Vec3::ONE).as_uvec3();
+        let half_diagonal = Vec3::splat(brick_size / 2.0).length();
+
+        let mut table = Vec::with_capacity((bricks.x * bricks.y * bricks.z) as usize * 2);
+        let mut samples = Vec::new();
+        for z in 0..bricks.z {
+            for y in 0..bricks.y {
+                for x in 0..bricks.x {
+                    let corner = min + UVec3::new(x, y, z).as_vec3() * brick_size;
+                    let center = distance(corner + Vec3::splat(brick_size / 2.0));
+                    // Leave a voxel of slack, so interpolation next to a left out brick is still right
+                    if center.abs() > half_diagonal + voxel_size {
+                        table.extend([-1.0, center - half_diagonal * center.signum()]);
+                        continue;
+                    }
+                    table.extend([(samples.len() / BRICK_LEN) as f32, 0.0]);
+                    for k in 0..BRICK_SAMPLES {
+                        for j in 0..BRICK_SAMPLES {
+                            for i in 0..BRICK_SAMPLES {
+                                samples.push(distance(corner + Vec3::new(i as f32, j as f32, k as f32) * voxel_size));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        table.extend(samples);
+        SdfGridData {
+            origin: min,
+            voxel_size,
+            format: SdfGridFormat {
+                cells: bricks * BRICK_CELLS as u32,
+                layout: SdfGridLayout::Sparse,
+                precision: SdfGridPrecision::Float,
+                range: 0.0,
+            },
+            data: table,
+        }
+    }
+
+    /**
+     * Re-encode the samples with another precision, clamping them to [-range, range].
+     *
+     * Clamped samples are still bounds on the distance, so a range of a few voxels is enough for
+     * sphere tracing, while it keeps the steps between quantized values small. The bounds of left
+     * out bricks stay full floats.
+     */
+    pub fn quantized(&self, precision: SdfGridPrecision, range: f32) -> Self {
+        let format = SdfGridFormat {
+            precision,
+            range,
+            ..self.format
+        };
+        let mut data = self.data[..self.format.table_len()].to_vec();
+        for block in 0..self.block_count() {
+            let samples = (0..self.format.block_len())
+                .map(|index| self.format.decode(&self.data, block, index))
+                .collect::<Vec<f32>>();
+            data.extend(format.encode(&samples));
+        }
+        SdfGridData {
+            origin: self.origin,
+            voxel_size: self.voxel_size,
+            format,
+            data,
+        }
+    }
+
+    fn block_count(&self) -> usize {
+        (self.data.len() - self.format.table_len()) / self.format.block_words()
+    }
+
+    // Number of bricks that were kept, or 1 for dense grids
+    pub fn brick_count(&self) -> usize {
+        match self.format.layout {
+            SdfGridLayout::Dense => 1,
+            SdfGridLayout::Sparse => self.block_count(),
+        }
+    }
+
+    // Indirection table and samples, as they're laid out in the side buffer
+    pub fn packed(&self) -> &[f32] {
+        &self.data
+    }
+}
+
+impl fmt::Debug for SdfGridData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SdfGridData")
+            .field("origin", &self.origin)
+            .field("voxel_size", &self.voxel_size)
+            .field("format", &self.format)
+            .field("brick_count", &self.brick_count())
+            .finish()
+    }
+}
+
+/**
+ * Bake a finished node into a sparse grid primitive, with `resolution` voxels along the longest
+ * side of the axis aligned box around it. The grid lives in the node's parent frame, so it can
+ * take the node's place without a transform.
+ */
+pub fn bake_grid(node: &SdfNode, resolution: u32) -> SdfGrid {
+    let (min, max) = node.bbox.unwrap().verts().iter()
+        .fold((Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)), |(min, max), vert| {
+            (min.min(vert.truncate()), max.max(vert.truncate()))
+        });
+    let voxel_size = (max - min).max_element() / resolution.max(1) as f32;
+    let margin = Vec3::splat(2.0 * voxel_size);
+    SdfGrid {
+        grid: Arc::new(SdfGridData::bake_sparse(min - margin, max + margin, voxel_size, |point| {
+            node.nearest_neighbor(point).distance
+        })),
+    }
+}
+
+// Sampled grid, with its samples read from packed side buffer data
+#[derive(Debug, Clone, Copy)]
+pub struct SdfGridView<'a> {
+    pub origin: Vec3,
+    pub voxel_size: f32,
+    pub format: SdfGridFormat,
+    data: &'a [f32],
+}
+
+impl<'a> SdfGridView<'a> {
+    pub fn new(grid: &'a SdfGridData) -> Self {
+        SdfGridView {
+            origin: grid.origin,
+            voxel_size: grid.voxel_size,
+            format: grid.format,
+            data: grid.packed(),
+        }
+    }
+
+    pub fn from_block(block: &SdfOpSpecificBlock, side_buffer: &'a [f32]) -> Self {
+        SdfGridView {
+            origin: block.vec4s[0].truncate(),
+            voxel_size: block.vec4s[0].w,
+            format: SdfGridFormat {
+                cells: block.vec4s[1].truncate().as_uvec3(),
+                layout: SdfGridLayout::from_id(block.vec4s[1].w as u32),
+                precision: SdfGridPrecision::from_bits(block.vec4s[2].x as usize),
+                range: block.vec4s[2].y,
+            },
+            data: &side_buffer[block.side_offset as usize..],
+        }
+    }
+
+    // Write the grid into an op-specific block; the side offset is filled in by the buffer
+    pub fn to_block(&self) -> SdfOpSpecificBlock {
+        let mut ret = SdfOpSpecificBlock::ZERO;
+        ret.vec4s[0] = self.origin.extend(self.voxel_size);
+        ret.vec4s[1] = self.format.cells.as_vec3().extend(self.format.layout.id() as f32);
+        ret.vec4s[2] = Vec4::new(self.format.precision.bits() as f32, self.format.range, 0.0, 0.0);
+        ret
+    }
+
+    // Center and half extents of the box the grid covers
+    pub fn extents(&self) -> (Vec3, Vec3) {
+        let half = self.format.cells.as_vec3() * (self.voxel_size / 2.0);
+        (self.origin + half, half)
+    }
+
+    // Interpolated distance at a point inside of the grid
+    fn sample(&self, point: Vec3) -> f32 {
+        let cells = self.format.cells;
+        let voxel = ((point - self.origin) / self.voxel_size).clamp(Vec3::ZERO, cells.as_vec3());
+        let (block, local) = match self.format.layout {
+            SdfGridLayout::Dense => (0, voxel),
+            SdfGridLayout::Sparse => {
+                let bricks = self.format.bricks();
+                let brick = (voxel / BRICK_CELLS as f32).floor().as_uvec3().min(bricks - UVec3::ONE);
+                let slot = ((brick.z * bricks.y + brick.y) * bricks.x + brick.x) as usize * 2;
+                if self.data[slot] < 0.0 {
+                    return self.data[slot + 1];
+                }
+                (self.data[slot] as usize, voxel - (brick * BRICK_CELLS as u32).as_vec3())
+            },
+        };
+
+        let samples = self.format.block_samples();
+        let cell = local.floor().min((samples - UVec3::splat(2)).as_vec3());
+        let t = local - cell;
+        let cell = cell.as_uvec3();
+        let at = |x: u32, y: u32, z: u32| self.format.decode(
+            self.data,
+            block,
+            (((cell.z + z) * samples.y + cell.y + y) * samples.x + cell.x + x) as usize,
+        );
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        lerp(
+            lerp(lerp(at(0, 0, 0), at(1, 0, 0), t.x), lerp(at(0, 1, 0), at(1, 1, 0), t.x), t.y),
+            lerp(lerp(at(0, 0, 1), at(1, 0, 1), t.x), lerp(at(0, 1, 1), at(1, 1, 1), t.x), t.y),
+            t.z,
+        )
+    }
+
+    /**
+     * Outside of the grid, the distance is bounded both by the distance to the grid's box and by
+     * the distance at the nearest point of the box, less the way there.
+     */
+    pub fn distance_to(&self, point: Vec3) -> f32 {
+        let (center, half_extents) = self.extents();
+        let inside = point.clamp(center - half_extents, center + half_extents);
+        let outside = (point - inside).length();
+        let distance = self.sample(inside);
+        if outside > 0.0 {
+            outside.max(distance - outside)
+        } else {
+            distance
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Arc;
+    use rand::prelude::*;
+    use crate::math::*;
+    use crate::{
+        grid::*,
+        node::*,
+        elements::*,
+        mesh::tests::sphere_mesh,
+        faux_shader,
+    };
+
+    fn random_point(rng: &mut ThreadRng, range: f32) -> Vec3 {
+        Vec3::new(
+            rng.gen_range(-range..range),
+            rng.gen_range(-range..range),
+            rng.gen_range(-range..range),
+        )
+    }
+
+    #[test]
+    fn test_sparse_grid() {
+        let mut rng = thread_rng();
+        let sphere = sphere_mesh(3);
+        let grid = sphere.bake(0.05);
+        let view = SdfGridView::new(&grid);
+        let bricks = grid.format.bricks();
+        let total = (bricks.x * bricks.y * bricks.z) as usize;
+        assert!(grid.brick_count() > 0 && grid.brick_count() < total,
+            "Kept {} of {} bricks!", grid.brick_count(), total);
+
+        for _ in 0..2000 {
+            let point = random_point(&mut rng, 1.6);
+            let exact = sphere.signed_distance(point);
+            let sampled = view.distance_to(point);
+            if exact.abs() < grid.voxel_size {
+                // Near the surface every brick is kept and interpolated
+                assert!((sampled - exact).abs() < 0.01, "Sampled {} at {}, exact {}!", sampled, point, exact);
+            } else {
+                // Farther away, left out bricks only give a bound, which has the right sign
+                assert!(sampled.signum() == exact.signum() && sampled.abs() <= exact.abs() + 0.01,
+                    "Sampled {} at {}, exact {}!", sampled, point, exact);
+            }
+        }
+
+        let far = Vec3::new(4.0, 0.0, 0.0);
+        assert!(view.distance_to(far) > 2.0 && view.distance_to(far) <= 3.0);
+    }
+
+    #[test]
+    fn test_grid_formats() {
+        let mut rng = thread_rng();
+        let sphere = |point: Vec3| point.length() - 1.0;
+        let (min, max) = (Vec3::splat(-1.5), Vec3::splat(1.5));
+        let dense = SdfGridData::bake_dense(min, max, 0.05, sphere);
+        let sparse = SdfGridData::bake_sparse(min, max, 0.05, sphere);
+        assert_eq!(dense.format.cells, UVec3::splat(60));
+        assert_eq!(dense.packed().len(), 61 * 61 * 61);
+        assert!(sparse.packed().len() < dense.packed().len());
+
+        let range = 0.5;
+        let half = dense.quantized(SdfGridPrecision::Unorm16, range);
+        let byte = dense.quantized(SdfGridPrecision::Unorm8, range);
+        let sparse_byte = sparse.quantized(SdfGridPrecision::Unorm8, range);
+        assert_eq!(half.packed().len(), (61 * 61 * 61_usize).div_ceil(2));
+        assert_eq!(byte.packed().len(), (61 * 61 * 61_usize).div_ceil(4));
+        assert_eq!(sparse_byte.brick_count(), sparse.brick_count());
+
+        for _ in 0..2000 {
+            let point = random_point(&mut rng, 1.5);
+            let exact = sphere(point);
+            let dense_distance = SdfGridView::new(&dense).distance_to(point);
+            // Interpolation can't follow the kink of the distance at the sphere's center
+            if point.length() > 2.0 * dense.voxel_size {
+                assert!((dense_distance - exact).abs() < 0.01);
+            }
+            if exact.abs() < 0.05 {
+                assert!((SdfGridView::new(&sparse).distance_to(point) - dense_distance).abs() < 1e-5);
+            }
+
+            // Quantization is off by at most one step, and never grows the distance past the range
+            for (grid, step) in [(&half, 2.0 * range / 65535.0), (&byte, 2.0 * range / 255.0), (&sparse_byte, 2.0 * range / 255.0)] {
+                let quantized = SdfGridView::new(grid).distance_to(point);
+                let expected = SdfGridView::new(if grid.format.layout == SdfGridLayout::Dense { &dense } else { &sparse })
+                    .distance_to(point);
+                // Corners of the cell past the range are clamped
+                if expected.abs() < range - 2.0 * dense.voxel_size {
+                    assert!((quantized - expected).abs() <= step, "{:?} gave {} instead of {}!", grid.format, quantized, expected);
+                } else {
+                    assert!(quantized.abs() <= expected.abs() + step && quantized.signum() == expected.signum());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bake_grid() {
+        let mut rng = thread_rng();
+        let sdf_tree = SdfBuilder::primitive(SdfSphere {
+            radius: 1.0,
+        })
+            .transform(Transform::from_xyz(-1.0, 0.0, 0.0))
+            .operation(SdfUnion::new(0.3))
+            .with(SdfBuilder::primitive(SdfSphere {
+                radius: 0.8,
+            }).transform(Transform::from_xyz(1.0, 0.5, 0.0)))
+            .transform(Transform::from_xyz(0.0, 2.0, 3.0).with_rotation(Quat::from_rotation_y(0.7)))
+            .finalize();
+        let baked = bake_grid(&sdf_tree, 48);
+        let grid = SdfGrid {
+            grid: Arc::new(baked.grid.quantized(SdfGridPrecision::Unorm16, 1.0)),
+        };
+        let grid_tree = SdfBuilder::primitive(grid).finalize();
+        let buffer = grid_tree.expanded().make_buffer();
+        let voxel_size = baked.grid.voxel_size;
+        for _ in 0..300 {
+            let point = random_point(&mut rng, 3.0) + Vec3::new(0.0, 2.0, 3.0);
+            let exact = sdf_tree.nearest_neighbor(point).distance;
+            let tree_result = grid_tree.nearest_neighbor(point).distance;
+            let buffer_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+            assert!((tree_result - buffer_result).abs() < 1e-4,
+                "Buffer Grid Failed! Tree Result: {}, Buffer Result: {}", tree_result, buffer_result);
+            if exact.abs() < voxel_size {
+                assert!((tree_result - exact).abs() < voxel_size / 2.0, "Baked {} at {}, exact {}!", tree_result, point, exact);
+            }
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use std::fmt;
+use super::math::*;
+use super::component::SdfOpSpecificBlock;
+
+// Enough levels for grids of up to 2^31 cells along each side
+const MAX_MIP_LEVELS: usize = 32;
+
+/**
+ * Dimensions and side data offsets of the levels of the min/max pyramid over the cells of a grid,
+ * finest first. Fixed size, so views can carry it without allocating on every query.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SdfMipLayout {
+    // Width, height and offset of each level, of which the first `len` are used
+    levels: [(usize, usize, usize); MAX_MIP_LEVELS],
+    len: usize,
+}
+
+impl SdfMipLayout {
+    pub fn new(columns: usize, rows: usize) -> Self {
+        let mut layout = SdfMipLayout {
+            levels: [(0, 0, 0); MAX_MIP_LEVELS],
+            len: 1,
+        };
+        layout.levels[0] = (columns - 1, rows - 1, columns * rows);
+        while layout.dims(layout.len - 1) != (1, 1) {
+            let (width, height, offset) = layout.levels[layout.len - 1];
+            layout.levels[layout.len] = (width.div_ceil(2), height.div_ceil(2), offset + width * height * 2);
+            layout.len += 1;
+        }
+        layout
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.len
+    }
+
+    pub fn dims(&self, level: usize) -> (usize, usize) {
+        let (width, height, _) = self.levels[level];
+        (width, height)
+    }
+
+    // Index of the min of a texel in the packed data, followed by its max
+    pub fn texel_index(&self, level: usize, texel: IVec2) -> usize {
+        let (width, _, offset) = self.levels[level];
+        offset + (texel.y as usize * width + texel.x as usize) * 2
+    }
+}
+
+/**
+ * Grid of heights in [0, 1], with columns along x and rows along z.
+ *
+ * Packed for the side buffer as the heights followed by a min/max pyramid over the cells between
+ * them, where each texel of a level covers 2x2 texels of the level below. A cell's bilinear patch
+ * never leaves the range of its four corners, so every texel bounds the surface over its cells.
+ */
+pub struct SdfHeightGrid {
+    pub columns: usize,
+    pub rows: usize,
+    data: Vec<f32>,
+    mips: SdfMipLayout,
+    // Steepest rise between neighboring samples along x and z, in heights per cell
+    steepest: Vec2,
+}
+
+impl SdfHeightGrid {
+    pub fn new(columns: usize, rows: usize, heights: Vec<f32>) -> Result<Self, &'static str> {
+        if columns < 2 || rows < 2 {
+            return Err("Height grid needs at least 2x2 samples!");
+        }
+        if heights.len() != columns * rows {
+            return Err("Height grid size doesn't match its dimensions!");
+        }
+        if heights.iter().any(|height| !height.is_finite()) {
+            return Err("Height grid has non-finite samples!");
+        }
+        // The slab between -depth and height that bounds the field relies on the range
+        if heights.iter().any(|height| !(0.0..=1.0).contains(height)) {
+            return Err("Height grid samples have to be in [0, 1]!");
+        }
+
+        let at = |x: usize, z: usize| heights[z * columns + x];
+        let mut steepest = Vec2::ZERO;
+        for z in 0..rows {
+            for x in 0..columns {
+                if x + 1 < columns {
+                    steepest.x = steepest.x.max((at(x + 1, z) - at(x, z)).abs());
+                }
+                if z + 1 < rows {
+                    steepest.y = steepest.y.max((at(x, z + 1) - at(x, z)).abs());
+                }
+            }
+        }
+
+        let mips = SdfMipLayout::new(columns, rows);
+        let mut levels: Vec<Vec<f32>> = Vec::with_capacity(mips.level_count());
+        for level in 0..mips.level_count() {
+            let (width, height) = mips.dims(level);
+            let mut texels = Vec::with_capacity(width * height * 2);
+            for z in 0..height {
+                for x in 0..width {
+                    let corners = match level {
+                        0 => vec![at(x, z), at(x + 1, z), at(x, z + 1), at(x + 1, z + 1)],
+                        _ => {
+                            let (below_width, below_height) = mips.dims(level - 1);
+                            let below = &levels[level - 1];
+                            (0..4)
+                                .map(|i| (2 * x + i % 2, 2 * z + i / 2))
+                                .filter(|(bx, bz)| *bx < below_width && *bz < below_height)
+                                .flat_map(|(bx, bz)| below[(bz * below_width + bx) * 2..][..2].to_vec())
+                                .collect()
+                        },
+                    };
+                    texels.push(corners.iter().copied().fold(f32::INFINITY, f32::min));
+                    texels.push(corners.iter().copied().fold(f32::NEG_INFINITY, f32::max));
+                }
+            }
+            levels.push(texels);
+        }
+
+        let mut data = heights;
+        data.extend(levels.into_iter().flatten());
+        Ok(SdfHeightGrid {
+            columns,
+            rows,
+            data,
+            mips,
+            steepest,
+        })
+    }
+
+    // Raw little-endian 16-bit samples, like the .r16 files that terrain tools export
+    pub fn from_raw_u16(bytes: &[u8], columns: usize, rows: usize) -> Result<Self, &'static str> {
+        if bytes.len() != columns * rows * 2 {
+            return Err("Raw height data size doesn't match its dimensions!");
+        }
+        let heights = bytes.chunks_exact(2)
+            .map(|sample| u16::from_le_bytes([sample[0], sample[1]]) as f32 / u16::MAX as f32)
+            .collect();
+        Self::new(columns, rows, heights)
+    }
+
+    // Grayscale PNG, preferably 16-bit, since 8 bits leave visible terraces
+    pub fn from_png(bytes: &[u8]) -> Result<Self, &'static str> {
+        let mut reader = png::Decoder::new(bytes)
+            .read_info()
+            .map_err(|_| "Failed reading PNG header!")?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).map_err(|_| "Failed decoding PNG!")?;
+        if info.color_type != png::ColorType::Grayscale {
+            return Err("Heightmap PNGs have to be grayscale!");
+        }
+        let heights = match info.bit_depth {
+            png::BitDepth::Sixteen => pixels[..info.buffer_size()]
+                .chunks_exact(2)
+                .map(|sample| u16::from_be_bytes([sample[0], sample[1]]) as f32 / u16::MAX as f32)
+                .collect(),
+            png::BitDepth::Eight => pixels[..info.buffer_size()]
+                .iter()
+                .map(|sample| *sample as f32 / u8::MAX as f32)
+                .collect(),
+            _ => return Err("Unsupported heightmap PNG bit depth!"),
+        };
+        Self::new(info.width as usize, info.height as usize, heights)
+    }
+
+    pub fn heights(&self) -> &[f32] {
+        &self.data[..self.columns * self.rows]
+    }
+
+    // Heights and pyramid, as they're laid out in the side buffer
+    pub fn packed(&self) -> &[f32] {
+        &self.data
+    }
+}
+
+impl fmt::Debug for SdfHeightGrid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SdfHeightGrid")
+            .field("columns", &self.columns)
+            .field("rows", &self.rows)
+            .field("steepest", &self.steepest)
+            .finish()
+    }
+}
+
+/**
+ * Heightfield over a rectangle of the xz-plane, with its grid read from packed side buffer data.
+ *
+ * The surface is `height` high at a sample of 1.0, and the heightfield is solid below it down to
+ * `depth` below the xz-plane.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SdfHeightfieldView<'a> {
+    pub columns: usize,
+    pub rows: usize,
+    pub half_size: Vec2,
+    pub height: f32,
+    pub depth: f32,
+    // Steepest slope of the surface, which bounds how much it can rise towards a point
+    pub lipschitz: f32,
+    mips: SdfMipLayout,
+    data: &'a [f32],
+}
+
+impl<'a> SdfHeightfieldView<'a> {
+    pub fn new(grid: &'a SdfHeightGrid, half_size: Vec2, height: f32, depth: f32) -> Self {
+        let cell = Self::cell_size_of(grid.columns, grid.rows, half_size);
+        SdfHeightfieldView {
+            columns: grid.columns,
+            rows: grid.rows,
+            half_size,
+            height,
+            depth,
+            lipschitz: (grid.steepest * height / cell).length(),
+            mips: grid.mips,
+            data: grid.packed(),
+        }
+    }
+
+    pub fn from_block(block: &SdfOpSpecificBlock, side_buffer: &'a [f32]) -> Self {
+        let (columns, rows) = (block.vec4s[1].x as usize, block.vec4s[1].y as usize);
+        SdfHeightfieldView {
+            columns,
+            rows,
+            half_size: block.vec4s[0].truncate().truncate(),
+            height: block.vec4s[0].z,
+            depth: block.vec4s[0].w,
+            lipschitz: block.vec4s[1].z,
+            mips: SdfMipLayout::new(columns, rows),
+            data: &side_buffer[block.side_offset as usize..],
+        }
+    }
+
+    // Write the shape into an op-specific block; the side offset is filled in by the buffer
+    pub fn to_block(&self) -> SdfOpSpecificBlock {
+        let mut ret = SdfOpSpecificBlock::ZERO;
+        ret.vec4s[0] = Vec4::new(self.half_size.x, self.half_size.y, self.height, self.depth);
+        ret.vec4s[1] = Vec4::new(self.columns as f32, self.rows as f32, self.lipschitz, 0.0);
+        ret
+    }
+
+    fn cell_size_of(columns: usize, rows: usize, half_size: Vec2) -> Vec2 {
+        half_size * 2.0 / Vec2::new((columns - 1) as f32, (rows - 1) as f32)
+    }
+
+    fn cell_size(&self) -> Vec2 {
+        Self::cell_size_of(self.columns, self.rows, self.half_size)
+    }
+
+    // Center and half extents of the box around the whole heightfield
+    pub fn slab(&self) -> (Vec3, Vec3) {
+        (
+            Vec3::new(0.0, (self.height - self.depth) / 2.0, 0.0),
+            Vec3::new(self.half_size.x, (self.height + self.depth) / 2.0, self.half_size.y),
+        )
+    }
+
+    // Bilinear interpolation of the grid, clamped to its edges
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let grid = ((Vec2::new(x, z) + self.half_size) / self.cell_size())
+            .clamp(Vec2::ZERO, Vec2::new((self.columns - 1) as f32, (self.rows - 1) as f32));
+        let cell = grid.floor().min(Vec2::new((self.columns - 2) as f32, (self.rows - 2) as f32));
+        let local = grid - cell;
+        let (cx, cz) = (cell.x as usize, cell.y as usize);
+        let at = |x: usize, z: usize| self.data[z * self.columns + x];
+        let near = at(cx, cz) + (at(cx + 1, cz) - at(cx, cz)) * local.x;
+        let far = at(cx, cz + 1) + (at(cx + 1, cz + 1) - at(cx, cz + 1)) * local.x;
+        (near + (far - near) * local.y) * self.height
+    }
+
+    /**
+     * Distance from a point to the solid columns under the pyramid texels around it.
+     *
+     * The pyramid is walked from its single top texel down, nearest texels first, skipping every
+     * texel that's no closer than the nearest column found so far. Far above low ground this is a
+     * much better bound than the slope, which has to account for the steepest part of the grid.
+     */
+    pub fn clearance(&self, point: Vec3) -> f32 {
+        let cells = IVec2::new(self.columns as i32 - 1, self.rows as i32 - 1);
+        let cell_size = self.cell_size();
+        let column_distance = |level: usize, texel: IVec2| {
+            let top = self.data[self.mips.texel_index(level, texel) + 1];
+            let first = texel * (1 << level);
+            let last = ((texel + IVec2::ONE) * (1 << level)).min(cells);
+            let min = (first.as_vec2() * cell_size - self.half_size).extend(-self.depth);
+            let max = (last.as_vec2() * cell_size - self.half_size).extend(top * self.height);
+            // Boxes are built in xzy order, so swizzle the point to match
+            let point = Vec3::new(point.x, point.z, point.y);
+            (min - point).max(point - max).max(Vec3::ZERO).length()
+        };
+
+        let mut best = f32::INFINITY;
+        // Every level leaves at most three siblings behind on the stack
+        let mut stack = [(0, IVec2::ZERO, 0.0); 3 * MAX_MIP_LEVELS + 1];
+        let top = self.mips.level_count() - 1;
+        stack[0] = (top, IVec2::ZERO, column_distance(top, IVec2::ZERO));
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let (level, texel, distance) = stack[stack_len];
+            if distance >= best {
+                continue;
+            }
+            if level == 0 {
+                best = distance;
+                continue;
+            }
+            let (width, height) = self.mips.dims(level - 1);
+            let children_start = stack_len;
+            for i in 0..4 {
+                let child = texel * 2 + IVec2::new(i % 2, i / 2);
+                if (child.x as usize) < width && (child.y as usize) < height {
+                    stack[stack_len] = (level - 1, child, column_distance(level - 1, child));
+                    stack_len += 1;
+                }
+            }
+            // The stack is popped from the back, so the nearest child goes last
+            stack[children_start..stack_len].sort_by(|a, b| b.2.total_cmp(&a.2));
+        }
+        best
+    }
+
+    pub fn distance_to(&self, point: Vec3) -> f32 {
+        let surface = (point.y - self.height_at(point.x, point.z)) / (1.0 + self.lipschitz * self.lipschitz).sqrt();
+        let (center, half_extents) = self.slab();
+        let q = (point - center).abs() - half_extents;
+        let slab = q.max(Vec3::ZERO).length() + q.max_element().min(0.0);
+        let distance = surface.max(slab);
+        if distance > 0.0 {
+            distance.max(self.clearance(point))
+        } else {
+            distance
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use float_cmp::approx_eq;
+    use crate::heightfield::*;
+
+    pub fn ridge_grid(columns: usize, rows: usize) -> SdfHeightGrid {
+        let heights = (0..rows)
+            .flat_map(|z| (0..columns).map(move |x| {
+                let (u, v) = (x as f32 / (columns - 1) as f32, z as f32 / (rows - 1) as f32);
+                0.5 + 0.5 * (u * 5.0).sin() * (v * 3.0).cos()
+            }))
+            .collect();
+        SdfHeightGrid::new(columns, rows, heights).unwrap()
+    }
+
+    // Brute force distance to the surface by densely sampling it
+    fn surface_distance(view: &SdfHeightfieldView, point: Vec3) -> f32 {
+        let steps = 200;
+        let mut best = f32::INFINITY;
+        for i in 0..=steps {
+            for j in 0..=steps {
+                let x = -view.half_size.x + 2.0 * view.half_size.x * i as f32 / steps as f32;
+                let z = -view.half_size.y + 2.0 * view.half_size.y * j as f32 / steps as f32;
+                best = best.min((Vec3::new(x, view.height_at(x, z), z) - point).length());
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn test_height_grid() {
+        let grid = ridge_grid(9, 5);
+        let view = SdfHeightfieldView::new(&grid, Vec2::new(4.0, 2.0), 2.0, 1.0);
+        // Samples are hit exactly, and cells are interpolated between them
+        assert!(approx_eq!(f32, view.height_at(-4.0, -2.0), grid.heights()[0] * 2.0));
+        assert!(approx_eq!(f32, view.height_at(4.0, 2.0), grid.heights()[44] * 2.0));
+        assert!(approx_eq!(f32, view.height_at(-3.5, -2.0), grid.heights()[0] + grid.heights()[1]));
+        assert!(approx_eq!(f32, view.height_at(-10.0, -2.0), view.height_at(-4.0, -2.0)));
+
+        // The top of the pyramid bounds the whole grid
+        let top = &grid.packed()[grid.packed().len() - 2..];
+        let mips = SdfMipLayout::new(9, 5);
+        assert_eq!((mips.level_count(), mips.dims(1)), (4, (4, 2)));
+        assert_eq!(mips.texel_index(mips.level_count() - 1, IVec2::ZERO), grid.packed().len() - 2);
+        assert_eq!(top[0], grid.heights().iter().copied().fold(f32::INFINITY, f32::min));
+        assert_eq!(top[1], grid.heights().iter().copied().fold(f32::NEG_INFINITY, f32::max));
+
+        assert!(SdfHeightGrid::new(1, 5, vec![0.0; 5]).is_err());
+        assert!(SdfHeightGrid::new(3, 3, vec![0.0; 8]).is_err());
+        assert!(SdfHeightGrid::new(2, 2, vec![0.0, 0.5, 1.0, 1.5]).is_err());
+        assert!(SdfHeightGrid::new(2, 2, vec![0.0, -0.1, 1.0, 0.5]).is_err());
+    }
+
+    #[test]
+    fn test_height_grid_loading() {
+        let (columns, rows) = (5, 3);
+        let samples = (0..(columns * rows) as u16).map(|i| i * 4000).collect::<Vec<u16>>();
+
+        let raw = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect::<Vec<u8>>();
+        let from_raw = SdfHeightGrid::from_raw_u16(&raw, columns, rows).unwrap();
+        assert!(SdfHeightGrid::from_raw_u16(&raw[1..], columns, rows).is_err());
+
+        let mut png_bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png_bytes, columns as u32, rows as u32);
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder.set_depth(png::BitDepth::Sixteen);
+            let big_endian = samples.iter().flat_map(|sample| sample.to_be_bytes()).collect::<Vec<u8>>();
+            encoder.write_header().unwrap().write_image_data(&big_endian).unwrap();
+        }
+        let from_png = SdfHeightGrid::from_png(&png_bytes).unwrap();
+        assert_eq!((from_png.columns, from_png.rows), (columns, rows));
+        assert_eq!(from_png.packed(), from_raw.packed());
+        assert!(approx_eq!(f32, from_png.heights()[1], 4000.0 / 65535.0));
+        assert!(SdfHeightGrid::from_png(&png_bytes[..20]).is_err());
+    }
+
+    #[test]
+    fn test_heightfield_bound() {
+        let mut rng = thread_rng();
+        let grid = ridge_grid(17, 17);
+        let view = SdfHeightfieldView::new(&grid, Vec2::splat(4.0), 3.0, 1.0);
+        for _ in 0..300 {
+            let point = Vec3::new(
+                rng.gen_range(-3.9..3.9),
+                rng.gen_range(-0.5..8.0),
+                rng.gen_range(-3.9..3.9),
+            );
+            let distance = view.distance_to(point);
+            let above = point.y > view.height_at(point.x, point.z);
+            assert_eq!(distance > 0.0, above, "Wrong side of the surface at {}!", point);
+            if above {
+                let exact = surface_distance(&view, point);
+                assert!(distance <= exact + 0.02, "Overestimated {} at {}, exact {}!", distance, point, exact);
+            }
+        }
+    }
+
+    #[test]
+    fn test_heightfield_skipping() {
+        // A single spike makes the slope bound useless everywhere, but the pyramid knows that the
+        // rest of the grid is flat
+        let mut heights = vec![0.0; 33 * 33];
+        heights[16 * 33 + 16] = 1.0;
+        let grid = SdfHeightGrid::new(33, 33, heights).unwrap();
+        let view = SdfHeightfieldView::new(&grid, Vec2::splat(16.0), 10.0, 1.0);
+        assert!(view.lipschitz > 10.0);
+
+        let point = Vec3::new(-12.0, 4.0, 9.0);
+        let slope_bound = point.y / (1.0 + view.lipschitz * view.lipschitz).sqrt();
+        assert!(slope_bound < 0.3);
+        assert!(approx_eq!(f32, view.distance_to(point), 4.0));
+        assert!(view.clearance(Vec3::new(0.0, 12.0, 0.0)) <= 2.0);
+    }
+}
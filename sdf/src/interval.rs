@@ -0,0 +1,303 @@
+use std::ops::{Add, Sub, Neg, Mul};
+use super::math::*;
+
+// Closed range of values, for evaluating distances over whole regions at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SdfInterval {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl SdfInterval {
+    pub fn new(min: f32, max: f32) -> Self {
+        SdfInterval { min, max }
+    }
+
+    pub fn point(value: f32) -> Self {
+        SdfInterval::new(value, value)
+    }
+
+    // Values of a function that changes at most by `radius` away from where it's `value`
+    pub fn around(value: f32, radius: f32) -> Self {
+        SdfInterval::new(value - radius, value + radius)
+    }
+
+    pub fn width(&self) -> f32 {
+        self.max - self.min
+    }
+
+    pub fn contains(&self, value: f32) -> bool {
+        self.min <= value && value <= self.max
+    }
+
+    // Overlap of two intervals that both hold, None if they don't overlap
+    pub fn intersect(self, other: Self) -> Option<Self> {
+        let overlap = SdfInterval::new(self.min.max(other.min), self.max.min(other.max));
+        if overlap.min <= overlap.max {
+            Some(overlap)
+        } else {
+            None
+        }
+    }
+
+    pub fn abs(self) -> Self {
+        if self.min >= 0.0 {
+            self
+        } else if self.max <= 0.0 {
+            -self
+        } else {
+            SdfInterval::new(0.0, self.max.max(-self.min))
+        }
+    }
+
+    pub fn sqr(self) -> Self {
+        let abs = self.abs();
+        SdfInterval::new(abs.min * abs.min, abs.max * abs.max)
+    }
+
+    pub fn sqrt(self) -> Self {
+        SdfInterval::new(self.min.max(0.0).sqrt(), self.max.max(0.0).sqrt())
+    }
+
+    pub fn min(self, other: Self) -> Self {
+        SdfInterval::new(self.min.min(other.min), self.max.min(other.max))
+    }
+
+    pub fn max(self, other: Self) -> Self {
+        SdfInterval::new(self.min.max(other.min), self.max.max(other.max))
+    }
+
+    pub fn clamp(self, min: f32, max: f32) -> Self {
+        SdfInterval::new(self.min.clamp(min, max), self.max.clamp(min, max))
+    }
+}
+
+impl Add for SdfInterval {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        SdfInterval::new(self.min + other.min, self.max + other.max)
+    }
+}
+
+impl Add<f32> for SdfInterval {
+    type Output = Self;
+
+    fn add(self, other: f32) -> Self {
+        SdfInterval::new(self.min + other, self.max + other)
+    }
+}
+
+impl Sub for SdfInterval {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        SdfInterval::new(self.min - other.max, self.max - other.min)
+    }
+}
+
+impl Sub<f32> for SdfInterval {
+    type Output = Self;
+
+    fn sub(self, other: f32) -> Self {
+        SdfInterval::new(self.min - other, self.max - other)
+    }
+}
+
+impl Neg for SdfInterval {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        SdfInterval::new(-self.max, -self.min)
+    }
+}
+
+impl Mul<f32> for SdfInterval {
+    type Output = Self;
+
+    fn mul(self, other: f32) -> Self {
+        if other >= 0.0 {
+            SdfInterval::new(self.min * other, self.max * other)
+        } else {
+            SdfInterval::new(self.max * other, self.min * other)
+        }
+    }
+}
+
+// Length of a vector whose components lie in the given intervals
+pub fn length_interval(components: [SdfInterval; 3]) -> SdfInterval {
+    components.iter()
+        .fold(SdfInterval::point(0.0), |sum, component| sum + component.sqr())
+        .sqrt()
+}
+
+// Axis-aligned region of space, which interval evaluation bounds distances over
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SdfAabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl SdfAabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        SdfAabb {
+            min: min.min(max),
+            max: min.max(max),
+        }
+    }
+
+    pub fn from_extents(center: Vec3, half_extents: Vec3) -> Self {
+        SdfAabb::new(center - half_extents, center + half_extents)
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn half_extents(&self) -> Vec3 {
+        (self.max - self.min) / 2.0
+    }
+
+    // Farthest any point of the region is from its center
+    pub fn radius(&self) -> f32 {
+        self.half_extents().length()
+    }
+
+    pub fn contains(&self, point: Vec3) -> bool {
+        point.cmpge(self.min).all() && point.cmple(self.max).all()
+    }
+
+    pub fn axis(&self, axis: usize) -> SdfInterval {
+        SdfInterval::new(self.min[axis], self.max[axis])
+    }
+
+    pub fn axes(&self) -> [SdfInterval; 3] {
+        [self.axis(0), self.axis(1), self.axis(2)]
+    }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i| Vec3::new(
+            if i & 1 == 0 { self.min.x } else { self.max.x },
+            if i & 2 == 0 { self.min.y } else { self.max.y },
+            if i & 4 == 0 { self.min.z } else { self.max.z },
+        ))
+    }
+
+    // Region around the corners moved by an affine map, which contains the whole moved region
+    pub fn map_corners<F: Fn(Vec3) -> Vec3>(&self, map: F) -> Self {
+        let corners = self.corners().map(map);
+        corners.iter().fold(
+            SdfAabb::new(corners[0], corners[0]),
+            |region, corner| SdfAabb::new(region.min.min(*corner), region.max.max(*corner)),
+        )
+    }
+
+    // Distances from the origin to the points of the region
+    pub fn norm(&self) -> SdfInterval {
+        length_interval(self.axes())
+    }
+
+    // Signed distances to a box around the origin, like the exact box SDF at every point
+    pub fn box_distance(&self, half_extents: Vec3) -> SdfInterval {
+        let q = [0, 1, 2].map(|i| self.axis(i).abs() - half_extents[i]);
+        let outside = length_interval(q.map(|q| q.max(SdfInterval::point(0.0))));
+        let inside = q[0].max(q[1]).max(q[2]).min(SdfInterval::point(0.0));
+        outside + inside
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use crate::{
+        interval::*,
+        node::*,
+        elements::*,
+        noise::*,
+    };
+
+    fn random_region<R: Rng>(rng: &mut R, size: f32) -> SdfAabb {
+        let center = Vec3::new(rng.gen_range(-6.0..6.0), rng.gen_range(-3.0..5.0), rng.gen_range(-6.0..6.0));
+        let half_extents = Vec3::new(rng.gen_range(0.0..size), rng.gen_range(0.0..size), rng.gen_range(0.0..size));
+        SdfAabb::from_extents(center, half_extents)
+    }
+
+    #[test]
+    fn test_eval_interval() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let sdf_tree = SdfBuilder::primitive(SdfTerrain {
+                half_size: Vec2::new(4.0, 4.0),
+                amplitude: 0.5,
+                depth: 1.0,
+                noise: SdfNoise::new(SdfNoiseBasis::Perlin, 2).with_frequency(0.5),
+            })
+            .operation(SdfUnion::new(0.3))
+            .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 0.8,
+                })
+                .operation(SdfDisplace {
+                    amplitude: 0.2,
+                    noise: SdfNoise::new(SdfNoiseBasis::Simplex, 4).with_frequency(2.0),
+                })
+                .transform(Transform::from_xyz(-2.0, 2.0, 1.0).with_scale(Vec3::new(1.0, 2.0, 0.5))))
+            .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 0.5,
+                })
+                .operation(SdfMorph {
+                    t: 0.4,
+                })
+                .with(SdfBuilder::primitive(SdfCapsule {
+                    a: Vec3::new(-0.5, 0.0, 0.0),
+                    b: Vec3::new(0.5, 0.0, 0.0),
+                    radius_a: 0.3,
+                    radius_b: 0.2,
+                }))
+                .transform(Transform::from_xyz(2.0, 2.5, -1.0)))
+            .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 0.2,
+                })
+                .operation(SdfCaaClone {
+                    displacement: Vec3::splat(1.0),
+                    neg_limit: Vec3::new(-1.0, 0.0, -1.0),
+                    pos_limit: Vec3::new(1.0, 1.0, 1.0),
+                })
+                .transform(Transform::from_xyz(0.0, 3.5, 3.0)))
+            .finalize();
+
+        // Every distance in a region lies in its interval
+        for _ in 0..300 {
+            let region = random_region(&mut rng, 1.5);
+            let (min, max) = sdf_tree.eval_interval(region);
+            assert!(min <= max, "Empty interval {} to {} over {:?}", min, max, region);
+            for _ in 0..20 {
+                let point = region.min + (region.max - region.min) * Vec3::new(rng.gen(), rng.gen(), rng.gen());
+                let distance = sdf_tree.nearest_neighbor(point).distance;
+                assert!(min - 1e-4 <= distance && distance <= max + 1e-4,
+                    "Distance {} at {} outside of {} to {} over {:?}", distance, point, min, max, region);
+            }
+        }
+
+        // Spheres map intervals exactly, which beats the bounds of their boxes
+        let sphere = SdfBuilder::primitive(SdfSphere {
+                radius: 1.0,
+            })
+            .transform(Transform::from_xyz(1.0, 0.0, 0.0))
+            .finalize();
+        let bbox = sphere.bbox.unwrap();
+        for _ in 0..100 {
+            let region = random_region(&mut rng, 0.5);
+            let (min, max) = sphere.eval_interval(region);
+            let exact = (region.map_corners(|corner| corner - Vec3::X).norm()) - 1.0;
+            assert!((min - exact.min).abs() < 1e-4 && (max - exact.max).abs() < 1e-4);
+            let (center, radius) = (region.center(), region.radius());
+            assert!(max <= bbox.max_distance(center) + radius + 1e-4);
+            assert!(min >= bbox.distance_to(center) - radius - 1e-4);
+        }
+        let far = SdfAabb::from_extents(Vec3::new(5.0, 0.0, 0.0), Vec3::splat(0.5));
+        assert!(sphere.eval_interval(far).0 > 0.0, "A region away from the sphere could contain surface!");
+        let across = SdfAabb::from_extents(Vec3::new(2.0, 0.0, 0.0), Vec3::splat(0.2));
+        let (min, max) = sphere.eval_interval(across);
+        assert!(min < 0.0 && max > 0.0, "A region across the surface can't contain it!");
+    }
+}
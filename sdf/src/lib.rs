@@ -1,5 +1,26 @@
+// Lets #[derive(SdfElement)] refer to this crate by name from inside of it
+extern crate self as sdf;
+
+pub mod math;
 pub mod obb;
 pub mod node;
 pub mod component;
 pub mod elements;
-pub mod faux_shader;
\ No newline at end of file
+pub mod faux_shader;
+pub mod cull;
+pub mod tree;
+pub mod buffer;
+pub mod material;
+pub mod anim;
+pub mod noise;
+pub mod heightfield;
+pub mod mesh;
+pub mod grid;
+pub mod shape2d;
+pub mod curve;
+pub mod text;
+pub mod registry;
+pub mod march;
+pub mod lipschitz;
+pub mod interval;
+pub mod dual;
@@ -0,0 +1,181 @@
+use rand::prelude::*;
+use super::math::*;
+use super::{
+    node::SdfNode,
+    registry::registry,
+};
+
+// Step of the finite differences, and how far a measured slope may go over its bound
+const STEP: f32 = 1e-3;
+const TOLERANCE: f32 = 0.05;
+
+#[derive(Debug, Clone)]
+pub struct SdfLipschitzViolation {
+    // Slot indices that lead from the root to the node
+    pub path: Vec<usize>,
+    // Name of the element in the registry
+    pub name: &'static str,
+    pub declared: f32,
+    pub measured: f32,
+    // Where the steepest slope was measured, in the frame of the node's bounding box
+    pub point: Vec3,
+}
+
+/**
+ * Debug check of the Lipschitz factors that elements declare. The distances of every subtree are
+ * sampled around its bounding box, and subtrees whose slope is steeper than their accumulated
+ * factor are reported.
+ *
+ * Only the deepest violating nodes are reported, since their ancestors are steep because of them.
+ * Slopes are measured along the gradient, so they never exceed the true factor, but a violation
+ * can still be missed if none of the samples hits the steepest spot.
+ */
+pub fn validate_lipschitz<R: Rng>(root: &SdfNode, samples: usize, rng: &mut R) -> Vec<SdfLipschitzViolation> {
+    let mut violations = Vec::new();
+    validate_node(root, &mut Vec::new(), samples, rng, &mut violations);
+    violations
+}
+
+// Whether the node or one of its descendants was reported
+fn validate_node<R: Rng>(
+    node: &SdfNode,
+    path: &mut Vec<usize>,
+    samples: usize,
+    rng: &mut R,
+    violations: &mut Vec<SdfLipschitzViolation>,
+) -> bool {
+    let mut reported = false;
+    for (i, slot) in node.slots().iter().enumerate() {
+        path.push(i);
+        reported |= validate_node(slot, path, samples, rng, violations);
+        path.pop();
+    }
+    if reported {
+        return true;
+    }
+
+    let declared = node.lipschitz();
+    let distance = |point: Vec3| node.raw_nearest_neighbor(point).distance;
+    let verts = node.bbox.unwrap().verts();
+    let (min, max) = verts.iter().fold(
+        (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+        |(min, max), vert| (min.min(vert.truncate()), max.max(vert.truncate())),
+    );
+    // Some of the samples land outside of the box, where the distance has to behave too
+    let margin = (max - min) * 0.25;
+    let (min, max) = (min - margin, max + margin);
+
+    let (measured, point) = (0..samples)
+        .map(|_| {
+            let point = min + (max - min) * Vec3::new(rng.gen(), rng.gen(), rng.gen());
+            (slope(&distance, point), point)
+        })
+        .fold((0.0, Vec3::ZERO), |worst, sample| if sample.0 > worst.0 { sample } else { worst });
+    if measured <= declared * (1.0 + TOLERANCE) {
+        return false;
+    }
+
+    let info = node.element().get_info();
+    let entry_name = if info.is_primitive {
+        registry().primitive(info.op_id).map(|entry| entry.name)
+    } else {
+        registry().operation(info.op_id).map(|entry| entry.name)
+    };
+    violations.push(SdfLipschitzViolation {
+        path: path.clone(),
+        name: entry_name.unwrap_or("unregistered element"),
+        declared,
+        measured,
+        point,
+    });
+    true
+}
+
+// Slope of a function along its gradient, estimated with central differences
+fn slope<F: Fn(Vec3) -> f32>(distance: &F, point: Vec3) -> f32 {
+    let gradient = Vec3::new(
+        distance(point + Vec3::X * STEP) - distance(point - Vec3::X * STEP),
+        distance(point + Vec3::Y * STEP) - distance(point - Vec3::Y * STEP),
+        distance(point + Vec3::Z * STEP) - distance(point - Vec3::Z * STEP),
+    );
+    if gradient.length_squared() == 0.0 || !gradient.is_finite() {
+        return 0.0;
+    }
+    let dir = gradient.normalize();
+    (distance(point + dir * STEP) - distance(point - dir * STEP)).abs() / (2.0 * STEP)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Arc;
+    use rand::prelude::*;
+    use crate::math::*;
+    use crate::{
+        lipschitz::*,
+        node::*,
+        elements::*,
+        noise::*,
+    };
+
+    fn steep_closure(lipschitz: f32) -> SdfBuilder {
+        SdfBuilder::primitive(SdfClosure {
+            min: Vec3::splat(-1.0),
+            max: Vec3::splat(1.0),
+            distance: Arc::new(|point: Vec3| 3.0 * (point.length() - 1.0)),
+            lipschitz,
+        })
+    }
+
+    fn scene(closure: SdfBuilder) -> SdfNode {
+        SdfBuilder::primitive(SdfSphere {
+                radius: 1.0,
+            })
+            .operation(SdfDisplace {
+                amplitude: 0.3,
+                noise: SdfNoise::new(SdfNoiseBasis::Perlin, 11).with_octaves(2, 2.0, 0.5),
+            })
+            .operation(SdfUnion::new(0.2))
+            .with(closure.transform(Transform::from_xyz(3.0, 0.0, 0.0)))
+            .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 0.5,
+                })
+                .operation(SdfMorph {
+                    t: 1.5,
+                })
+                .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 1.0,
+                }))
+                .transform(Transform::from_xyz(0.0, 3.0, 0.0)))
+            .finalize()
+    }
+
+    #[test]
+    fn test_validate_lipschitz() {
+        let mut rng = StdRng::seed_from_u64(5);
+
+        // Only the closure that understates its factor is flagged
+        let violations = validate_lipschitz(&scene(steep_closure(1.0)), 400, &mut rng);
+        assert_eq!(violations.len(), 1, "{:?}", violations);
+        assert_eq!(violations[0].path, vec![1]);
+        assert_eq!(violations[0].name, "closure");
+        assert!((violations[0].measured - 3.0).abs() < 0.1, "Measured {}", violations[0].measured);
+
+        let sdf_tree = scene(steep_closure(3.0));
+        assert!(validate_lipschitz(&sdf_tree, 400, &mut rng).is_empty());
+        // Morphing past either shape, displacement and the closure each only steepen their own
+        // subtree, since the union divides its slots by their factors
+        let displace_factor = 1.0 + 0.3 * SdfNoise::new(SdfNoiseBasis::Perlin, 11).with_octaves(2, 2.0, 0.5).lipschitz();
+        assert!((sdf_tree.slots()[0].lipschitz() - displace_factor).abs() < 1e-6);
+        assert_eq!(sdf_tree.slots()[1].lipschitz(), 3.0);
+        assert_eq!(sdf_tree.slots()[2].lipschitz(), 2.0);
+        assert_eq!(sdf_tree.lipschitz(), 1.0);
+
+        // Scaled distances never overestimate the distance to the closure's sphere
+        for _ in 0..100 {
+            let point = Vec3::new(rng.gen_range(2.0..4.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+            let exact = (point - Vec3::new(3.0, 0.0, 0.0)).length() - 1.0;
+            let distance = sdf_tree.nearest_neighbor(point).distance;
+            assert!(distance <= exact + 1e-4, "{} over exact distance {} at {}", distance, exact, point);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use super::math::*;
+use super::{
+    node::SdfNode,
+    component::SdfTreeBuffer,
+    faux_shader,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SdfRayHit {
+    // Distance along the normalized ray
+    pub t: f32,
+    pub point: Vec3,
+    pub material: u32,
+    pub steps: usize,
+}
+
+/**
+ * Sphere tracer that runs on the CPU, for picking and for checking what the shader draws.
+ *
+ * Steps are distances divided by the Lipschitz factors of the subtrees, so displaced and otherwise
+ * steepened surfaces aren't stepped through, without slowing down the steps towards the rest.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SdfRaymarcher {
+    pub max_steps: usize,
+    // Distance below which the surface counts as hit
+    pub epsilon: f32,
+    pub max_distance: f32,
+}
+
+impl SdfRaymarcher {
+    pub fn new(max_distance: f32) -> Self {
+        SdfRaymarcher {
+            max_steps: 256,
+            epsilon: 1e-4,
+            max_distance,
+        }
+    }
+
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn with_epsilon(mut self, epsilon: f32) -> Self {
+        self.epsilon = epsilon;
+        self
+    }
+
+    // Trace a ray through a tree, starting where it enters the tree's bounding box
+    pub fn march(&self, node: &SdfNode, origin: Vec3, dir: Vec3) -> Option<SdfRayHit> {
+        let dir = dir.normalize();
+        let (t_enter, t_exit) = node.bbox.unwrap().ray_intersect(origin, dir)?;
+        self.trace(origin, dir, t_enter.max(0.0), t_exit.min(self.max_distance), |point| {
+            node.nearest_material_id(point)
+        })
+    }
+
+    // Trace a ray through a buffer with the faux shader, which scales by the factors of its blocks
+    pub fn march_buffer(&self, buffer: &SdfTreeBuffer, origin: Vec3, dir: Vec3) -> Option<SdfRayHit> {
+        let dir = dir.normalize();
+        self.trace(origin, dir, 0.0, self.max_distance, |point| {
+            faux_shader::nearest_neighbor(buffer, point.extend(1.0))
+        })
+    }
+
+    fn trace<F: Fn(Vec3) -> (f32, u32)>(&self, origin: Vec3, dir: Vec3, t_start: f32, t_end: f32, distance: F) -> Option<SdfRayHit> {
+        let mut t = t_start;
+        for steps in 0..self.max_steps {
+            if t > t_end {
+                return None;
+            }
+            let point = origin + dir * t;
+            let (distance, material) = distance(point);
+            if distance < self.epsilon {
+                return Some(SdfRayHit {
+                    t,
+                    point,
+                    material,
+                    steps: steps + 1,
+                });
+            }
+            t += distance;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use crate::{
+        march::*,
+        node::*,
+        elements::*,
+        noise::*,
+    };
+
+    #[test]
+    fn test_raymarch() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let center = Vec3::new(2.0, -1.0, 3.0);
+        let sphere = SdfBuilder::primitive(SdfSphere {
+                radius: 1.5,
+            })
+            .transform(Transform::from_translation(center))
+            .finalize();
+        let marcher = SdfRaymarcher::new(50.0);
+        for _ in 0..50 {
+            let dir = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)).normalize();
+            let origin = center + dir * 8.0;
+            // Straight at the center, the hit is a radius away from it
+            let hit = marcher.march(&sphere, origin, -dir).unwrap();
+            assert!((hit.t - 6.5).abs() < 1e-3, "Hit at {} instead of 6.5", hit.t);
+            assert!(((hit.point - center).length() - 1.5).abs() < 1e-3);
+            // Away from it, the ray misses
+            assert!(marcher.march(&sphere, origin, dir).is_none());
+        }
+
+        // Displacement steepens the distance, which the factor of its subtree makes up for
+        let displace = SdfDisplace {
+            amplitude: 0.6,
+            noise: SdfNoise::new(SdfNoiseBasis::Perlin, 3).with_octaves(3, 2.0, 0.5),
+        };
+        let sdf_tree = SdfBuilder::primitive(SdfSphere {
+                radius: 2.0,
+            })
+            .operation(displace.clone())
+            .operation(SdfUnion::new(0.0))
+            .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 1.0,
+                })
+                .transform(Transform::from_xyz(4.0, 0.0, 0.0)))
+            .finalize();
+        assert!((sdf_tree.slots()[0].lipschitz() - 1.0 / displace.step_scale()).abs() < 1e-6);
+        // ...but not for the sibling of the displaced subtree
+        assert_eq!(sdf_tree.lipschitz(), 1.0);
+        let buffer = sdf_tree.expanded().make_buffer();
+        assert_eq!(buffer.downtree_buffer[0].lipschitz, 1.0);
+        assert_eq!(buffer.downtree_buffer.iter().filter(|block| block.lipschitz == sdf_tree.slots()[0].lipschitz()).count(), 1);
+        // Straight at the center of the sphere, the steps aren't shortened by the displacement
+        let hit = marcher.march(&sdf_tree, Vec3::new(4.0, 0.0, 10.0), -Vec3::Z).unwrap();
+        assert!((hit.t - 9.0).abs() < 1e-3);
+        assert!(hit.steps < 16, "Took {} steps", hit.steps);
+        let raw = |point: Vec3| (point.length() - 2.0 + displace.amplitude * displace.noise.sample(point))
+            .min((point - Vec3::new(4.0, 0.0, 0.0)).length() - 1.0);
+        for _ in 0..50 {
+            let dir = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)).normalize();
+            let origin = -dir * 10.0;
+            // Grazing rays take many short steps along the displaced surface
+            let marcher = marcher.with_max_steps(1024);
+            let hit = marcher.march(&sdf_tree, origin, dir).unwrap();
+            assert!(raw(hit.point).abs() < 1e-3, "Hit at {} isn't on the surface", hit.point);
+            // Nothing before the hit is inside
+            let steps = 200;
+            for i in 0..steps {
+                assert!(raw(origin + dir * hit.t * i as f32 / steps as f32) > 0.0, "Ray stepped through the surface!");
+            }
+            let buffer_hit = marcher.march_buffer(&buffer, origin, dir).unwrap();
+            assert!((buffer_hit.t - hit.t).abs() < 1e-3, "Buffer hit at {} instead of {}", buffer_hit.t, hit.t);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use super::math::*;
+
+// Surface appearance of a primitive. Laid out as two vec4s for the shader.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct SdfMaterial {
+    pub albedo: Vec3,
+    pub roughness: f32,
+    pub emission: Vec3,
+    pub metallic: f32,
+}
+
+impl SdfMaterial {
+    pub const DEFAULT: SdfMaterial = SdfMaterial {
+        albedo: Vec3::ONE,
+        roughness: 0.5,
+        emission: Vec3::ZERO,
+        metallic: 0.0,
+    };
+
+    // Linear interpolation between two materials, where t = 1.0 gives the other material
+    pub fn mix(&self, other: &SdfMaterial, t: f32) -> SdfMaterial {
+        SdfMaterial {
+            albedo: self.albedo.lerp(other.albedo, t),
+            roughness: self.roughness + (other.roughness - self.roughness) * t,
+            emission: self.emission.lerp(other.emission, t),
+            metallic: self.metallic + (other.metallic - self.metallic) * t,
+        }
+    }
+}
+
+impl Default for SdfMaterial {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/**
+ * Material IDs of the surface found by a nearest neighbor search.
+ *
+ * Smooth unions blend the materials of both of their sides. Only the two heaviest materials are
+ * kept, so nested blends are approximated by their dominant pair.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SdfMaterialMix {
+    pub ids: [u32; 2],
+    // Weight of ids[1]; always at most 0.5, so ids[0] is the dominant material
+    pub weight: f32,
+}
+
+impl SdfMaterialMix {
+    pub fn single(id: u32) -> Self {
+        SdfMaterialMix {
+            ids: [id, id],
+            weight: 0.0,
+        }
+    }
+
+    pub fn dominant(&self) -> u32 {
+        self.ids[0]
+    }
+
+    // Blend two mixes, where t = 1.0 gives the other mix
+    pub fn blend(&self, other: &SdfMaterialMix, t: f32) -> SdfMaterialMix {
+        let mut weights: Vec<(u32, f32)> = Vec::with_capacity(4);
+        let parts = [
+            (self.ids[0], (1.0 - t) * (1.0 - self.weight)),
+            (self.ids[1], (1.0 - t) * self.weight),
+            (other.ids[0], t * (1.0 - other.weight)),
+            (other.ids[1], t * other.weight),
+        ];
+        for (id, weight) in parts {
+            match weights.iter_mut().find(|(other_id, _)| *other_id == id) {
+                Some((_, total)) => *total += weight,
+                None => weights.push((id, weight)),
+            }
+        }
+        weights.sort_by(|a, b| b.1.total_cmp(&a.1));
+        match weights.as_slice() {
+            [(id, _)] => Self::single(*id),
+            [(first, first_weight), (second, second_weight), ..] if *second_weight > 0.0 => SdfMaterialMix {
+                ids: [*first, *second],
+                weight: second_weight / (first_weight + second_weight),
+            },
+            _ => Self::single(weights[0].0),
+        }
+    }
+}
+
+/**
+ * Polynomial smooth minimum of two distances, with the weight of the second distance.
+ *
+ * The result only differs from min(a, b) when the distances are less than `radius` apart, which
+ * is what lets union pruning skip subtrees that are farther than that from their sibling.
+ */
+pub fn smooth_min(a: f32, b: f32, radius: f32) -> (f32, f32) {
+    if radius <= 0.0 || !a.is_finite() || !b.is_finite() {
+        return if b < a { (b, 1.0) } else { (a, 0.0) };
+    }
+    let h = (0.5 + 0.5 * (a - b) / radius).clamp(0.0, 1.0);
+    (a + (b - a) * h - radius * h * (1.0 - h), h)
+}
+
+// Combine two sides of a union, blending their materials if the union is smooth
+pub fn smooth_union(
+    left: (f32, SdfMaterialMix),
+    right: (f32, SdfMaterialMix),
+    radius: f32,
+) -> (f32, SdfMaterialMix) {
+    let (distance, weight) = smooth_min(left.0, right.0, radius);
+    (distance, left.1.blend(&right.1, weight))
+}
+
+// Materials of a scene, indexed by the material IDs of its primitives
+pub struct SdfMaterialTable {
+    pub materials: Vec<SdfMaterial>,
+}
+
+impl SdfMaterialTable {
+    // Table holding only the default material, at ID 0
+    pub fn new() -> Self {
+        SdfMaterialTable {
+            materials: vec![SdfMaterial::DEFAULT],
+        }
+    }
+
+    pub fn add(&mut self, material: SdfMaterial) -> u32 {
+        self.materials.push(material);
+        (self.materials.len() - 1) as u32
+    }
+
+    // Unknown IDs fall back to the default material
+    pub fn get(&self, id: u32) -> &SdfMaterial {
+        self.materials.get(id as usize).unwrap_or(&SdfMaterial::DEFAULT)
+    }
+
+    pub fn resolve(&self, mix: &SdfMaterialMix) -> SdfMaterial {
+        self.get(mix.ids[0]).mix(self.get(mix.ids[1]), mix.weight)
+    }
+}
+
+impl Default for SdfMaterialTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::math::*;
+    use float_cmp::approx_eq;
+    use crate::material::*;
+
+    #[test]
+    fn test_smooth_min() {
+        // Far apart distances aren't blended at all
+        assert_eq!(smooth_min(1.0, 3.0, 1.0), (1.0, 0.0));
+        assert_eq!(smooth_min(3.0, 1.0, 1.0), (1.0, 1.0));
+        assert_eq!(smooth_min(1.0, f32::INFINITY, 1.0), (1.0, 0.0));
+        let (distance, weight) = smooth_min(1.0, 1.0, 1.0);
+        assert!(approx_eq!(f32, distance, 0.75));
+        assert!(approx_eq!(f32, weight, 0.5));
+        for (a, b) in [(0.0, 0.4), (2.0, 1.7), (-1.0, -0.8)] {
+            let (distance, _) = smooth_min(a, b, 1.0);
+            assert!(distance <= a.min(b) && distance >= a.min(b) - 0.25);
+        }
+    }
+
+    #[test]
+    fn test_material_blend() {
+        let mut table = SdfMaterialTable::new();
+        let red = table.add(SdfMaterial {
+            albedo: Vec3::X,
+            ..SdfMaterial::DEFAULT
+        });
+        let blue = table.add(SdfMaterial {
+            albedo: Vec3::Z,
+            metallic: 1.0,
+            ..SdfMaterial::DEFAULT
+        });
+        assert_eq!(table.get(100), &SdfMaterial::DEFAULT);
+
+        let mix = SdfMaterialMix::single(red).blend(&SdfMaterialMix::single(blue), 0.25);
+        assert_eq!(mix.ids, [red, blue]);
+        assert!(approx_eq!(f32, mix.weight, 0.25));
+        let resolved = table.resolve(&mix);
+        assert!((resolved.albedo - Vec3::new(0.75, 0.0, 0.25)).length() < 1e-6);
+        assert!(approx_eq!(f32, resolved.metallic, 0.25));
+
+        // The dominant material always comes first
+        let flipped = SdfMaterialMix::single(red).blend(&SdfMaterialMix::single(blue), 0.75);
+        assert_eq!(flipped.dominant(), blue);
+        assert!(approx_eq!(f32, flipped.weight, 0.25));
+
+        // Blending three materials keeps the heaviest two
+        let green = table.add(SdfMaterial {
+            albedo: Vec3::Y,
+            ..SdfMaterial::DEFAULT
+        });
+        let triple = mix.blend(&SdfMaterialMix::single(green), 0.2);
+        assert_eq!(triple.ids, [red, blue]);
+        assert!(approx_eq!(f32, triple.weight, 0.25));
+        assert_eq!(SdfMaterialMix::single(red).blend(&SdfMaterialMix::single(red), 0.5), SdfMaterialMix::single(red));
+        // NaN weights from degenerate distances don't panic the sort
+        let nan = SdfMaterialMix::single(red).blend(&SdfMaterialMix::single(blue), f32::NAN);
+        assert!([red, blue].contains(&nan.dominant()));
+    }
+}
@@ -0,0 +1,159 @@
+use std::ops::Mul;
+
+/**
+ * Math types of the crate. These are glam's, which bevy uses as well, so with the `bevy` feature
+ * they're the very same types as bevy's and need no conversions.
+ */
+pub use glam::{
+    BVec2, BVec3, BVec4, EulerRot, IVec2, IVec3, IVec4, Mat2, Mat3, Mat4, Quat, UVec2, UVec3, UVec4,
+    Vec2, Vec3, Vec4, DVec3,
+};
+
+/**
+ * Translation, rotation and scale of a node, like bevy's `Transform`, so that trees can be built
+ * and evaluated without bevy. With the `bevy` feature, it converts to and from bevy's with `From`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform {
+    pub const fn identity() -> Self {
+        Transform {
+            translation: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+        }
+    }
+
+    pub fn from_xyz(x: f32, y: f32, z: f32) -> Self {
+        Self::from_translation(Vec3::new(x, y, z))
+    }
+
+    pub fn from_matrix(matrix: Mat4) -> Self {
+        let (scale, rotation, translation) = matrix.to_scale_rotation_translation();
+        Transform {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    pub fn from_translation(translation: Vec3) -> Self {
+        Transform {
+            translation,
+            ..Self::identity()
+        }
+    }
+
+    pub fn from_rotation(rotation: Quat) -> Self {
+        Transform {
+            rotation,
+            ..Self::identity()
+        }
+    }
+
+    pub fn from_scale(scale: Vec3) -> Self {
+        Transform {
+            scale,
+            ..Self::identity()
+        }
+    }
+
+    pub fn with_translation(mut self, translation: Vec3) -> Self {
+        self.translation = translation;
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Quat) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: Vec3) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn compute_matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+
+    pub fn mul_vec3(&self, point: Vec3) -> Vec3 {
+        self.rotation * (self.scale * point) + self.translation
+    }
+
+    // Same as bevy, the scales are multiplied component-wise instead of in the rotated frame
+    pub fn mul_transform(&self, other: Transform) -> Self {
+        Transform {
+            translation: self.mul_vec3(other.translation),
+            rotation: self.rotation * other.rotation,
+            scale: self.scale * other.scale,
+        }
+    }
+}
+
+impl Mul<Transform> for Transform {
+    type Output = Transform;
+
+    fn mul(self, other: Transform) -> Transform {
+        self.mul_transform(other)
+    }
+}
+
+#[cfg(feature = "bevy")]
+impl From<bevy::prelude::Transform> for Transform {
+    fn from(trans: bevy::prelude::Transform) -> Self {
+        Transform {
+            translation: trans.translation,
+            rotation: trans.rotation,
+            scale: trans.scale,
+        }
+    }
+}
+
+#[cfg(feature = "bevy")]
+impl From<Transform> for bevy::prelude::Transform {
+    fn from(trans: Transform) -> Self {
+        bevy::prelude::Transform {
+            translation: trans.translation,
+            rotation: trans.rotation,
+            scale: trans.scale,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::math::*;
+
+    #[test]
+    fn test_transform() {
+        let outer = Transform::from_xyz(1.0, -2.0, 3.0)
+            .with_rotation(Quat::from_rotation_y(0.7))
+            .with_scale(Vec3::splat(2.0));
+        let inner = Transform::from_xyz(0.5, 0.0, -1.0)
+            .with_rotation(Quat::from_rotation_x(-0.3))
+            .with_scale(Vec3::splat(0.5));
+        let point = Vec3::new(0.3, 0.2, -0.1);
+
+        // With uniform scales, composing transforms is the same as multiplying their matrices
+        let composed = (outer * inner).compute_matrix();
+        assert!(composed.abs_diff_eq(outer.compute_matrix() * inner.compute_matrix(), 1e-5));
+        assert!((outer * inner).mul_vec3(point).abs_diff_eq(composed.transform_point3(point), 1e-5));
+        let decomposed = Transform::from_matrix(composed);
+        assert!(decomposed.compute_matrix().abs_diff_eq(composed, 1e-5));
+
+        #[cfg(feature = "bevy")]
+        assert_eq!(Transform::from(bevy::prelude::Transform::from(outer)), outer);
+    }
+}
@@ -0,0 +1,514 @@
+use std::{
+    collections::HashMap,
+    fmt,
+};
+use super::math::*;
+use super::grid::SdfGridData;
+
+// Triangles per BVH leaf
+const LEAF_SIZE: usize = 4;
+
+// Part of a triangle that a closest point lies on, which picks the pseudo-normal used for its sign
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SdfTriangleFeature {
+    Vertex(usize),
+    // Edge from corner i to corner (i + 1) % 3
+    Edge(usize),
+    Face,
+}
+
+// Closest point on a triangle to a point, following Real-Time Collision Detection 5.1.5
+fn closest_on_triangle(point: Vec3, [a, b, c]: [Vec3; 3]) -> (Vec3, SdfTriangleFeature) {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = point - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (a, SdfTriangleFeature::Vertex(0));
+    }
+
+    let bp = point - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return (b, SdfTriangleFeature::Vertex(1));
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return (a + ab * (d1 / (d1 - d3)), SdfTriangleFeature::Edge(0));
+    }
+
+    let cp = point - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return (c, SdfTriangleFeature::Vertex(2));
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return (a + ac * (d2 / (d2 - d6)), SdfTriangleFeature::Edge(2));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return (b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6))), SdfTriangleFeature::Edge(1));
+    }
+
+    let denom = 1.0 / (va + vb + vc);
+    (a + ab * (vb * denom) + ac * (vc * denom), SdfTriangleFeature::Face)
+}
+
+fn box_distance_squared(point: Vec3, min: Vec3, max: Vec3) -> f32 {
+    (min - point).max(point - max).max(Vec3::ZERO).length_squared()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SdfMeshBvhNode {
+    min: Vec3,
+    max: Vec3,
+    // Leaves cover `count` triangles of the BVH order from `first`; inner nodes have their left
+    // child right after them and their right child at `first`
+    first: usize,
+    count: usize,
+}
+
+/**
+ * Closed triangle mesh with exact signed distance.
+ *
+ * Signs come from angle-weighted pseudo-normals (Bærentzen and Aanæs), which are only reliable
+ * for closed, consistently wound meshes, so anything else is rejected. Nearest triangles are found
+ * with a BVH over the triangles.
+ */
+pub struct SdfTriangleMesh {
+    vertices: Vec<Vec3>,
+    triangles: Vec<[usize; 3]>,
+    face_normals: Vec<Vec3>,
+    // Pseudo-normals of the edges of each triangle, in the order of SdfTriangleFeature::Edge
+    edge_normals: Vec<[Vec3; 3]>,
+    vertex_normals: Vec<Vec3>,
+    nodes: Vec<SdfMeshBvhNode>,
+    // Triangle indices, ordered so every BVH leaf covers a contiguous range
+    order: Vec<usize>,
+}
+
+impl SdfTriangleMesh {
+    // Triangles are wound counterclockwise when seen from outside
+    pub fn new(vertices: Vec<Vec3>, triangles: Vec<[usize; 3]>) -> Result<Self, &'static str> {
+        if triangles.is_empty() {
+            return Err("Mesh has no triangles!");
+        }
+        if triangles.iter().flatten().any(|index| *index >= vertices.len()) {
+            return Err("Mesh triangle refers to a missing vertex!");
+        }
+        // The BVH sorts triangles by their centroids, which have to be comparable
+        if vertices.iter().any(|vertex| !vertex.is_finite()) {
+            return Err("Mesh has non-finite vertices!");
+        }
+
+        // Count edges regardless of direction first, so extra triangles aren't taken for flipped ones
+        let mut edge_counts: HashMap<(usize, usize), usize> = HashMap::new();
+        for triangle in triangles.iter() {
+            for i in 0..3 {
+                let (from, to) = (triangle[i], triangle[(i + 1) % 3]);
+                *edge_counts.entry((from.min(to), from.max(to))).or_insert(0) += 1;
+            }
+        }
+        if edge_counts.values().any(|count| *count > 2) {
+            return Err("Mesh has an edge that's shared by more than two triangles!");
+        }
+
+        // In a closed mesh every directed edge is matched by exactly one edge going the other way
+        let mut edges: HashMap<(usize, usize), usize> = HashMap::new();
+        for (t, triangle) in triangles.iter().enumerate() {
+            for i in 0..3 {
+                if edges.insert((triangle[i], triangle[(i + 1) % 3]), t).is_some() {
+                    return Err("Mesh triangles aren't wound consistently!");
+                }
+            }
+        }
+        if edges.keys().any(|(from, to)| !edges.contains_key(&(*to, *from))) {
+            return Err("Mesh isn't closed!");
+        }
+
+        let corners = |triangle: &[usize; 3]| triangle.map(|index| vertices[index]);
+        let face_normals = triangles.iter()
+            .map(|triangle| {
+                let [a, b, c] = corners(triangle);
+                (b - a).cross(c - a).normalize_or_zero()
+            })
+            .collect::<Vec<Vec3>>();
+        let edge_normals = triangles.iter()
+            .enumerate()
+            .map(|(t, triangle)| [0, 1, 2].map(|i| {
+                let twin = edges[&(triangle[(i + 1) % 3], triangle[i])];
+                (face_normals[t] + face_normals[twin]).normalize_or_zero()
+            }))
+            .collect::<Vec<[Vec3; 3]>>();
+        let mut vertex_normals = vec![Vec3::ZERO; vertices.len()];
+        for (t, triangle) in triangles.iter().enumerate() {
+            let points = corners(triangle);
+            for i in 0..3 {
+                let to_next = points[(i + 1) % 3] - points[i];
+                let to_prev = points[(i + 2) % 3] - points[i];
+                vertex_normals[triangle[i]] += face_normals[t] * to_next.angle_between(to_prev);
+            }
+        }
+        let vertex_normals = vertex_normals.into_iter()
+            .map(|normal| normal.normalize_or_zero())
+            .collect();
+
+        let mut mesh = SdfTriangleMesh {
+            vertices,
+            order: (0..triangles.len()).collect(),
+            triangles,
+            face_normals,
+            edge_normals,
+            vertex_normals,
+            nodes: Vec::new(),
+        };
+        mesh.build_bvh(0, mesh.triangles.len());
+        Ok(mesh)
+    }
+
+    fn corners(&self, triangle: usize) -> [Vec3; 3] {
+        self.triangles[triangle].map(|index| self.vertices[index])
+    }
+
+    // Split at the median centroid along the longest axis, until the leaves are small enough
+    fn build_bvh(&mut self, first: usize, count: usize) -> usize {
+        let range = first..(first + count);
+        let (min, max) = self.order[range.clone()].iter()
+            .flat_map(|triangle| self.corners(*triangle))
+            .fold((Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)), |(min, max), corner| {
+                (min.min(corner), max.max(corner))
+            });
+        let index = self.nodes.len();
+        self.nodes.push(SdfMeshBvhNode {
+            min,
+            max,
+            first,
+            count,
+        });
+        if count <= LEAF_SIZE {
+            return index;
+        }
+
+        let centroid = |mesh: &SdfTriangleMesh, triangle: usize| mesh.corners(triangle).iter().sum::<Vec3>() / 3.0;
+        let extent = max - min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        let mut order = std::mem::take(&mut self.order);
+        order[range].select_nth_unstable_by(count / 2, |a, b| {
+            centroid(self, *a)[axis].partial_cmp(&centroid(self, *b)[axis]).unwrap()
+        });
+        self.order = order;
+
+        self.build_bvh(first, count / 2);
+        let right = self.build_bvh(first + count / 2, count - count / 2);
+        self.nodes[index].first = right;
+        self.nodes[index].count = 0;
+        index
+    }
+
+    // Loads the vertices and faces of Wavefront OBJ text. Polygons are triangulated as fans.
+    pub fn from_obj(text: &str) -> Result<Self, &'static str> {
+        let mut vertices = Vec::new();
+        let mut triangles = Vec::new();
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("v") => {
+                    let coords = words.take(3)
+                        .map(|word| word.parse::<f32>().map_err(|_| "Invalid OBJ vertex!"))
+                        .collect::<Result<Vec<f32>, &'static str>>()?;
+                    if coords.len() != 3 {
+                        return Err("Invalid OBJ vertex!");
+                    }
+                    vertices.push(Vec3::new(coords[0], coords[1], coords[2]));
+                },
+                Some("f") => {
+                    // Only the position index matters, and negative indices count from the end
+                    let face = words
+                        .map(|word| {
+                            let index = word.split('/').next().unwrap().parse::<i64>().map_err(|_| "Invalid OBJ face!")?;
+                            match index {
+                                0 => Err("Invalid OBJ face!"),
+                                1.. => Ok(index as usize - 1),
+                                _ => vertices.len().checked_sub(index.unsigned_abs() as usize).ok_or("Invalid OBJ face!"),
+                            }
+                        })
+                        .collect::<Result<Vec<usize>, &'static str>>()?;
+                    if face.len() < 3 {
+                        return Err("Invalid OBJ face!");
+                    }
+                    triangles.extend((1..(face.len() - 1)).map(|i| [face[0], face[i], face[i + 1]]));
+                },
+                _ => (),
+            }
+        }
+        Self::new(vertices, triangles)
+    }
+
+    // Loads binary or ASCII STL. STL repeats the corners of every triangle, so they're welded.
+    pub fn from_stl(bytes: &[u8]) -> Result<Self, &'static str> {
+        let is_binary = bytes.len() >= 84
+            && bytes.len() == 84 + 50 * u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+        let corners = if is_binary {
+            bytes[84..].chunks_exact(50)
+                .flat_map(|record| (0..3).map(move |corner| {
+                    let coord = |i: usize| {
+                        let at = 12 + corner * 12 + i * 4;
+                        f32::from_le_bytes([record[at], record[at + 1], record[at + 2], record[at + 3]])
+                    };
+                    Vec3::new(coord(0), coord(1), coord(2))
+                }))
+                .collect::<Vec<Vec3>>()
+        } else {
+            let text = std::str::from_utf8(bytes).map_err(|_| "Invalid STL!")?;
+            text.lines()
+                .filter_map(|line| line.trim().strip_prefix("vertex"))
+                .map(|coords| {
+                    let coords = coords.split_whitespace()
+                        .map(|word| word.parse::<f32>().map_err(|_| "Invalid STL vertex!"))
+                        .collect::<Result<Vec<f32>, &'static str>>()?;
+                    match coords.as_slice() {
+                        [x, y, z] => Ok(Vec3::new(*x, *y, *z)),
+                        _ => Err("Invalid STL vertex!"),
+                    }
+                })
+                .collect::<Result<Vec<Vec3>, &'static str>>()?
+        };
+        if corners.len() % 3 != 0 {
+            return Err("Invalid STL!");
+        }
+
+        let mut welded: HashMap<[u32; 3], usize> = HashMap::new();
+        let mut vertices = Vec::new();
+        let indices = corners.iter()
+            .map(|corner| *welded.entry(corner.to_array().map(f32::to_bits)).or_insert_with(|| {
+                vertices.push(*corner);
+                vertices.len() - 1
+            }))
+            .collect::<Vec<usize>>();
+        let triangles = indices.chunks_exact(3)
+            .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+            .collect();
+        Self::new(vertices, triangles)
+    }
+
+    pub fn vertices(&self) -> &[Vec3] {
+        &self.vertices
+    }
+
+    pub fn triangles(&self) -> &[[usize; 3]] {
+        &self.triangles
+    }
+
+    // Corners of the axis aligned box around the mesh
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        (self.nodes[0].min, self.nodes[0].max)
+    }
+
+    // Signed distance for the closest point on one triangle
+    fn triangle_distance(&self, point: Vec3, triangle: usize) -> f32 {
+        let (closest, feature) = closest_on_triangle(point, self.corners(triangle));
+        let normal = match feature {
+            SdfTriangleFeature::Vertex(i) => self.vertex_normals[self.triangles[triangle][i]],
+            SdfTriangleFeature::Edge(i) => self.edge_normals[triangle][i],
+            SdfTriangleFeature::Face => self.face_normals[triangle],
+        };
+        let distance = (point - closest).length();
+        if (point - closest).dot(normal) < 0.0 { -distance } else { distance }
+    }
+
+    pub fn signed_distance(&self, point: Vec3) -> f32 {
+        let mut best = (f32::INFINITY, 0);
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if box_distance_squared(point, node.min, node.max) >= best.0 {
+                continue;
+            }
+            if node.count > 0 {
+                for triangle in &self.order[node.first..(node.first + node.count)] {
+                    let (closest, _) = closest_on_triangle(point, self.corners(*triangle));
+                    let distance = (point - closest).length_squared();
+                    if distance < best.0 {
+                        best = (distance, *triangle);
+                    }
+                }
+                continue;
+            }
+            // Visit the nearer child first, so the farther one is more likely to be skipped
+            let (left, right) = (index + 1, node.first);
+            let to_left = box_distance_squared(point, self.nodes[left].min, self.nodes[left].max);
+            let to_right = box_distance_squared(point, self.nodes[right].min, self.nodes[right].max);
+            if to_left < to_right {
+                stack.extend([right, left]);
+            } else {
+                stack.extend([left, right]);
+            }
+        }
+        self.triangle_distance(point, best.1)
+    }
+
+    // Sample the distance into a sparse grid that covers the mesh with a margin of two voxels
+    pub fn bake(&self, voxel_size: f32) -> SdfGridData {
+        let (min, max) = self.bounds();
+        let margin = Vec3::splat(2.0 * voxel_size);
+        SdfGridData::bake_sparse(min - margin, max + margin, voxel_size, |point| self.signed_distance(point))
+    }
+}
+
+impl fmt::Debug for SdfTriangleMesh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SdfTriangleMesh")
+            .field("vertices", &self.vertices.len())
+            .field("triangles", &self.triangles.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use float_cmp::approx_eq;
+    use crate::mesh::*;
+
+    pub const CUBE_OBJ: &str = "
+# Unit cube, with quads for faces
+v -1 -1 -1
+v 1 -1 -1
+v 1 1 -1
+v -1 1 -1
+v -1 -1 1
+v 1 -1 1
+v 1 1 1
+v -1 1 1
+f 1 4 3 2
+f 5 6 7 8
+f 1 2 6 5
+f 3 4 8 7
+f 2 3 7 6
+f 1/1/1 5/1/1 8/1/1 4/1/1
+";
+
+    fn box_distance(point: Vec3) -> f32 {
+        let q = point.abs() - Vec3::ONE;
+        q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+    }
+
+    // Subdivided octahedron pushed out onto the unit sphere
+    pub fn sphere_mesh(subdivisions: u32) -> SdfTriangleMesh {
+        let mut vertices = vec![Vec3::X, -Vec3::X, Vec3::Y, -Vec3::Y, Vec3::Z, -Vec3::Z];
+        let mut triangles = vec![
+            [0, 2, 4], [2, 1, 4], [1, 3, 4], [3, 0, 4],
+            [2, 0, 5], [1, 2, 5], [3, 1, 5], [0, 3, 5],
+        ];
+        for _ in 0..subdivisions {
+            let mut midpoints: HashMap<(usize, usize), usize> = HashMap::new();
+            let mut midpoint = |a: usize, b: usize, vertices: &mut Vec<Vec3>| *midpoints
+                .entry((a.min(b), a.max(b)))
+                .or_insert_with(|| {
+                    vertices.push(((vertices[a] + vertices[b]) / 2.0).normalize());
+                    vertices.len() - 1
+                });
+            triangles = triangles.iter()
+                .flat_map(|[a, b, c]| {
+                    let ab = midpoint(*a, *b, &mut vertices);
+                    let bc = midpoint(*b, *c, &mut vertices);
+                    let ca = midpoint(*c, *a, &mut vertices);
+                    [[*a, ab, ca], [ab, *b, bc], [ca, bc, *c], [ab, bc, ca]]
+                })
+                .collect();
+        }
+        SdfTriangleMesh::new(vertices, triangles).unwrap()
+    }
+
+    fn random_point(rng: &mut ThreadRng, range: f32) -> Vec3 {
+        Vec3::new(
+            rng.gen_range(-range..range),
+            rng.gen_range(-range..range),
+            rng.gen_range(-range..range),
+        )
+    }
+
+    #[test]
+    fn test_mesh_cube() {
+        let mut rng = thread_rng();
+        let cube = SdfTriangleMesh::from_obj(CUBE_OBJ).unwrap();
+        assert_eq!(cube.triangles().len(), 12);
+        for _ in 0..2000 {
+            let point = random_point(&mut rng, 3.0);
+            let distance = cube.signed_distance(point);
+            assert!(approx_eq!(f32, distance, box_distance(point), epsilon = 1e-5),
+                "Wrong distance at {}: {} instead of {}", point, distance, box_distance(point));
+        }
+        // Corners and edges are signed by their pseudo-normals
+        assert!(cube.signed_distance(Vec3::splat(0.999)) < 0.0);
+        assert!(cube.signed_distance(Vec3::splat(1.001)) > 0.0);
+        assert!(cube.signed_distance(Vec3::new(1.001, 1.001, 0.0)) > 0.0);
+    }
+
+    #[test]
+    fn test_mesh_loading() {
+        let cube = SdfTriangleMesh::from_obj(CUBE_OBJ).unwrap();
+
+        let mut binary = vec![0_u8; 80];
+        binary.extend((cube.triangles().len() as u32).to_le_bytes());
+        let mut ascii = String::from("solid cube\n");
+        for triangle in cube.triangles() {
+            binary.extend([0_u8; 12]);
+            ascii += "facet normal 0 0 0\nouter loop\n";
+            for corner in triangle {
+                let vertex = cube.vertices()[*corner];
+                binary.extend(vertex.to_array().iter().flat_map(|coord| coord.to_le_bytes()));
+                ascii += &format!("vertex {} {} {}\n", vertex.x, vertex.y, vertex.z);
+            }
+            binary.extend([0_u8; 2]);
+            ascii += "endloop\nendfacet\n";
+        }
+        ascii += "endsolid cube\n";
+
+        for stl in [binary, ascii.into_bytes()] {
+            let loaded = SdfTriangleMesh::from_stl(&stl).unwrap();
+            assert_eq!(loaded.vertices().len(), 8);
+            assert_eq!(loaded.triangles().len(), 12);
+            let point = Vec3::new(0.3, 1.7, -2.1);
+            assert!(approx_eq!(f32, loaded.signed_distance(point), cube.signed_distance(point), epsilon = 1e-6));
+        }
+
+        // Open or badly wound meshes can't be signed
+        let open = CUBE_OBJ.replace("f 5 6 7 8", "");
+        assert_eq!(SdfTriangleMesh::from_obj(&open).err(), Some("Mesh isn't closed!"));
+        let flipped = CUBE_OBJ.replace("f 5 6 7 8", "f 8 7 6 5");
+        assert_eq!(SdfTriangleMesh::from_obj(&flipped).err(), Some("Mesh triangles aren't wound consistently!"));
+        let fin = format!("{}\nv 0 0 5\nf 5 6 9\nf 6 5 9\n", CUBE_OBJ);
+        assert_eq!(SdfTriangleMesh::from_obj(&fin).err(), Some("Mesh has an edge that's shared by more than two triangles!"));
+        assert!(SdfTriangleMesh::from_obj("v 0 0 0\nf 1 2 3").is_err());
+        let nan = SdfTriangleMesh::new(vec![Vec3::ZERO, Vec3::X, Vec3::new(f32::NAN, 1.0, 0.0)], vec![[0, 1, 2], [0, 2, 1]]);
+        assert_eq!(nan.err(), Some("Mesh has non-finite vertices!"));
+    }
+
+    #[test]
+    fn test_mesh_bvh() {
+        // The BVH search has to find the same triangle as checking all of them
+        let mut rng = thread_rng();
+        let sphere = sphere_mesh(3);
+        assert!(sphere.nodes.len() > 1);
+        for _ in 0..300 {
+            let point = random_point(&mut rng, 2.0);
+            let brute = (0..sphere.triangles().len())
+                .map(|triangle| closest_on_triangle(point, sphere.corners(triangle)).0)
+                .map(|closest| (point - closest).length())
+                .fold(f32::INFINITY, f32::min);
+            let distance = sphere.signed_distance(point);
+            assert!(approx_eq!(f32, distance.abs(), brute, epsilon = 1e-5));
+            // The mesh is a little smaller than the sphere it approximates
+            assert!(distance >= point.length() - 1.0 - 1e-5 && distance <= point.length() - 1.0 + 0.02);
+        }
+    }
+}
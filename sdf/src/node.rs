@@ -1,15 +1,29 @@
 use std::ops::Range;
 use std::collections::VecDeque;
-use bevy::prelude::*;
+use std::sync::{Arc, OnceLock};
+use super::math::*;
 use super::{
     obb::*,
     component::*,
-    elements::{SdfElement, SdfUnion},
+    cull::*,
+    tree::NodeId,
+    material::*,
+    interval::*,
+    dual::*,
+    elements::{SdfElement, SdfUnion, SdfClosure},
+    registry::registry,
 };
 
 pub struct NnResult<'a> {
     pub node: &'a SdfNode,
     pub distance: f32,
+    pub material: SdfMaterialMix,
+}
+
+impl<'a> NnResult<'a> {
+    pub fn material_id(&self) -> u32 {
+        self.material.dominant()
+    }
 }
 
 pub struct NodeDistInfo {
@@ -17,10 +31,56 @@ pub struct NodeDistInfo {
     pub max_bound: f32,
 }
 
+// How the bounding box of an expanded node can be recalculated from its source SdfTree node
+#[derive(Clone, Debug, PartialEq)]
+pub enum SdfBoundsRule {
+    // The bounding box of the source node itself
+    Source,
+    // The bounding box of the first expanded child
+    FirstChild,
+    // The merged bounding boxes of some of the source node's slots
+    Merge(Vec<usize>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SdfBlockSource {
+    pub node: Option<NodeId>,
+    pub bounds: SdfBoundsRule,
+    // Number of single child unions above the source node that the box was moved out of
+    pub collapsed: usize,
+}
+
+impl SdfBlockSource {
+    pub fn new(bounds: SdfBoundsRule) -> Self {
+        SdfBlockSource {
+            node: None,
+            bounds,
+            collapsed: 0,
+        }
+    }
+}
+
+// Elements are only written into buffers if the registry can traverse them
+pub fn check_gpu_encoding(intern: &dyn SdfElement) -> Result<(), &'static str> {
+    let info = intern.get_info();
+    let encoded = if info.is_primitive {
+        registry().primitive(info.op_id).is_some_and(|entry| entry.distance.is_some())
+    } else {
+        registry().operation(info.op_id).is_some()
+    };
+    if encoded {
+        Ok(())
+    } else {
+        Err("Tree has an element without a GPU encoding, like a closure or an unbaked mesh!")
+    }
+}
+
 pub struct ExpandedSdfNode {
     expanded_slots: Option<[Box<ExpandedSdfNode>; 2]>,
     pub bbox: SdfBoundingBox,
     intern: Option<Box<dyn SdfElement>>,
+    source: SdfBlockSource,
+    material: u32,
 }
 
 impl ExpandedSdfNode {
@@ -29,6 +89,8 @@ impl ExpandedSdfNode {
             expanded_slots: None,
             bbox: SdfBoundingBox::zero(),
             intern: None,
+            source: SdfBlockSource::new(SdfBoundsRule::Source),
+            material: 0,
         }
     }
 
@@ -37,6 +99,8 @@ impl ExpandedSdfNode {
             expanded_slots: None,
             bbox,
             intern: Some(intern),
+            source: SdfBlockSource::new(SdfBoundsRule::Source),
+            material: 0,
         }
     }
 
@@ -45,6 +109,8 @@ impl ExpandedSdfNode {
             bbox: downtree_union.bbox,
             expanded_slots: Some([Box::new(downtree_union), Box::new(Self::null())]),
             intern: Some(intern),
+            source: SdfBlockSource::new(SdfBoundsRule::FirstChild),
+            material: 0,
         }
     }
 
@@ -53,7 +119,69 @@ impl ExpandedSdfNode {
             expanded_slots: Some(expanded_slots),
             bbox,
             intern: Some(intern),
+            source: SdfBlockSource::new(SdfBoundsRule::Source),
+            material: 0,
+        }
+    }
+
+    pub fn with_bounds(mut self, bounds: SdfBoundsRule) -> Self {
+        self.source.bounds = bounds;
+        self
+    }
+
+    // Move the node out of the frame of a single child union that it replaces
+    pub fn collapse_into(&mut self, union_box: &SdfBoundingBox) {
+        self.bbox = self.bbox.apply_frame(union_box);
+        self.source.collapsed += 1;
+    }
+
+    // Tag every node that doesn't belong to an already tagged subtree with its source SdfTree node
+    fn stamp_source(&mut self, node: Option<NodeId>) {
+        if self.is_null() || self.source.node.is_some() {
+            return;
         }
+        self.source.node = node;
+        if let Some(slots) = self.expanded_slots.as_mut() {
+            slots[0].stamp_source(node);
+            slots[1].stamp_source(node);
+        }
+    }
+
+    // Sources and bounding boxes of the blocks that make_buffer() writes, in downtree order
+    pub fn block_sources(&self) -> Vec<(SdfBlockSource, SdfBoundingBox)> {
+        fn recurse(root: &ExpandedSdfNode, sources: &mut Vec<(SdfBlockSource, SdfBoundingBox)>) {
+            if root.is_null() {
+                return;
+            }
+            sources.push((root.source.clone(), root.bbox));
+            if let Some(slots) = root.expanded_slots.as_ref() {
+                recurse(&slots[0], sources);
+                recurse(&slots[1], sources);
+            }
+        }
+
+        let mut sources = Vec::new();
+        recurse(self, &mut sources);
+        sources
+    }
+
+    // Origins that the bounding boxes of the blocks are rebased to by make_relative_buffer()
+    pub fn block_origins(&self, origin: DVec3) -> Vec<DVec3> {
+        fn recurse(root: &ExpandedSdfNode, origin: DVec3, origins: &mut Vec<DVec3>) {
+            if root.is_null() {
+                return;
+            }
+            origins.push(origin);
+            if let Some(slots) = root.expanded_slots.as_ref() {
+                let (_, inner_origin) = root.bbox.rebased(origin, root.is_union());
+                recurse(&slots[0], inner_origin, origins);
+                recurse(&slots[1], inner_origin, origins);
+            }
+        }
+
+        let mut origins = Vec::new();
+        recurse(self, origin, &mut origins);
+        origins
     }
 
     pub fn is_null(&self) -> bool {
@@ -72,25 +200,157 @@ impl ExpandedSdfNode {
         self.is_operation() && self.intern.as_ref().unwrap().get_info().is_union
     }
 
+    pub fn node_count(&self) -> usize {
+        if self.is_null() {
+            return 0;
+        }
+        1 + self.expanded_slots.as_ref()
+            .map(|slots| slots[0].node_count() + slots[1].node_count())
+            .unwrap_or(0)
+    }
+
+    pub fn full_clone(&self) -> Self {
+        ExpandedSdfNode {
+            expanded_slots: self.expanded_slots.as_ref()
+                .map(|slots| [Box::new(slots[0].full_clone()), Box::new(slots[1].full_clone())]),
+            bbox: self.bbox,
+            intern: self.intern.clone(),
+            source: self.source.clone(),
+            material: self.material,
+        }
+    }
+
+    /**
+     * Make a copy of the tree without the subtrees that can't be seen by the culler.
+     *
+     * Only the children of unions are ever removed, since removing the child of any other
+     * operation would change its result. A union that's left with a single child is replaced
+     * by that child, which is moved into the union's parent frame.
+     */
+    pub fn culled(&self, culler: &SdfCuller, stats: &mut SdfCullStats) -> ExpandedSdfNode {
+        fn recurse(
+            root: &ExpandedSdfNode,
+            to_world: Mat4,
+            culler: &SdfCuller,
+            stats: &mut SdfCullStats,
+        ) -> Option<ExpandedSdfNode> {
+            if root.is_null() {
+                return None;
+            }
+            stats.visited_nodes += 1;
+
+            let world_box = root.bbox.apply_matrix(to_world);
+            if culler.is_outside(&world_box) {
+                stats.frustum_culled += root.node_count();
+                return None;
+            }
+            if culler.is_occluded(&world_box) {
+                stats.occlusion_culled += root.node_count();
+                return None;
+            }
+            if !root.is_union() {
+                return Some(root.full_clone());
+            }
+
+            let exp_slots = root.expanded_slots.as_ref().unwrap();
+            let child_to_world = to_world * root.bbox.trans_basis();
+            match (
+                recurse(&exp_slots[0], child_to_world, culler, stats),
+                recurse(&exp_slots[1], child_to_world, culler, stats),
+            ) {
+                (Some(left), Some(right)) => Some(ExpandedSdfNode {
+                    expanded_slots: Some([Box::new(left), Box::new(right)]),
+                    bbox: root.bbox,
+                    intern: root.intern.clone(),
+                    source: root.source.clone(),
+                    material: root.material,
+                }),
+                (Some(mut only), None) | (None, Some(mut only)) => {
+                    stats.collapsed_nodes += 1;
+                    only.collapse_into(&root.bbox);
+                    Some(only)
+                },
+                (None, None) => {
+                    stats.collapsed_nodes += 1;
+                    None
+                },
+            }
+        }
+
+        stats.total_nodes += self.node_count();
+        recurse(self, Mat4::IDENTITY, culler, stats).unwrap_or_else(Self::null)
+    }
+
+    pub fn make_culled_buffer(&self, culler: &SdfCuller) -> Result<(SdfTreeBuffer, SdfCullStats), &'static str> {
+        let mut stats = SdfCullStats::default();
+        let buffer = self.culled(culler, &mut stats).try_make_buffer()?;
+        stats.emitted_nodes = buffer.buffer_len as usize;
+        Ok((buffer, stats))
+    }
+
+    // Same as SdfNode::lipschitz, for the tree that's written into buffers
+    pub fn lipschitz(&self) -> f32 {
+        let own = self.intern.as_ref().map_or(1.0, |intern| intern.lipschitz());
+        let slots = self.expanded_slots.iter().flatten().filter(|slot| !slot.is_null());
+        if self.is_union() && slots.clone().count() > 1 {
+            return own;
+        }
+        own * slots.map(|slot| slot.lipschitz()).fold(1.0, f32::max)
+    }
+
+    // Panics if an element can't be encoded, see try_make_buffer()
     pub fn make_buffer(&self) -> SdfTreeBuffer {
+        self.try_make_buffer().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_make_buffer(&self) -> Result<SdfTreeBuffer, &'static str> {
+        self.try_make_relative_buffer(DVec3::ZERO)
+    }
+
+    // Panics if an element can't be encoded, see try_make_buffer()
+    pub fn make_relative_buffer(&self, origin: DVec3) -> SdfTreeBuffer {
+        self.try_make_relative_buffer(origin).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /**
+     * Make a buffer that's evaluated at points relative to `origin`, like positions relative to the
+     * camera. Far from the world origin, f32 positions are too coarse to evaluate the tree at, but
+     * the rebased boxes and the points relative to them stay small.
+     */
+    pub fn try_make_relative_buffer(&self, origin: DVec3) -> Result<SdfTreeBuffer, &'static str> {
+        fn check(root: &ExpandedSdfNode) -> Result<(), &'static str> {
+            if let Some(intern) = root.intern.as_ref() {
+                check_gpu_encoding(intern.as_ref())?;
+            }
+            root.expanded_slots.iter().flatten().try_for_each(|slot| check(slot))
+        }
+        check(self)?;
+
         let mut buffer = SdfTreeBuffer::make_empty();
 
         fn recurse(
             buffer: &mut SdfTreeBuffer,
             root: &ExpandedSdfNode,
             other_box: &SdfBoundingBox,
+            origin: DVec3,
             level: u32,
             parent_is_union: bool,
         ) {
             if root.is_null() {
                 return;
             }
+            let (bbox, inner_origin) = root.bbox.rebased(origin, root.is_union());
 
             let intern = root.intern.as_ref().unwrap();
             
             let intern_info = intern.get_info();
-            let dt_block_spec = intern.get_dt_specific_block();
+            let mut dt_block_spec = intern.get_dt_specific_block();
             let ut_block_spec = intern.get_ut_specific_block();
+            let side_data = intern.side_data();
+            if !side_data.is_empty() {
+                dt_block_spec.side_offset = buffer.side_buffer.len() as u32;
+                buffer.side_buffer.extend_from_slice(side_data);
+            }
             let this_ind = buffer.downtree_buffer.len() as usize;
 
 
@@ -100,9 +360,11 @@ impl ExpandedSdfNode {
                 parent_is_union,
                 len: 0,
                 level,
+                material: root.material,
+                lipschitz: root.lipschitz(),
                 op_specific: dt_block_spec,
-                bounding_box: root.bbox.get_bbox_block(),
-                other_box: other_box.get_bbox_block(),
+                bounding_box: bbox.get_bbox_block(),
+                other_box: other_box.rebased(origin, root.is_union()).0.get_bbox_block(),
             });
 
             if !root.is_primitive() {
@@ -111,12 +373,14 @@ impl ExpandedSdfNode {
                     buffer,
                     &exp_slots[0],
                     &exp_slots[0].bbox,
+                    inner_origin,
                     level + 1,
                     root.is_union());
                 recurse(
                     buffer,
                     &exp_slots[1],
                     &exp_slots[1].bbox,
+                    inner_origin,
                     level + 1,
                     root.is_union());
             }
@@ -124,6 +388,7 @@ impl ExpandedSdfNode {
             buffer.uptree_buffer.push(SdfOperationUptreeBlock {
                 op_code: intern_info.op_id,
                 parent_is_union,
+                lipschitz: root.lipschitz(),
                 op_specific: ut_block_spec,
                 level,
             });
@@ -132,16 +397,36 @@ impl ExpandedSdfNode {
         }
 
         let throwaway_box = SdfBoundingBox::zero();
-        recurse(&mut buffer, self, &throwaway_box, 1, false);
+        recurse(&mut buffer, self, &throwaway_box, origin, 1, false);
         buffer.buffer_len = buffer.downtree_buffer.len() as u32;
-        buffer
+        Ok(buffer)
     }
 }
 
+// Nodes are built with SdfBuilder and edited through SdfTree, the rest is read-only
 pub struct SdfNode {
-    pub slots: Vec<SdfNode>,
+    slots: Vec<SdfNode>,
     pub bbox: Option<SdfBoundingBox>,
+    transform: Transform,
+    // Translation applied after the transform, in f64 for nodes far from the origin
+    offset: DVec3,
+    // Handle of the node in the SdfTree it was made from, if any
+    id: Option<NodeId>,
+    // Index into an SdfMaterialTable, only used by primitives
+    material: u32,
+    // Fixed box that the parent is fit around instead of bbox, e.g. one covering an animation
+    sweep: Option<SdfBoundingBox>,
     intern: Box<dyn SdfElement>,
+    // Order that smooth unions blend their slots in, see fold_split()
+    split_tree: OnceLock<SdfSplitTree>,
+    // Cached lipschitz(), which is needed at every union on every query
+    lipschitz: OnceLock<f32>,
+}
+
+// Binary hierarchy over the slots of a union, with the box around every split
+enum SdfSplitTree {
+    Slot(usize),
+    Split(Box<SdfBoundingBox>, Box<SdfSplitTree>, Box<SdfSplitTree>),
 }
 
 impl SdfNode {
@@ -153,19 +438,111 @@ impl SdfNode {
             )),
             intern: intern,
             bbox: None,
+            transform: Transform::identity(),
+            offset: DVec3::ZERO,
+            id: None,
+            material: 0,
+            split_tree: OnceLock::new(),
+            lipschitz: OnceLock::new(),
+            sweep: None,
         }
     }
 
     pub fn empty() -> Self {
         SdfNode {
             slots: Vec::with_capacity(0),
-            intern: Box::new(SdfUnion {
-                smooth_radius: 0.0,
-            }),
+            intern: Box::new(SdfUnion::new(0.0)),
             bbox: Some(SdfBoundingBox::zero()),
+            transform: Transform::identity(),
+            offset: DVec3::ZERO,
+            id: None,
+            material: 0,
+            split_tree: OnceLock::new(),
+            lipschitz: OnceLock::new(),
+            sweep: None,
+        }
+    }
+
+    /**
+     * Bounding box of an element with the given child boxes, moved by the element's transform and
+     * then its offset. Zero boxes of empty children are ignored.
+     */
+    pub fn fit_bbox(
+        intern: &dyn SdfElement,
+        transform: Transform,
+        offset: DVec3,
+        slots_bboxes: &[SdfBoundingBox],
+    ) -> SdfBoundingBox {
+        intern.get_bbox(
+            slots_bboxes.iter()
+                .filter(|bbox| !bbox.is_zero())
+                .copied()
+                .collect::<Vec<SdfBoundingBox>>()
+                .as_slice()
+        ).apply_transform(transform).apply_translation(offset)
+    }
+
+    // Node of an SdfTree, whose box has already been fit
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn from_tree(
+        intern: Box<dyn SdfElement>,
+        slots: Vec<SdfNode>,
+        transform: Transform,
+        offset: DVec3,
+        bbox: SdfBoundingBox,
+        material: u32,
+        sweep: Option<SdfBoundingBox>,
+        id: Option<NodeId>,
+    ) -> Self {
+        SdfNode {
+            slots,
+            bbox: Some(bbox),
+            transform,
+            offset,
+            id,
+            material,
+            sweep,
+            intern,
+            split_tree: OnceLock::new(),
+            lipschitz: OnceLock::new(),
         }
     }
 
+    // Move the children out, for SdfTree to take them over
+    pub(crate) fn take_slots(&mut self) -> Vec<SdfNode> {
+        self.split_tree = OnceLock::new();
+        self.lipschitz = OnceLock::new();
+        std::mem::take(&mut self.slots)
+    }
+
+    pub fn element(&self) -> &dyn SdfElement {
+        self.intern.as_ref()
+    }
+
+    pub fn slots(&self) -> &[SdfNode] {
+        self.slots.as_slice()
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.transform
+    }
+
+    pub fn offset(&self) -> DVec3 {
+        self.offset
+    }
+
+    pub fn id(&self) -> Option<NodeId> {
+        self.id
+    }
+
+    pub fn material(&self) -> u32 {
+        self.material
+    }
+
+    pub fn sweep(&self) -> Option<SdfBoundingBox> {
+        self.sweep
+    }
+
     pub fn set_slot(&mut self, child_node: SdfNode) -> Result<(), &'static str> {
         let intern_info = self.intern.get_info();
         if intern_info.is_primitive {
@@ -174,6 +551,8 @@ impl SdfNode {
             Err("Slots already full!")
         } else {
             self.slots.push(child_node);
+            self.split_tree = OnceLock::new();
+            self.lipschitz = OnceLock::new();
             Ok(())
         }
     }
@@ -187,27 +566,56 @@ impl SdfNode {
         if let Some(bbox) = self.bbox {
             bbox
         } else {
-            let bbox = self.intern.get_bbox(
-                self.slots.iter_mut()
-                    .map(|node| node.calc_bbox_assign())
-                    .filter(|bbox| !bbox.is_zero()) // Filter after bbox calculation so entire tree is initialized
-                    .collect::<Vec<SdfBoundingBox>>()
-                    .as_slice()
-            );
+            // Calculate every child's box before filtering so entire tree is initialized
+            let slots_bboxes = self.slots.iter_mut()
+                .map(|node| {
+                    node.calc_bbox_assign();
+                    node.outer_bbox()
+                })
+                .collect::<Vec<SdfBoundingBox>>();
+            let bbox = Self::fit_bbox(self.intern.as_ref(), self.transform, self.offset, slots_bboxes.as_slice());
             self.bbox = Some(bbox);
             bbox
         }
     }
 
+    /**
+     * Box around a subset of the slots, and the two halves that the subset is split into in the
+     * hierarchy that SdfUnion::expand builds. Smooth unions blend their children pairwise in this
+     * order, and the blend isn't associative, so the CPU has to follow it to match the buffer.
+     */
+    pub fn split_slots(&self, index_vec: &[usize], split_method: SdfSplitMethod) -> (SdfBoundingBox, Vec<usize>, Vec<usize>) {
+        let bboxes = index_vec.iter()
+            .map(|i| self.slots[*i].outer_bbox())
+            .collect::<Vec<SdfBoundingBox>>();
+        let merged_box = SdfBoundingBox::merge(bboxes.as_slice());
+        // Split indices are into the subset, so map them back to slot indices
+        let (left_inds, right_inds) = merged_box.split_with(bboxes.as_slice(), split_method);
+        let to_slot_inds = |inds: Vec<usize>| inds.iter()
+            .map(|i| index_vec[*i])
+            .collect::<Vec<usize>>();
+        (merged_box, to_slot_inds(left_inds), to_slot_inds(right_inds))
+    }
+
+    // Box that the parent node is fit around
+    pub fn outer_bbox(&self) -> SdfBoundingBox {
+        self.sweep.unwrap_or_else(|| self.bbox.unwrap())
+    }
+
     pub fn get_sub_boxes(&self) -> Vec<SdfBoundingBox> {
         self.slots.iter()
-            .map(|node| node.bbox.unwrap())
+            .map(|node| node.outer_bbox())
             .filter(|bbox| !bbox.is_zero()) // Filter after bbox calculation so entire tree is initialized
             .collect::<Vec<SdfBoundingBox>>()
     }
 
     pub fn expanded(&self) -> ExpandedSdfNode {
-        self.intern.expand(self)
+        let mut expanded = self.intern.expand(self);
+        expanded.stamp_source(self.id);
+        if self.is_primitive() {
+            expanded.material = self.material;
+        }
+        expanded
     }
 
     pub fn is_finished(&self) -> bool {
@@ -231,6 +639,13 @@ impl SdfNode {
             slots: self.slots.iter().map(|child| child.full_clone()).collect(),
             intern: self.intern.clone(),
             bbox: self.bbox,
+            transform: self.transform,
+            offset: self.offset,
+            id: self.id,
+            material: self.material,
+            split_tree: OnceLock::new(),
+            lipschitz: OnceLock::new(),
+            sweep: self.sweep,
         }
     }
 
@@ -328,43 +743,331 @@ impl SdfNode {
     }
     */
 
+    /**
+     * Bound on how steep the distances of the subtree are, which nearest_neighbor divides them by.
+     *
+     * Operations multiply the factors of their slots, but unions of several slots divide each
+     * slot's distance by the slot's own factor before blending them, so a steep subtree doesn't
+     * shorten the steps towards its siblings. A union of a single slot passes it on unchanged,
+     * like the buffer that collapses it does.
+     */
+    pub fn lipschitz(&self) -> f32 {
+        *self.lipschitz.get_or_init(|| {
+            if self.intern.get_info().is_union && self.slots.len() > 1 {
+                return self.intern.lipschitz();
+            }
+            self.intern.lipschitz() * self.slots.iter().map(|slot| slot.lipschitz()).fold(1.0, f32::max)
+        })
+    }
+
+    // Factor that a union scales the raw distances of a slot by, see lipschitz()
+    fn slot_scale(&self, slot: &SdfNode) -> f32 {
+        self.lipschitz() / slot.lipschitz()
+    }
+
     pub fn nearest_neighbor(&self, point: Vec3) -> NnResult {
+        let mut nn = self.raw_nearest_neighbor(point);
+        nn.distance /= self.lipschitz();
+        nn
+    }
+
+    /**
+     * Nearest neighbor without dividing the distance by the Lipschitz factor of the subtree, which
+     * is what operations combine. Unions prune with the distances of their slots scaled to their
+     * own factor, which are never farther than the slots' boxes.
+     */
+    pub fn raw_nearest_neighbor<'a>(&'a self, point: Vec3) -> NnResult<'a> {
         if self.is_primitive() {
+            let bbox = self.bbox.unwrap();
             return NnResult {
                 node: self,
-                distance: self.intern.distance_to(self.bbox.unwrap().in_box_trans_basis(point.extend(1.0)).truncate()),
+                distance: self.intern.distance_to(bbox.in_box_trans_basis(point.extend(1.0)).truncate()) * bbox.dist_scale,
+                material: SdfMaterialMix::single(self.material),
+            };
+        }
+        // Child boxes live in this node's frame, and so do the distances of the children
+        let local_point = self.bbox.unwrap().in_box_trans_basis(point.extend(1.0)).truncate();
+        let dist_scale = self.bbox.unwrap().dist_scale;
+        // Every slot of other operations is needed to combine them
+        if !self.intern.get_info().is_union {
+            let slot_point = self.intern.slot_point(local_point);
+            let slots_nn = self.slots.iter()
+                .map(|node| node.raw_nearest_neighbor(node.downtree(slot_point)))
+                .collect::<Vec<NnResult>>();
+            let (distance, material) = self.intern.combine(
+                local_point,
+                slots_nn.iter()
+                    .map(|nn| (nn.distance, nn.material))
+                    .collect::<Vec<(f32, SdfMaterialMix)>>()
+                    .as_slice()
+            );
+            return NnResult {
+                node: if slots_nn.len() == 1 { slots_nn[0].node } else { self },
+                distance: distance * dist_scale,
+                material,
             };
         }
+        // Children can only be skipped if they're farther than the blend radius from the nearest one
+        let blend_radius = self.intern.blend_radius();
+        if blend_radius > 0.0 {
+            let mut nn = self.fold_split(
+                self.split_tree(),
+                local_point,
+                &mut |node| {
+                    let mut nn = node.raw_nearest_neighbor(node.downtree(local_point));
+                    nn.distance *= self.slot_scale(node);
+                    nn
+                },
+                &|left: NnResult<'a>, right: NnResult<'a>| {
+                    let (distance, weight) = smooth_min(left.distance, right.distance, blend_radius);
+                    NnResult {
+                        node: if weight > 0.5 { right.node } else { left.node },
+                        distance,
+                        material: left.material.blend(&right.material, weight),
+                    }
+                },
+                &|nn: &NnResult| nn.distance,
+            );
+            nn.distance *= dist_scale;
+            return nn;
+        }
         let mut bounds = self.slots.iter()
             .enumerate()
-            .map(|(i, node)| (i, node.bbox_dist_info(point)))
+            .map(|(i, node)| (i, node.bbox_dist_info(local_point)))
             .collect::<Vec<(usize, NodeDistInfo)>>();
         let min_maxdist = bounds.iter()
             .map(|(_, bound)| CmpFloat(bound.max_bound))
             .min().unwrap().0;
         bounds.sort_unstable_by_key(|(_, bound)| CmpFloat(bound.min_bound));
-        bounds.iter()
-            .take_while(|(_, bound)| bound.min_bound < min_maxdist)
+        let mut nn = bounds.iter()
+            .take_while(|(_, bound)| bound.min_bound < min_maxdist + blend_radius)
             .map(|(i, bound)| (self.slots.get(*i).unwrap(), bound))
             .fold(
                 NnResult {
                     node: self,
                     distance: f32::INFINITY,
+                    material: SdfMaterialMix::single(0),
                 },
                 |accum, (node, bound)| {
-                    if bound.min_bound > accum.distance {
+                    if bound.min_bound > accum.distance + blend_radius {
                         accum
                     } else {
-                        let child_nn = node.nearest_neighbor(node.downtree(
-                            self.bbox.unwrap().in_box_trans_basis(point.extend(1.0)).truncate()
-                        ));
-                        match child_nn.distance < accum.distance {
-                            true => child_nn,
-                            false => accum,
+                        let child_nn = node.raw_nearest_neighbor(node.downtree(local_point));
+                        let child_distance = child_nn.distance * self.slot_scale(node);
+                        let (distance, weight) = smooth_min(accum.distance, child_distance, blend_radius);
+                        NnResult {
+                            node: if weight > 0.5 { child_nn.node } else { accum.node },
+                            distance,
+                            material: accum.material.blend(&child_nn.material, weight),
                         }
                     }
                 }
-            )
+            );
+        nn.distance *= dist_scale;
+        nn
+    }
+
+    // Distance and dominant material ID, like faux_shader::nearest_neighbor gives for the buffer
+    pub fn nearest_material_id(&self, point: Vec3) -> (f32, u32) {
+        let nn = self.nearest_neighbor(point);
+        (nn.distance, nn.material_id())
+    }
+
+    // Split hierarchy of the slots, built on the first query since the slots are fixed by then
+    fn split_tree(&self) -> &SdfSplitTree {
+        self.split_tree.get_or_init(|| self.build_split_tree((0..self.slots.len()).collect::<Vec<usize>>().as_slice()))
+    }
+
+    fn build_split_tree(&self, index_vec: &[usize]) -> SdfSplitTree {
+        if index_vec.len() == 1 {
+            return SdfSplitTree::Slot(index_vec[0]);
+        }
+        let (merged_box, left_inds, right_inds) = self.split_slots(index_vec, self.intern.split_method());
+        SdfSplitTree::Split(
+            Box::new(merged_box),
+            Box::new(self.build_split_tree(&left_inds)),
+            Box::new(self.build_split_tree(&right_inds)),
+        )
+    }
+
+    /**
+     * Blend the results of the slots of a smooth union in the order of its split hierarchy, see
+     * [`SdfNode::split_slots()`]. Halves are pruned like the buffer prunes the blocks of the
+     * hierarchy: the left half is always evaluated, and the right one is skipped if its box is
+     * farther than the blend radius from the left half's result.
+     */
+    fn fold_split<'a, T>(
+        &'a self,
+        split: &SdfSplitTree,
+        local_point: Vec3,
+        eval: &mut impl FnMut(&'a SdfNode) -> T,
+        blend: &impl Fn(T, T) -> T,
+        distance: &impl Fn(&T) -> f32,
+    ) -> T {
+        let (left, right) = match split {
+            SdfSplitTree::Slot(i) => return eval(&self.slots[*i]),
+            SdfSplitTree::Split(_, left, right) => (left, right),
+        };
+        let left = self.fold_split(left, local_point, eval, blend, distance);
+        let right_box = match right.as_ref() {
+            SdfSplitTree::Slot(i) => self.slots[*i].outer_bbox(),
+            SdfSplitTree::Split(merged_box, ..) => **merged_box,
+        };
+        let min_bound = right_box.distance_to(local_point);
+        if min_bound > 0.0 && min_bound > distance(&left) + self.intern.blend_radius() {
+            return left;
+        }
+        let right = self.fold_split(right, local_point, eval, blend, distance);
+        blend(left, right)
+    }
+
+    /**
+     * Bounds on the nearest neighbor distances over an axis-aligned region, for asking whether a
+     * region could contain surface. Elements that map intervals give tighter bounds than the
+     * bounding boxes, which the others fall back to.
+     */
+    pub fn eval_interval(&self, region: SdfAabb) -> (f32, f32) {
+        let lipschitz = self.lipschitz();
+        let interval = self.raw_eval_interval(region);
+        (interval.min / lipschitz, interval.max / lipschitz)
+    }
+
+    // Interval of raw_nearest_neighbor distances, which are steeper than the bounds of the box
+    pub fn raw_eval_interval(&self, region: SdfAabb) -> SdfInterval {
+        let bbox = self.bbox.unwrap();
+        let (center, radius) = (region.center(), region.radius());
+        // The surface is inside of the box, so distances are positive outside of it, and nothing is
+        // farther from the surface than the farthest corner
+        let max_bound = (bbox.max_distance(center) + radius) * self.lipschitz();
+        let min_bound = if bbox.distance_to(center) > radius { 0.0 } else { -max_bound };
+        let fallback = SdfInterval::new(min_bound, max_bound);
+
+        let local_region = region.map_corners(|corner| bbox.in_box_trans_basis(corner.extend(1.0)).truncate());
+        let mapped = if self.is_primitive() {
+            self.intern.distance_interval(local_region)
+        } else {
+            let slots = self.slots.iter()
+                .map(|node| node.raw_eval_interval(node.element().downtree_interval(local_region)))
+                .collect::<Vec<SdfInterval>>();
+            if self.intern.get_info().is_union {
+                let slots = slots.iter()
+                    .zip(self.slots.iter())
+                    .map(|(interval, node)| *interval * self.slot_scale(node))
+                    .collect::<Vec<SdfInterval>>();
+                self.union_interval(local_region, &slots)
+            } else {
+                self.intern.combine_interval(local_region, &slots)
+            }
+        };
+        mapped.and_then(|interval| (interval * bbox.dist_scale).intersect(fallback))
+            .unwrap_or(fallback)
+    }
+
+    /**
+     * Bounds on the smooth union of the slots of a union, given their intervals. Every blend is
+     * below the nearer distance by at most a quarter of the radius, and only slots within the
+     * radius of the nearest one blend.
+     *
+     * Pruning can skip any slot except the one whose box has the nearest far corner, and a skipped
+     * slot's box is farther than the result. So the result is at most the upper bound or the box
+     * distance of one of the slots that can have the nearest far corner somewhere in the region.
+     */
+    fn union_interval(&self, region: SdfAabb, slots: &[SdfInterval]) -> Option<SdfInterval> {
+        let blend_radius = self.intern.blend_radius().max(0.0);
+        let nearest = slots.iter().copied().reduce(SdfInterval::min)?;
+        let blended = slots.iter()
+            .filter(|slot| slot.min < nearest.max + blend_radius)
+            .count();
+
+        let (center, radius) = (region.center(), region.radius());
+        let max_bounds = self.slots.iter()
+            .map(|node| node.bbox.unwrap().max_distance(center))
+            .collect::<Vec<f32>>();
+        let nearest_max_bound = max_bounds.iter().copied().fold(f32::INFINITY, f32::min) + radius;
+        let max = self.slots.iter()
+            .zip(max_bounds.iter())
+            .zip(slots.iter())
+            .filter(|((_, max_bound), _)| *max_bound - radius <= nearest_max_bound)
+            .map(|((node, _), slot)| slot.max.max(node.bbox.unwrap().distance_to(center) + radius))
+            .fold(f32::NEG_INFINITY, f32::max);
+        Some(SdfInterval::new(nearest.min - blend_radius / 4.0 * blended.saturating_sub(1) as f32, max))
+    }
+
+    /**
+     * Nearest neighbor distance in dual numbers, `Dual3` for its exact gradient or `HyperDual3` for
+     * its Hessian as well. None if an element in the tree has no distance in dual numbers.
+     */
+    pub fn dual_distance<S: SdfDualScalar>(&self, point: Vec3) -> Option<S> {
+        self.raw_dual_distance(S::variables(point))
+            .map(|distance| distance / S::from_f32(self.lipschitz()))
+    }
+
+    // Same evaluation and pruning as raw_nearest_neighbor, so values match it exactly
+    pub fn raw_dual_distance<S: SdfDualScalar>(&self, point: SdfVec3<S>) -> Option<S> {
+        let bbox = self.bbox.unwrap();
+        let local_point = point.transform_point(bbox.trans_inverse_basis());
+        let dist_scale = S::from_f32(bbox.dist_scale);
+        if self.is_primitive() {
+            return S::element_distance(self.intern.as_ref(), local_point).map(|distance| distance * dist_scale);
+        }
+        if !self.intern.get_info().is_union {
+            let slots = self.slots.iter()
+                .map(|node| node.raw_dual_distance(node.dual_downtree(local_point)))
+                .collect::<Option<Vec<S>>>()?;
+            return S::element_combine(self.intern.as_ref(), local_point, &slots).map(|distance| distance * dist_scale);
+        }
+        let blend_radius = self.intern.blend_radius();
+        if blend_radius > 0.0 {
+            return self.fold_split(
+                self.split_tree(),
+                local_point.value(),
+                &mut |node| node.raw_dual_distance(node.dual_downtree(local_point))
+                    .map(|distance| distance * S::from_f32(self.slot_scale(node))),
+                &|left: Option<S>, right: Option<S>| Some(smooth_min_scalar(left?, right?, blend_radius)),
+                &|distance: &Option<S>| distance.map_or(f32::NEG_INFINITY, |distance| distance.value()),
+            ).map(|distance| distance * dist_scale);
+        }
+        let mut bounds = self.slots.iter()
+            .enumerate()
+            .map(|(i, node)| (i, node.bbox_dist_info(local_point.value())))
+            .collect::<Vec<(usize, NodeDistInfo)>>();
+        let min_maxdist = bounds.iter()
+            .map(|(_, bound)| CmpFloat(bound.max_bound))
+            .min().unwrap().0;
+        bounds.sort_unstable_by_key(|(_, bound)| CmpFloat(bound.min_bound));
+        let mut distance = S::from_f32(f32::INFINITY);
+        for (i, bound) in bounds.iter().take_while(|(_, bound)| bound.min_bound < min_maxdist + blend_radius) {
+            if bound.min_bound <= distance.value() + blend_radius {
+                let node = &self.slots[*i];
+                let child = node.raw_dual_distance(node.dual_downtree(local_point))? * S::from_f32(self.slot_scale(node));
+                distance = smooth_min_scalar(distance, child, blend_radius);
+            }
+        }
+        Some(distance * dist_scale)
+    }
+
+    // Downtree transforms move points piecewise, like into the cells of a clone, so derivatives pass through
+    fn dual_downtree<S: SdfScalar>(&self, point: SdfVec3<S>) -> SdfVec3<S> {
+        let value = point.value();
+        point + SdfVec3::from_vec3(self.downtree(value) - value)
+    }
+
+    // Unit normal from the exact gradient, or from central differences for trees without duals
+    pub fn normal(&self, point: Vec3) -> Vec3 {
+        const STEP: f32 = 1e-4;
+        match self.dual_distance::<Dual3>(point) {
+            Some(dual) => dual.gradient.normalize(),
+            None => Vec3::new(
+                self.nearest_neighbor(point + Vec3::X * STEP).distance - self.nearest_neighbor(point - Vec3::X * STEP).distance,
+                self.nearest_neighbor(point + Vec3::Y * STEP).distance - self.nearest_neighbor(point - Vec3::Y * STEP).distance,
+                self.nearest_neighbor(point + Vec3::Z * STEP).distance - self.nearest_neighbor(point - Vec3::Z * STEP).distance,
+            ).normalize(),
+        }
+    }
+
+    // Sum of the principal curvatures of the level set through the point, see HyperDual3::curvature()
+    pub fn curvature(&self, point: Vec3) -> Option<f32> {
+        self.dual_distance::<HyperDual3>(point).map(|dual| dual.curvature())
     }
 }
 
@@ -393,6 +1096,19 @@ impl SdfBuilder {
         }
     }
 
+    // Primitive evaluated by an exact distance function of points in its frame, which stays between min and max
+    pub fn closure<F>((min, max): (Vec3, Vec3), distance: F) -> Self
+    where
+        F: Fn(Vec3) -> f32 + Send + Sync + 'static,
+    {
+        Self::primitive(SdfClosure {
+            min,
+            max,
+            distance: Arc::new(distance),
+            lipschitz: 1.0,
+        })
+    }
+
     pub fn operation<T: SdfElement + 'static>(self, op: T) -> Self {
         self.dyn_operation(Box::new(op))
     }
@@ -402,8 +1118,31 @@ impl SdfBuilder {
         self
     }
 
+    // Give every primitive of the subtree built so far the material with the given ID
+    pub fn material(mut self, material: u32) -> Self {
+        fn recurse(node: &mut SdfNode, material: u32) {
+            node.material = material;
+            node.slots.iter_mut().for_each(|child| recurse(child, material));
+        }
+        recurse(&mut self.root, material);
+        self
+    }
+
     pub fn transform(mut self, trans: Transform) -> Self {
         self.root.bbox = Some(self.root.calc_bbox_assign().apply_transform(trans));
+        self.root.transform = trans.mul_transform(self.root.transform);
+        // The offset comes after the old transform, so it's rotated and scaled by the new one
+        self.root.offset = trans.rotation.as_f64() * (self.root.offset * trans.scale.as_dvec3());
+        self
+    }
+
+    /**
+     * Move the node by an f64 translation, after its transform. Bounding boxes keep it as precise
+     * as SdfReal allows, so with the `f64` feature nodes can be placed far from the origin.
+     */
+    pub fn translate(mut self, offset: DVec3) -> Self {
+        self.root.bbox = Some(self.root.calc_bbox_assign().apply_translation(offset));
+        self.root.offset += offset;
         self
     }
 
@@ -417,34 +1156,35 @@ impl SdfBuilder {
 pub mod tests {
     use rand::prelude::*;
     use std::f32::consts::{PI, FRAC_PI_2, SQRT_2};
-    use bevy::prelude::*;
+    use crate::math::*;
     use crate::{
         node::*,
+        tree::SdfTree,
         elements::*,
+        noise::*,
+        mesh::tests::sphere_mesh,
+        shape2d::{SdfShape2d, tests::star},
+        curve::*,
+        faux_shader,
+        registry::registry,
+        dual::Dual3,
     };
+    use std::sync::Arc;
     use float_cmp::approx_eq;
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone, SdfElement)]
+    #[sdf(op = 1, primitive)]
     struct TestPrimitive {
+        #[sdf(floats = 0)]
         pub scale: f32,
+        #[sdf(floats = 1)]
         pub max_dev: f32,
     }
 
     impl SdfElement for TestPrimitive {
-        fn get_info(&self) -> SdfElementInfo {
-            SdfElementInfo::primitive_info(1)
-        }
-
         fn get_bbox(&self, _slots_bboxes: &[SdfBoundingBox]) -> SdfBoundingBox {
             SdfBoundingBox::from_transform(Transform::from_scale(Vec3::splat(self.scale)))
         }
-        
-        fn clone(&self) -> Box<dyn SdfElement> {
-            Box::new(TestPrimitive {
-                scale: self.scale,
-                max_dev: self.max_dev,
-            })
-        }
 
         fn distance_to(&self, point: Vec3) -> f32 {
             let sectors = 360.0 / (self.max_dev / SQRT_2);
@@ -455,17 +1195,6 @@ pub mod tests {
             let paired = zenith_quant * sectors + azimuth_quant;
             point.length() - paired / (sectors * sectors / 2.0) * self.scale
         }
-
-        fn get_dt_specific_block(&self) -> SdfOpSpecificBlock {
-            let mut ret = SdfOpSpecificBlock::ZERO;
-            ret.floats[0] = self.scale;
-            ret.floats[1] = self.max_dev;
-            ret
-        }
-
-        fn expand(&self, this_node: &SdfNode) -> ExpandedSdfNode {
-            ExpandedSdfNode::primitive(this_node.bbox.unwrap(), self.clone())
-        }
     }
 
     fn get_random_transforms(count: u32) -> Vec<Transform> {
@@ -498,6 +1227,9 @@ pub mod tests {
      * translation. For any given primitive, the sdf-tree nearest neighbor results should match the
      * computed ground truth values.
      * 
+     * Non-uniform scales are tested alone and before and after rotation, where the ground truth is
+     * the distance in the scaled frame times the smallest scale, which never overestimates.
+     * 
      * See [`test_dense_nn_single_uni()`] and [`test_dense_nn_single_dir()`] for usage.
      * 
      * Generally, this test is meant to check whether [`SdfBuilder::transform()`] and
@@ -505,6 +1237,7 @@ pub mod tests {
      */
     fn do_dense_nn_single(prim: Box<dyn SdfElement>) {
         let mut rng = thread_rng();
+        let prim_code = prim.get_info().op_id;
         let tlate_trans = Transform::from_translation(Vec3::new(
             rng.gen_range(-50.0..50.0),
             rng.gen_range(-50.0..50.0),
@@ -557,7 +1290,7 @@ pub mod tests {
             .finalize()
             .nearest_neighbor(point)
             .distance;
-        let tree_rot_tlate = SdfBuilder::dyn_primitive(prim)
+        let tree_rot_tlate = SdfBuilder::dyn_primitive(prim.clone())
             .transform(rot_trans)
             .transform(tlate_trans)
             .finalize()
@@ -577,6 +1310,55 @@ pub mod tests {
             "Simple Translation->Rotation Failed!"); 
         assert!(approx_eq!(f32, gt_rot_tlate, tree_rot_tlate),
             "Simple Rotation->Translation Failed!"); 
+
+        let scale_trans = Transform::from_scale(Vec3::new(
+            rng.gen_range(0.5..3.0),
+            rng.gen_range(0.5..3.0),
+            rng.gen_range(0.5..3.0),
+        ));
+        let scale_trans_inv = scale_trans.compute_matrix().inverse();
+        let min_scale = scale_trans.scale.min_element();
+        let scale_point = point / 5.0;
+        println!("Scale: {}", scale_trans.scale);
+
+        let gt_scale = prim.distance_to((scale_trans_inv * scale_point.extend(1.0)).truncate()) * min_scale;
+        let gt_rot_scale = prim.distance_to((rot_trans_inv * scale_trans_inv * scale_point.extend(1.0)).truncate()) * min_scale;
+        let gt_scale_rot = prim.distance_to((scale_trans_inv * rot_trans_inv * scale_point.extend(1.0)).truncate()) * min_scale;
+        let trees = [
+            (gt_scale, SdfBuilder::dyn_primitive(prim.clone())
+                .transform(scale_trans), "Scale"),
+            // Scaling a rotated box skews it, so it has to be refit
+            (gt_rot_scale, SdfBuilder::dyn_primitive(prim.clone())
+                .transform(rot_trans)
+                .transform(scale_trans), "Rotation->Scale"),
+            (gt_scale_rot, SdfBuilder::dyn_primitive(prim.clone())
+                .transform(scale_trans)
+                .transform(rot_trans), "Scale->Rotation"),
+            // Same as the scale alone, from inside of a union that gets collapsed
+            (gt_scale, SdfBuilder::dyn_primitive(prim.clone())
+                .operation(SdfUnion::new(0.0))
+                .transform(scale_trans), "Union->Scale"),
+            // A smooth union with a sphere that's too far away to blend in gives the same
+            (gt_scale, SdfBuilder::dyn_primitive(prim)
+                .operation(SdfUnion::new(0.25))
+                .with(SdfBuilder::primitive(SdfSphere { radius: 1.0 })
+                    .transform(Transform::from_xyz(100.0, 0.0, 0.0)))
+                .transform(scale_trans), "SmoothUnion->Scale"),
+        ];
+        // The faux shader can only check primitives that have a registered distance
+        let has_shader = registry().primitive(prim_code).and_then(|entry| entry.distance).is_some();
+        for (ground_truth, builder, name) in trees {
+            let sdf_tree = builder.finalize();
+            let tree_result = sdf_tree.nearest_neighbor(scale_point).distance;
+            println!("{} Tree Result: {}, Ground Truth: {}", name, tree_result, ground_truth);
+            assert!(approx_eq!(f32, ground_truth, tree_result, epsilon = 1e-4),
+                "Simple {} Failed!", name);
+            if has_shader {
+                let buffer_result = faux_shader::nearest_neighbor(&sdf_tree.expanded().make_buffer(), scale_point.extend(1.0)).0;
+                assert!(approx_eq!(f32, ground_truth, buffer_result, epsilon = 1e-4),
+                    "Buffer {} Failed!", name);
+            }
+        }
     }
 
     fn do_dense_nn_chain(prim: Box<dyn SdfElement>) {
@@ -594,9 +1376,7 @@ pub mod tests {
         let sdf_tree = trans_vec.iter()
             .fold(
                 SdfBuilder::dyn_primitive(prim),
-                |acc, trans| acc.transform(*trans).operation(SdfUnion {
-                    smooth_radius: 0.0,
-                })
+                |acc, trans| acc.transform(*trans).operation(SdfUnion::new(0.0))
             )
             .finalize();
         let nn_result = sdf_tree.nearest_neighbor(point).distance;
@@ -604,6 +1384,56 @@ pub mod tests {
             "Transform Chain Failed! Ground Truth: {}, NN Result: {}", ground_truth, nn_result);
     }
 
+    /**
+     * Re-used test for checking whether a sparse union of many spheres performs nearest neighbor
+     * search correctly, both on the tree and on its buffer.
+     *
+     * This mostly exercises [`SdfUnion::expand()`] with the given split method, and the union
+     * pruning in [`faux_shader::nearest_neighbor()`].
+     */
+    fn do_sparse_nn_union(split_method: SdfSplitMethod) {
+        let mut rng = thread_rng();
+        let centers = (0..64)
+            .map(|_| Vec3::new(
+                rng.gen_range(-50.0..50.0),
+                rng.gen_range(-50.0..50.0),
+                rng.gen_range(-50.0..50.0),
+            ))
+            .collect::<Vec<Vec3>>();
+        let sdf_tree = centers[1..].iter()
+            .fold(
+                SdfBuilder::primitive(SdfSphere {
+                    radius: 1.0,
+                })
+                .transform(Transform::from_translation(centers[0]))
+                .operation(SdfUnion::new(0.0).with_split_method(split_method)),
+                |acc, center| acc.with(
+                    SdfBuilder::primitive(SdfSphere {
+                        radius: 1.0,
+                    })
+                    .transform(Transform::from_translation(*center))
+                )
+            )
+            .finalize();
+        let buffer = sdf_tree.expanded().make_buffer();
+        for _ in 0..100 {
+            let point = Vec3::new(
+                rng.gen_range(-60.0..60.0),
+                rng.gen_range(-60.0..60.0),
+                rng.gen_range(-60.0..60.0),
+            );
+            let ground_truth = centers.iter()
+                .map(|center| (point - *center).length() - 1.0)
+                .fold(f32::INFINITY, f32::min);
+            let tree_result = sdf_tree.nearest_neighbor(point).distance;
+            let buffer_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+            assert!(approx_eq!(f32, ground_truth, tree_result, epsilon = 1e-4),
+                "Tree Union Failed! Ground Truth: {}, NN Result: {}", ground_truth, tree_result);
+            assert!(approx_eq!(f32, ground_truth, buffer_result, epsilon = 1e-4),
+                "Buffer Union Failed! Ground Truth: {}, NN Result: {}", ground_truth, buffer_result);
+        }
+    }
+
     /**
      * Run a sanity test for whether TestPrimitive actually works as intended.
      * 
@@ -655,4 +1485,512 @@ pub mod tests {
         };
         do_dense_nn_chain(Box::new(prim));
     }
+
+    // f64 offsets come after the transform, so later transforms rotate and scale them too
+    #[test]
+    fn test_translate() {
+        let mut rng = StdRng::seed_from_u64(0xf64);
+        let prim = TestPrimitive {
+            scale: 1.0,
+            max_dev: 0.1,
+        };
+        let outer = Transform::from_rotation(Quat::from_rotation_z(0.7)).with_scale(Vec3::new(1.0, 2.0, 1.5));
+        let offset = DVec3::new(1.25, -2.5, 3.0);
+        let translated = SdfBuilder::primitive(prim.clone())
+            .transform(Transform::from_rotation(Quat::from_rotation_x(0.4)))
+            .translate(offset)
+            .transform(outer)
+            .finalize();
+        let expected = SdfBuilder::primitive(prim.clone())
+            .transform(Transform::from_rotation(Quat::from_rotation_x(0.4)))
+            .transform(Transform::from_translation(offset.as_vec3()))
+            .transform(outer)
+            .finalize();
+        let mut tree = SdfTree::new(SdfBuilder::primitive(prim.clone()).finalize());
+        tree.set_offset(tree.root(), offset).unwrap();
+        tree.refit();
+        let moved = tree.to_node(tree.root());
+        for _ in 0..100 {
+            let point = Vec3::new(rng.gen_range(-8.0..8.0), rng.gen_range(-8.0..8.0), rng.gen_range(-8.0..8.0));
+            assert!(approx_eq!(f32, translated.nearest_neighbor(point).distance,
+                expected.nearest_neighbor(point).distance, epsilon = 1e-4));
+            assert!(approx_eq!(f32, moved.nearest_neighbor(point).distance,
+                prim.distance_to(point - offset.as_vec3()), epsilon = 1e-4));
+        }
+    }
+
+    /**
+     * Check that the split hierarchy of a wide union still reaches every child in the buffer.
+     *
+     * The spheres are spread far apart so that every one of them is the nearest for some points,
+     * and a child that's dropped or duplicated while splitting shows up as a wrong distance.
+     */
+    #[test]
+    fn test_wide_union_buffer() {
+        let mut rng = thread_rng();
+        let centers = (0..6)
+            .map(|i| Vec3::new(
+                i as f32 * 10.0 + rng.gen_range(-1.0..1.0),
+                rng.gen_range(-3.0..3.0),
+                rng.gen_range(-3.0..3.0),
+            ))
+            .collect::<Vec<Vec3>>();
+        let sdf_tree = centers.iter()
+            .skip(1)
+            .fold(
+                SdfBuilder::primitive(SdfSphere { radius: 1.0 })
+                    .transform(Transform::from_translation(centers[0]))
+                    .operation(SdfUnion::new(0.0)),
+                |acc, center| acc.with(
+                    SdfBuilder::primitive(SdfSphere { radius: 1.0 })
+                        .transform(Transform::from_translation(*center))
+                )
+            )
+            .finalize();
+        let buffer = sdf_tree.expanded().make_buffer();
+
+        for center in centers.iter() {
+            let point = *center + Vec3::new(
+                rng.gen_range(-2.0..2.0),
+                rng.gen_range(-2.0..2.0),
+                rng.gen_range(-2.0..2.0),
+            );
+            let ground_truth = centers.iter()
+                .map(|other| (point - *other).length() - 1.0)
+                .fold(f32::INFINITY, f32::min);
+            let nn_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+            assert!(approx_eq!(f32, ground_truth, nn_result, epsilon = 1e-4),
+                "Wide Union Failed! Ground Truth: {}, NN Result: {}", ground_truth, nn_result);
+        }
+    }
+
+    #[test]
+    fn test_sparse_nn_union_median() {
+        do_sparse_nn_union(SdfSplitMethod::Median);
+    }
+
+    #[test]
+    fn test_sparse_nn_union_sah() {
+        do_sparse_nn_union(SdfSplitMethod::SurfaceArea);
+    }
+
+    #[test]
+    fn test_sparse_nn_union_volume() {
+        do_sparse_nn_union(SdfSplitMethod::Volume);
+    }
+
+    #[test]
+    fn test_nn_materials() {
+        let mut rng = thread_rng();
+        let two_spheres = |smooth_radius: f32| SdfBuilder::primitive(SdfSphere {
+                radius: 1.0,
+            })
+            .material(1)
+            .operation(SdfUnion::new(smooth_radius))
+            .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 1.0,
+                })
+                .transform(Transform::from_xyz(2.5, 0.0, 0.0))
+                .material(2))
+            .finalize();
+
+        // Hard unions pick the material of the nearest primitive
+        let hard = two_spheres(0.0);
+        let buffer = hard.expanded().make_buffer();
+        for (point, material) in [(Vec3::new(-2.0, 0.0, 0.0), 1), (Vec3::new(4.0, 1.0, 0.0), 2)] {
+            assert_eq!(hard.nearest_neighbor(point).material_id(), material);
+            assert_eq!(faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).1, material);
+        }
+
+        // Smooth unions blend both materials near the seam, and agree between the tree and buffer
+        let smooth = two_spheres(1.0);
+        let buffer = smooth.expanded().make_buffer();
+        // Halfway between the spheres, which of the two equal weights comes first is up to the blend order
+        let seam = smooth.nearest_neighbor(Vec3::new(1.25, 1.0, 0.0));
+        let mut seam_ids = seam.material.ids;
+        seam_ids.sort_unstable();
+        assert_eq!(seam_ids, [1, 2]);
+        assert!(approx_eq!(f32, seam.material.weight, 0.5, epsilon = 1e-4));
+        assert_eq!(seam.material, faux_shader::nearest_material(&buffer, Vec3::new(1.25, 1.0, 0.0).extend(1.0)).1);
+        for _ in 0..100 {
+            let point = Vec3::new(
+                rng.gen_range(-5.0..7.0),
+                rng.gen_range(-5.0..5.0),
+                rng.gen_range(-5.0..5.0),
+            );
+            let (a, b) = ((point.length() - 1.0), (point - Vec3::new(2.5, 0.0, 0.0)).length() - 1.0);
+            let (ground_truth, weight) = smooth_min(a, b, 1.0);
+            let tree_result = smooth.nearest_neighbor(point);
+            let (buffer_result, buffer_material) = faux_shader::nearest_material(&buffer, point.extend(1.0));
+            assert!(approx_eq!(f32, ground_truth, tree_result.distance, epsilon = 1e-4),
+                "Tree Smooth Union Failed! Ground Truth: {}, NN Result: {}", ground_truth, tree_result.distance);
+            assert!(approx_eq!(f32, ground_truth, buffer_result, epsilon = 1e-4),
+                "Buffer Smooth Union Failed! Ground Truth: {}, NN Result: {}", ground_truth, buffer_result);
+            assert_eq!(tree_result.material, buffer_material);
+            assert_eq!(tree_result.material_id(), if weight > 0.5 { 2 } else { 1 });
+            let dual = smooth.dual_distance::<Dual3>(point).unwrap();
+            assert!(approx_eq!(f32, dual.value, tree_result.distance, epsilon = 1e-4));
+        }
+    }
+
+    #[test]
+    fn test_nn_smooth_union_order() {
+        let mut rng = thread_rng();
+        // Overlapping blends of many children, which only agree if they're blended in the same order
+        let mut builder = SdfBuilder::primitive(SdfSphere {
+                radius: 1.0,
+            })
+            .material(1)
+            .operation(SdfUnion::new(1.5));
+        for material in 2..=6 {
+            builder = builder.with(SdfBuilder::primitive(SdfSphere {
+                    radius: rng.gen_range(0.5..1.0),
+                })
+                .transform(Transform::from_xyz(
+                    rng.gen_range(-2.0..2.0),
+                    rng.gen_range(-2.0..2.0),
+                    rng.gen_range(-2.0..2.0),
+                ))
+                .material(material));
+        }
+        let sdf_tree = builder.finalize();
+        let buffer = sdf_tree.expanded().make_buffer();
+        for _ in 0..200 {
+            let point = Vec3::new(
+                rng.gen_range(-4.0..4.0),
+                rng.gen_range(-4.0..4.0),
+                rng.gen_range(-4.0..4.0),
+            );
+            let tree_result = sdf_tree.nearest_neighbor(point);
+            let (buffer_result, buffer_material) = faux_shader::nearest_material(&buffer, point.extend(1.0));
+            assert!(approx_eq!(f32, tree_result.distance, buffer_result, epsilon = 1e-4),
+                "Smooth Union Order Failed! Tree Result: {}, Buffer Result: {}", tree_result.distance, buffer_result);
+            assert!(approx_eq!(f32, tree_result.material.weight, buffer_material.weight, epsilon = 1e-4));
+            if (buffer_material.weight - 0.5).abs() > 1e-3 {
+                assert_eq!(sdf_tree.nearest_material_id(point).1, faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_nn_morph() {
+        let mut rng = thread_rng();
+        let frame = Transform::from_xyz(1.0, -2.0, 3.0).with_rotation(Quat::from_rotation_z(0.7));
+        let sdf_tree = SdfBuilder::primitive(SdfSphere {
+                radius: 1.0,
+            })
+            .material(1)
+            .operation(SdfMorph {
+                t: 0.3,
+            })
+            .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 2.0,
+                })
+                .transform(Transform::from_xyz(2.0, 0.0, 0.0))
+                .material(2))
+            .operation(SdfUnion::new(0.0))
+            .with(SdfBuilder::primitive(SdfSphere {
+                    radius: 1.0,
+                })
+                .transform(Transform::from_xyz(0.0, 10.0, 0.0)))
+            .transform(frame)
+            .finalize();
+        let buffer = sdf_tree.expanded().make_buffer();
+        let frame_inv = frame.compute_matrix().inverse();
+        for _ in 0..100 {
+            let point = Vec3::new(
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0),
+            );
+            let local = frame_inv.transform_point3(point);
+            let morphed = 0.7 * (local.length() - 1.0) + 0.3 * ((local - Vec3::new(2.0, 0.0, 0.0)).length() - 2.0);
+            let ground_truth = morphed.min((local - Vec3::new(0.0, 10.0, 0.0)).length() - 1.0);
+            let tree_result = sdf_tree.nearest_neighbor(point);
+            let (buffer_result, buffer_material) = faux_shader::nearest_material(&buffer, point.extend(1.0));
+            assert!(approx_eq!(f32, ground_truth, tree_result.distance, epsilon = 1e-4),
+                "Tree Morph Failed! Ground Truth: {}, NN Result: {}", ground_truth, tree_result.distance);
+            assert!(approx_eq!(f32, ground_truth, buffer_result, epsilon = 1e-4),
+                "Buffer Morph Failed! Ground Truth: {}, NN Result: {}", ground_truth, buffer_result);
+            assert_eq!(tree_result.material, buffer_material);
+            if ground_truth == morphed {
+                assert_eq!(buffer_material.ids, [1, 2]);
+                assert!(approx_eq!(f32, buffer_material.weight, 0.3));
+            }
+        }
+    }
+
+    #[test]
+    fn test_nn_displace() {
+        // Seeded, since grazing rays can take more steps than the trace allows
+        let mut rng = StdRng::seed_from_u64(0xd15);
+        let displace = SdfDisplace {
+            amplitude: 0.5,
+            noise: SdfNoise::new(SdfNoiseBasis::Perlin, 42).with_octaves(3, 2.0, 0.5),
+        };
+        let frame = Transform::from_xyz(5.0, 0.0, -2.0).with_rotation(Quat::from_rotation_x(0.4));
+        let sdf_tree = SdfBuilder::primitive(SdfSphere {
+                radius: 3.0,
+            })
+            .operation(SdfDisplace {
+                amplitude: displace.amplitude,
+                noise: displace.noise,
+            })
+            .transform(frame)
+            .finalize();
+        let buffer = sdf_tree.expanded().make_buffer();
+        let frame_inv = frame.compute_matrix().inverse();
+        let raw = |point: Vec3| {
+            let local = frame_inv.transform_point3(point);
+            local.length() - 3.0 + displace.amplitude * displace.noise.sample(local)
+        };
+        for _ in 0..100 {
+            let point = frame.compute_matrix().transform_point3(Vec3::new(
+                rng.gen_range(-6.0..6.0),
+                rng.gen_range(-6.0..6.0),
+                rng.gen_range(-6.0..6.0),
+            ));
+            let ground_truth = raw(point) * displace.step_scale();
+            let tree_result = sdf_tree.nearest_neighbor(point).distance;
+            let buffer_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+            assert!(approx_eq!(f32, ground_truth, tree_result, epsilon = 1e-4),
+                "Tree Displacement Failed! Ground Truth: {}, NN Result: {}", ground_truth, tree_result);
+            assert!(approx_eq!(f32, ground_truth, buffer_result, epsilon = 1e-4),
+                "Buffer Displacement Failed! Ground Truth: {}, NN Result: {}", ground_truth, buffer_result);
+            if raw(point) <= 0.0 {
+                assert!(sdf_tree.could_contain(point), "Displaced surface outside of its box!");
+            }
+        }
+
+        // Sphere tracing with the scaled distance never steps through the displaced surface
+        for _ in 0..20 {
+            let dir = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)).normalize();
+            let origin = frame.translation + dir * 10.0;
+            let mut t = 0.0;
+            for _ in 0..200 {
+                t += sdf_tree.nearest_neighbor(origin - dir * t).distance;
+                assert!(raw(origin - dir * t) > -1e-3, "Sphere tracing overshot the surface!");
+            }
+            assert!(raw(origin - dir * t).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_nn_terrain() {
+        let mut rng = thread_rng();
+        let terrain = SdfTerrain {
+            half_size: Vec2::new(20.0, 20.0),
+            amplitude: 3.0,
+            depth: 2.0,
+            noise: SdfNoise::new(SdfNoiseBasis::Simplex, 7)
+                .with_frequency(0.1)
+                .with_octaves(4, 2.0, 0.5),
+        };
+        let sdf_tree = SdfBuilder::primitive(terrain.clone())
+            .transform(Transform::from_xyz(0.0, -5.0, 0.0))
+            .finalize();
+        let buffer = sdf_tree.expanded().make_buffer();
+        for _ in 0..20 {
+            let (x, z) = (rng.gen_range(-18.0..18.0), rng.gen_range(-18.0..18.0));
+            let height = terrain.height_at(x, z) - 5.0;
+            assert!(sdf_tree.could_contain(Vec3::new(x, height, z)));
+
+            // Tracing straight down ends up on the surface, and never below it
+            let mut y = 10.0;
+            for _ in 0..200 {
+                let point = Vec3::new(x, y, z);
+                let tree_result = sdf_tree.nearest_neighbor(point).distance;
+                let buffer_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+                assert!(approx_eq!(f32, tree_result, buffer_result, epsilon = 1e-4),
+                    "Buffer Terrain Failed! Tree Result: {}, Buffer Result: {}", tree_result, buffer_result);
+                y -= tree_result;
+                assert!(y > height - 1e-3, "Sphere tracing went through the terrain!");
+            }
+            assert!((y - height).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "without a GPU encoding")]
+    fn test_mesh_buffer_guard() {
+        let sdf_tree = SdfBuilder::primitive(SdfMesh {
+            mesh: Arc::new(sphere_mesh(1)),
+        }).finalize();
+        sdf_tree.expanded().make_buffer();
+    }
+
+    #[test]
+    fn test_nn_grid() {
+        let mut rng = thread_rng();
+        let mesh = SdfMesh {
+            mesh: Arc::new(sphere_mesh(3)),
+        };
+        let transform = Transform::from_xyz(3.0, 0.0, -1.0).with_scale(Vec3::splat(2.0));
+        let exact_tree = SdfBuilder::primitive(mesh.clone()).transform(transform).finalize();
+        let sdf_tree = SdfBuilder::primitive(mesh.baked(0.05)).transform(transform).finalize();
+        let buffer = sdf_tree.expanded().make_buffer();
+        for _ in 0..200 {
+            let point = Vec3::new(
+                rng.gen_range(0.0..6.0),
+                rng.gen_range(-3.0..3.0),
+                rng.gen_range(-4.0..2.0),
+            );
+            let tree_result = sdf_tree.nearest_neighbor(point).distance;
+            let buffer_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+            assert!(approx_eq!(f32, tree_result, buffer_result, epsilon = 1e-4),
+                "Buffer Grid Failed! Tree Result: {}, Buffer Result: {}", tree_result, buffer_result);
+            // Baking keeps the sign of the mesh
+            let exact = exact_tree.nearest_neighbor(point).distance;
+            assert!(exact.abs() < 0.1 || exact.signum() == tree_result.signum());
+        }
+    }
+
+    #[test]
+    fn test_nn_closure() {
+        let mut rng = thread_rng();
+        // Rounded box from a distance function, next to a regular sphere
+        let rounded_box = |point: Vec3| {
+            let q = point.abs() - Vec3::new(1.0, 0.5, 0.5);
+            q.max(Vec3::ZERO).length() + q.max_element().min(0.0) - 0.1
+        };
+        let sdf_tree = SdfBuilder::closure((Vec3::new(-1.1, -0.6, -0.6), Vec3::new(1.1, 0.6, 0.6)), rounded_box)
+            .transform(Transform::from_xyz(2.0, 0.0, 0.0))
+            .operation(SdfUnion::new(0.0))
+            .with(SdfBuilder::primitive(SdfSphere {
+                radius: 1.0,
+            }))
+            .finalize();
+        for _ in 0..200 {
+            let point = Vec3::new(rng.gen_range(-3.0..5.0), rng.gen_range(-3.0..3.0), rng.gen_range(-3.0..3.0));
+            let expected = rounded_box(point - Vec3::new(2.0, 0.0, 0.0)).min(point.length() - 1.0);
+            assert!(approx_eq!(f32, sdf_tree.nearest_neighbor(point).distance, expected, epsilon = 1e-5));
+        }
+
+        // Closures and meshes can't go to the GPU
+        assert!(sdf_tree.expanded().try_make_buffer().is_err());
+        let mesh_tree = SdfBuilder::primitive(SdfMesh {
+            mesh: Arc::new(sphere_mesh(1)),
+        }).finalize();
+        assert!(mesh_tree.expanded().try_make_buffer().is_err());
+        let sphere_tree = SdfBuilder::primitive(SdfSphere {
+            radius: 1.0,
+        }).finalize();
+        assert!(sphere_tree.expanded().try_make_buffer().is_ok());
+    }
+
+    #[test]
+    fn test_nn_curves() {
+        let mut rng = thread_rng();
+        let points = [
+            Vec3::new(-3.0, 0.0, 0.0),
+            Vec3::new(-1.0, 2.0, 1.0),
+            Vec3::new(1.0, -1.0, 0.0),
+            Vec3::new(3.0, 1.0, -1.0),
+        ];
+        let sdf_tree = polyline(&points, &[0.3, 0.1, 0.4, 0.2])
+            .unwrap()
+            .operation(SdfUnion::new(0.0))
+            .with(quad_bezier_tube([points[0], points[1], points[2]], 0.2, 0.5)
+                .unwrap()
+                .transform(Transform::from_xyz(0.0, 3.0, 0.0)))
+            .with(cubic_bezier_tube(points, 0.15, 0.5)
+                .unwrap()
+                .transform(Transform::from_xyz(0.0, -3.0, 0.0)))
+            .finalize();
+        let buffer = sdf_tree.expanded().make_buffer();
+        for _ in 0..200 {
+            let point = Vec3::new(
+                rng.gen_range(-5.0..5.0),
+                rng.gen_range(-6.0..6.0),
+                rng.gen_range(-3.0..3.0),
+            );
+            let tree_result = sdf_tree.nearest_neighbor(point).distance;
+            let buffer_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+            assert!(approx_eq!(f32, tree_result, buffer_result, epsilon = 1e-4),
+                "Buffer Curves Failed! Tree Result: {}, Buffer Result: {}", tree_result, buffer_result);
+        }
+
+        // Capsule boxes follow the segment
+        let capsule = SdfCapsule {
+            a: Vec3::ZERO,
+            b: Vec3::splat(4.0),
+            radius_a: 0.5,
+            radius_b: 0.25,
+        };
+        let bbox = capsule.get_bbox(&[]);
+        for _ in 0..100 {
+            let point = Vec3::splat(rng.gen_range(-0.5..4.5))
+                + Vec3::new(rng.gen_range(-0.5..0.5), rng.gen_range(-0.5..0.5), rng.gen_range(-0.5..0.5));
+            assert!(capsule.distance_to(point) > 0.0 || bbox.contains(point));
+        }
+        assert!(!bbox.contains(Vec3::new(4.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_nn_extrude_revolve() {
+        let mut rng = thread_rng();
+        let profile = |shape: SdfShape2d| SdfBuilder::primitive(SdfProfile {
+            shape,
+        });
+        let sdf_tree = profile(star())
+            .operation(SdfExtrude {
+                height: 0.5,
+            })
+            .transform(Transform::from_xyz(-3.0, 0.0, 0.0))
+            .operation(SdfUnion::new(0.0))
+            .with(profile(SdfShape2d::QuadBezier {
+                    points: [Vec2::new(0.0, -1.0), Vec2::new(1.0, 0.0), Vec2::new(0.0, 1.0)],
+                    thickness: 0.1,
+                })
+                .operation(SdfRevolve {
+                    offset: 0.5,
+                })
+                .transform(Transform::from_xyz(3.0, 0.0, 0.0)))
+            .finalize();
+        let buffer = sdf_tree.expanded().make_buffer();
+        for _ in 0..200 {
+            let point = Vec3::new(
+                rng.gen_range(-6.0..6.0),
+                rng.gen_range(-3.0..3.0),
+                rng.gen_range(-3.0..3.0),
+            );
+            let tree_result = sdf_tree.nearest_neighbor(point).distance;
+            let buffer_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+            assert!(approx_eq!(f32, tree_result, buffer_result, epsilon = 1e-4),
+                "Buffer Extrusion Failed! Tree Result: {}, Buffer Result: {}", tree_result, buffer_result);
+        }
+
+        // A revolved circle is a torus, and an extruded rect is a box, also from a moved child
+        let torus = profile(SdfShape2d::Circle { radius: 0.5 })
+            .operation(SdfRevolve {
+                offset: 2.0,
+            })
+            .finalize();
+        let slab = profile(SdfShape2d::Rect { half_size: Vec2::new(1.0, 0.5) })
+            .transform(Transform::from_xyz(1.0, 0.0, 0.0))
+            .operation(SdfExtrude {
+                height: 1.0,
+            })
+            .finalize();
+        // Profile parts behind the axis are cut off, so this revolves a half disc into a sphere
+        let cut = profile(SdfShape2d::Circle { radius: 1.0 })
+            .operation(SdfRevolve {
+                offset: 0.0,
+            })
+            .finalize();
+        for _ in 0..100 {
+            let point = Vec3::new(rng.gen_range(-4.0..4.0), rng.gen_range(-4.0..4.0), rng.gen_range(-4.0..4.0));
+            let ring = Vec2::new(Vec2::new(point.x, point.z).length() - 2.0, point.y);
+            assert!(approx_eq!(f32, torus.nearest_neighbor(point).distance, ring.length() - 0.5, epsilon = 1e-5));
+            let q = (point - Vec3::new(1.0, 0.0, 0.0)).abs() - Vec3::new(1.0, 0.5, 0.5);
+            let box_distance = q.max(Vec3::ZERO).length() + q.max_element().min(0.0);
+            assert!(approx_eq!(f32, slab.nearest_neighbor(point).distance, box_distance, epsilon = 1e-5));
+            assert!(approx_eq!(f32, cut.nearest_neighbor(point).distance, point.length() - 1.0, epsilon = 1e-5));
+            // All of them stay inside of their boxes
+            for (sdf_tree, distance) in [(&torus, ring.length() - 0.5), (&slab, box_distance), (&cut, point.length() - 1.0)] {
+                assert!(distance > 1e-5 || sdf_tree.bbox.unwrap().contains(point));
+            }
+        }
+        assert!(cut.bbox.unwrap().contains(Vec3::new(0.0, 0.0, -0.9)));
+    }
 }
@@ -0,0 +1,349 @@
+use super::math::*;
+use super::{
+    component::SdfOpSpecificBlock,
+    interval::*,
+    dual::*,
+};
+
+// Seeds are stored as floats in op-specific blocks, so only the bits a float holds exactly are used
+pub const SEED_MASK: u32 = 0x00ff_ffff;
+
+/*
+Gradient magnitude of each basis at frequency 1. The value bound is conservative: it follows from
+the quintic fade, whose slope is at most 15/8, over a difference of at most 2 along each axis.
+
+The Perlin and simplex factors are NOT conservative bounds, only estimates. They're the steepest
+slopes found by hill climbing from random points (3.3 and 6.9) with some margin, because bounding
+every term of the gradient separately gives factors several times larger, which would slow sphere
+tracing down as much. test_basis_lipschitz checks them on a dense grid.
+*/
+const VALUE_LIPSCHITZ: f32 = 6.5;
+const PERLIN_LIPSCHITZ: f32 = 4.0;
+const SIMPLEX_LIPSCHITZ: f32 = 8.0;
+
+// Edge midpoints of a cube, the gradients of improved Perlin noise
+const GRADIENTS: [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0], [0.0, -1.0, 1.0], [0.0, 1.0, -1.0], [0.0, -1.0, -1.0],
+];
+
+fn gradient(seed: u32, lattice: IVec3) -> Vec3 {
+    Vec3::from(GRADIENTS[(hash(seed, lattice.x, lattice.y, lattice.z) % 12) as usize])
+}
+
+// Integer hash of a lattice point, using only operations that GLSL has too
+pub fn hash(seed: u32, x: i32, y: i32, z: i32) -> u32 {
+    let mut h = (seed & SEED_MASK)
+        ^ (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+fn fade<S: SdfScalar>(t: S) -> S {
+    t * t * t * (t * (t * S::from_f32(6.0) - S::from_f32(15.0)) + S::from_f32(10.0))
+}
+
+fn lerp<S: SdfScalar>(a: S, b: S, t: S) -> S {
+    a + (b - a) * t
+}
+
+/**
+ * Trilinear interpolation of the eight corners of a lattice cell. The cell only depends on the
+ * value of the point, so derivatives come from the offsets within it.
+ */
+fn cell_noise<S: SdfScalar>(point: SdfVec3<S>, corner: impl Fn(IVec3, SdfVec3<S>) -> S) -> S {
+    let cell = point.value().floor();
+    let local = point - SdfVec3::from_vec3(cell);
+    let cell = cell.as_ivec3();
+    let (ux, uy, uz) = (fade(local.x), fade(local.y), fade(local.z));
+    let c = |x: i32, y: i32, z: i32| corner(
+        cell + IVec3::new(x, y, z),
+        local - SdfVec3::from_vec3(Vec3::new(x as f32, y as f32, z as f32)),
+    );
+    lerp(
+        lerp(lerp(c(0, 0, 0), c(1, 0, 0), ux), lerp(c(0, 1, 0), c(1, 1, 0), ux), uy),
+        lerp(lerp(c(0, 0, 1), c(1, 0, 1), ux), lerp(c(0, 1, 1), c(1, 1, 1), ux), uy),
+        uz,
+    )
+}
+
+// Random values at the lattice points, smoothly interpolated. In [-1, 1].
+pub fn value3(point: Vec3, seed: u32) -> f32 {
+    value3_scalar(SdfVec3::from_vec3(point), seed)
+}
+
+pub fn value3_scalar<S: SdfScalar>(point: SdfVec3<S>, seed: u32) -> S {
+    cell_noise(point, |corner, _| {
+        S::from_f32(hash(seed, corner.x, corner.y, corner.z) as f32 / u32::MAX as f32 * 2.0 - 1.0)
+    })
+}
+
+// Improved Perlin gradient noise. In [-1, 1].
+pub fn perlin3(point: Vec3, seed: u32) -> f32 {
+    perlin3_scalar(SdfVec3::from_vec3(point), seed)
+}
+
+pub fn perlin3_scalar<S: SdfScalar>(point: SdfVec3<S>, seed: u32) -> S {
+    cell_noise(point, |corner, offset| {
+        SdfVec3::from_vec3(gradient(seed, corner)).dot(offset)
+    }).clamp(-1.0, 1.0)
+}
+
+// Simplex noise on the skewed tetrahedral lattice. In [-1, 1].
+pub fn simplex3(point: Vec3, seed: u32) -> f32 {
+    simplex3_scalar(SdfVec3::from_vec3(point), seed)
+}
+
+pub fn simplex3_scalar<S: SdfScalar>(point: SdfVec3<S>, seed: u32) -> S {
+    const SKEW: f32 = 1.0 / 3.0;
+    const UNSKEW: f32 = 1.0 / 6.0;
+    let skew = (point.x + point.y + point.z) * S::from_f32(SKEW);
+    let cell = (point + SdfVec3::splat(skew)).value().floor();
+    let origin = point - SdfVec3::from_vec3(cell - Vec3::splat((cell.x + cell.y + cell.z) * UNSKEW));
+
+    // Walk from the origin to the far corner of the cell along the largest offsets first
+    let origin_value = origin.value();
+    let mut order = [0, 1, 2];
+    order.sort_by(|a, b| origin_value[*b].partial_cmp(&origin_value[*a]).unwrap());
+    let mut steps = [IVec3::ZERO; 4];
+    for (i, axis) in order.into_iter().enumerate() {
+        steps[i + 1] = steps[i];
+        steps[i + 1][axis] += 1;
+    }
+
+    let cell = cell.as_ivec3();
+    let total = steps.iter()
+        .enumerate()
+        .fold(S::from_f32(0.0), |total, (i, step)| {
+            let offset = origin - SdfVec3::from_vec3(step.as_vec3()) + SdfVec3::splat(S::from_f32(i as f32 * UNSKEW));
+            // Corners only reach as far as the opposite face of the simplex, which keeps the sum continuous
+            let t = S::from_f32(0.5) - offset.length_squared();
+            if t.value() <= 0.0 {
+                return total;
+            }
+            total + t * t * t * t * SdfVec3::from_vec3(gradient(seed, cell + *step)).dot(offset)
+        });
+    (S::from_f32(76.0) * total).clamp(-1.0, 1.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdfNoiseBasis {
+    Value,
+    Perlin,
+    Simplex,
+}
+
+impl SdfNoiseBasis {
+    fn id(&self) -> u32 {
+        match self {
+            SdfNoiseBasis::Value => 0,
+            SdfNoiseBasis::Perlin => 1,
+            SdfNoiseBasis::Simplex => 2,
+        }
+    }
+
+    fn from_id(id: u32) -> Self {
+        match id {
+            0 => SdfNoiseBasis::Value,
+            1 => SdfNoiseBasis::Perlin,
+            _ => SdfNoiseBasis::Simplex,
+        }
+    }
+
+    pub fn sample(&self, point: Vec3, seed: u32) -> f32 {
+        self.sample_scalar(SdfVec3::from_vec3(point), seed)
+    }
+
+    pub fn sample_scalar<S: SdfScalar>(&self, point: SdfVec3<S>, seed: u32) -> S {
+        match self {
+            SdfNoiseBasis::Value => value3_scalar(point, seed),
+            SdfNoiseBasis::Perlin => perlin3_scalar(point, seed),
+            SdfNoiseBasis::Simplex => simplex3_scalar(point, seed),
+        }
+    }
+
+    // Steepest slope at frequency 1, which is only an estimate for Perlin and simplex noise
+    pub fn lipschitz(&self) -> f32 {
+        match self {
+            SdfNoiseBasis::Value => VALUE_LIPSCHITZ,
+            SdfNoiseBasis::Perlin => PERLIN_LIPSCHITZ,
+            SdfNoiseBasis::Simplex => SIMPLEX_LIPSCHITZ,
+        }
+    }
+}
+
+/**
+ * Fractal Brownian motion: octaves of a noise basis, each at `lacunarity` times the frequency and
+ * `gain` times the amplitude of the last. Normalized to [-1, 1].
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SdfNoise {
+    pub basis: SdfNoiseBasis,
+    pub seed: u32,
+    pub frequency: f32,
+    pub octaves: u32,
+    pub lacunarity: f32,
+    pub gain: f32,
+}
+
+impl SdfNoise {
+    pub fn new(basis: SdfNoiseBasis, seed: u32) -> Self {
+        SdfNoise {
+            basis,
+            seed,
+            frequency: 1.0,
+            octaves: 1,
+            lacunarity: 2.0,
+            gain: 0.5,
+        }
+    }
+
+    pub fn with_frequency(mut self, frequency: f32) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    pub fn with_octaves(mut self, octaves: u32, lacunarity: f32, gain: f32) -> Self {
+        self.octaves = octaves;
+        self.lacunarity = lacunarity;
+        self.gain = gain;
+        self
+    }
+
+    fn octave_amplitudes(&self) -> impl Iterator<Item = (f32, f32)> + '_ {
+        (0..self.octaves.max(1) as i32)
+            .map(|i| (self.frequency * self.lacunarity.powi(i), self.gain.powi(i)))
+    }
+
+    pub fn sample(&self, point: Vec3) -> f32 {
+        self.sample_scalar(SdfVec3::from_vec3(point))
+    }
+
+    // sample() in any scalar, for exact derivatives of displaced surfaces
+    pub fn sample_scalar<S: SdfScalar>(&self, point: SdfVec3<S>) -> S {
+        let (sum, norm) = self.octave_amplitudes()
+            .enumerate()
+            .fold((S::from_f32(0.0), 0.0), |(sum, norm), (i, (frequency, amplitude))| (
+                // Every octave gets its own seed, so they don't line up at the origin
+                sum + S::from_f32(amplitude) * self.basis.sample_scalar(point * S::from_f32(frequency), self.seed.wrapping_add(i as u32)),
+                norm + amplitude,
+            ));
+        sum / S::from_f32(norm)
+    }
+
+    // Upper bound on the gradient magnitude of sample()
+    pub fn lipschitz(&self) -> f32 {
+        let (sum, norm) = self.octave_amplitudes()
+            .fold((0.0, 0.0), |(sum, norm), (frequency, amplitude)| (sum + amplitude * frequency, norm + amplitude));
+        self.basis.lipschitz() * sum / norm
+    }
+
+    // Bounds on sample() over a region, from its value at the center and its steepest slope
+    pub fn interval(&self, region: SdfAabb) -> SdfInterval {
+        SdfInterval::around(self.sample(region.center()), self.lipschitz() * region.radius()).clamp(-1.0, 1.0)
+    }
+
+    // Pack the noise into vec4s[0] and vec4s[1] of an op-specific block
+    pub fn write_block(&self, block: &mut SdfOpSpecificBlock) {
+        let [a, b] = self.to_vec4s();
+        block.vec4s[0] = a;
+        block.vec4s[1] = b;
+    }
+
+    pub fn to_vec4s(&self) -> [Vec4; 2] {
+        [
+            Vec4::new(self.frequency, self.lacunarity, self.gain, self.octaves as f32),
+            Vec4::new(self.basis.id() as f32, (self.seed & SEED_MASK) as f32, 0.0, 0.0),
+        ]
+    }
+
+    pub fn from_vec4s(vec4s: &[Vec4]) -> Self {
+        SdfNoise {
+            basis: SdfNoiseBasis::from_id(vec4s[1].x as u32),
+            seed: vec4s[1].y as u32,
+            frequency: vec4s[0].x,
+            octaves: vec4s[0].w as u32,
+            lacunarity: vec4s[0].y,
+            gain: vec4s[0].z,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use crate::noise::*;
+
+    fn random_point(rng: &mut ThreadRng, range: f32) -> Vec3 {
+        Vec3::new(
+            rng.gen_range(-range..range),
+            rng.gen_range(-range..range),
+            rng.gen_range(-range..range),
+        )
+    }
+
+    #[test]
+    fn test_noise_bounds() {
+        let mut rng = thread_rng();
+        for basis in [SdfNoiseBasis::Value, SdfNoiseBasis::Perlin, SdfNoiseBasis::Simplex] {
+            let noise = SdfNoise::new(basis, 1234)
+                .with_frequency(0.7)
+                .with_octaves(4, 2.0, 0.5);
+            let mut max_slope = 0_f32;
+            let mut spread = (f32::INFINITY, f32::NEG_INFINITY);
+            for _ in 0..20000 {
+                let point = random_point(&mut rng, 20.0);
+                let value = noise.sample(point);
+                assert!((-1.0..=1.0).contains(&value), "{:?} out of range: {}", basis, value);
+                spread = (spread.0.min(value), spread.1.max(value));
+
+                // Seeded noise is deterministic
+                assert_eq!(value, noise.sample(point));
+
+                let other = point + random_point(&mut rng, 1.0).normalize() * 1e-3;
+                max_slope = max_slope.max((noise.sample(other) - value).abs() / (other - point).length());
+            }
+            assert!(max_slope <= noise.lipschitz(), "{:?} slope {} over bound {}", basis, max_slope, noise.lipschitz());
+            assert!(spread.0 < -0.3 && spread.1 > 0.3, "{:?} is too flat: {:?}", basis, spread);
+        }
+    }
+
+    #[test]
+    fn test_noise_seeds() {
+        let point = Vec3::new(0.3, 1.7, -2.2);
+        let noise = SdfNoise::new(SdfNoiseBasis::Perlin, 7).with_octaves(3, 2.0, 0.5);
+        assert_ne!(noise.sample(point), SdfNoise { seed: 8, ..noise }.sample(point));
+        assert_eq!(SdfNoise::from_vec4s(&noise.to_vec4s()), noise);
+        assert_eq!(SdfNoise::from_vec4s(&SdfNoise { seed: u32::MAX, ..noise }.to_vec4s()).sample(point),
+            SdfNoise { seed: u32::MAX, ..noise }.sample(point));
+        // Gradient noise vanishes on the lattice
+        assert_eq!(perlin3(Vec3::new(3.0, -2.0, 5.0), 7), 0.0);
+    }
+
+    #[test]
+    fn test_basis_lipschitz() {
+        // Central differences on a dense grid over a few cells, offset from the lattice
+        let (size, step) = (32, 1.0 / 16.0);
+        for basis in [SdfNoiseBasis::Value, SdfNoiseBasis::Perlin, SdfNoiseBasis::Simplex] {
+            let mut max_slope = 0_f32;
+            for seed in 0..2 {
+                for i in 0..size * size * size {
+                    let point = Vec3::new((i % size) as f32, (i / size % size) as f32, (i / size / size) as f32) * step + Vec3::splat(0.37);
+                    let slope = |axis: Vec3| (basis.sample(point + axis * 1e-3, seed) - basis.sample(point - axis * 1e-3, seed)) / 2e-3;
+                    max_slope = max_slope.max(Vec3::new(slope(Vec3::X), slope(Vec3::Y), slope(Vec3::Z)).length());
+                }
+            }
+            assert!(max_slope <= basis.lipschitz(), "{:?} slope {} over bound {}", basis, max_slope, basis.lipschitz());
+            // The sampled bounds shouldn't be much looser than the slopes either, which would slow down marching
+            assert!(basis == SdfNoiseBasis::Value || max_slope > basis.lipschitz() * 0.6,
+                "{:?} slope {} far under bound {}", basis, max_slope, basis.lipschitz());
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use nalgebra::{
-    Matrix4, Vector4, matrix, Matrix, Matrix4xX, Point, U1, U4, Scalar, Const,
+    Matrix3, Matrix4, Vector2, Vector3, Vector4, matrix, Matrix, Point, U1, U4, Scalar, Const,
     storage::{Storage}
 };
 use std::{
@@ -7,10 +7,21 @@ use std::{
     cmp::Ordering,
 };
 use float_cmp::approx_eq;
-use bevy::prelude::*;
+use super::math::*;
 use super::component::*;
 
-const VERT_LIST: [Vector4<f32>; 8] = [
+/**
+ * Precision that bounding boxes are stored and transformed in. Apart from the f64 offsets of
+ * [`SdfBoundingBox::apply_translation()`], the API stays in f32, but with the `f64` feature, boxes
+ * far from the origin keep their precision until they're rebased close to a camera with
+ * [`SdfBoundingBox::rebased()`] and written out as f32 blocks.
+ */
+#[cfg(not(feature = "f64"))]
+pub type SdfReal = f32;
+#[cfg(feature = "f64")]
+pub type SdfReal = f64;
+
+const VERT_LIST: [Vector4<SdfReal>; 8] = [
     Vector4::new(1.0, 1.0, 1.0, 1.0),
     Vector4::new(-1.0, 1.0, 1.0, 1.0),
     Vector4::new(1.0, -1.0, 1.0, 1.0),
@@ -24,64 +35,322 @@ const VERT_LIST: [Vector4<f32>; 8] = [
 // I'm so done with this floats-can't-be-compared bullshit; I don't care 
 // if it isn't right. 
 #[derive(PartialEq)]
-pub struct CmpFloat(pub f32);
+pub struct CmpFloat<T: PartialOrd = f32>(pub T);
 
-impl Eq for CmpFloat {}
+impl<T: PartialOrd> Eq for CmpFloat<T> {}
 
-impl PartialOrd for CmpFloat {
+impl<T: PartialOrd> PartialOrd for CmpFloat<T> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         self.0.partial_cmp(&other.0)
     }
 }
 
-impl Ord for CmpFloat {
+impl<T: PartialOrd> Ord for CmpFloat<T> {
     fn cmp(&self, other: &Self) -> Ordering {
         self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
     }
 }
 
-fn vec_bevy_to_nalgebra(bevy_vec: Vec4) -> Vector4<f32> {
-    Vector4::from_row_slice(bevy_vec.as_ref())
+fn vec_glam_to_nalgebra(glam_vec: Vec4) -> Vector4<SdfReal> {
+    Vector4::from_row_slice(glam_vec.as_ref()).cast()
 }
 
-fn mat_bevy_to_nalgebra(bevy_mat: Mat4) -> Matrix4<f32> {
-    Matrix4::from_row_slice(bevy_mat.as_ref())
+fn mat_glam_to_nalgebra(glam_mat: Mat4) -> Matrix4<SdfReal> {
+    Matrix4::from_row_slice(glam_mat.as_ref()).cast()
 }
 
-fn vec_nalgebra_to_bevy(nalgebra_vec: Vector4<f32>) -> Vec4 {
+fn vec_nalgebra_to_glam(nalgebra_vec: Vector4<SdfReal>) -> Vec4 {
     // Fully qualified syntax because I am fully qualified to write
     // this kind of code B)
     <Vec4 as From<[f32; 4]>>::from(
-        nalgebra_vec.iter()
+        nalgebra_vec.cast::<f32>().iter()
             .copied()
             .collect::<Vec<f32>>()
             .try_into().unwrap()
     )
 }
 
-fn mat_nalgebra_to_bevy(nalgebra_mat: Matrix4<f32>) -> Mat4 {
+fn mat_nalgebra_to_glam(nalgebra_mat: Matrix4<SdfReal>) -> Mat4 {
     Mat4::from_cols_array(
-        &nalgebra_mat.iter()
+        &nalgebra_mat.cast::<f32>().iter()
             .copied()
             .collect::<Vec<f32>>()
             .try_into().unwrap()
     )
 }
 
-pub fn vec_nalgebra_minmax<T>(nalgebra_vec: Matrix<f32, U4, U1, T>) -> Vector4<f32> where 
-    T: Storage<f32, Const<4_usize>>
+// Back to the precision of the public API, whichever precision boxes are stored in
+trait ToF32 {
+    fn to_f32(self) -> f32;
+}
+
+impl ToF32 for SdfReal {
+    #[allow(clippy::unnecessary_cast)]
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+}
+
+// Column major, unlike mat_glam_to_nalgebra()
+fn mat_cols_to_nalgebra(glam_mat: Mat4) -> Matrix4<SdfReal> {
+    Matrix4::from_column_slice(&glam_mat.to_cols_array()).cast()
+}
+
+fn vec_dvec_to_nalgebra(dvec: DVec3) -> Vector3<SdfReal> {
+    Vector3::new(dvec.x, dvec.y, dvec.z).cast()
+}
+
+fn vec_nalgebra_to_dvec(nalgebra_vec: Vector3<SdfReal>) -> DVec3 {
+    let dvec = nalgebra_vec.cast::<f64>();
+    DVec3::new(dvec.x, dvec.y, dvec.z)
+}
+
+pub fn vec_nalgebra_minmax<T>(nalgebra_vec: Matrix<SdfReal, U4, U1, T>) -> Vector4<SdfReal> where 
+    T: Storage<SdfReal, Const<4_usize>>
 {
     let mut onehot = [0.0, 0.0, 0.0, 0.0];
     onehot[nalgebra_vec.iamax()] = 1.0;
     Vector4::from_row_slice(&onehot)
 }
 
-#[derive(Copy, Clone)]
+// Smallest half extent of a merged box, relative to the size of the merged vertex set
+const MIN_EXTENT_RATIO: SdfReal = 1e-4;
+const MIN_EXTENT: SdfReal = 1e-6;
+// Number of the largest sub-boxes whose frames are tried when merging
+const MERGE_CHILD_FRAMES: usize = 4;
+// Maximum number of passes of hull-edge refinement when merging
+const REFINE_ITERATIONS: usize = 4;
+// Cosine between the axes of a transformed box above which it's refit instead of kept
+const SKEW_TOLERANCE: SdfReal = 1e-4;
+
+/**
+ * Orthonormal, right-handed basis that follows the given axes as closely as possible. Axes that are
+ * (nearly) zero or parallel to earlier ones are skipped, and missing axes are filled in with
+ * arbitrary perpendicular ones.
+ */
+fn orthonormal_frame(axes: &[Vector3<SdfReal>]) -> Matrix3<SdfReal> {
+    let mut basis: Vec<Vector3<SdfReal>> = Vec::with_capacity(3);
+    for axis in axes {
+        let mut rest = *axis;
+        for prev in basis.iter() {
+            rest -= prev * prev.dot(&rest);
+        }
+        if let Some(unit) = rest.try_normalize(axis.norm() * 1e-3 + SdfReal::MIN_POSITIVE) {
+            basis.push(unit);
+        }
+        if basis.len() == 2 {
+            break;
+        }
+    }
+    if basis.is_empty() {
+        basis.push(Vector3::x());
+    }
+    if basis.len() == 1 {
+        let helper = if basis[0].x.abs() < 0.9 { Vector3::x() } else { Vector3::y() };
+        basis.push(basis[0].cross(&helper).normalize());
+    }
+    // Taking the cross product for the last axis keeps the basis right-handed
+    Matrix3::from_columns(&[basis[0], basis[1], basis[0].cross(&basis[1])])
+}
+
+// Minimum and maximum coordinates of the vertices in an orthonormal frame
+fn fit_extents(frame: &Matrix3<SdfReal>, verts: &[Vector3<SdfReal>]) -> (Vector3<SdfReal>, Vector3<SdfReal>) {
+    let frame_trans = frame.transpose();
+    verts.iter()
+        .map(|vert| frame_trans * vert)
+        .fold(
+            (Vector3::repeat(SdfReal::INFINITY), Vector3::repeat(SdfReal::NEG_INFINITY)),
+            |(lo, hi), proj| (lo.inf(&proj), hi.sup(&proj))
+        )
+}
+
+fn fit_volume(frame: &Matrix3<SdfReal>, verts: &[Vector3<SdfReal>], min_extent: SdfReal) -> SdfReal {
+    let (lo, hi) = fit_extents(frame, verts);
+    ((hi - lo) / 2.0).map(|x| x.max(min_extent)).iter().product()
+}
+
+// Convex hull of a set of 2D points in counter-clockwise order (Andrew's monotone chain)
+fn convex_hull_2d(mut points: Vec<Vector2<SdfReal>>) -> Vec<Vector2<SdfReal>> {
+    points.sort_by(|a, b| CmpFloat(a.x).cmp(&CmpFloat(b.x)).then(CmpFloat(a.y).cmp(&CmpFloat(b.y))));
+    points.dedup();
+    if points.len() < 3 {
+        return points;
+    }
+    let mut hull: Vec<Vector2<SdfReal>> = Vec::with_capacity(points.len() + 1);
+    for pass in [points.clone(), points.into_iter().rev().collect()] {
+        let start = hull.len();
+        for point in pass {
+            while hull.len() >= start + 2
+                && (hull[hull.len() - 1] - hull[hull.len() - 2]).perp(&(point - hull[hull.len() - 2])) <= 0.0
+            {
+                hull.pop();
+            }
+            hull.push(point);
+        }
+        // The last point of each chain is the first point of the other one
+        hull.pop();
+    }
+    hull
+}
+
+/**
+ * Direction of one side of the minimum area rectangle around a set of 2D points. One side of that
+ * rectangle always lies along an edge of the convex hull, so only hull edges need to be tried.
+ */
+fn min_area_direction(points: Vec<Vector2<SdfReal>>, min_extent: SdfReal) -> Option<Vector2<SdfReal>> {
+    let hull = convex_hull_2d(points);
+    (0..hull.len())
+        .filter_map(|i| (hull[(i + 1) % hull.len()] - hull[i]).try_normalize(SdfReal::MIN_POSITIVE))
+        .map(|dir| {
+            let (lo, hi) = hull.iter()
+                .map(|point| Vector2::new(dir.dot(point), dir.perp(point)))
+                .fold(
+                    (Vector2::repeat(SdfReal::INFINITY), Vector2::repeat(SdfReal::NEG_INFINITY)),
+                    |(lo, hi), proj| (lo.inf(&proj), hi.sup(&proj))
+                );
+            (dir, ((hi - lo) / 2.0).map(|x| x.max(min_extent)).iter().product::<SdfReal>())
+        })
+        .min_by_key(|(_, area)| CmpFloat(*area))
+        .map(|(dir, _)| dir)
+}
+
+/**
+ * Shrink a fitted frame by repeatedly keeping one of its axes and fitting the minimum area rectangle
+ * to the vertices projected along it, until the volume stops improving.
+ */
+fn refine_frame(mut frame: Matrix3<SdfReal>, verts: &[Vector3<SdfReal>], min_extent: SdfReal) -> Matrix3<SdfReal> {
+    let mut volume = fit_volume(&frame, verts, min_extent);
+    for _ in 0..REFINE_ITERATIONS {
+        let mut improved = false;
+        for axis in 0..3 {
+            let keep = frame.column(axis).into_owned();
+            let plane_u = frame.column((axis + 1) % 3).into_owned();
+            let plane_v = frame.column((axis + 2) % 3).into_owned();
+            let projected = verts.iter()
+                .map(|vert| Vector2::new(plane_u.dot(vert), plane_v.dot(vert)))
+                .collect();
+            if let Some(dir) = min_area_direction(projected, min_extent) {
+                let side = (plane_u * dir.x + plane_v * dir.y).normalize();
+                let candidate = Matrix3::from_columns(&[side, keep.cross(&side), keep]);
+                let candidate_volume = fit_volume(&candidate, verts, min_extent);
+                // Require a real improvement so that rounding can't make this cycle
+                if candidate_volume < volume * (1.0 - 1e-5) {
+                    frame = candidate;
+                    volume = candidate_volume;
+                    improved = true;
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    frame
+}
+
+// Number of bins along each axis that binned splits evaluate
+const SPLIT_BINS: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum SdfSplitMethod {
+    #[default]
+    Median,
+    SurfaceArea,
+    Volume,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SdfIntersection {
+    Inside,
+    Outside,
+    Intersecting,
+}
+
+// Points with normal.dot(point) + offset >= 0 are in front of the plane.
+#[derive(Copy, Clone, Debug)]
+pub struct SdfPlane {
+    pub normal: Vec3,
+    pub offset: f32,
+}
+
+impl SdfPlane {
+    pub fn new(normal: Vec3, offset: f32) -> Self {
+        SdfPlane {
+            normal,
+            offset,
+        }
+    }
+
+    pub fn from_point_normal(point: Vec3, normal: Vec3) -> Self {
+        let normal = normal.normalize();
+        SdfPlane {
+            normal,
+            offset: -normal.dot(point),
+        }
+    }
+
+    pub fn from_coefficients(coeffs: Vec4) -> Self {
+        let normal = coeffs.truncate();
+        let length = normal.length();
+        // Degenerate planes (e.g. the far plane of an infinite projection) keep their raw
+        // coefficients, so that everything ends up either entirely in front or behind them.
+        if length > f32::EPSILON {
+            SdfPlane::new(normal / length, coeffs.w / length)
+        } else {
+            SdfPlane::new(normal, coeffs.w)
+        }
+    }
+
+    pub fn signed_distance(&self, point: Vec3) -> f32 {
+        self.normal.dot(point) + self.offset
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SdfFrustum {
+    pub planes: [SdfPlane; 6],
+}
+
+impl SdfFrustum {
+    /**
+     * Extract the six inward facing clip planes from a combined view-projection matrix.
+     *
+     * Assumes a [0, 1] clip space depth range, which covers both regular and reversed-z
+     * projections since the near and far planes only trade places between the two.
+     */
+    pub fn from_view_projection(view_proj: Mat4) -> Self {
+        let rows = [
+            view_proj.row(0),
+            view_proj.row(1),
+            view_proj.row(2),
+            view_proj.row(3),
+        ];
+        SdfFrustum {
+            planes: [
+                SdfPlane::from_coefficients(rows[3] + rows[0]),
+                SdfPlane::from_coefficients(rows[3] - rows[0]),
+                SdfPlane::from_coefficients(rows[3] + rows[1]),
+                SdfPlane::from_coefficients(rows[3] - rows[1]),
+                SdfPlane::from_coefficients(rows[2]),
+                SdfPlane::from_coefficients(rows[3] - rows[2]),
+            ],
+        }
+    }
+
+    pub fn contains(&self, point: Vec3) -> bool {
+        self.planes.iter()
+            .all(|plane| plane.signed_distance(point) >= 0.0)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
 pub struct SdfBoundingBox {
-    pub matrix: Matrix4<f32>,
-    pub scale: Vector4<f32>,
-    pub full_inverse: Matrix4<f32>,
-    pub trans_inverse: Matrix4<f32>,
+    pub matrix: Matrix4<SdfReal>,
+    pub scale: Vector4<SdfReal>,
+    pub full_inverse: Matrix4<SdfReal>,
+    pub trans_inverse: Matrix4<SdfReal>,
+    // Smallest scale of trans_inverse, which distances in the evaluation frame are multiplied by
+    pub dist_scale: f32,
 }
 
 impl SdfBoundingBox {
@@ -96,6 +365,7 @@ impl SdfBoundingBox {
             ],
             full_inverse: Matrix4::identity(),
             trans_inverse: Matrix4::identity(),
+            dist_scale: 1.0,
         }
     }
 
@@ -104,85 +374,111 @@ impl SdfBoundingBox {
             matrix: Matrix4::zeros(),
             scale: Vector4::zeros(),
             full_inverse: matrix![
-                f32::INFINITY, 0.0, 0.0, 0.0;
-                0.0, f32::INFINITY, 0.0, 0.0;
-                0.0, 0.0, f32::INFINITY, 0.0;
+                SdfReal::INFINITY, 0.0, 0.0, 0.0;
+                0.0, SdfReal::INFINITY, 0.0, 0.0;
+                0.0, 0.0, SdfReal::INFINITY, 0.0;
                 0.0, 0.0, 0.0,           1.0;
             ],
             trans_inverse: Matrix4::identity(),
+            dist_scale: 1.0,
         }
     }
 
-    #[allow(clippy::map_clone)]
+    /**
+     * Fit an oriented box around a set of boxes.
+     *
+     * The box is fit to the vertices of the sub-boxes in a few candidate frames (the principal axes
+     * of the vertices, the parent frame and the frames of the largest sub-boxes), keeping whichever
+     * gives the smallest volume. Flat, collinear and point-like inputs get a small minimum extent
+     * instead of a singular matrix, and a single box is returned as is.
+     */
     pub fn merge(sub_boxes: &[Self]) -> Self {
-        if sub_boxes.len() == 0 {
-            return SdfBoundingBox::zero();
-        }
-        // Build a matrix from all box vertices
-        let mut vert_mat = Matrix4xX::from_iterator(
-            sub_boxes.len() * 8,
-            sub_boxes.iter()
-                .map(|sub_box| VERT_LIST.iter()
-                    .map(|vert| (sub_box.matrix * vert).iter()
-                        .copied().collect::<Vec<f32>>())
-                    .flatten())
-                .flatten()
-        );
-        // Get mean of vertices
-        let vert_mean = vert_mat.column_mean();
-        // Subtract mean from vert columns
-        for mut col in vert_mat.column_iter_mut() {
-            col -= vert_mean;
-        }
-        // Get transpose
-        let vert_mat_mean_trans = vert_mat.transpose();
-        // Get covariance matrix by right-multiply with transpose
-        let covar_mat = (vert_mat * &vert_mat_mean_trans) / ((sub_boxes.len() * 8 - 1) as f32);
-        // Get eigenstuff of covariance matrix. Covariance is symmetric so we gucci.
+        Self::merge_with(sub_boxes, false)
+    }
+
+    /**
+     * Like [`SdfBoundingBox::merge()`], but also searches for a smaller box by aligning its sides with
+     * edges of the convex hull of the vertices. Slower, but finds tight fits that PCA misses.
+     */
+    pub fn merge_refined(sub_boxes: &[Self]) -> Self {
+        Self::merge_with(sub_boxes, true)
+    }
+
+    fn merge_with(sub_boxes: &[Self], refine: bool) -> Self {
+        match sub_boxes {
+            [] => return SdfBoundingBox::zero(),
+            [single] => return SdfBoundingBox {
+                trans_inverse: Matrix4::identity(),
+                dist_scale: 1.0,
+                ..*single
+            },
+            _ => (),
+        }
+        let verts = sub_boxes.iter()
+            .flat_map(|sub_box| VERT_LIST.iter()
+                .map(move |vert| (sub_box.matrix * vert).xyz()))
+            .collect::<Vec<Vector3<SdfReal>>>();
+        let mut by_volume = sub_boxes.iter().collect::<Vec<&Self>>();
+        by_volume.sort_by_key(|sub_box| std::cmp::Reverse(CmpFloat(sub_box.scale.xyz().iter().product::<SdfReal>())));
+        let child_frames = by_volume.iter()
+            .take(MERGE_CHILD_FRAMES)
+            .map(|sub_box| orthonormal_frame(&sub_box.half_axes()))
+            .collect::<Vec<Matrix3<SdfReal>>>();
+        Self::fit_verts(&verts, child_frames, refine)
+    }
+
+    /**
+     * Oriented box around a set of points, in whichever of the candidate frames, the parent frame
+     * and the principal axes of the points gives the smallest volume.
+     */
+    fn fit_verts(verts: &[Vector3<SdfReal>], candidate_frames: Vec<Matrix3<SdfReal>>, refine: bool) -> Self {
+        let vert_mean = verts.iter().sum::<Vector3<SdfReal>>() / verts.len() as SdfReal;
+        let radius = verts.iter()
+            .map(|vert| (vert - vert_mean).norm())
+            .fold(0.0, SdfReal::max);
+        // Keep every extent above a small fraction of the size of the whole set, so that the box
+        // stays invertible, and so that flat fits can still be compared by area.
+        let min_extent = (radius * MIN_EXTENT_RATIO).max(MIN_EXTENT);
+
+        // Principal axes of the vertices, sorted by variance. Repeated eigenvalues still give an
+        // orthonormal basis, it's just an arbitrary one within the degenerate subspace.
+        let covar_mat = verts.iter()
+            .map(|vert| (vert - vert_mean) * (vert - vert_mean).transpose())
+            .sum::<Matrix3<SdfReal>>() / verts.len() as SdfReal;
         let eigen_info = covar_mat.symmetric_eigen();
-        // For some reason, symmetric eigen loves to make the W-vector negative sometimes, so we have to set it 
-        // let sort_transform = Matrix4::from_columns(
-        //     eigen_info.eigenvectors.column_iter()
-        //         .map(vec_nalgebra_minmax)
-        //         // .inspect(|x| println!("{}", x))
-        //         .collect::<Vec<Vector4<f32>>>()
-        //         .as_slice()
-        // );
-        let mut eigen_basis = eigen_info.eigenvectors;
-        // println!("eigen: {}", eigen_info.eigenvectors);
-        eigen_basis.set_column(3, &matrix![0.0; 0.0; 0.0; 1.0;]);
-        eigen_basis.set_row(3, &matrix![0.0, 0.0, 0.0, 1.0;]);
-        // Get projections of box verts on normalized eigenvector basis
-        let vert_proj_mat = vert_mat_mean_trans * eigen_basis;
-        // Get minimums and maximums of verts along eigenvector basis
-        let mut box_min = Vector4::zeros();
-        let mut box_max = Vector4::zeros();
-        for proj_vert in vert_proj_mat.row_iter() {
-            // proj_vert is a row vector, so transpose it to compare with box_min, box_max
-            let proj_vert_trans = proj_vert.transpose();
-            box_min = box_min.inf(&proj_vert_trans);
-            box_max = box_max.sup(&proj_vert_trans);
-        }
-        // Get centroid of new bounding box
-        let centroid = (box_max + box_min) / 2.0;
-        // Get scale of new bounding box using centroid
-        let scale = box_max - centroid;
-        let scale_recip = scale.map(|x| 1.0 / x);
-        // Create new bounding box matrix in eigenbasis of vertices, then change to standard basis
-        let new_bbox_mat = Matrix4::new_translation(&vert_mean.xyz())
-            * eigen_basis
-            * Matrix4::new_nonuniform_scaling(&scale.xyz()).append_translation(&centroid.xyz());
-        let mut scale_iter = scale.iter();
-        // println!("eigen full_inverse: {}", (eigen_basis * Matrix4::new_nonuniform_scaling(&scale_recip.xyz())).transpose());
+        let mut eigen_order = [0, 1, 2];
+        eigen_order.sort_by_key(|i| std::cmp::Reverse(CmpFloat(eigen_info.eigenvalues[*i])));
+        let eigen_axes = eigen_order.map(|i| eigen_info.eigenvectors.column(i).into_owned());
+
+        let mut candidates = vec![Matrix3::identity(), orthonormal_frame(&eigen_axes)];
+        candidates.extend(candidate_frames);
+
+        let mut frame = candidates.into_iter()
+            .filter(|frame| frame.iter().all(|x| x.is_finite()))
+            .min_by_key(|frame| CmpFloat(fit_volume(frame, verts, min_extent)))
+            .unwrap_or_else(Matrix3::identity);
+        if refine {
+            frame = refine_frame(frame, verts, min_extent);
+        }
+
+        let (box_min, box_max) = fit_extents(&frame, verts);
+        let scale = ((box_max - box_min) / 2.0).map(|x| x.max(min_extent));
+        let center = frame * ((box_max + box_min) / 2.0);
+        let mut new_bbox_mat = (frame * Matrix3::from_diagonal(&scale)).to_homogeneous();
+        new_bbox_mat.set_column(3, &center.push(1.0));
+        // The frame is orthonormal, so the inverse can be built directly instead of hoping that
+        // a general inverse succeeds.
+        let inv_axes = Matrix3::from_diagonal(&scale.map(|x| 1.0 / x)) * frame.transpose();
+        let mut full_inverse = inv_axes.to_homogeneous();
+        full_inverse.set_column(3, &(-(inv_axes * center)).push(1.0));
         SdfBoundingBox {
             matrix: new_bbox_mat,
-            scale,
-            full_inverse: new_bbox_mat
-                .try_inverse()
-                .unwrap(),
-            trans_inverse: (Matrix4::new_translation(&vert_mean.xyz()) * eigen_basis)
-                .try_inverse()
-                .unwrap(),
+            scale: scale.push(0.0),
+            full_inverse,
+            // The fitting frame is only used for the bound; children stay in the frame they were
+            // merged in, so points must not be moved into it.
+            trans_inverse: Matrix4::identity(),
+            dist_scale: 1.0,
         }
     }
 
@@ -193,9 +489,9 @@ impl SdfBoundingBox {
         let y_axis = self.matrix.column(1);
         let z_axis = self.matrix.column(2);
         // Get bounding box scale in local coordinates
-        let x_scale: f32 = x_axis.iter().map(|x| *x * *x).sum();
-        let y_scale: f32 = y_axis.iter().map(|x| *x * *x).sum();
-        let z_scale: f32 = z_axis.iter().map(|x| *x * *x).sum();
+        let x_scale: SdfReal = x_axis.iter().map(|x| *x * *x).sum();
+        let y_scale: SdfReal = y_axis.iter().map(|x| *x * *x).sum();
+        let z_scale: SdfReal = z_axis.iter().map(|x| *x * *x).sum();
         // Get normalized axis of bound boxes longest side
         let split_axis = {
             if x_scale >= y_scale && x_scale >= z_scale {
@@ -213,67 +509,271 @@ impl SdfBoundingBox {
         (left_side, inds)
     }
 
+    pub fn split_with(&self, sub_boxes: &[Self], method: SdfSplitMethod) -> (Vec<usize>, Vec<usize>) {
+        match method {
+            SdfSplitMethod::Median => self.split(sub_boxes),
+            SdfSplitMethod::SurfaceArea | SdfSplitMethod::Volume => self.split_binned(sub_boxes, method),
+        }
+    }
+
+    /**
+     * Split the sub-boxes using a binned cost heuristic, instead of at the median.
+     *
+     * The centroids of the sub-boxes are binned along all three axes of this box, and every bin
+     * boundary is evaluated with the cost `measure(left) * left_count + measure(right) * right_count`.
+     * The measure is either the surface area or the volume of the bounds of each side, which are
+     * tracked as axis aligned boxes in the basis of this box. Falls back to a median split when
+     * the centroids can't be separated.
+     */
+    pub fn split_binned(&self, sub_boxes: &[Self], method: SdfSplitMethod) -> (Vec<usize>, Vec<usize>) {
+        if sub_boxes.len() <= 2 {
+            return self.split(sub_boxes);
+        }
+        let measure = |lo: &Vector3<SdfReal>, hi: &Vector3<SdfReal>| {
+            let extent = (hi - lo).sup(&Vector3::zeros());
+            match method {
+                SdfSplitMethod::Volume => extent.x * extent.y * extent.z,
+                _ => 2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x),
+            }
+        };
+        // Flat boxes can have zero length axes, which are just skipped
+        let axes = self.half_axes()
+            .map(|axis| if axis.norm_squared() > SdfReal::EPSILON { axis.normalize() } else { Vector3::zeros() });
+        // Bounds of every sub-box in the basis of this box
+        let sub_bounds = sub_boxes.iter()
+            .map(|sub_box| {
+                let mut lo = Vector3::repeat(SdfReal::INFINITY);
+                let mut hi = Vector3::repeat(SdfReal::NEG_INFINITY);
+                for vert in VERT_LIST.iter() {
+                    let world_vert = (sub_box.matrix * vert).xyz();
+                    let proj = Vector3::new(
+                        world_vert.dot(&axes[0]),
+                        world_vert.dot(&axes[1]),
+                        world_vert.dot(&axes[2]),
+                    );
+                    lo = lo.inf(&proj);
+                    hi = hi.sup(&proj);
+                }
+                (lo, hi)
+            })
+            .collect::<Vec<(Vector3<SdfReal>, Vector3<SdfReal>)>>();
+        let bin_of = |centroid: SdfReal, lo: SdfReal, hi: SdfReal| {
+            usize::min(((centroid - lo) / (hi - lo) * SPLIT_BINS as SdfReal) as usize, SPLIT_BINS - 1)
+        };
+
+        let mut best: Option<(SdfReal, usize, usize, SdfReal, SdfReal)> = None;
+        for axis in 0..3 {
+            if axes[axis] == Vector3::zeros() {
+                continue;
+            }
+            let (cent_lo, cent_hi) = sub_bounds.iter()
+                .map(|(lo, hi)| (lo[axis] + hi[axis]) / 2.0)
+                .fold((SdfReal::INFINITY, SdfReal::NEG_INFINITY), |(lo, hi), cent| (lo.min(cent), hi.max(cent)));
+            if cent_hi - cent_lo <= SdfReal::EPSILON * cent_hi.abs().max(1.0) {
+                continue;
+            }
+            let mut bin_counts = [0_usize; SPLIT_BINS];
+            let mut bin_bounds = [(Vector3::repeat(SdfReal::INFINITY), Vector3::repeat(SdfReal::NEG_INFINITY)); SPLIT_BINS];
+            for (lo, hi) in sub_bounds.iter() {
+                let bin = bin_of((lo[axis] + hi[axis]) / 2.0, cent_lo, cent_hi);
+                bin_counts[bin] += 1;
+                bin_bounds[bin] = (bin_bounds[bin].0.inf(lo), bin_bounds[bin].1.sup(hi));
+            }
+            // Sweep from the right to get the cost of everything above each boundary
+            let mut right_costs = [SdfReal::INFINITY; SPLIT_BINS];
+            let mut acc_count = 0;
+            let mut acc_bounds = (Vector3::repeat(SdfReal::INFINITY), Vector3::repeat(SdfReal::NEG_INFINITY));
+            for bin in (1..SPLIT_BINS).rev() {
+                acc_count += bin_counts[bin];
+                acc_bounds = (acc_bounds.0.inf(&bin_bounds[bin].0), acc_bounds.1.sup(&bin_bounds[bin].1));
+                if acc_count > 0 {
+                    right_costs[bin] = measure(&acc_bounds.0, &acc_bounds.1) * acc_count as SdfReal;
+                }
+            }
+            // Then sweep from the left and evaluate each boundary
+            acc_count = 0;
+            acc_bounds = (Vector3::repeat(SdfReal::INFINITY), Vector3::repeat(SdfReal::NEG_INFINITY));
+            for bin in 1..SPLIT_BINS {
+                acc_count += bin_counts[bin - 1];
+                acc_bounds = (acc_bounds.0.inf(&bin_bounds[bin - 1].0), acc_bounds.1.sup(&bin_bounds[bin - 1].1));
+                if acc_count == 0 || acc_count == sub_boxes.len() {
+                    continue;
+                }
+                let cost = measure(&acc_bounds.0, &acc_bounds.1) * acc_count as SdfReal + right_costs[bin];
+                match best {
+                    Some((best_cost, ..)) if best_cost <= cost => {},
+                    _ => best = Some((cost, axis, bin, cent_lo, cent_hi)),
+                }
+            }
+        }
+
+        match best {
+            Some((_, axis, split_bin, cent_lo, cent_hi)) => (0..sub_boxes.len())
+                .partition(|i| {
+                    let (lo, hi) = &sub_bounds[*i];
+                    bin_of((lo[axis] + hi[axis]) / 2.0, cent_lo, cent_hi) < split_bin
+                }),
+            None => self.split(sub_boxes),
+        }
+    }
+
     pub fn verts(&self) -> Vec<Vec4> {
         VERT_LIST.iter()
-            .map(|vert| vec_nalgebra_to_bevy(self.matrix * vert))
+            .map(|vert| vec_nalgebra_to_glam(self.matrix * vert))
             .collect()
     }
 
+    /**
+     * Box with the extents of the transform's scale. Points evaluated in it are only moved by the
+     * rotation and translation, since the scale is the size of the box and not of the element.
+     */
     pub fn from_transform(trans: Transform) -> Self {
-        Self::unit().apply_transform(trans)
+        let rigid = Transform {
+            scale: Vec3::ONE,
+            ..trans
+        };
+        // Built directly, since flat boxes have singular matrices that can't be applied
+        SdfBoundingBox {
+            matrix: mat_cols_to_nalgebra(trans.compute_matrix()),
+            scale: vec_glam_to_nalgebra(trans.scale.extend(0.0)),
+            full_inverse: mat_cols_to_nalgebra(trans.compute_matrix().inverse()),
+            // Rigid transforms are always invertible, so this is inverted at full precision
+            trans_inverse: mat_cols_to_nalgebra(rigid.compute_matrix())
+                .try_inverse()
+                .unwrap_or_else(Matrix4::identity),
+            dist_scale: 1.0,
+        }
+    }
+
+    /**
+     * Axis aligned box around a region of an element's own frame. Unlike boxes made from
+     * transforms, it doesn't move the points that are evaluated in it.
+     */
+    pub fn from_extents(center: Vec3, half_extents: Vec3) -> Self {
+        Self::from_oriented_extents(center, Quat::IDENTITY, half_extents)
+    }
+
+    // Like from_extents, for a box whose axes are rotated in the element's frame
+    pub fn from_oriented_extents(center: Vec3, rotation: Quat, half_extents: Vec3) -> Self {
+        SdfBoundingBox {
+            trans_inverse: Matrix4::identity(),
+            ..Self::from_transform(Transform {
+                translation: center,
+                rotation,
+                scale: half_extents,
+            })
+        }
     }
 
+    // Grow the box by a margin on every side. Like merged boxes, the result doesn't move points.
+    pub fn inflated(&self, margin: f32) -> Self {
+        if self.is_zero() {
+            return *self;
+        }
+        let trans = self.get_transform();
+        SdfBoundingBox {
+            trans_inverse: Matrix4::identity(),
+            ..Self::from_transform(trans.with_scale(trans.scale + Vec3::splat(margin)))
+        }
+    }
+
+    /**
+     * Move the box and the points evaluated in it by a transform, scale included. Non-uniform
+     * scales make distances in the evaluation frame inexact, so they're multiplied by the smallest
+     * scale, which keeps them from overestimating.
+     */
     pub fn apply_transform(self, trans: Transform) -> Self {
-        let mut no_scale = trans.clone();
-        no_scale.scale = Vec3::new(1.0, 1.0, 1.0);
-        let mat = Matrix4::from_column_slice(
-            &trans.compute_matrix().to_cols_array()
-        ) * self.matrix;
-        let full_inv = self.full_inverse * Matrix4::from_column_slice(
-            &trans.compute_matrix().inverse().to_cols_array()
-        );
-        let trans_inv = self.trans_inverse * Matrix4::from_column_slice(
-            &no_scale.compute_matrix().inverse().to_cols_array()
-        );
+        let mut moved = self.apply_matrix(trans.compute_matrix());
+        moved.dist_scale *= trans.scale.abs().min_element();
+        moved
+    }
+
+    /**
+     * Apply an affine matrix to the box, like [`SdfBoundingBox::apply_transform()`] does for
+     * transforms. Boxes whose axes are skewed by the matrix are refit around their corners. The
+     * distance scale is left alone, see [`SdfBoundingBox::apply_frame()`] for matrices that scale.
+     */
+    pub fn apply_matrix(self, mat: Mat4) -> Self {
+        let mat = mat_cols_to_nalgebra(mat);
+        let mat_inv = mat.try_inverse()
+            .expect("Tried applying a singular matrix to a bounding box!");
+        let matrix = mat * self.matrix;
+        let axes = [0, 1, 2].map(|i| matrix.column(i).xyz());
+        let skewed = (0..3)
+            .flat_map(|i| ((i + 1)..3).map(move |j| (i, j)))
+            .any(|(i, j)| axes[i].dot(&axes[j]).abs() > SKEW_TOLERANCE * axes[i].norm() * axes[j].norm());
+        let bounds = if skewed {
+            let verts = VERT_LIST.iter()
+                .map(|vert| (matrix * vert).xyz())
+                .collect::<Vec<Vector3<SdfReal>>>();
+            Self::fit_verts(&verts, vec![orthonormal_frame(&axes)], false)
+        } else {
+            SdfBoundingBox {
+                matrix,
+                scale: Vector3::from_iterator(axes.iter().map(|axis| axis.norm())).push(0.0),
+                full_inverse: self.full_inverse * mat_inv,
+                ..self
+            }
+        };
         SdfBoundingBox {
-            matrix: mat,
-            scale: self.scale.component_mul(&vec_bevy_to_nalgebra(trans.scale.extend(0.0))),
-            full_inverse: full_inv,
-            trans_inverse: trans_inv,
+            trans_inverse: self.trans_inverse * mat_inv,
+            dist_scale: self.dist_scale,
+            ..bounds
         }
     }
 
+    // Move a box that lives in the evaluation frame of another box into the frame that one is in
+    pub fn apply_frame(self, frame: &SdfBoundingBox) -> Self {
+        let mut moved = self.apply_matrix(frame.trans_basis());
+        moved.dist_scale *= frame.dist_scale;
+        moved
+    }
+
+    // Matrix that takes points from the box's evaluation frame back to its parent's frame
+    pub fn trans_basis(&self) -> Mat4 {
+        mat_nalgebra_to_glam(
+            self.trans_inverse
+                .try_inverse()
+                .unwrap_or_else(Matrix4::identity)
+        )
+    }
+
+    // Matrix that takes points from the box's parent's frame into its evaluation frame
+    pub fn trans_inverse_basis(&self) -> Mat4 {
+        mat_nalgebra_to_glam(self.trans_inverse)
+    }
+
     pub fn get_transform(&self) -> Transform {
-        Transform::from_matrix(mat_nalgebra_to_bevy(self.matrix))
+        Transform::from_matrix(mat_nalgebra_to_glam(self.matrix))
     }
 
     pub fn in_box_basis(&self, point: Vec4) -> Vec4 {
-        vec_nalgebra_to_bevy(
-            self.full_inverse * vec_bevy_to_nalgebra(point)
+        vec_nalgebra_to_glam(
+            self.full_inverse * vec_glam_to_nalgebra(point)
         )
     }
 
     pub fn in_box_trans_basis(&self, point: Vec4) -> Vec4 {
-        vec_nalgebra_to_bevy(
-            self.trans_inverse * vec_bevy_to_nalgebra(point)
+        vec_nalgebra_to_glam(
+            self.trans_inverse * vec_glam_to_nalgebra(point)
         )
     }
 
     pub fn in_parent_basis(&self, point: Vec4) -> Vec4 {
-        vec_nalgebra_to_bevy(
-            self.matrix * vec_bevy_to_nalgebra(point)
+        vec_nalgebra_to_glam(
+            self.matrix * vec_glam_to_nalgebra(point)
         )
     }
 
     pub fn mat_in_box_basis(&self, mat: Mat4) -> Mat4 {
-        mat_nalgebra_to_bevy(
-            self.full_inverse * mat_bevy_to_nalgebra(mat)
+        mat_nalgebra_to_glam(
+            self.full_inverse * mat_glam_to_nalgebra(mat)
         )
     }
 
     pub fn mat_in_box_trans_basis(&self, mat: Mat4) -> Mat4 {
-        mat_nalgebra_to_bevy(
-            self.trans_inverse * mat_bevy_to_nalgebra(mat)
+        mat_nalgebra_to_glam(
+            self.trans_inverse * mat_glam_to_nalgebra(mat)
         )
     }
 
@@ -283,9 +783,59 @@ impl SdfBoundingBox {
             scale: other.scale,
             full_inverse: other.full_inverse * self.trans_inverse,
             trans_inverse: other.trans_inverse * self.trans_inverse,
+            dist_scale: other.dist_scale,
         }
     }
 
+    /**
+     * Box for points that are given relative to `origin` instead of to the origin of the parent
+     * frame, like positions relative to a camera. This is done in the precision boxes are stored
+     * in, so that only small values are left when the box is written out as f32.
+     *
+     * Also returns the origin that points are relative to in the box's evaluation frame. With
+     * `pass_through`, frames that share their origin with the parent frame, like those of merged
+     * unions, keep points relative and carry the origin into the frame. Every other frame takes
+     * points back to its absolute coordinates, since elements like domain repetition depend on
+     * them, and the returned origin is zero.
+     */
+    pub fn rebased(&self, origin: DVec3, pass_through: bool) -> (Self, DVec3) {
+        if origin == DVec3::ZERO || self.is_zero() {
+            return (*self, DVec3::ZERO);
+        }
+        let offset = vec_dvec_to_nalgebra(origin);
+        // Only the translations are moved, so the infinities in inverses of flat boxes are never
+        // multiplied by zero
+        let mut matrix = self.matrix;
+        matrix.set_column(3, &(self.matrix.column(3).xyz() - offset).push(1.0));
+        let mut full_inverse = self.full_inverse;
+        full_inverse.set_column(3, &(self.full_inverse.fixed_slice::<3, 3>(0, 0) * offset
+            + self.full_inverse.column(3).xyz()).push(1.0));
+        let linear = self.trans_inverse.fixed_slice::<3, 3>(0, 0).into_owned();
+        let frame_offset = self.trans_inverse.column(3).xyz();
+        let (trans_inverse, inner_origin) = if pass_through && frame_offset == Vector3::zeros() {
+            (self.trans_inverse, vec_nalgebra_to_dvec(linear * offset))
+        } else {
+            let mut trans_inverse = self.trans_inverse;
+            trans_inverse.set_column(3, &(linear * offset + frame_offset).push(1.0));
+            (trans_inverse, DVec3::ZERO)
+        };
+        (
+            SdfBoundingBox {
+                matrix,
+                full_inverse,
+                trans_inverse,
+                ..*self
+            },
+            inner_origin,
+        )
+    }
+
+    // Move the box by an f64 offset, which keeps far translations as precise as SdfReal allows
+    pub fn apply_translation(self, offset: DVec3) -> Self {
+        // Rebasing to an origin moves the box by the opposite of it
+        self.rebased(-offset, false).0
+    }
+
     pub fn is_zero(&self) -> bool {
         self.get_transform().scale.as_ref().iter()
             .all(|comp| approx_eq!(f32, *comp, 0.0, ulps = 2))
@@ -294,16 +844,17 @@ impl SdfBoundingBox {
     pub fn distance_to(&self, point: Vec3) -> f32 {
         let trans = self.in_box_basis(point.extend(1.0));
         // println!("scale: {}", self.scale);
-        let q_local = (trans.abs() - Vec4::splat(1.0)) * vec_nalgebra_to_bevy(self.scale);
+        let q_local = (trans.abs() - Vec4::splat(1.0)) * vec_nalgebra_to_glam(self.scale);
         // println!("q_local: {}", q_local);
         q_local.max(Vec4::ZERO).length() + q_local.y.max(q_local.z).max(q_local.x).min(0.0)
     }
 
     pub fn max_distance(&self, point: Vec3) -> f32 {
-        let nalgebra_point = Point::from_slice(point.extend(1.0).as_ref()).coords;
+        let nalgebra_point = vec_glam_to_nalgebra(point.extend(1.0));
         VERT_LIST.iter()
             .map(|vert| CmpFloat(((self.matrix * vert) - nalgebra_point).magnitude()))
             .max().unwrap().0
+            .to_f32()
     }
 
     pub fn centroid(&self) -> Vec3 {
@@ -311,15 +862,535 @@ impl SdfBoundingBox {
     }
 
     pub fn contains(&self, point: Vec3) -> bool {
-        self.full_inverse.transform_point(&Point::from_slice(point.as_ref())).coords.amax() <= 1.0
+        self.full_inverse.transform_point(&Point::from(vec_glam_to_nalgebra(point.extend(1.0)).xyz())).coords.amax() <= 1.0
+    }
+
+    pub fn center(&self) -> Vec3 {
+        vec_nalgebra_to_glam(self.matrix.column(3).into_owned()).truncate()
+    }
+
+    // Half-extent vectors of the box, i.e. the images of the unit box axes.
+    fn half_axes(&self) -> [Vector3<SdfReal>; 3] {
+        [
+            self.matrix.column(0).xyz(),
+            self.matrix.column(1).xyz(),
+            self.matrix.column(2).xyz(),
+        ]
+    }
+
+    /**
+     * Separating axis test between two oriented boxes.
+     *
+     * Checks the three face normals of both boxes and the nine pairwise edge cross products.
+     * Cross products of (nearly) parallel edges are skipped, since they can't separate anything
+     * that the face normals don't already separate.
+     */
+    pub fn overlaps(&self, other: &Self) -> bool {
+        if self.is_zero() || other.is_zero() {
+            return false;
+        }
+        let self_axes = self.half_axes();
+        let other_axes = other.half_axes();
+        let offset = other.matrix.column(3).xyz() - self.matrix.column(3).xyz();
+        let mut test_axes = Vec::with_capacity(15);
+        test_axes.extend_from_slice(&self_axes);
+        test_axes.extend_from_slice(&other_axes);
+        for self_axis in self_axes.iter() {
+            for other_axis in other_axes.iter() {
+                let cross = self_axis.cross(other_axis);
+                if cross.norm_squared() > SdfReal::EPSILON * self_axis.norm_squared() * other_axis.norm_squared() {
+                    test_axes.push(cross);
+                }
+            }
+        }
+        test_axes.iter()
+            .filter(|axis| axis.norm_squared() > 0.0)
+            .all(|axis| {
+                let self_radius: SdfReal = self_axes.iter().map(|half| half.dot(axis).abs()).sum();
+                let other_radius: SdfReal = other_axes.iter().map(|half| half.dot(axis).abs()).sum();
+                offset.dot(axis).abs() <= self_radius + other_radius
+            })
+    }
+
+    /**
+     * Slab test of the ray `origin + t * dir` against the box.
+     *
+     * Returns the entry and exit parameters `(t_enter, t_exit)` of the ray. The test is done in the
+     * unit box basis, and since that basis is affine the parameters are the same as for the
+     * original ray. `t_enter` is negative if the origin is inside the box, and nothing is returned
+     * if the box is entirely behind the origin.
+     */
+    pub fn ray_intersect(&self, origin: Vec3, dir: Vec3) -> Option<(f32, f32)> {
+        if self.is_zero() {
+            return None;
+        }
+        let local_origin = self.full_inverse * vec_glam_to_nalgebra(origin.extend(1.0));
+        let local_dir = self.full_inverse * vec_glam_to_nalgebra(dir.extend(0.0));
+        let mut t_enter = SdfReal::NEG_INFINITY;
+        let mut t_exit = SdfReal::INFINITY;
+        for axis in 0..3 {
+            if local_dir[axis] == 0.0 {
+                // Parallel to the slab, so it either always or never overlaps it
+                if local_origin[axis].abs() > 1.0 {
+                    return None;
+                }
+                continue;
+            }
+            let t_near = (-1.0 - local_origin[axis]) / local_dir[axis];
+            let t_far = (1.0 - local_origin[axis]) / local_dir[axis];
+            t_enter = t_enter.max(t_near.min(t_far));
+            t_exit = t_exit.min(t_near.max(t_far));
+        }
+        if t_enter <= t_exit && t_exit >= 0.0 {
+            Some((t_enter.to_f32(), t_exit.to_f32()))
+        } else {
+            None
+        }
+    }
+
+    pub fn classify_plane(&self, plane: &SdfPlane) -> SdfIntersection {
+        let normal = Vector3::new(plane.normal.x, plane.normal.y, plane.normal.z).cast();
+        let radius = self.half_axes().iter()
+            .map(|half| half.dot(&normal).abs())
+            .sum::<SdfReal>()
+            .to_f32();
+        let center_dist = plane.signed_distance(self.center());
+        if center_dist > radius {
+            SdfIntersection::Inside
+        } else if center_dist < -radius {
+            SdfIntersection::Outside
+        } else {
+            SdfIntersection::Intersecting
+        }
+    }
+
+    /**
+     * Classify the box against all planes of a frustum.
+     *
+     * This is the usual conservative plane-by-plane test: boxes near the corners of the frustum
+     * can be reported as intersecting even though they are outside, but a box is never reported
+     * as outside when it isn't.
+     */
+    pub fn classify_frustum(&self, frustum: &SdfFrustum) -> SdfIntersection {
+        if self.is_zero() {
+            return SdfIntersection::Outside;
+        }
+        let mut result = SdfIntersection::Inside;
+        for plane in frustum.planes.iter() {
+            match self.classify_plane(plane) {
+                SdfIntersection::Outside => return SdfIntersection::Outside,
+                SdfIntersection::Intersecting => result = SdfIntersection::Intersecting,
+                SdfIntersection::Inside => {},
+            }
+        }
+        result
     }
 
     pub fn get_bbox_block(&self) -> SdfBoundingBoxBlock {
         SdfBoundingBoxBlock {
-            matrix: mat_nalgebra_to_bevy(self.matrix),
-            scale: vec_nalgebra_to_bevy(self.scale),
-            full_inverse: mat_nalgebra_to_bevy(self.full_inverse),
-            trans_inverse: mat_nalgebra_to_bevy(self.trans_inverse),
+            matrix: mat_nalgebra_to_glam(self.matrix),
+            scale: vec_nalgebra_to_glam(self.scale).truncate().extend(self.dist_scale),
+            full_inverse: mat_nalgebra_to_glam(self.full_inverse),
+            trans_inverse: mat_nalgebra_to_glam(self.trans_inverse),
+        }
+    }
+}
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use std::f32::consts::{PI, FRAC_PI_4, SQRT_2};
+    use crate::math::*;
+    use crate::obb::*;
+
+    const TRIALS: usize = 500;
+
+    fn random_box(rng: &mut ThreadRng) -> SdfBoundingBox {
+        SdfBoundingBox::from_transform(Transform {
+            translation: Vec3::new(
+                rng.gen_range(-5.0..5.0),
+                rng.gen_range(-5.0..5.0),
+                rng.gen_range(-5.0..5.0),
+            ),
+            rotation: Quat::from_euler(
+                EulerRot::XYZ,
+                rng.gen_range(0.0..(2.0 * PI)),
+                rng.gen_range(0.0..(2.0 * PI)),
+                rng.gen_range(0.0..(2.0 * PI)),
+            ),
+            scale: Vec3::new(
+                rng.gen_range(0.2..4.0),
+                rng.gen_range(0.2..4.0),
+                rng.gen_range(0.2..4.0),
+            ),
+        })
+    }
+
+    fn random_point_in(bbox: &SdfBoundingBox, rng: &mut ThreadRng) -> Vec3 {
+        bbox.in_parent_basis(Vec4::new(
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            1.0,
+        )).truncate()
+    }
+
+    /**
+     * Brute-force separating axis test that projects all 16 box vertices onto every candidate
+     * axis, instead of using projected radii like [`SdfBoundingBox::overlaps()`].
+     */
+    fn brute_overlaps(a: &SdfBoundingBox, b: &SdfBoundingBox) -> bool {
+        let a_verts = a.verts();
+        let b_verts = b.verts();
+        let a_axes = [a_verts[0] - a_verts[1], a_verts[0] - a_verts[2], a_verts[0] - a_verts[4]];
+        let b_axes = [b_verts[0] - b_verts[1], b_verts[0] - b_verts[2], b_verts[0] - b_verts[4]];
+        let mut axes = Vec::new();
+        axes.extend(a_axes.iter().map(|axis| axis.truncate()));
+        axes.extend(b_axes.iter().map(|axis| axis.truncate()));
+        for a_axis in a_axes.iter() {
+            for b_axis in b_axes.iter() {
+                axes.push(a_axis.truncate().cross(b_axis.truncate()));
+            }
+        }
+        let project = |verts: &Vec<Vec4>, axis: Vec3| {
+            verts.iter()
+                .map(|vert| vert.truncate().dot(axis))
+                .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), proj| (lo.min(proj), hi.max(proj)))
+        };
+        axes.iter()
+            .filter(|axis| axis.length_squared() > 1e-6)
+            .all(|axis| {
+                let (a_lo, a_hi) = project(&a_verts, *axis);
+                let (b_lo, b_hi) = project(&b_verts, *axis);
+                a_hi >= b_lo && b_hi >= a_lo
+            })
+    }
+
+    fn box_volume(bbox: &SdfBoundingBox) -> f32 {
+        bbox.scale.xyz().iter().product::<SdfReal>().to_f32()
+    }
+
+    fn aabb_volume(sub_boxes: &[SdfBoundingBox]) -> f32 {
+        let (lo, hi) = sub_boxes.iter()
+            .flat_map(|sub_box| sub_box.verts())
+            .fold((Vec4::splat(f32::INFINITY), Vec4::splat(f32::NEG_INFINITY)), |(lo, hi), vert| (lo.min(vert), hi.max(vert)));
+        ((hi - lo) / 2.0).truncate().max(Vec3::splat(1e-6)).as_ref().iter().product()
+    }
+
+    /**
+     * Check that a merged box is finite, right-handed and properly inverted, and that it contains
+     * every vertex of the boxes it was merged from.
+     */
+    fn validate_merge(merged: &SdfBoundingBox, sub_boxes: &[SdfBoundingBox]) {
+        assert!(
+            merged.matrix.iter().chain(merged.full_inverse.iter()).chain(merged.scale.iter()).all(|x| x.is_finite()),
+            "Merged box isn't finite!"
+        );
+        assert!(Matrix3::from_fn(|r, c| merged.matrix[(r, c)]).determinant() > 0.0, "Merged box is left-handed!");
+        assert!((merged.full_inverse * merged.matrix - Matrix4::identity()).amax() < 1e-3, "Merged box has a bad inverse!");
+        assert_eq!(merged.trans_inverse, Matrix4::identity());
+        for vert in sub_boxes.iter().flat_map(|sub_box| sub_box.verts()) {
+            let local = merged.in_box_basis(vert).truncate();
+            assert!(local.abs().max_element() <= 1.0 + 1e-3, "Merged box doesn't contain vertex {}!", vert);
+        }
+    }
+
+    #[test]
+    fn test_overlap_edge_case() {
+        let a = SdfBoundingBox::unit();
+        // Rotated so that only an edge-edge cross product axis can separate the boxes
+        let rot = Quat::from_rotation_z(FRAC_PI_4) * Quat::from_rotation_x(FRAC_PI_4);
+        let near = SdfBoundingBox::from_transform(
+            Transform::from_translation(Vec3::new(1.0 + SQRT_2 - 0.05, 0.0, 0.0)).with_rotation(rot)
+        );
+        let far = SdfBoundingBox::from_transform(
+            Transform::from_translation(Vec3::new(1.0 + SQRT_2 + 0.05, 1.0 + SQRT_2 + 0.05, 0.0)).with_rotation(rot)
+        );
+        assert_eq!(a.overlaps(&near), brute_overlaps(&a, &near));
+        assert_eq!(a.overlaps(&far), brute_overlaps(&a, &far));
+        assert!(a.overlaps(&a));
+        assert!(!a.overlaps(&SdfBoundingBox::zero()));
+    }
+
+    #[test]
+    fn test_overlap_brute() {
+        let mut rng = thread_rng();
+        let mut overlapping = 0;
+        for _ in 0..TRIALS {
+            let a = random_box(&mut rng);
+            let b = random_box(&mut rng);
+            let result = a.overlaps(&b);
+            assert_eq!(result, brute_overlaps(&a, &b), "SAT disagrees with brute-force vertex projection!");
+            assert_eq!(result, b.overlaps(&a), "Overlap test isn't symmetric!");
+            if a.verts().iter().any(|vert| b.contains(vert.truncate())) {
+                assert!(result, "Box contains a vertex of the other box, but doesn't overlap it!");
+            }
+            if (0..20).any(|_| b.contains(random_point_in(&a, &mut rng))) {
+                assert!(result, "Boxes share an interior point, but don't overlap!");
+            }
+            overlapping += result as usize;
+        }
+        // Make sure both branches are actually exercised
+        assert!(overlapping > 0 && overlapping < TRIALS);
+    }
+
+    #[test]
+    fn test_split_partitions() {
+        let mut rng = thread_rng();
+        for method in [SdfSplitMethod::Median, SdfSplitMethod::SurfaceArea, SdfSplitMethod::Volume] {
+            for count in [2, 3, 10, 50] {
+                let mut sub_boxes = (0..count)
+                    .map(|_| random_box(&mut rng))
+                    .collect::<Vec<SdfBoundingBox>>();
+                // Identical centroids can't be binned, so this has to fall back to the median
+                sub_boxes.push(sub_boxes[0]);
+                sub_boxes.push(sub_boxes[0]);
+                let merged = SdfBoundingBox::merge(&sub_boxes);
+                let (left, right) = merged.split_with(&sub_boxes, method);
+                assert!(!left.is_empty() && !right.is_empty(), "{:?} split left a side empty!", method);
+                let mut all = left.iter().chain(right.iter()).copied().collect::<Vec<usize>>();
+                all.sort_unstable();
+                assert_eq!(all, (0..sub_boxes.len()).collect::<Vec<usize>>());
+            }
+            let same = vec![SdfBoundingBox::unit(); 5];
+            let (left, right) = SdfBoundingBox::unit().split_with(&same, method);
+            assert_eq!(left.len() + right.len(), 5);
+            assert!(!left.is_empty() && !right.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_split_unbalanced() {
+        // One huge box plus a cluster of small ones; the heuristic should isolate the huge box
+        let mut sub_boxes = vec![SdfBoundingBox::from_transform(
+            Transform::from_xyz(0.0, -50.0, 0.0).with_scale(Vec3::new(100.0, 50.0, 100.0))
+        )];
+        sub_boxes.extend((0..9).map(|i| SdfBoundingBox::from_transform(
+            Transform::from_xyz(i as f32 * 3.0, 1.0, 0.0)
+        )));
+        let merged = SdfBoundingBox::merge(&sub_boxes);
+        for method in [SdfSplitMethod::SurfaceArea, SdfSplitMethod::Volume] {
+            let (left, right) = merged.split_with(&sub_boxes, method);
+            let (alone, rest) = if left.len() == 1 { (left, right) } else { (right, left) };
+            assert_eq!(alone, vec![0], "{:?} split didn't isolate the huge box!", method);
+            assert_eq!(rest.len(), 9);
+        }
+    }
+
+    #[test]
+    fn test_merge_degenerate() {
+        let mut rng = thread_rng();
+        assert!(SdfBoundingBox::merge(&[]).is_zero());
+
+        // A single box is its own best fit
+        let single = random_box(&mut rng);
+        let merged = SdfBoundingBox::merge(&[single]);
+        assert_eq!(merged.matrix, single.matrix);
+        assert_eq!(merged.full_inverse, single.full_inverse);
+
+        let identical = [single, single];
+        let merged = SdfBoundingBox::merge(&identical);
+        validate_merge(&merged, &identical);
+        assert!((box_volume(&merged) / box_volume(&single) - 1.0).abs() < 1e-3);
+
+        let rot = Quat::from_euler(EulerRot::XYZ, 0.3, 1.1, -0.7);
+        let cases: Vec<(&str, Vec<SdfBoundingBox>)> = vec![
+            ("coplanar", (0..4)
+                .map(|i| SdfBoundingBox::from_transform(
+                    Transform::from_xyz(i as f32 * 3.0, (i * i) as f32, 0.0).with_scale(Vec3::new(1.0, 1.0, 0.0))
+                ).apply_matrix(Mat4::from_quat(rot)))
+                .collect()),
+            ("collinear", (0..4)
+                .map(|i| SdfBoundingBox::from_transform(
+                    Transform::from_xyz(i as f32 * 3.0, 0.0, 0.0).with_scale(Vec3::new(1.0, 0.0, 0.0))
+                ).apply_matrix(Mat4::from_quat(rot)))
+                .collect()),
+            ("scattered points", (0..4)
+                .map(|i| SdfBoundingBox::from_transform(
+                    Transform::from_xyz(i as f32, (i % 2) as f32, (i / 2) as f32).with_scale(Vec3::ZERO)
+                ))
+                .collect()),
+            ("single point", vec![SdfBoundingBox::from_transform(
+                Transform::from_xyz(1.0, 2.0, 3.0).with_scale(Vec3::ZERO)
+            ); 3]),
+        ];
+        for (name, sub_boxes) in cases.iter() {
+            for merged in [SdfBoundingBox::merge(sub_boxes), SdfBoundingBox::merge_refined(sub_boxes)] {
+                validate_merge(&merged, sub_boxes);
+                assert!(!merged.is_zero(), "Merging {} gave a zero box!", name);
+                assert!(merged.scale.xyz().min() < 1e-2, "Merging {} didn't give a flat box!", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_fallback() {
+        // The covariance of a symmetric cross of cubes is isotropic in the plane, so PCA axes are
+        // arbitrary there, but the bound should never be worse than the AABB or the child frames.
+        let cross = [(5.0, 0.0), (-5.0, 0.0), (0.0, 5.0), (0.0, -5.0)]
+            .iter()
+            .map(|(x, y)| SdfBoundingBox::from_transform(Transform::from_xyz(*x, *y, 0.0)))
+            .collect::<Vec<SdfBoundingBox>>();
+        let turn = Mat4::from_rotation_translation(Quat::from_rotation_z(0.5), Vec3::new(1.0, -2.0, 3.0));
+        let turned = cross.iter()
+            .map(|sub_box| sub_box.apply_matrix(turn))
+            .collect::<Vec<SdfBoundingBox>>();
+        for sub_boxes in [cross, turned] {
+            let merged = SdfBoundingBox::merge(&sub_boxes);
+            validate_merge(&merged, &sub_boxes);
+            assert!(box_volume(&merged) <= 36.0 + 1e-2, "Got volume {}!", box_volume(&merged));
+        }
+
+        let mut rng = thread_rng();
+        for _ in 0..(TRIALS / 10) {
+            let sub_boxes = (0..rng.gen_range(2..20))
+                .map(|_| random_box(&mut rng))
+                .collect::<Vec<SdfBoundingBox>>();
+            let merged = SdfBoundingBox::merge(&sub_boxes);
+            validate_merge(&merged, &sub_boxes);
+            assert!(box_volume(&merged) <= aabb_volume(&sub_boxes) * (1.0 + 1e-4), "Merged box is worse than the AABB!");
+        }
+    }
+
+    #[test]
+    fn test_merge_refined() {
+        // Small axis aligned cubes on the corners of a rotated square. Neither the parent frame,
+        // the child frames nor the (isotropic) principal axes fit them well.
+        let rot = Mat2::from_angle(0.5);
+        let corners = [Vec2::new(5.0, 5.0), Vec2::new(-5.0, 5.0), Vec2::new(-5.0, -5.0), Vec2::new(5.0, -5.0)]
+            .iter()
+            .map(|corner| {
+                let pos = rot * *corner;
+                SdfBoundingBox::from_transform(Transform::from_xyz(pos.x, pos.y, 0.0).with_scale(Vec3::splat(0.1)))
+            })
+            .collect::<Vec<SdfBoundingBox>>();
+        let refined = SdfBoundingBox::merge_refined(&corners);
+        validate_merge(&refined, &corners);
+        assert!(box_volume(&refined) < 0.6 * aabb_volume(&corners), "Refinement didn't find the rotated fit!");
+
+        let mut rng = thread_rng();
+        for _ in 0..(TRIALS / 10) {
+            let sub_boxes = (0..rng.gen_range(2..20))
+                .map(|_| random_box(&mut rng))
+                .collect::<Vec<SdfBoundingBox>>();
+            let merged = SdfBoundingBox::merge(&sub_boxes);
+            let refined = SdfBoundingBox::merge_refined(&sub_boxes);
+            validate_merge(&refined, &sub_boxes);
+            assert!(box_volume(&refined) <= box_volume(&merged) * (1.0 + 1e-4), "Refinement made the box bigger!");
+        }
+    }
+
+    #[test]
+    fn test_ray_brute() {
+        let mut rng = thread_rng();
+        let mut hits = 0;
+        for _ in 0..TRIALS {
+            let bbox = random_box(&mut rng);
+            let origin = Vec3::new(
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+            );
+            let dir = (random_point_in(&bbox, &mut rng) + Vec3::new(
+                rng.gen_range(-3.0..3.0),
+                rng.gen_range(-3.0..3.0),
+                rng.gen_range(-3.0..3.0),
+            ) - origin).normalize();
+            let result = bbox.ray_intersect(origin, dir);
+            // March the ray and record where it is inside the box
+            let samples = (0..4000)
+                .map(|i| i as f32 * 0.01)
+                .filter(|t| bbox.contains(origin + dir * *t))
+                .collect::<Vec<f32>>();
+            match result {
+                Some((t_enter, t_exit)) => {
+                    hits += 1;
+                    assert!(t_enter <= t_exit && t_exit >= 0.0);
+                    assert!(bbox.contains(origin + dir * (t_enter.max(0.0) + t_exit) / 2.0),
+                        "Midpoint of ray hit isn't inside the box!");
+                    for t in samples.iter() {
+                        assert!(*t >= t_enter - 1e-3 && *t <= t_exit + 1e-3,
+                            "Marched point inside the box at {} is outside of [{}, {}]!", t, t_enter, t_exit);
+                    }
+                },
+                None => assert!(samples.is_empty(), "Ray missed, but marched points are inside the box!"),
+            }
         }
+        assert!(hits > 0 && hits < TRIALS);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_plane_brute() {
+        let mut rng = thread_rng();
+        for _ in 0..TRIALS {
+            let bbox = random_box(&mut rng);
+            let plane = SdfPlane::from_point_normal(
+                Vec3::new(
+                    rng.gen_range(-5.0..5.0),
+                    rng.gen_range(-5.0..5.0),
+                    rng.gen_range(-5.0..5.0),
+                ),
+                Vec3::new(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                ),
+            );
+            let (vmin, vmax) = bbox.verts().iter()
+                .map(|vert| plane.signed_distance(vert.truncate()))
+                .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), dist| (lo.min(dist), hi.max(dist)));
+            match bbox.classify_plane(&plane) {
+                SdfIntersection::Inside => assert!(vmin >= -1e-3),
+                SdfIntersection::Outside => assert!(vmax <= 1e-3),
+                SdfIntersection::Intersecting => assert!(vmin <= 1e-3 && vmax >= -1e-3),
+            }
+        }
+    }
+
+    #[test]
+    fn test_frustum_brute() {
+        let mut rng = thread_rng();
+        let view = Mat4::look_at_rh(Vec3::new(0.0, 0.0, 10.0), Vec3::ZERO, Vec3::Y);
+        let projections = [
+            Mat4::perspective_rh(FRAC_PI_4, 1.5, 0.5, 20.0),
+            Mat4::perspective_infinite_reverse_rh(FRAC_PI_4, 1.5, 0.5),
+        ];
+        for proj in projections.iter() {
+            let frustum = SdfFrustum::from_view_projection(*proj * view);
+            assert!(frustum.contains(Vec3::ZERO));
+            assert!(!frustum.contains(Vec3::new(0.0, 0.0, 11.0)));
+            for _ in 0..TRIALS {
+                let bbox = random_box(&mut rng);
+                let verts = bbox.verts();
+                match bbox.classify_frustum(&frustum) {
+                    SdfIntersection::Inside => assert!(verts.iter().all(|vert| frustum.contains(vert.truncate()))),
+                    SdfIntersection::Outside => {
+                        assert!(frustum.planes.iter().any(|plane| verts.iter()
+                            .all(|vert| plane.signed_distance(vert.truncate()) <= 1e-3)));
+                        assert!((0..20).all(|_| !frustum.contains(random_point_in(&bbox, &mut rng))));
+                    },
+                    SdfIntersection::Intersecting => {},
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_keeps_child_frame() {
+        let mut rng = thread_rng();
+        for _ in 0..TRIALS {
+            let sub_boxes = (0..rng.gen_range(2..6))
+                .map(|_| random_box(&mut rng))
+                .collect::<Vec<SdfBoundingBox>>();
+            let merged = SdfBoundingBox::merge(&sub_boxes);
+
+            // Children were merged in their parent's frame, so moving a point into the merged
+            // box's evaluation frame mustn't move it out of the children
+            for sub_box in sub_boxes.iter() {
+                let point = random_point_in(sub_box, &mut rng);
+                let moved = merged.in_box_trans_basis(point.extend(1.0)).truncate();
+                assert!(sub_box.distance_to(moved) <= 1e-3,
+                    "Point {} left its box after moving to {}", point, moved);
+            }
+        }
+    }
+}
@@ -0,0 +1,1074 @@
+use std::sync::OnceLock;
+use super::math::*;
+use super::{
+    component::SdfOpSpecificBlock,
+    material::{SdfMaterialMix, smooth_union},
+    elements::*,
+    heightfield::SdfHeightfieldView,
+    grid::{SdfGridView, BRICK_CELLS},
+    noise::SdfNoiseBasis,
+    curve::{CUBIC_SAMPLES, CUBIC_ITERATIONS, CUBIC_PIECES, CUBIC_DEPTH},
+    text::CROSSING_ITERATIONS,
+};
+
+// Distance to a primitive from its op-specific block and the side buffer, at a point in its frame
+pub type SdfDistanceFn = fn(&SdfOpSpecificBlock, &[f32], Vec4) -> f32;
+// Points passed down to both slots of an operation
+pub type SdfDowntreeFn = fn(&SdfOpSpecificBlock, Vec4) -> [Vec4; 2];
+// Combined result of both slots of an operation, at the operation's own point in its frame
+pub type SdfUptreeFn = fn(&SdfOpSpecificBlock, Vec4, (f32, SdfMaterialMix), (f32, SdfMaterialMix)) -> (f32, SdfMaterialMix);
+// Distance by which a subtree may undercut its siblings and still matter, like a blend radius
+pub type SdfPruneMarginFn = fn(&SdfOpSpecificBlock) -> f32;
+
+/**
+ * Everything the traversal needs to know about a primitive op code. Primitives without a
+ * distance function claim their op code all the same, so nothing else can take it.
+ *
+ * Shader snippets are bodies of a GLSL function that returns the distance, with `block`,
+ * `point` and the global `side_buffer` in scope.
+ */
+#[derive(Clone, Copy)]
+pub struct SdfPrimitiveEntry {
+    pub name: &'static str,
+    pub op_code: u32,
+    pub distance: Option<SdfDistanceFn>,
+    pub glsl: Option<&'static str>,
+}
+
+/**
+ * Everything the traversal needs to know about an operation op code.
+ *
+ * Downtree snippets fill `branch_points[2]` from `block` and `point`, uptree snippets return the
+ * combined distance of `left` and `right`. Materials are only blended on the CPU for now.
+ */
+#[derive(Clone, Copy)]
+pub struct SdfOperationEntry {
+    pub name: &'static str,
+    pub op_code: u32,
+    pub downtree: SdfDowntreeFn,
+    pub uptree: SdfUptreeFn,
+    pub prune_margin: SdfPruneMarginFn,
+    pub glsl_downtree: Option<&'static str>,
+    pub glsl_uptree: Option<&'static str>,
+}
+
+fn no_prune_margin(_block: &SdfOpSpecificBlock) -> f32 {
+    0.0
+}
+
+// Same round cone as curve::capsule_distance
+const CAPSULE_GLSL: &str = "
+    vec3 a = block.vec4s[0].xyz;
+    vec3 b = block.vec4s[1].xyz;
+    float ra = block.vec4s[0].w;
+    float rb = block.vec4s[1].w;
+    vec3 ba = b - a;
+    float l2 = dot(ba, ba);
+    float rr = ra - rb;
+    float a2 = l2 - rr * rr;
+    if (a2 <= 0.0) {
+        return min(length(point.xyz - a) - ra, length(point.xyz - b) - rb);
+    }
+    float il2 = 1.0 / l2;
+    vec3 pa = point.xyz - a;
+    float y = dot(pa, ba);
+    float z = y - l2;
+    vec3 xv = pa * l2 - ba * y;
+    float x2 = dot(xv, xv);
+    float y2 = y * y * l2;
+    float z2 = z * z * l2;
+    float k = sign(rr) * rr * rr * x2;
+    if (sign(z) * a2 * z2 > k) {
+        return sqrt(x2 + z2) * il2 - rb;
+    }
+    if (sign(y) * a2 * y2 < k) {
+        return sqrt(x2 + y2) * il2 - ra;
+    }
+    return (sqrt(x2 * a2 * il2) + y * rr) * il2 - ra;
+";
+
+// Same smooth minimum as material::smooth_min
+const UNION_GLSL: &str = "
+    float radius = block.floats[0];
+    if (radius <= 0.0) {
+        return min(left, right);
+    }
+    float h = clamp(0.5 + 0.5 * (left - right) / radius, 0.0, 1.0);
+    return mix(left, right, h) - radius * h * (1.0 - h);
+";
+
+const CAA_CLONE_GLSL: &str = "
+    vec4 cell = clamp(round(point / block.vec4s[0]), block.vec4s[1], block.vec4s[2]);
+    branch_points[0] = point - block.vec4s[0] * cell;
+    branch_points[1] = vec4(0.0);
+";
+
+// Same as SdfExtrude::extrude
+const EXTRUDE_GLSL: &str = "
+    vec2 w = vec2(left, abs(point.z) - block.floats[0] * 0.5);
+    return min(max(w.x, w.y), 0.0) + length(max(w, 0.0));
+";
+
+// Same as SdfRevolve::unrevolve
+const REVOLVE_GLSL: &str = "
+    branch_points[0] = vec4(length(point.xz) - block.floats[0], point.y, 0.0, 1.0);
+    branch_points[1] = vec4(0.0);
+";
+
+// Same as SdfBoxFrame::distance_to
+const BOX_FRAME_GLSL: &str = "
+    vec3 p = abs(point.xyz) - block.vec4s[0].xyz;
+    float e = block.floats[0];
+    vec3 q = abs(p + e) - e;
+    return min(min(
+        length(max(vec3(p.x, q.y, q.z), 0.0)) + min(max(p.x, max(q.y, q.z)), 0.0),
+        length(max(vec3(q.x, p.y, q.z), 0.0)) + min(max(q.x, max(p.y, q.z)), 0.0)),
+        length(max(vec3(q.x, q.y, p.z), 0.0)) + min(max(q.x, max(q.y, p.z)), 0.0));
+";
+
+// Same as SdfTerrain::distance_to
+const TERRAIN_GLSL: &str = "
+    vec4 shape = block.vec4s[2];
+    float slope = shape.z * noise_lipschitz(block.vec4s[0], block.vec4s[1]);
+    float height = shape.z * noise_fbm(block.vec4s[0], block.vec4s[1], vec3(point.x, 0.0, point.z));
+    float surface = (point.y - height) / sqrt(1.0 + slope * slope);
+    vec3 q = abs(point.xyz - vec3(0.0, -shape.w / 2.0, 0.0)) - vec3(shape.x, shape.z + shape.w / 2.0, shape.y);
+    float slab = length(max(q, 0.0)) + min(max(q.x, max(q.y, q.z)), 0.0);
+    return max(surface, slab);
+";
+
+// Same as SdfHeightfieldView::distance_to
+const HEIGHTFIELD_GLSL: &str = "
+    vec4 shape = block.vec4s[0];
+    float lipschitz = block.vec4s[1].z;
+    float surface = (point.y - heightfield_height(block, point.xz)) / sqrt(1.0 + lipschitz * lipschitz);
+    vec3 q = abs(point.xyz - vec3(0.0, (shape.z - shape.w) / 2.0, 0.0)) - vec3(shape.x, (shape.z + shape.w) / 2.0, shape.y);
+    float distance = max(surface, length(max(q, 0.0)) + min(max(q.x, max(q.y, q.z)), 0.0));
+    if (distance > 0.0) {
+        return max(distance, heightfield_clearance(block, point.xyz));
+    }
+    return distance;
+";
+
+// Same as SdfGridView::distance_to
+const GRID_GLSL: &str = "
+    vec3 half_extents = block.vec4s[1].xyz * (block.vec4s[0].w / 2.0);
+    vec3 center = block.vec4s[0].xyz + half_extents;
+    vec3 inside = clamp(point.xyz, center - half_extents, center + half_extents);
+    float outside = length(point.xyz - inside);
+    float distance = grid_sample(block, inside);
+    if (outside > 0.0) {
+        return max(outside, distance - outside);
+    }
+    return distance;
+";
+
+const QUAD_BEZIER_TUBE_GLSL: &str = "
+    return quad_bezier_distance(point.xyz, block.vec4s[0].xyz, block.vec4s[1].xyz, block.vec4s[2].xyz) - block.floats[0];
+";
+
+// The fourth point is in the w components, see SdfCubicBezierTube
+const CUBIC_BEZIER_TUBE_GLSL: &str = "
+    vec3 d = vec3(block.vec4s[0].w, block.vec4s[1].w, block.vec4s[2].w);
+    return cubic_bezier_distance(point.xyz, block.vec4s[0].xyz, block.vec4s[1].xyz, block.vec4s[2].xyz, d) - block.floats[0];
+";
+
+// Same as SdfDisplace::displace
+const DISPLACE_GLSL: &str = "
+    return left + block.floats[0] * noise_fbm(block.vec4s[0], block.vec4s[1], point.xyz);
+";
+
+/*
+Functions that the snippets share, ported from the CPU code that each one names. The constants
+they use are generated from the CPU ones by glsl_constants().
+*/
+const GLSL_LIBRARY: &str = "
+// Same as noise::hash
+uint noise_hash(uint seed, ivec3 lattice) {
+    uint h = (seed & 0xffffffu)
+        ^ (uint(lattice.x) * 0x8da6b343u)
+        ^ (uint(lattice.y) * 0xd8163841u)
+        ^ (uint(lattice.z) * 0xcb1ab31fu);
+    h ^= h >> 16;
+    h *= 0x85ebca6bu;
+    h ^= h >> 13;
+    h *= 0xc2b2ae35u;
+    return h ^ (h >> 16);
+}
+
+// Same as noise::GRADIENTS
+const vec3 NOISE_GRADIENTS[12] = vec3[12](
+    vec3(1.0, 1.0, 0.0), vec3(-1.0, 1.0, 0.0), vec3(1.0, -1.0, 0.0), vec3(-1.0, -1.0, 0.0),
+    vec3(1.0, 0.0, 1.0), vec3(-1.0, 0.0, 1.0), vec3(1.0, 0.0, -1.0), vec3(-1.0, 0.0, -1.0),
+    vec3(0.0, 1.0, 1.0), vec3(0.0, -1.0, 1.0), vec3(0.0, 1.0, -1.0), vec3(0.0, -1.0, -1.0)
+);
+
+// Same as noise::value3 and noise::perlin3, which only differ in what their corners hold
+float noise_cell(vec3 point, uint seed, bool gradient) {
+    vec3 cell = floor(point);
+    vec3 local = point - cell;
+    vec3 u = local * local * local * (local * (local * 6.0 - 15.0) + 10.0);
+    float corners[8];
+    for (int i = 0; i < 8; i++) {
+        ivec3 corner = ivec3(i & 1, (i >> 1) & 1, i >> 2);
+        uint hash = noise_hash(seed, ivec3(cell) + corner);
+        if (gradient) {
+            corners[i] = dot(NOISE_GRADIENTS[hash % 12u], local - vec3(corner));
+        } else {
+            corners[i] = float(hash) / 4294967295.0 * 2.0 - 1.0;
+        }
+    }
+    return mix(
+        mix(mix(corners[0], corners[1], u.x), mix(corners[2], corners[3], u.x), u.y),
+        mix(mix(corners[4], corners[5], u.x), mix(corners[6], corners[7], u.x), u.y),
+        u.z
+    );
+}
+
+// Same as noise::simplex3
+float noise_simplex(vec3 point, uint seed) {
+    vec3 cell = floor(point + (point.x + point.y + point.z) / 3.0);
+    vec3 origin = point - (cell - (cell.x + cell.y + cell.z) / 6.0);
+    // Walk from the origin to the far corner of the cell along the largest offsets first
+    vec3 g = step(origin.yzx, origin.xyz);
+    vec3 l = 1.0 - g;
+    vec3 steps[4] = vec3[4](vec3(0.0), min(g, l.zxy), max(g, l.zxy), vec3(1.0));
+    float total = 0.0;
+    for (int i = 0; i < 4; i++) {
+        vec3 offset = origin - steps[i] + float(i) / 6.0;
+        float t = 0.5 - dot(offset, offset);
+        if (t > 0.0) {
+            uint hash = noise_hash(seed, ivec3(cell + steps[i]));
+            total += t * t * t * t * dot(NOISE_GRADIENTS[hash % 12u], offset);
+        }
+    }
+    return clamp(76.0 * total, -1.0, 1.0);
+}
+
+// Same as SdfNoiseBasis::sample, by the id that SdfNoise::to_vec4s packs
+float noise_basis(uint basis, vec3 point, uint seed) {
+    if (basis == 0u) {
+        return noise_cell(point, seed, false);
+    }
+    if (basis == 1u) {
+        return clamp(noise_cell(point, seed, true), -1.0, 1.0);
+    }
+    return noise_simplex(point, seed);
+}
+
+// Same as SdfNoise::sample, from the vec4s that SdfNoise::to_vec4s packs
+float noise_fbm(vec4 a, vec4 b, vec3 point) {
+    float sum = 0.0;
+    float norm = 0.0;
+    float frequency = a.x;
+    float amplitude = 1.0;
+    for (int i = 0; i < max(int(a.w), 1); i++) {
+        sum += amplitude * noise_basis(uint(b.x), point * frequency, uint(b.y) + uint(i));
+        norm += amplitude;
+        frequency *= a.y;
+        amplitude *= a.z;
+    }
+    return sum / norm;
+}
+
+// Same as SdfNoise::lipschitz
+float noise_lipschitz(vec4 a, vec4 b) {
+    float sum = 0.0;
+    float norm = 0.0;
+    float frequency = a.x;
+    float amplitude = 1.0;
+    for (int i = 0; i < max(int(a.w), 1); i++) {
+        sum += amplitude * frequency;
+        norm += amplitude;
+        frequency *= a.y;
+        amplitude *= a.z;
+    }
+    return NOISE_LIPSCHITZ[min(uint(b.x), 2u)] * sum / norm;
+}
+
+// Same as SdfHeightfieldView::height_at
+float heightfield_height(SdfOpSpecificBlock block, vec2 p) {
+    uint columns = uint(block.vec4s[1].x);
+    vec2 cells = block.vec4s[1].xy - 1.0;
+    vec2 grid = clamp((p + block.vec4s[0].xy) * cells / (block.vec4s[0].xy * 2.0), vec2(0.0), cells);
+    vec2 cell = min(floor(grid), cells - 1.0);
+    vec2 local = grid - cell;
+    uint i = block.side_offset + uint(cell.y) * columns + uint(cell.x);
+    float near = mix(side_buffer[i], side_buffer[i + 1u], local.x);
+    float far = mix(side_buffer[i + columns], side_buffer[i + columns + 1u], local.x);
+    return mix(near, far, local.y) * block.vec4s[0].z;
+}
+
+// Distance to the column under a texel of a level of the pyramid, see SdfHeightfieldView::clearance
+float heightfield_column(SdfOpSpecificBlock block, vec3 point, uint offset, int width, int level, ivec2 texel) {
+    ivec2 cells = ivec2(block.vec4s[1].xy) - 1;
+    vec2 half_size = block.vec4s[0].xy;
+    vec2 cell_size = half_size * 2.0 / vec2(cells);
+    float top = side_buffer[offset + uint((texel.y * width + texel.x) * 2 + 1)];
+    ivec2 first = texel * (1 << level);
+    ivec2 last = min((texel + 1) * (1 << level), cells);
+    vec3 low = vec3(vec2(first) * cell_size - half_size, -block.vec4s[0].w);
+    vec3 high = vec3(vec2(last) * cell_size - half_size, top * block.vec4s[0].z);
+    vec3 p = point.xzy;
+    return length(max(max(low - p, p - high), 0.0));
+}
+
+// Same as SdfHeightfieldView::clearance
+float heightfield_clearance(SdfOpSpecificBlock block, vec3 point) {
+    ivec2 samples = ivec2(block.vec4s[1].xy);
+    ivec2 dims[HEIGHTFIELD_LEVELS];
+    uint offsets[HEIGHTFIELD_LEVELS];
+    int levels = 0;
+    ivec2 level_dims = samples - 1;
+    uint offset = block.side_offset + uint(samples.x * samples.y);
+    while (levels < HEIGHTFIELD_LEVELS) {
+        dims[levels] = level_dims;
+        offsets[levels] = offset;
+        levels += 1;
+        if (level_dims == ivec2(1)) {
+            break;
+        }
+        offset += uint(level_dims.x * level_dims.y * 2);
+        level_dims = (level_dims + 1) / 2;
+    }
+
+    // Every level pushes at most three more texels than it pops
+    int stack_levels[HEIGHTFIELD_LEVELS * 3 + 1];
+    ivec2 stack_texels[HEIGHTFIELD_LEVELS * 3 + 1];
+    float stack_distances[HEIGHTFIELD_LEVELS * 3 + 1];
+    int top = levels - 1;
+    stack_levels[0] = top;
+    stack_texels[0] = ivec2(0);
+    stack_distances[0] = heightfield_column(block, point, offsets[top], dims[top].x, top, ivec2(0));
+    int size = 1;
+    float best = 1.0 / 0.0;
+    while (size > 0) {
+        size -= 1;
+        int level = stack_levels[size];
+        ivec2 texel = stack_texels[size];
+        float distance = stack_distances[size];
+        if (distance >= best) {
+            continue;
+        }
+        if (level == 0) {
+            best = distance;
+            continue;
+        }
+        ivec2 below = dims[level - 1];
+        ivec2 children[4];
+        float distances[4];
+        int count = 0;
+        for (int i = 0; i < 4; i++) {
+            ivec2 child = texel * 2 + ivec2(i % 2, i / 2);
+            if (child.x < below.x && child.y < below.y) {
+                children[count] = child;
+                distances[count] = heightfield_column(block, point, offsets[level - 1], below.x, level - 1, child);
+                count += 1;
+            }
+        }
+        // The stack is popped from the back, so the nearest child goes last
+        for (int i = 1; i < count; i++) {
+            for (int j = i; j > 0 && distances[j] > distances[j - 1]; j--) {
+                ivec2 texel_swap = children[j];
+                children[j] = children[j - 1];
+                children[j - 1] = texel_swap;
+                float distance_swap = distances[j];
+                distances[j] = distances[j - 1];
+                distances[j - 1] = distance_swap;
+            }
+        }
+        for (int i = 0; i < count; i++) {
+            stack_levels[size] = level - 1;
+            stack_texels[size] = children[i];
+            stack_distances[size] = distances[i];
+            size += 1;
+        }
+    }
+    return best;
+}
+
+// Same as SdfGridFormat::decode, for the samples of a block that start at `start`
+float grid_decode(SdfOpSpecificBlock block, uint start, uint index) {
+    uint bits = uint(block.vec4s[2].x);
+    if (bits != 8u && bits != 16u) {
+        return side_buffer[start + index];
+    }
+    uint max_code = (1u << bits) - 1u;
+    uint word = floatBitsToUint(side_buffer[start + index * bits / 32u]);
+    uint code = (word >> (index * bits % 32u)) & max_code;
+    return (float(code) / float(max_code) * 2.0 - 1.0) * block.vec4s[2].y;
+}
+
+// Same as SdfGridView::sample
+float grid_sample(SdfOpSpecificBlock block, vec3 point) {
+    uvec3 cells = uvec3(block.vec4s[1].xyz);
+    vec3 voxel = clamp((point - block.vec4s[0].xyz) / block.vec4s[0].w, vec3(0.0), vec3(cells));
+    uint table_len = 0u;
+    uint index = 0u;
+    vec3 local = voxel;
+    uvec3 samples = cells + 1u;
+    if (block.vec4s[1].w != 0.0) {
+        uvec3 bricks = cells / GRID_BRICK_CELLS;
+        uvec3 brick = min(uvec3(floor(voxel / float(GRID_BRICK_CELLS))), bricks - 1u);
+        uint slot = block.side_offset + ((brick.z * bricks.y + brick.y) * bricks.x + brick.x) * 2u;
+        if (side_buffer[slot] < 0.0) {
+            return side_buffer[slot + 1u];
+        }
+        index = uint(side_buffer[slot]);
+        local = voxel - vec3(brick * GRID_BRICK_CELLS);
+        table_len = bricks.x * bricks.y * bricks.z * 2u;
+        samples = uvec3(GRID_BRICK_CELLS + 1u);
+    }
+    uint bits = uint(block.vec4s[2].x);
+    if (bits != 8u && bits != 16u) {
+        bits = 32u;
+    }
+    uint words = (samples.x * samples.y * samples.z * bits + 31u) / 32u;
+    uint start = block.side_offset + table_len + index * words;
+
+    vec3 cell = min(floor(local), vec3(samples - 2u));
+    vec3 t = local - cell;
+    uvec3 base = uvec3(cell);
+    float corners[8];
+    for (uint i = 0u; i < 8u; i++) {
+        uvec3 corner = base + uvec3(i & 1u, (i >> 1u) & 1u, i >> 2u);
+        corners[i] = grid_decode(block, start, (corner.z * samples.y + corner.y) * samples.x + corner.x);
+    }
+    return mix(
+        mix(mix(corners[0], corners[1], t.x), mix(corners[2], corners[3], t.x), t.y),
+        mix(mix(corners[4], corners[5], t.x), mix(corners[6], corners[7], t.x), t.y),
+        t.z
+    );
+}
+
+// Same as curve::segment_distance
+float segment_distance(vec3 p, vec3 a, vec3 b) {
+    vec3 pa = p - a;
+    vec3 ba = b - a;
+    return length(pa - ba * clamp(dot(pa, ba) / max(dot(ba, ba), 1.17549435e-38), 0.0, 1.0));
+}
+
+// Same as curve::quad_bezier_distance
+float quad_bezier_distance(vec3 pos, vec3 a, vec3 b, vec3 c) {
+    vec3 ab = b - a;
+    vec3 curve = a - 2.0 * b + c;
+    if (dot(curve, curve) < 1e-12) {
+        return segment_distance(pos, a, c);
+    }
+    vec3 lin = ab * 2.0;
+    vec3 d = a - pos;
+    float kk = 1.0 / dot(curve, curve);
+    float kx = kk * dot(ab, curve);
+    float ky = kk * (2.0 * dot(ab, ab) + dot(d, curve)) / 3.0;
+    float kz = kk * dot(d, ab);
+    float p = ky - kx * kx;
+    float q = kx * (2.0 * kx * kx - 3.0 * ky) + kz;
+    float h = q * q + 4.0 * p * p * p;
+    if (h >= 0.0) {
+        h = sqrt(h);
+        vec2 x = (vec2(h, -h) - q) / 2.0;
+        vec2 uv = sign(x) * pow(abs(x), vec2(1.0 / 3.0));
+        float t = clamp(uv.x + uv.y - kx, 0.0, 1.0);
+        return length(d + (lin + curve * t) * t);
+    }
+    // Three real roots, of which the middle one is never the closest
+    float z = sqrt(-p);
+    float v = acos(clamp(q / (p * z * 2.0), -1.0, 1.0)) / 3.0;
+    float m = cos(v);
+    float n = sin(v) * sqrt(3.0);
+    vec3 t = clamp(vec3(m + m, -n - m, n - m) * z - kx, 0.0, 1.0);
+    return min(length(d + (lin + curve * t.x) * t.x), length(d + (lin + curve * t.y) * t.y));
+}
+
+// Same as curve::cubic_bezier_point
+vec3 cubic_bezier_point(vec3 a, vec3 b, vec3 c, vec3 d, float t) {
+    float s = 1.0 - t;
+    return a * s * s * s + b * 3.0 * s * s * t + c * 3.0 * s * t * t + d * t * t * t;
+}
+
+// First derivative of a cubic bezier, see curve::cubic_bezier_derivatives
+vec3 cubic_bezier_tangent(vec3 a, vec3 b, vec3 c, vec3 d, float t) {
+    float s = 1.0 - t;
+    return (b - a) * 3.0 * s * s + (c - b) * 6.0 * s * t + (d - c) * 3.0 * t * t;
+}
+
+// Same as curve::cubic_hull_bound, for the piece between two parameters
+float cubic_piece_bound(vec3 pos, vec3 a, vec3 b, vec3 c, vec3 d, float t0, float t1) {
+    vec3 start = cubic_bezier_point(a, b, c, d, t0);
+    vec3 end = cubic_bezier_point(a, b, c, d, t1);
+    float span = (t1 - t0) / 3.0;
+    vec3 first = start + cubic_bezier_tangent(a, b, c, d, t0) * span;
+    vec3 second = end - cubic_bezier_tangent(a, b, c, d, t1) * span;
+    float deviation = max(segment_distance(first, start, end), segment_distance(second, start, end));
+    return max(segment_distance(pos, start, end) - deviation, 0.0);
+}
+
+// Same as curve::cubic_bezier_distance
+float cubic_bezier_distance(vec3 pos, vec3 a, vec3 b, vec3 c, vec3 d) {
+    float t = 0.0;
+    float best = 1.0 / 0.0;
+    for (int i = 0; i <= CUBIC_SAMPLES; i++) {
+        float sample_t = float(i) / float(CUBIC_SAMPLES);
+        vec3 offset = cubic_bezier_point(a, b, c, d, sample_t) - pos;
+        if (dot(offset, offset) < best) {
+            best = dot(offset, offset);
+            t = sample_t;
+        }
+    }
+    for (int i = 0; i < CUBIC_ITERATIONS; i++) {
+        float s = 1.0 - t;
+        vec3 offset = cubic_bezier_point(a, b, c, d, t) - pos;
+        vec3 first = cubic_bezier_tangent(a, b, c, d, t);
+        vec3 second = (c - b * 2.0 + a) * 6.0 * s + (d - c * 2.0 + b) * 6.0 * t;
+        float slope = dot(first, first) + dot(offset, second);
+        if (slope <= 1.1920929e-7) {
+            break;
+        }
+        t = clamp(t - dot(offset, first) / slope, 0.0, 1.0);
+        offset = cubic_bezier_point(a, b, c, d, t) - pos;
+        best = min(best, dot(offset, offset));
+    }
+    float upper = sqrt(best);
+
+    // Pieces as their parameter range and depth; every level of halving adds at most one
+    vec3 stack[CUBIC_PIECES + CUBIC_DEPTH];
+    int size = 0;
+    for (int i = 0; i < CUBIC_PIECES; i++) {
+        stack[size] = vec3(float(i) / float(CUBIC_PIECES), float(i + 1) / float(CUBIC_PIECES), 0.0);
+        size += 1;
+    }
+    float lower = 1.0 / 0.0;
+    while (size > 0) {
+        size -= 1;
+        vec3 piece = stack[size];
+        float bound = cubic_piece_bound(pos, a, b, c, d, piece.x, piece.y);
+        if (bound >= upper || int(piece.z) == CUBIC_DEPTH) {
+            lower = min(lower, bound);
+        } else {
+            float middle = (piece.x + piece.y) / 2.0;
+            stack[size] = vec3(piece.x, middle, piece.z + 1.0);
+            stack[size + 1] = vec3(middle, piece.y, piece.z + 1.0);
+            size += 2;
+        }
+    }
+    return lower;
+}
+
+// Same as the polygon case of SdfShape2d::distance
+float polygon_distance(uint offset, uint count, vec2 p) {
+    vec2 first = vec2(side_buffer[offset], side_buffer[offset + 1u]);
+    float d = dot(p - first, p - first);
+    float s = 1.0;
+    for (uint i = 0u; i < count; i++) {
+        uint j = (i + count - 1u) % count;
+        vec2 vi = vec2(side_buffer[offset + i * 2u], side_buffer[offset + i * 2u + 1u]);
+        vec2 vj = vec2(side_buffer[offset + j * 2u], side_buffer[offset + j * 2u + 1u]);
+        vec2 e = vj - vi;
+        vec2 w = p - vi;
+        vec2 b = w - e * clamp(dot(w, e) / max(dot(e, e), 1.17549435e-38), 0.0, 1.0);
+        d = min(d, dot(b, b));
+        // Count crossings of a ray towards +x to find out if the point is inside
+        bvec3 c = bvec3(p.y >= vi.y, p.y < vj.y, e.x * w.y > e.y * w.x);
+        if (all(c) || !any(c)) {
+            s = -s;
+        }
+    }
+    return s * sqrt(d);
+}
+
+// Same as text::quad_point
+vec2 glyph_point(vec2 a, vec2 b, vec2 c, float t) {
+    float s = 1.0 - t;
+    return a * s * s + b * 2.0 * s * t + c * t * t;
+}
+
+// Same as SdfGlyphOutline::distance and SdfGlyphOutline::winding
+float glyph_distance(uint offset, uint count, vec2 p) {
+    float distance = 1.0 / 0.0;
+    int winding = 0;
+    for (uint i = 0u; i < count; i++) {
+        uint base = offset + i * 6u;
+        vec2 a = vec2(side_buffer[base], side_buffer[base + 1u]);
+        vec2 b = vec2(side_buffer[base + 2u], side_buffer[base + 3u]);
+        vec2 c = vec2(side_buffer[base + 4u], side_buffer[base + 5u]);
+        distance = min(distance, quad_bezier_distance(vec3(p, 0.0), vec3(a, 0.0), vec3(b, 0.0), vec3(c, 0.0)));
+
+        // Split the segment where it turns around vertically, so each piece crosses at most once
+        float denominator = a.y - 2.0 * b.y + c.y;
+        float turn = abs(denominator) > 1.1920929e-7 ? (a.y - b.y) / denominator : -1.0;
+        vec4 pieces = turn > 0.0 && turn < 1.0 ? vec4(0.0, turn, turn, 1.0) : vec4(0.0, 1.0, 1.0, 1.0);
+        for (int k = 0; k < 2; k++) {
+            vec2 range = k == 0 ? pieces.xy : pieces.zw;
+            vec2 start = glyph_point(a, b, c, range.x);
+            vec2 end = glyph_point(a, b, c, range.y);
+            if ((start.y <= p.y) == (end.y <= p.y)) {
+                continue;
+            }
+            for (int j = 0; j < GLYPH_CROSSING_ITERATIONS; j++) {
+                float middle = (range.x + range.y) / 2.0;
+                if ((glyph_point(a, b, c, middle).y <= p.y) == (start.y <= p.y)) {
+                    range.x = middle;
+                } else {
+                    range.y = middle;
+                }
+            }
+            if (glyph_point(a, b, c, (range.x + range.y) / 2.0).x > p.x) {
+                winding += end.y > start.y ? 1 : -1;
+            }
+        }
+    }
+    return winding != 0 ? -distance : distance;
+}
+
+// Same as SdfShape2d::distance, for the shape that SdfShape2d::write_block packs
+float shape2d_distance(SdfOpSpecificBlock block, vec2 p) {
+    vec4 a = block.vec4s[1];
+    vec4 b = block.vec4s[2];
+    uint id = uint(block.vec4s[0].x);
+    if (id == 0u) {
+        return length(p) - a.x;
+    }
+    if (id == 1u) {
+        vec2 d = abs(p) - a.xy;
+        return length(max(d, 0.0)) + min(max(d.x, d.y), 0.0);
+    }
+    if (id == 2u) {
+        return polygon_distance(block.side_offset, uint(a.x), p);
+    }
+    if (id == 3u) {
+        return quad_bezier_distance(vec3(p, 0.0), vec3(a.xy, 0.0), vec3(a.zw, 0.0), vec3(b.xy, 0.0)) - b.z;
+    }
+    if (id == 4u) {
+        vec2 sc = vec2(sin(a.y), cos(a.y));
+        vec2 q = vec2(abs(p.x), p.y);
+        float d = sc.y * q.x > sc.x * q.y ? length(q - sc * a.x) : abs(length(q) - a.x);
+        return d - a.z;
+    }
+    return glyph_distance(block.side_offset, uint(a.x), p);
+}
+";
+
+// Levels of the heightfield pyramid the shader walks, enough for grids of 2^15 cells a side
+const HEIGHTFIELD_LEVELS: usize = 16;
+
+// Constants of the CPU code that GLSL_LIBRARY uses
+fn glsl_constants() -> String {
+    format!(
+        "const float NOISE_LIPSCHITZ[3] = float[3]({:?}, {:?}, {:?});
+const int HEIGHTFIELD_LEVELS = {};
+const uint GRID_BRICK_CELLS = {}u;
+const int CUBIC_SAMPLES = {};
+const int CUBIC_ITERATIONS = {};
+const int CUBIC_PIECES = {};
+const int CUBIC_DEPTH = {};
+const int GLYPH_CROSSING_ITERATIONS = {};
+",
+        SdfNoiseBasis::Value.lipschitz(),
+        SdfNoiseBasis::Perlin.lipschitz(),
+        SdfNoiseBasis::Simplex.lipschitz(),
+        HEIGHTFIELD_LEVELS,
+        BRICK_CELLS,
+        CUBIC_SAMPLES,
+        CUBIC_ITERATIONS,
+        CUBIC_PIECES,
+        CUBIC_DEPTH,
+        CROSSING_ITERATIONS,
+    )
+}
+
+/**
+ * Op codes of primitives and operations, which live in separate tables, with the CPU dispatch
+ * and shader snippets that belong to them. The faux shader dispatches through the global
+ * registry, and the dispatch functions of the real shader are generated from it.
+ */
+pub struct SdfRegistry {
+    primitives: Vec<Option<SdfPrimitiveEntry>>,
+    operations: Vec<Option<SdfOperationEntry>>,
+}
+
+// Insert into a table indexed by op code, refusing codes that are already taken
+fn claim<T>(table: &mut Vec<Option<T>>, op_code: u32, entry: T) -> bool {
+    let index = op_code as usize;
+    if table.len() <= index {
+        table.resize_with(index + 1, || None);
+    }
+    if table[index].is_some() {
+        return false;
+    }
+    table[index] = Some(entry);
+    true
+}
+
+impl SdfRegistry {
+    pub fn empty() -> Self {
+        SdfRegistry {
+            primitives: vec![],
+            operations: vec![],
+        }
+    }
+
+    pub fn register_primitive(&mut self, entry: SdfPrimitiveEntry) -> Result<&mut Self, &'static str> {
+        if !claim(&mut self.primitives, entry.op_code, entry) {
+            return Err("Primitive op code is already registered!");
+        }
+        Ok(self)
+    }
+
+    pub fn register_operation(&mut self, entry: SdfOperationEntry) -> Result<&mut Self, &'static str> {
+        if !claim(&mut self.operations, entry.op_code, entry) {
+            return Err("Operation op code is already registered!");
+        }
+        Ok(self)
+    }
+
+    pub fn primitive(&self, op_code: u32) -> Option<&SdfPrimitiveEntry> {
+        self.primitives.get(op_code as usize)?.as_ref()
+    }
+
+    pub fn operation(&self, op_code: u32) -> Option<&SdfOperationEntry> {
+        self.operations.get(op_code as usize)?.as_ref()
+    }
+
+    pub fn primitives(&self) -> impl Iterator<Item = &SdfPrimitiveEntry> {
+        self.primitives.iter().flatten()
+    }
+
+    pub fn operations(&self) -> impl Iterator<Item = &SdfOperationEntry> {
+        self.operations.iter().flatten()
+    }
+
+    // Registry with every element of this crate
+    pub fn builtin() -> Result<Self, &'static str> {
+        let mut registry = SdfRegistry::empty();
+        registry
+            .register_primitive(SdfPrimitiveEntry {
+                name: "sphere",
+                op_code: SdfSphere::OP_CODE,
+                distance: Some(|block, _, point| point.truncate().length() - block.floats[0]),
+                glsl: Some("return length(point.xyz) - block.floats[0];"),
+            })?
+            .register_primitive(SdfPrimitiveEntry {
+                name: "box frame",
+                op_code: SdfBoxFrame::OP_CODE,
+                distance: Some(|block, _, point| SdfBoxFrame::from_block(block).distance_to(point.truncate())),
+                glsl: Some(BOX_FRAME_GLSL),
+            })?
+            .register_primitive(SdfPrimitiveEntry {
+                name: "terrain",
+                op_code: SdfTerrain::OP_CODE,
+                distance: Some(|block, _, point| SdfTerrain::from_block(block).distance_to(point.truncate())),
+                glsl: Some(TERRAIN_GLSL),
+            })?
+            .register_primitive(SdfPrimitiveEntry {
+                name: "heightfield",
+                op_code: SdfHeightfield::OP_CODE,
+                distance: Some(|block, side_buffer, point| {
+                    SdfHeightfieldView::from_block(block, side_buffer).distance_to(point.truncate())
+                }),
+                glsl: Some(HEIGHTFIELD_GLSL),
+            })?
+            // Meshes have to be baked into grids first
+            .register_primitive(SdfPrimitiveEntry {
+                name: "mesh",
+                op_code: SdfMesh::OP_CODE,
+                distance: None,
+                glsl: None,
+            })?
+            .register_primitive(SdfPrimitiveEntry {
+                name: "grid",
+                op_code: SdfGrid::OP_CODE,
+                distance: Some(|block, side_buffer, point| {
+                    SdfGridView::from_block(block, side_buffer).distance_to(point.truncate())
+                }),
+                glsl: Some(GRID_GLSL),
+            })?
+            .register_primitive(SdfPrimitiveEntry {
+                name: "profile",
+                op_code: SdfProfile::OP_CODE,
+                distance: Some(|block, side_buffer, point| {
+                    SdfProfile::from_block(block, side_buffer).distance_to(point.truncate())
+                }),
+                glsl: Some("return shape2d_distance(block, point.xy);"),
+            })?
+            .register_primitive(SdfPrimitiveEntry {
+                name: "capsule",
+                op_code: SdfCapsule::OP_CODE,
+                distance: Some(|block, _, point| SdfCapsule::from_block(block).distance_to(point.truncate())),
+                glsl: Some(CAPSULE_GLSL),
+            })?
+            .register_primitive(SdfPrimitiveEntry {
+                name: "quadratic bezier tube",
+                op_code: SdfQuadBezierTube::OP_CODE,
+                distance: Some(|block, _, point| SdfQuadBezierTube::from_block(block).distance_to(point.truncate())),
+                glsl: Some(QUAD_BEZIER_TUBE_GLSL),
+            })?
+            .register_primitive(SdfPrimitiveEntry {
+                name: "cubic bezier tube",
+                op_code: SdfCubicBezierTube::OP_CODE,
+                distance: Some(|block, _, point| SdfCubicBezierTube::from_block(block).distance_to(point.truncate())),
+                glsl: Some(CUBIC_BEZIER_TUBE_GLSL),
+            })?
+            // Closures only run on the CPU
+            .register_primitive(SdfPrimitiveEntry {
+                name: "closure",
+                op_code: SdfClosure::OP_CODE,
+                distance: None,
+                glsl: None,
+            })?;
+
+        registry
+            .register_operation(SdfOperationEntry {
+                name: "union",
+                op_code: SdfUnion::OP_CODE,
+                downtree: |_, point| [point, point],
+                uptree: |block, _, left, right| smooth_union(left, right, block.floats[0]),
+                prune_margin: |block| block.floats[0],
+                glsl_downtree: Some("branch_points[0] = point;\nbranch_points[1] = point;"),
+                glsl_uptree: Some(UNION_GLSL),
+            })?
+            .register_operation(SdfOperationEntry {
+                name: "CAA clone",
+                op_code: SdfCaaClone::OP_CODE,
+                downtree: |block, point| [
+                    point - block.vec4s[0] * (point / block.vec4s[0]).round().clamp(block.vec4s[1], block.vec4s[2]),
+                    Vec4::ZERO,
+                ],
+                uptree: |_, _, _, right| right,
+                prune_margin: no_prune_margin,
+                glsl_downtree: Some(CAA_CLONE_GLSL),
+                glsl_uptree: Some("return right;"),
+            })?
+            .register_operation(SdfOperationEntry {
+                name: "morph",
+                op_code: SdfMorph::OP_CODE,
+                downtree: |_, point| [point, point],
+                uptree: |block, _, left, right| (
+                    left.0 + (right.0 - left.0) * block.floats[0],
+                    left.1.blend(&right.1, block.floats[0]),
+                ),
+                prune_margin: no_prune_margin,
+                glsl_downtree: Some("branch_points[0] = point;\nbranch_points[1] = point;"),
+                glsl_uptree: Some("return mix(left, right, block.floats[0]);"),
+            })?
+            .register_operation(SdfOperationEntry {
+                name: "displacement",
+                op_code: SdfDisplace::OP_CODE,
+                downtree: |_, point| [point, Vec4::ZERO],
+                uptree: |block, point, left, _| {
+                    (SdfDisplace::from_block(block).displace(point.truncate(), left.0), left.1)
+                },
+                prune_margin: no_prune_margin,
+                glsl_downtree: Some("branch_points[0] = point;\nbranch_points[1] = vec4(0.0);"),
+                glsl_uptree: Some(DISPLACE_GLSL),
+            })?
+            .register_operation(SdfOperationEntry {
+                name: "extrude",
+                op_code: SdfExtrude::OP_CODE,
+                downtree: |_, point| [point.truncate().truncate().extend(0.0).extend(1.0), Vec4::ZERO],
+                uptree: |block, point, left, _| (SdfExtrude::from_block(block).extrude(point.truncate(), left.0), left.1),
+                prune_margin: no_prune_margin,
+                glsl_downtree: Some("branch_points[0] = vec4(point.xy, 0.0, 1.0);\nbranch_points[1] = vec4(0.0);"),
+                glsl_uptree: Some(EXTRUDE_GLSL),
+            })?
+            .register_operation(SdfOperationEntry {
+                name: "revolve",
+                op_code: SdfRevolve::OP_CODE,
+                downtree: |block, point| [SdfRevolve::from_block(block).unrevolve(point.truncate()).extend(1.0), Vec4::ZERO],
+                uptree: |_, _, left, _| left,
+                prune_margin: no_prune_margin,
+                glsl_downtree: Some(REVOLVE_GLSL),
+                glsl_uptree: Some("return left;"),
+            })?;
+        Ok(registry)
+    }
+
+    /**
+     * GLSL source of `prim_dispatch`, `downtree_dispatch` and `uptree_dispatch`, with a case for
+     * every entry that has a snippet, after the functions that the builtin snippets share. It
+     * expects `SdfOpSpecificBlock` and `side_buffer` to be declared before it.
+     */
+    pub fn generate_glsl(&self) -> String {
+        let mut source = String::from("// Generated from the SDF op code registry\n\n");
+        source.push_str(&glsl_constants());
+        source.push_str(GLSL_LIBRARY);
+        source.push('\n');
+
+        source.push_str("float prim_dispatch(uint code, SdfOpSpecificBlock block, vec4 point) {\n    switch (code) {\n");
+        for entry in self.primitives() {
+            glsl_case(&mut source, entry.name, entry.op_code, entry.glsl, "");
+        }
+        source.push_str("        default:\n            return 1.0 / 0.0;\n    }\n}\n\n");
+
+        source.push_str("void downtree_dispatch(uint code, SdfOpSpecificBlock block, vec4 point, out vec4 branch_points[2]) {\n    switch (code) {\n");
+        for entry in self.operations() {
+            glsl_case(&mut source, entry.name, entry.op_code, entry.glsl_downtree, "return;");
+        }
+        source.push_str("        default:\n            branch_points[0] = point;\n            branch_points[1] = point;\n    }\n}\n\n");
+
+        source.push_str("float uptree_dispatch(uint code, SdfOpSpecificBlock block, vec4 point, float left, float right) {\n    switch (code) {\n");
+        for entry in self.operations() {
+            glsl_case(&mut source, entry.name, entry.op_code, entry.glsl_uptree, "");
+        }
+        source.push_str("        default:\n            return min(left, right);\n    }\n}\n");
+        source
+    }
+}
+
+static REGISTRY: OnceLock<SdfRegistry> = OnceLock::new();
+
+/**
+ * Registry that the faux shader dispatches through. It's the builtin one unless another one was
+ * installed before the first traversal, and colliding builtin op codes fail on first use.
+ */
+pub fn registry() -> &'static SdfRegistry {
+    REGISTRY.get_or_init(|| SdfRegistry::builtin().expect("Colliding op codes in the builtin registry"))
+}
+
+// Use a registry with custom elements, which has to happen before anything is traversed
+pub fn install(registry: SdfRegistry) -> Result<(), &'static str> {
+    REGISTRY.set(registry).map_err(|_| "Registry is already in use!")
+}
+
+// Case of a generated switch, with the snippet re-indented and followed by `end`
+fn glsl_case(source: &mut String, name: &str, op_code: u32, snippet: Option<&str>, end: &str) {
+    let snippet = match snippet {
+        Some(snippet) => snippet,
+        None => {
+            source.push_str(&format!("        // No shader snippet for {} ({})\n", name, op_code));
+            return;
+        },
+    };
+    source.push_str(&format!("        // {}\n        case {}u: {{\n", name, op_code));
+    let lines = snippet.lines().filter(|line| !line.trim().is_empty()).collect::<Vec<_>>();
+    let indent = lines.iter()
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    for line in lines.iter().map(|line| &line[indent..]).chain(Some(end).filter(|end| !end.is_empty())) {
+        source.push_str(&format!("            {}\n", line));
+    }
+    source.push_str("        }\n");
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use std::sync::Arc;
+    use crate::{
+        registry::*,
+        shape2d::SdfShape2d,
+        noise::{SdfNoise, SdfNoiseBasis},
+    };
+
+    #[test]
+    fn test_registry() {
+        let registry = SdfRegistry::builtin().unwrap();
+
+        // Elements report the op codes that the registry dispatches on
+        let primitives: Vec<(Box<dyn SdfElement>, &str)> = vec![
+            (Box::new(SdfSphere { radius: 1.0 }), "sphere"),
+            (Box::new(SdfBoxFrame { dimension: Vec3::new(1.0, 0.5, 0.8), thickness: 0.1 }), "box frame"),
+            (Box::new(SdfTerrain {
+                half_size: Vec2::splat(4.0),
+                amplitude: 1.0,
+                depth: 1.0,
+                noise: SdfNoise::new(SdfNoiseBasis::Perlin, 7),
+            }), "terrain"),
+            (Box::new(SdfProfile { shape: SdfShape2d::Circle { radius: 1.0 } }), "profile"),
+            (Box::new(SdfCapsule { a: Vec3::ZERO, b: Vec3::X, radius_a: 0.5, radius_b: 0.2 }), "capsule"),
+            (Box::new(SdfClosure {
+                min: Vec3::splat(-1.0),
+                max: Vec3::splat(1.0),
+                distance: Arc::new(|point: Vec3| point.length() - 1.0),
+                lipschitz: 1.0,
+            }), "closure"),
+        ];
+        let mut rng = thread_rng();
+        for (element, name) in primitives.iter() {
+            let entry = registry.primitive(element.get_info().op_id).unwrap();
+            assert_eq!(entry.name, *name);
+            // CPU dispatch agrees with the element itself
+            if let Some(distance) = entry.distance {
+                let block = element.get_dt_specific_block();
+                for _ in 0..20 {
+                    let point = Vec3::new(rng.gen_range(-2.0..2.0), rng.gen_range(-2.0..2.0), rng.gen_range(-2.0..2.0));
+                    let expected = element.distance_to(point);
+                    assert!((distance(&block, element.side_data(), point.extend(1.0)) - expected).abs() < 1e-4);
+                }
+            }
+        }
+        let operations: Vec<(Box<dyn SdfElement>, &str)> = vec![
+            (Box::new(SdfUnion::new(0.0)), "union"),
+            (Box::new(SdfMorph { t: 0.5 }), "morph"),
+            (Box::new(SdfExtrude { height: 1.0 }), "extrude"),
+            (Box::new(SdfRevolve { offset: 1.0 }), "revolve"),
+        ];
+        for (element, name) in operations.iter() {
+            assert_eq!(registry.operation(element.get_info().op_id).unwrap().name, *name);
+        }
+
+        // Op codes are only shared between the two tables
+        let mut registry = SdfRegistry::builtin().unwrap();
+        let custom = SdfPrimitiveEntry {
+            name: "custom",
+            op_code: SdfSphere::OP_CODE,
+            distance: None,
+            glsl: None,
+        };
+        assert!(registry.register_primitive(custom).is_err());
+        assert!(registry.register_primitive(SdfPrimitiveEntry { op_code: 100, ..custom }).is_ok());
+        assert_eq!(registry.primitive(100).unwrap().name, "custom");
+        assert!(registry.primitive(99).is_none());
+
+        // Every snippet becomes a case, everything else a note
+        let glsl = registry.generate_glsl();
+        let (prim_dispatch, _) = glsl.split_once("void downtree_dispatch").unwrap();
+        for entry in registry.primitives() {
+            let case = format!("case {}u: {{", entry.op_code);
+            assert_eq!(prim_dispatch.contains(&case), entry.glsl.is_some(), "{}", entry.name);
+        }
+        assert!(glsl.contains("// No shader snippet for closure (15)"));
+        assert_eq!(glsl.matches('{').count(), glsl.matches('}').count());
+
+        // Everything the CPU can traverse can be traversed by the shader too
+        let builtin = SdfRegistry::builtin().unwrap();
+        for entry in builtin.primitives() {
+            assert_eq!(entry.distance.is_some(), entry.glsl.is_some(), "{}", entry.name);
+        }
+        for entry in builtin.operations() {
+            assert!(entry.glsl_downtree.is_some() && entry.glsl_uptree.is_some(), "{}", entry.name);
+        }
+        assert!(glsl.contains(&format!("const int CUBIC_PIECES = {};", CUBIC_PIECES)));
+    }
+
+    // builtin() is kept by hand, so check it against the op codes that elements.rs declares
+    #[test]
+    fn test_registry_complete() {
+        let registry = SdfRegistry::builtin().unwrap();
+        let check = |op_code: u32, primitive: bool| {
+            let registered = match primitive {
+                true => registry.primitive(op_code).is_some(),
+                false => registry.operation(op_code).is_some(),
+            };
+            assert!(registered, "Op code {} isn't in the builtin registry", op_code);
+        };
+
+        let mut declared = 0;
+        let mut op_code = None;
+        for line in include_str!("elements.rs").lines().map(str::trim) {
+            if let Some(attrs) = line.strip_prefix("#[sdf(op = ") {
+                let (code, kind) = attrs.split_once(", ").unwrap();
+                check(code.parse().unwrap(), kind.starts_with("primitive"));
+                declared += 1;
+            } else if let Some(code) = line.strip_prefix("pub const OP_CODE: u32 = ") {
+                op_code = Some(code.trim_end_matches(';').parse().unwrap());
+            } else if line.contains("_info(Self::OP_CODE") {
+                // Elements that implement SdfElementCore by hand
+                check(op_code.take().unwrap(), line.contains("primitive_info"));
+                declared += 1;
+            }
+        }
+        assert_eq!(declared, registry.primitives().count() + registry.operations().count());
+    }
+}
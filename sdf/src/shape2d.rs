@@ -0,0 +1,299 @@
+use std::sync::Arc;
+use super::math::*;
+use super::{
+    component::SdfOpSpecificBlock,
+    curve::quad_bezier_distance,
+    text::SdfGlyphOutline,
+};
+
+fn dot2(v: Vec2) -> f32 {
+    v.dot(v)
+}
+
+// Closed polygon, in either winding order, with its points packed for the side buffer
+#[derive(Debug, Clone, PartialEq)]
+pub struct SdfPolygon {
+    points: Vec<Vec2>,
+    packed: Vec<f32>,
+}
+
+impl SdfPolygon {
+    pub fn new(points: Vec<Vec2>) -> Result<Self, &'static str> {
+        if points.len() < 3 {
+            return Err("Polygon needs at least three points!");
+        }
+        if points.iter().any(|point| !point.is_finite()) {
+            return Err("Polygon points must be finite!");
+        }
+        let packed = points.iter().flat_map(|point| [point.x, point.y]).collect();
+        Ok(SdfPolygon { points, packed })
+    }
+
+    pub fn points(&self) -> &[Vec2] {
+        &self.points
+    }
+
+    pub fn packed(&self) -> &[f32] {
+        &self.packed
+    }
+}
+
+/**
+ * Signed distance functions in the plane, for profiles that are extruded or revolved into 3D.
+ * The distances are exact, following Inigo Quilez's 2D distance functions.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum SdfShape2d {
+    Circle {
+        radius: f32,
+    },
+    Rect {
+        half_size: Vec2,
+    },
+    Polygon {
+        polygon: Arc<SdfPolygon>,
+    },
+    // Stroke of a quadratic bezier curve
+    QuadBezier {
+        points: [Vec2; 3],
+        thickness: f32,
+    },
+    // Stroke of a circular arc, centered on the +y axis and spanning `aperture` to either side
+    Arc {
+        radius: f32,
+        aperture: f32,
+        thickness: f32,
+    },
+    // Glyph of a font, or any other outline made of quadratic segments
+    Glyph {
+        outline: Arc<SdfGlyphOutline>,
+    },
+}
+
+impl SdfShape2d {
+    fn id(&self) -> u32 {
+        match self {
+            SdfShape2d::Circle { .. } => 0,
+            SdfShape2d::Rect { .. } => 1,
+            SdfShape2d::Polygon { .. } => 2,
+            SdfShape2d::QuadBezier { .. } => 3,
+            SdfShape2d::Arc { .. } => 4,
+            SdfShape2d::Glyph { .. } => 5,
+        }
+    }
+
+    pub fn distance(&self, p: Vec2) -> f32 {
+        match self {
+            SdfShape2d::Circle { radius } => p.length() - radius,
+            SdfShape2d::Rect { half_size } => {
+                let d = p.abs() - *half_size;
+                d.max(Vec2::ZERO).length() + d.x.max(d.y).min(0.0)
+            },
+            SdfShape2d::Polygon { polygon } => {
+                let points = polygon.points();
+                let mut d = dot2(p - points[0]);
+                let mut s = 1.0;
+                for i in 0..points.len() {
+                    let (v_i, v_j) = (points[i], points[(i + points.len() - 1) % points.len()]);
+                    let e = v_j - v_i;
+                    let w = p - v_i;
+                    let b = w - e * (w.dot(e) / e.dot(e).max(f32::MIN_POSITIVE)).clamp(0.0, 1.0);
+                    d = d.min(dot2(b));
+                    // Count crossings of a ray towards +x to find out if the point is inside
+                    let c = [p.y >= v_i.y, p.y < v_j.y, e.x * w.y > e.y * w.x];
+                    if c.iter().all(|c| *c) || c.iter().all(|c| !*c) {
+                        s = -s;
+                    }
+                }
+                s * d.sqrt()
+            },
+            SdfShape2d::QuadBezier { points, thickness } => {
+                quad_bezier_distance(p.extend(0.0), points.map(|point| point.extend(0.0))) - thickness
+            },
+            SdfShape2d::Arc { radius, aperture, thickness } => {
+                let sc = Vec2::new(aperture.sin(), aperture.cos());
+                let p = Vec2::new(p.x.abs(), p.y);
+                let d = if sc.y * p.x > sc.x * p.y {
+                    (p - sc * *radius).length()
+                } else {
+                    (p.length() - radius).abs()
+                };
+                d - thickness
+            },
+            SdfShape2d::Glyph { outline } => outline.distance(p),
+        }
+    }
+
+    // Corners of a box around the shape
+    pub fn bounds(&self) -> (Vec2, Vec2) {
+        match self {
+            SdfShape2d::Circle { radius } => (Vec2::splat(-radius), Vec2::splat(*radius)),
+            SdfShape2d::Rect { half_size } => (-*half_size, *half_size),
+            SdfShape2d::Polygon { polygon } => polygon.points().iter()
+                .fold((Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY)), |(min, max), point| {
+                    (min.min(*point), max.max(*point))
+                }),
+            // The curve stays inside of the triangle of its control points
+            SdfShape2d::QuadBezier { points, thickness } => (
+                points[0].min(points[1]).min(points[2]) - Vec2::splat(*thickness),
+                points[0].max(points[1]).max(points[2]) + Vec2::splat(*thickness),
+            ),
+            SdfShape2d::Arc { radius, thickness, .. } => (
+                Vec2::splat(-radius - thickness),
+                Vec2::splat(radius + thickness),
+            ),
+            SdfShape2d::Glyph { outline } => outline.bounds(),
+        }
+    }
+
+    /**
+     * Write the shape into vec4s[1] and vec4s[2] of an op-specific block, and its id into
+     * vec4s[0].x. Polygons and glyphs only store their point or segment count, with the points in
+     * the side buffer.
+     */
+    pub fn write_block(&self, block: &mut SdfOpSpecificBlock) {
+        block.vec4s[0].x = self.id() as f32;
+        match self {
+            SdfShape2d::Circle { radius } => block.vec4s[1].x = *radius,
+            SdfShape2d::Rect { half_size } => block.vec4s[1] = half_size.extend(0.0).extend(0.0),
+            SdfShape2d::Polygon { polygon } => block.vec4s[1].x = polygon.points().len() as f32,
+            SdfShape2d::QuadBezier { points, thickness } => {
+                block.vec4s[1] = Vec4::new(points[0].x, points[0].y, points[1].x, points[1].y);
+                block.vec4s[2] = Vec4::new(points[2].x, points[2].y, *thickness, 0.0);
+            },
+            SdfShape2d::Arc { radius, aperture, thickness } => {
+                block.vec4s[1] = Vec4::new(*radius, *aperture, *thickness, 0.0);
+            },
+            SdfShape2d::Glyph { outline } => block.vec4s[1].x = outline.segments().len() as f32,
+        }
+    }
+
+    pub fn from_block(block: &SdfOpSpecificBlock, side_buffer: &[f32]) -> Self {
+        let [a, b] = [block.vec4s[1], block.vec4s[2]];
+        match block.vec4s[0].x as u32 {
+            0 => SdfShape2d::Circle {
+                radius: a.x,
+            },
+            1 => SdfShape2d::Rect {
+                half_size: Vec2::new(a.x, a.y),
+            },
+            2 => {
+                let packed = side_buffer[block.side_offset as usize..][..a.x as usize * 2].to_vec();
+                let points = packed.chunks_exact(2).map(|point| Vec2::new(point[0], point[1])).collect();
+                SdfShape2d::Polygon {
+                    polygon: Arc::new(SdfPolygon { points, packed }),
+                }
+            },
+            3 => SdfShape2d::QuadBezier {
+                points: [Vec2::new(a.x, a.y), Vec2::new(a.z, a.w), Vec2::new(b.x, b.y)],
+                thickness: b.z,
+            },
+            4 => SdfShape2d::Arc {
+                radius: a.x,
+                aperture: a.y,
+                thickness: a.z,
+            },
+            _ => SdfShape2d::Glyph {
+                outline: Arc::new(SdfGlyphOutline::from_packed(
+                    &side_buffer[block.side_offset as usize..][..a.x as usize * 6],
+                )),
+            },
+        }
+    }
+
+    // Points of polygons and glyphs, for the side buffer
+    pub fn side_data(&self) -> &[f32] {
+        match self {
+            SdfShape2d::Polygon { polygon } => polygon.packed(),
+            SdfShape2d::Glyph { outline } => outline.packed(),
+            _ => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use std::{f32::consts::PI, sync::Arc};
+    use crate::shape2d::*;
+
+    pub fn star() -> SdfShape2d {
+        let points = (0..10)
+            .map(|i| {
+                let angle = i as f32 * PI / 5.0;
+                Vec2::new(angle.cos(), angle.sin()) * if i % 2 == 0 { 2.0 } else { 0.8 }
+            })
+            .collect();
+        SdfShape2d::Polygon {
+            polygon: Arc::new(SdfPolygon::new(points).unwrap()),
+        }
+    }
+
+    // Points on the boundary of a shape, found by densely sampling a box around it
+    fn boundary(shape: &SdfShape2d) -> Vec<Vec2> {
+        let (min, max) = shape.bounds();
+        let steps = 400;
+        let step = (max - min) / steps as f32;
+        let mut crossings = vec![];
+        for i in 0..steps {
+            for j in 0..steps {
+                let a = min + step * Vec2::new(i as f32, j as f32);
+                // Sign changes between neighboring samples mark the boundary
+                for b in [a + Vec2::new(step.x, 0.0), a + Vec2::new(0.0, step.y)] {
+                    let (da, db) = (shape.distance(a), shape.distance(b));
+                    if da.signum() != db.signum() {
+                        crossings.push(a + (b - a) * (da / (da - db)));
+                    }
+                }
+            }
+        }
+        crossings
+    }
+
+    #[test]
+    fn test_shape2d_distances() {
+        let mut rng = thread_rng();
+        let shapes = [
+            SdfShape2d::Circle { radius: 1.5 },
+            SdfShape2d::Rect { half_size: Vec2::new(1.0, 0.5) },
+            star(),
+            SdfShape2d::QuadBezier {
+                points: [Vec2::new(-1.5, -1.0), Vec2::new(0.0, 2.0), Vec2::new(1.5, -0.5)],
+                thickness: 0.2,
+            },
+            SdfShape2d::Arc { radius: 1.2, aperture: 2.0, thickness: 0.1 },
+        ];
+        for shape in shapes.iter() {
+            let (min, max) = shape.bounds();
+            let boundary = boundary(shape);
+            for _ in 0..40 {
+                let p = Vec2::new(rng.gen_range(-3.0..3.0), rng.gen_range(-3.0..3.0));
+                let distance = shape.distance(p);
+                let expected = boundary.iter()
+                    .map(|crossing| (*crossing - p).length())
+                    .fold(f32::INFINITY, f32::min);
+                assert!((distance.abs() - expected).abs() < 0.02,
+                    "{:?} at {}: {} instead of {}", shape, p, distance, expected);
+                // Anything inside of the shape is inside of its bounds
+                assert!(distance > 0.0 || (p.cmpge(min).all() && p.cmple(max).all()));
+            }
+
+            // Shapes survive being written to a block
+            let mut block = SdfOpSpecificBlock::ZERO;
+            shape.write_block(&mut block);
+            let side_buffer = [vec![7.0; 3], shape.side_data().to_vec()].concat();
+            block.side_offset = 3;
+            assert_eq!(&SdfShape2d::from_block(&block, &side_buffer), shape);
+        }
+
+        // A straight bezier is just a segment
+        let straight = SdfShape2d::QuadBezier {
+            points: [Vec2::ZERO, Vec2::X, Vec2::X * 2.0],
+            thickness: 0.0,
+        };
+        assert!((straight.distance(Vec2::new(1.0, 1.0)) - 1.0).abs() < 1e-6);
+
+        assert!(SdfPolygon::new(vec![Vec2::ZERO, Vec2::X]).is_err());
+    }
+}
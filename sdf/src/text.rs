@@ -0,0 +1,377 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+use super::math::*;
+use self_cell::self_cell;
+use super::{
+    node::SdfBuilder,
+    elements::{SdfUnion, SdfExtrude, SdfProfile},
+    shape2d::SdfShape2d,
+    curve::quad_bezier_distance,
+};
+
+// Bisection steps taken to find where a piece of an outline crosses a horizontal line
+pub(crate) const CROSSING_ITERATIONS: usize = 24;
+
+fn quad_point([a, b, c]: [Vec2; 3], t: f32) -> Vec2 {
+    let s = 1.0 - t;
+    a * s * s + b * 2.0 * s * t + c * t * t
+}
+
+/**
+ * Closed outline made of quadratic bezier segments, like the contours of TrueType glyphs.
+ * Straight edges are segments with their control point in the middle.
+ *
+ * The distance to the outline is exact, and its sign comes from the nonzero winding rule, so
+ * overlapping contours and holes work the way they do in fonts.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SdfGlyphOutline {
+    segments: Vec<[Vec2; 3]>,
+    packed: Vec<f32>,
+    bounds: (Vec2, Vec2),
+}
+
+impl SdfGlyphOutline {
+    // Segments have to form closed contours, but don't need to be in any order
+    pub fn new(segments: Vec<[Vec2; 3]>) -> Result<Self, &'static str> {
+        if segments.is_empty() {
+            return Err("Glyph outline has no segments!");
+        }
+        if segments.iter().flatten().any(|point| !point.is_finite()) {
+            return Err("Glyph outline points must be finite!");
+        }
+        let packed = segments.iter()
+            .flatten()
+            .flat_map(|point| [point.x, point.y])
+            .collect();
+        // Segments stay inside of the triangles of their control points
+        let bounds = segments.iter()
+            .flatten()
+            .fold((Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY)), |(min, max), point| {
+                (min.min(*point), max.max(*point))
+            });
+        Ok(SdfGlyphOutline { segments, packed, bounds })
+    }
+
+    pub fn from_packed(packed: &[f32]) -> Self {
+        let segments = packed.chunks_exact(6)
+            .map(|segment| [
+                Vec2::new(segment[0], segment[1]),
+                Vec2::new(segment[2], segment[3]),
+                Vec2::new(segment[4], segment[5]),
+            ])
+            .collect();
+        Self::new(segments).expect("Packed glyph outlines come from valid ones")
+    }
+
+    pub fn segments(&self) -> &[[Vec2; 3]] {
+        &self.segments
+    }
+
+    pub fn packed(&self) -> &[f32] {
+        &self.packed
+    }
+
+    pub fn bounds(&self) -> (Vec2, Vec2) {
+        self.bounds
+    }
+
+    // Sum of the directions of the contours that cross a ray from the point towards +x
+    pub fn winding(&self, p: Vec2) -> i32 {
+        let mut winding = 0;
+        for segment in self.segments.iter() {
+            let [a, b, c] = *segment;
+            // Split the segment where it turns around vertically, so each piece crosses at most once
+            let denominator = a.y - 2.0 * b.y + c.y;
+            let turn = if denominator.abs() > f32::EPSILON { (a.y - b.y) / denominator } else { -1.0 };
+            let pieces = if turn > 0.0 && turn < 1.0 { [(0.0, turn), (turn, 1.0)] } else { [(0.0, 1.0), (1.0, 1.0)] };
+            for (t0, t1) in pieces {
+                let (start, end) = (quad_point(*segment, t0), quad_point(*segment, t1));
+                if (start.y <= p.y) == (end.y <= p.y) {
+                    continue;
+                }
+                let (mut low, mut high) = (t0, t1);
+                for _ in 0..CROSSING_ITERATIONS {
+                    let mid = (low + high) / 2.0;
+                    if (quad_point(*segment, mid).y <= p.y) == (start.y <= p.y) {
+                        low = mid;
+                    } else {
+                        high = mid;
+                    }
+                }
+                if quad_point(*segment, (low + high) / 2.0).x > p.x {
+                    winding += if end.y > start.y { 1 } else { -1 };
+                }
+            }
+        }
+        winding
+    }
+
+    pub fn distance(&self, p: Vec2) -> f32 {
+        let distance = self.segments.iter()
+            .map(|segment| quad_bezier_distance(p.extend(0.0), segment.map(|point| point.extend(0.0))))
+            .fold(f32::INFINITY, f32::min);
+        if self.winding(p) != 0 { -distance } else { distance }
+    }
+}
+
+// Collects the contours of a glyph as quadratic segments, scaled from font units
+struct OutlineCollector {
+    scale: f32,
+    segments: Vec<[Vec2; 3]>,
+    start: Vec2,
+    current: Vec2,
+}
+
+impl OutlineCollector {
+    fn point(&self, x: f32, y: f32) -> Vec2 {
+        Vec2::new(x, y) * self.scale
+    }
+
+    fn quad(&mut self, control: Vec2, to: Vec2) {
+        self.segments.push([self.current, control, to]);
+        self.current = to;
+    }
+
+    fn line(&mut self, to: Vec2) {
+        self.quad((self.current + to) / 2.0, to);
+    }
+}
+
+impl ttf_parser::OutlineBuilder for OutlineCollector {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.start = self.point(x, y);
+        self.current = self.start;
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.line(self.point(x, y));
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (control, to) = (self.point(x1, y1), self.point(x, y));
+        self.quad(control, to);
+    }
+
+    // CFF fonts use cubics, which are split in half and approximated with a quadratic per half
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let [p0, p1, p2, p3] = [self.current, self.point(x1, y1), self.point(x2, y2), self.point(x, y)];
+        let (p01, p12, p23) = ((p0 + p1) / 2.0, (p1 + p2) / 2.0, (p2 + p3) / 2.0);
+        let (p012, p123) = ((p01 + p12) / 2.0, (p12 + p23) / 2.0);
+        let mid = (p012 + p123) / 2.0;
+        for [a, b, c, d] in [[p0, p01, p012, mid], [mid, p123, p23, p3]] {
+            self.quad((3.0 * (b + c) - a - d) / 4.0, d);
+        }
+    }
+
+    fn close(&mut self) {
+        if self.current != self.start {
+            self.line(self.start);
+        }
+    }
+}
+
+type Face<'a> = ttf_parser::Face<'a>;
+
+// Face parsed once, which borrows from the raw data it's kept alongside
+self_cell!(
+    struct SdfFontFace {
+        owner: Vec<u8>,
+        #[covariant]
+        dependent: Face,
+    }
+);
+
+/**
+ * TrueType or OpenType font, parsed when it's loaded. Glyphs come out in the xy plane with y up
+ * and the baseline on the x axis, scaled so that the em square is `size` wide.
+ */
+pub struct SdfFont {
+    face: SdfFontFace,
+}
+
+impl SdfFont {
+    pub fn new(data: Vec<u8>) -> Result<Self, &'static str> {
+        let face = SdfFontFace::try_new(data, |data| Face::from_slice(data, 0))
+            .map_err(|_| "Failed parsing font!")?;
+        Ok(SdfFont { face })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, &'static str> {
+        Self::new(std::fs::read(path).map_err(|_| "Failed reading font file!")?)
+    }
+
+    fn face(&self) -> &Face<'_> {
+        self.face.borrow_dependent()
+    }
+
+    fn scale(&self, size: f32) -> f32 {
+        size / self.face().units_per_em() as f32
+    }
+
+    // Outline of the glyph of a character, or none for characters without one, like spaces
+    pub fn glyph(&self, character: char, size: f32) -> Option<SdfGlyphOutline> {
+        let face = self.face();
+        let mut collector = OutlineCollector {
+            scale: self.scale(size),
+            segments: Vec::new(),
+            start: Vec2::ZERO,
+            current: Vec2::ZERO,
+        };
+        face.outline_glyph(face.glyph_index(character)?, &mut collector)?;
+        SdfGlyphOutline::new(collector.segments).ok()
+    }
+
+    // Horizontal advance from a character to the next one, including the kerning between them
+    pub fn advance(&self, character: char, next: Option<char>, size: f32) -> f32 {
+        let face = self.face();
+        let glyph = face.glyph_index(character).unwrap_or_default();
+        let advance = face.glyph_hor_advance(glyph).unwrap_or(0) as f32;
+        let kerning = next
+            .and_then(|next| face.glyph_index(next))
+            .and_then(|next| {
+                face.tables().kern?.subtables.into_iter()
+                    .filter(|subtable| subtable.horizontal && !subtable.variable)
+                    .find_map(|subtable| subtable.glyphs_kerning(glyph, next))
+            })
+            .unwrap_or(0) as f32;
+        (advance + kerning) * self.scale(size)
+    }
+
+    /**
+     * Lay out a line of text from the origin along +x and extrude it along z. Every glyph is an
+     * extrusion of its own in a union, so each gets its own bounding box and far away glyphs of
+     * long strings get pruned.
+     *
+     * Kerning comes from the legacy kern table; fonts that only kern through GPOS aren't kerned.
+     */
+    pub fn text(&self, text: &str, size: f32, depth: f32) -> Result<SdfBuilder, &'static str> {
+        let mut outlines = HashMap::new();
+        let mut glyphs = Vec::new();
+        let mut pen = 0.0;
+        let characters: Vec<char> = text.chars().collect();
+        for (i, character) in characters.iter().enumerate() {
+            let outline = outlines.entry(*character)
+                .or_insert_with(|| self.glyph(*character, size).map(Arc::new))
+                .clone();
+            if let Some(outline) = outline {
+                glyphs.push(SdfBuilder::primitive(SdfProfile {
+                    shape: SdfShape2d::Glyph { outline },
+                })
+                    .operation(SdfExtrude {
+                        height: depth,
+                    })
+                    .transform(Transform::from_xyz(pen, 0.0, 0.0)));
+            }
+            pen += self.advance(*character, characters.get(i + 1).copied(), size);
+        }
+
+        let mut glyphs = glyphs.into_iter();
+        let first = glyphs.next().ok_or("Text has no visible glyphs!")?;
+        Ok(glyphs.fold(
+            first.operation(SdfUnion::new(0.0)),
+            |union, glyph| union.with(glyph),
+        ))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use float_cmp::approx_eq;
+    use crate::{text::*, faux_shader};
+
+    // Contour of a box as straight segments, counter-clockwise unless flipped
+    fn box_contour(min: Vec2, max: Vec2, flip: bool) -> Vec<[Vec2; 3]> {
+        let mut corners = [min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)];
+        if flip {
+            corners.reverse();
+        }
+        (0..4).map(|i| {
+            let (a, b) = (corners[i], corners[(i + 1) % 4]);
+            [a, (a + b) / 2.0, b]
+        }).collect()
+    }
+
+    // Subset of DejaVu Sans with just the characters the tests lay out
+    pub fn test_font() -> SdfFont {
+        SdfFont::new(include_bytes!("../tests/fonts/DejaVuSans-subset.ttf").to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_glyph_outline() {
+        let mut rng = thread_rng();
+        // Square frame, with the hole going the other way around
+        let frame = SdfGlyphOutline::new([
+            box_contour(Vec2::splat(-2.0), Vec2::splat(2.0), false),
+            box_contour(Vec2::splat(-1.0), Vec2::splat(1.0), true),
+        ].concat()).unwrap();
+        for _ in 0..200 {
+            let p = Vec2::new(rng.gen_range(-3.0..3.0), rng.gen_range(-3.0..3.0));
+            let outer = {
+                let d = p.abs() - Vec2::splat(2.0);
+                d.max(Vec2::ZERO).length() + d.max_element().min(0.0)
+            };
+            let inner = {
+                let d = p.abs() - Vec2::splat(1.0);
+                d.max(Vec2::ZERO).length() + d.max_element().min(0.0)
+            };
+            assert!(approx_eq!(f32, frame.distance(p), outer.max(-inner), epsilon = 1e-5));
+        }
+
+        // Overlapping contours in the same direction are a union under the nonzero rule
+        let overlap = SdfGlyphOutline::new([
+            box_contour(Vec2::splat(-2.0), Vec2::splat(1.0), false),
+            box_contour(Vec2::splat(-1.0), Vec2::splat(2.0), false),
+        ].concat()).unwrap();
+        assert_eq!(overlap.winding(Vec2::ZERO), 2);
+        assert!(overlap.distance(Vec2::ZERO) < 0.0);
+
+        // A curved contour: a lens of two quadratic arcs
+        let lens = SdfGlyphOutline::new(vec![
+            [Vec2::new(-1.0, 0.0), Vec2::new(0.0, 1.0), Vec2::new(1.0, 0.0)],
+            [Vec2::new(1.0, 0.0), Vec2::new(0.0, -1.0), Vec2::new(-1.0, 0.0)],
+        ]).unwrap();
+        assert!(lens.distance(Vec2::ZERO) < 0.0 && lens.distance(Vec2::new(0.0, 0.6)) > 0.0);
+        assert!(approx_eq!(f32, lens.distance(Vec2::new(0.0, 0.5)), 0.0, epsilon = 1e-5));
+        assert_eq!(&SdfGlyphOutline::from_packed(lens.packed()), &lens);
+
+        assert!(SdfGlyphOutline::new(vec![]).is_err());
+        assert!(SdfFont::new(vec![0; 16]).is_err());
+    }
+
+    #[test]
+    fn test_text_layout() {
+        let font = test_font();
+        let glyph = font.glyph('o', 1.0).unwrap();
+        let (min, max) = glyph.bounds();
+        // The middle of an 'o' is a hole, its ring isn't
+        let center = (min + max) / 2.0;
+        assert!(glyph.distance(center) > 0.0);
+        assert!(glyph.distance(Vec2::new(min.x + 0.03, center.y)) < 0.0);
+        assert!(font.glyph(' ', 1.0).is_none());
+
+        // Kerning pulls pairs like "AV" together
+        assert!(font.advance('A', Some('V'), 1.0) < font.advance('A', None, 1.0));
+
+        // One primitive per visible glyph, each with its own box
+        let sdf_tree = font.text("Hi there", 1.0, 0.2).unwrap().finalize();
+        assert_eq!(sdf_tree.slots().len(), 7);
+        let h = font.glyph('H', 1.0).unwrap();
+        let probe = Vec3::new(0.05, 0.3, 0.0);
+        let expected = h.distance(probe.truncate()).max(-0.1);
+        assert!(approx_eq!(f32, sdf_tree.nearest_neighbor(probe).distance, expected, epsilon = 1e-5));
+
+        // Glyphs survive the trip through the buffer
+        let mut rng = thread_rng();
+        let buffer = sdf_tree.expanded().make_buffer();
+        for _ in 0..50 {
+            let point = Vec3::new(rng.gen_range(-0.5..4.5), rng.gen_range(-0.5..1.2), rng.gen_range(-0.5..0.5));
+            let tree_result = sdf_tree.nearest_neighbor(point).distance;
+            let buffer_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+            assert!(approx_eq!(f32, tree_result, buffer_result, epsilon = 1e-4),
+                "Buffer Text Failed! Tree Result: {}, Buffer Result: {}", tree_result, buffer_result);
+        }
+        assert!(font.text("   ", 1.0, 0.2).is_err());
+    }
+}
@@ -0,0 +1,542 @@
+use std::collections::BTreeSet;
+use stable_vec::StableVec;
+use super::math::*;
+use super::{
+    obb::*,
+    node::*,
+    elements::SdfElement,
+};
+
+// Handle to a node of an SdfTree. Handles stay valid until their node is removed, and are never reused.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+struct SdfTreeNode {
+    intern: Box<dyn SdfElement>,
+    transform: Transform,
+    offset: DVec3,
+    bbox: SdfBoundingBox,
+    material: u32,
+    sweep: Option<SdfBoundingBox>,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    depth: usize,
+}
+
+/**
+ * Editable SDF tree with stable node handles.
+ *
+ * Edits only mark nodes as dirty. [`SdfTree::refit()`] then recalculates the bounding boxes of the
+ * dirty nodes from the bottom up, and only moves on to a node's parent if its box actually changed,
+ * so moving a single node only touches its ancestors.
+ */
+pub struct SdfTree {
+    nodes: StableVec<SdfTreeNode>,
+    root: NodeId,
+    // Ordered by depth, so that the deepest nodes are refit first
+    dirty: BTreeSet<(usize, NodeId)>,
+    // Nodes whose bounding box, element or material changed since the last call to take_changed()
+    changed: BTreeSet<NodeId>,
+    topology_version: u64,
+}
+
+impl SdfTree {
+    pub fn new(root: SdfNode) -> Self {
+        let mut tree = SdfTree {
+            nodes: StableVec::new(),
+            root: NodeId(0),
+            dirty: BTreeSet::new(),
+            changed: BTreeSet::new(),
+            topology_version: 0,
+        };
+        tree.root = tree.add_subtree(root, None, 0);
+        tree
+    }
+
+    fn add_subtree(&mut self, mut node: SdfNode, parent: Option<NodeId>, depth: usize) -> NodeId {
+        let bbox = node.calc_bbox_assign();
+        let id = NodeId(self.nodes.push(SdfTreeNode {
+            intern: node.element().box_clone(),
+            transform: node.transform(),
+            offset: node.offset(),
+            bbox,
+            material: node.material(),
+            sweep: node.sweep(),
+            parent,
+            children: Vec::with_capacity(node.slots().len()),
+            depth,
+        }));
+        for child in node.take_slots() {
+            let child_id = self.add_subtree(child, Some(id), depth + 1);
+            self.nodes[id.0].children.push(child_id);
+        }
+        id
+    }
+
+    fn take_subtree(&mut self, id: NodeId) -> SdfNode {
+        let tree_node = self.nodes.remove(id.0).unwrap();
+        self.dirty.remove(&(tree_node.depth, id));
+        let slots = tree_node.children.into_iter()
+            .map(|child| self.take_subtree(child))
+            .collect();
+        SdfNode::from_tree(
+            tree_node.intern,
+            slots,
+            tree_node.transform,
+            tree_node.offset,
+            tree_node.bbox,
+            tree_node.material,
+            tree_node.sweep,
+            None,
+        )
+    }
+
+    fn node(&self, id: NodeId) -> &SdfTreeNode {
+        self.nodes.get(id.0).expect("Tried using a removed SDF tree node!")
+    }
+
+    fn mark_dirty(&mut self, id: NodeId) {
+        self.dirty.insert((self.nodes[id.0].depth, id));
+    }
+
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.nodes.has_element_at(id.0)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.num_elements()
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).parent
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        self.node(id).children.as_slice()
+    }
+
+    pub fn element(&self, id: NodeId) -> &dyn SdfElement {
+        self.node(id).intern.as_ref()
+    }
+
+    pub fn transform(&self, id: NodeId) -> Transform {
+        self.node(id).transform
+    }
+
+    pub fn offset(&self, id: NodeId) -> DVec3 {
+        self.node(id).offset
+    }
+
+    pub fn material(&self, id: NodeId) -> u32 {
+        self.node(id).material
+    }
+
+    // Bounding box of a node, as of the last refit
+    pub fn bbox(&self, id: NodeId) -> SdfBoundingBox {
+        self.node(id).bbox
+    }
+
+    // Box that the parent is fit around, i.e. the sweep of the node if it has one
+    pub fn outer_bbox(&self, id: NodeId) -> SdfBoundingBox {
+        let tree_node = self.node(id);
+        tree_node.sweep.unwrap_or(tree_node.bbox)
+    }
+
+    pub fn sweep(&self, id: NodeId) -> Option<SdfBoundingBox> {
+        self.node(id).sweep
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    // Bumped whenever nodes are added or removed, or an element is swapped for one with different slots
+    pub fn topology_version(&self) -> u64 {
+        self.topology_version
+    }
+
+    // Nodes whose bounding box, element or material changed since the last call, as of the last refit
+    pub fn take_changed(&mut self) -> Vec<NodeId> {
+        let changed = std::mem::take(&mut self.changed);
+        changed.into_iter()
+            .filter(|id| self.contains(*id))
+            .collect()
+    }
+
+    pub fn insert_child(&mut self, parent: NodeId, child: SdfNode) -> Result<NodeId, &'static str> {
+        let parent_node = self.nodes.get(parent.0).ok_or("Invalid node handle!")?;
+        let intern_info = parent_node.intern.get_info();
+        if intern_info.is_primitive {
+            return Err("Can't set slots on primitive!");
+        } else if parent_node.children.len() >= intern_info.num_slots() {
+            return Err("Slots already full!");
+        }
+        let depth = parent_node.depth + 1;
+        let id = self.add_subtree(child, Some(parent), depth);
+        self.nodes[parent.0].children.push(id);
+        self.mark_dirty(parent);
+        self.topology_version += 1;
+        Ok(id)
+    }
+
+    /**
+     * Remove a node and everything below it, and hand it back as a finished [`SdfNode`].
+     *
+     * Only children of unions can be removed, since every other operation needs all of its slots.
+     */
+    pub fn remove(&mut self, id: NodeId) -> Result<SdfNode, &'static str> {
+        let parent = self.nodes.get(id.0)
+            .ok_or("Invalid node handle!")?
+            .parent
+            .ok_or("Can't remove the root of an SDF tree!")?;
+        if !self.nodes[parent.0].intern.get_info().is_union {
+            return Err("Can only remove children of unions!");
+        }
+        self.nodes[parent.0].children.retain(|child| *child != id);
+        self.mark_dirty(parent);
+        self.topology_version += 1;
+        Ok(self.take_subtree(id))
+    }
+
+    // Swap out the element of a node, keeping its children. Returns the old element.
+    pub fn replace_element(&mut self, id: NodeId, intern: Box<dyn SdfElement>) -> Result<Box<dyn SdfElement>, &'static str> {
+        let tree_node = self.nodes.get_mut(id.0).ok_or("Invalid node handle!")?;
+        let intern_info = intern.get_info();
+        let num_children = tree_node.children.len();
+        if intern_info.is_primitive && num_children > 0 {
+            return Err("Can't replace an operation with a primitive!");
+        } else if !intern_info.is_primitive && num_children > intern_info.num_slots() {
+            return Err("Too many children for new element!");
+        } else if !intern_info.is_primitive && !intern_info.is_union && num_children < intern_info.num_slots() {
+            return Err("Not enough children for new element!");
+        }
+        let old = std::mem::replace(&mut tree_node.intern, intern);
+        if old.get_info() != intern_info {
+            self.topology_version += 1;
+        }
+        self.changed.insert(id);
+        self.mark_dirty(id);
+        Ok(old)
+    }
+
+    // Materials don't affect bounding boxes, so this doesn't need a refit
+    pub fn set_material(&mut self, id: NodeId, material: u32) -> Result<(), &'static str> {
+        self.nodes.get_mut(id.0).ok_or("Invalid node handle!")?.material = material;
+        self.changed.insert(id);
+        Ok(())
+    }
+
+    pub fn set_param(&mut self, id: NodeId, name: &str, value: f32) -> Result<(), &'static str> {
+        self.nodes.get_mut(id.0).ok_or("Invalid node handle!")?.intern.set_param(name, value)?;
+        self.changed.insert(id);
+        self.mark_dirty(id);
+        Ok(())
+    }
+
+    /**
+     * Fit the parent of a node around a fixed box instead of the node's own box.
+     *
+     * As long as the node stays inside of its sweep, moving it won't refit or patch anything
+     * above it.
+     */
+    pub fn set_sweep(&mut self, id: NodeId, sweep: Option<SdfBoundingBox>) -> Result<(), &'static str> {
+        let tree_node = self.nodes.get_mut(id.0).ok_or("Invalid node handle!")?;
+        tree_node.sweep = sweep;
+        let parent = tree_node.parent;
+        self.changed.insert(id);
+        if let Some(parent) = parent {
+            self.mark_dirty(parent);
+        }
+        Ok(())
+    }
+
+    pub fn set_transform(&mut self, id: NodeId, transform: Transform) -> Result<(), &'static str> {
+        self.nodes.get_mut(id.0).ok_or("Invalid node handle!")?.transform = transform;
+        self.mark_dirty(id);
+        Ok(())
+    }
+
+    // Translation applied after the transform, see SdfBuilder::translate()
+    pub fn set_offset(&mut self, id: NodeId, offset: DVec3) -> Result<(), &'static str> {
+        self.nodes.get_mut(id.0).ok_or("Invalid node handle!")?.offset = offset;
+        self.mark_dirty(id);
+        Ok(())
+    }
+
+    /**
+     * Recalculate the bounding boxes of the dirty nodes, deepest first. A parent is only refit if
+     * the box of one of its children changed, and the child has no sweep.
+     *
+     * Returns the number of nodes that were refit.
+     */
+    pub fn refit(&mut self) -> usize {
+        let mut refit_count = 0;
+        while let Some((_, id)) = self.dirty.pop_last() {
+            let tree_node = &self.nodes[id.0];
+            let slots_bboxes = tree_node.children.iter()
+                .map(|child| self.outer_bbox(*child))
+                .collect::<Vec<SdfBoundingBox>>();
+            let bbox = SdfNode::fit_bbox(
+                tree_node.intern.as_ref(),
+                tree_node.transform,
+                tree_node.offset,
+                slots_bboxes.as_slice(),
+            );
+            let changed = bbox != tree_node.bbox;
+            let parent = tree_node.parent.filter(|_| tree_node.sweep.is_none());
+            self.nodes[id.0].bbox = bbox;
+            refit_count += 1;
+            if changed {
+                self.changed.insert(id);
+                if let Some(parent) = parent {
+                    self.mark_dirty(parent);
+                }
+            }
+        }
+        refit_count
+    }
+
+    // Same as SdfNode::lipschitz, for a subtree of this tree
+    pub fn lipschitz(&self, id: NodeId) -> f32 {
+        let tree_node = self.node(id);
+        if tree_node.intern.get_info().is_union && tree_node.children.len() > 1 {
+            return tree_node.intern.lipschitz();
+        }
+        tree_node.intern.lipschitz() * tree_node.children.iter()
+            .map(|child| self.lipschitz(*child))
+            .fold(1.0, f32::max)
+    }
+
+    // Finished SdfNode copy of a subtree, with every node tagged with its handle
+    pub fn to_node(&self, id: NodeId) -> SdfNode {
+        assert!(
+            !self.is_dirty(),
+            "Tried building an SDF node from a tree with stale bounding boxes!"
+        );
+        let tree_node = self.node(id);
+        SdfNode::from_tree(
+            tree_node.intern.clone(),
+            tree_node.children.iter().map(|child| self.to_node(*child)).collect(),
+            tree_node.transform,
+            tree_node.offset,
+            tree_node.bbox,
+            tree_node.material,
+            tree_node.sweep,
+            Some(id),
+        )
+    }
+
+    pub fn expanded(&self) -> ExpandedSdfNode {
+        self.to_node(self.root).expanded()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::prelude::*;
+    use crate::math::*;
+    use float_cmp::approx_eq;
+    use crate::{
+        tree::*,
+        elements::*,
+        faux_shader,
+    };
+
+    fn sphere_at(center: Vec3, radius: f32) -> SdfBuilder {
+        SdfBuilder::primitive(SdfSphere {
+            radius,
+        })
+        .transform(Transform::from_translation(center))
+    }
+
+    fn union_of(spheres: Vec<SdfBuilder>) -> SdfNode {
+        spheres.into_iter()
+            .fold(
+                SdfBuilder::primitive(SdfSphere {
+                    radius: 0.5,
+                })
+                .operation(SdfUnion::new(0.0)),
+                |acc, sphere| acc.with(sphere)
+            )
+            .finalize()
+    }
+
+    /**
+     * Check the distances of a tree against ground truth, with both the CPU and the faux shader
+     * nearest neighbor searches.
+     */
+    fn check_distances(tree: &SdfTree, ground_truth: impl Fn(Vec3) -> f32) {
+        let mut rng = thread_rng();
+        let node = tree.to_node(tree.root());
+        let buffer = tree.expanded().make_buffer();
+        for _ in 0..100 {
+            let point = Vec3::new(
+                rng.gen_range(-30.0..30.0),
+                rng.gen_range(-30.0..30.0),
+                rng.gen_range(-30.0..30.0),
+            );
+            let truth = ground_truth(point);
+            let tree_result = node.nearest_neighbor(point).distance;
+            let buffer_result = faux_shader::nearest_neighbor(&buffer, point.extend(1.0)).0;
+            assert!(approx_eq!(f32, truth, tree_result, epsilon = 1e-3),
+                "Tree failed! Ground Truth: {}, NN Result: {}", truth, tree_result);
+            assert!(approx_eq!(f32, truth, buffer_result, epsilon = 1e-3),
+                "Buffer failed! Ground Truth: {}, NN Result: {}", truth, buffer_result);
+        }
+    }
+
+    fn sphere_dist(point: Vec3, spheres: &[(Vec3, f32)]) -> f32 {
+        spheres.iter()
+            .map(|(center, radius)| (point - *center).length() - radius)
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    #[test]
+    fn test_refit_dirty_ancestors() {
+        // Eight groups of eight spheres
+        let groups = (0..8)
+            .map(|group| (1..8)
+                .fold(
+                    SdfBuilder::primitive(SdfSphere {
+                        radius: 1.0,
+                    })
+                    .operation(SdfUnion::new(0.0)),
+                    |acc, i| acc.with(sphere_at(Vec3::new(0.0, i as f32 * 2.5, 0.0), 1.0))
+                )
+                .transform(Transform::from_xyz(group as f32 * 6.0 - 20.0, 0.0, 0.0)))
+            .collect::<Vec<SdfBuilder>>();
+        let mut tree = SdfTree::new(union_of(groups));
+        assert_eq!(tree.node_count(), 2 + 8 * 9);
+        assert_eq!(tree.refit(), 0);
+
+        // The first child of the root is its own sphere
+        let group = tree.children(tree.root())[4];
+        let prop = tree.children(group)[5];
+        tree.set_transform(prop, Transform::from_xyz(0.0, -4.0, 2.0)).unwrap();
+        assert!(tree.is_dirty());
+        // Only the prop, its group and the root
+        assert_eq!(tree.refit(), 3);
+        assert!(!tree.is_dirty());
+
+        // Moving the prop to where it already is doesn't touch its ancestors
+        tree.set_transform(prop, Transform::from_xyz(0.0, -4.0, 2.0)).unwrap();
+        assert_eq!(tree.refit(), 1);
+
+        // Moving a whole group
+        tree.set_transform(group, Transform::from_xyz(0.0, 0.0, -10.0)).unwrap();
+        assert_eq!(tree.refit(), 2);
+
+        let mut spheres = vec![(Vec3::ZERO, 0.5)];
+        for group_ind in 0..8 {
+            let offset = match group_ind {
+                3 => Vec3::new(0.0, 0.0, -10.0),
+                _ => Vec3::new(group_ind as f32 * 6.0 - 20.0, 0.0, 0.0),
+            };
+            spheres.push((offset, 1.0));
+            for i in 1..8 {
+                spheres.push(match (group_ind, i) {
+                    (3, 5) => (offset + Vec3::new(0.0, -4.0, 2.0), 1.0),
+                    _ => (offset + Vec3::new(0.0, i as f32 * 2.5, 0.0), 1.0),
+                });
+            }
+        }
+        check_distances(&tree, |point| sphere_dist(point, &spheres));
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let centers = (0..6)
+            .map(|i| Vec3::new(i as f32 * 4.0, 0.0, 0.0))
+            .collect::<Vec<Vec3>>();
+        let mut tree = SdfTree::new(union_of(centers.iter().map(|center| sphere_at(*center, 1.0)).collect()));
+        let root = tree.root();
+        let children = tree.children(root).to_vec();
+        assert_eq!(children.len(), 7);
+
+        let removed = tree.remove(children[2]).unwrap();
+        assert!(!tree.contains(children[2]));
+        assert!(children.iter().filter(|child| **child != children[2]).all(|child| tree.contains(*child)));
+        assert_eq!(tree.parent(children[3]), Some(root));
+        tree.refit();
+        let mut spheres = vec![(Vec3::ZERO, 0.5)];
+        spheres.extend(centers.iter().enumerate().filter(|(i, _)| *i != 1).map(|(_, center)| (*center, 1.0)));
+        check_distances(&tree, |point| sphere_dist(point, &spheres));
+
+        // Handing the removed node back gives it a new handle
+        let reinserted = tree.insert_child(root, removed).unwrap();
+        assert_ne!(reinserted, children[2]);
+        let far = tree.insert_child(root, sphere_at(Vec3::new(0.0, 0.0, 15.0), 2.0).finalize()).unwrap();
+        assert_eq!(tree.refit(), 1);
+        assert!(tree.bbox(root).contains(Vec3::new(0.0, 0.0, 16.5)));
+        spheres.push((centers[1], 1.0));
+        spheres.push((Vec3::new(0.0, 0.0, 15.0), 2.0));
+        check_distances(&tree, |point| sphere_dist(point, &spheres));
+
+        tree.remove(far).unwrap();
+        tree.refit();
+        assert!(!tree.bbox(root).contains(Vec3::new(0.0, 0.0, 16.5)));
+    }
+
+    #[test]
+    fn test_replace_transform() {
+        let mut tree = SdfTree::new(union_of(vec![sphere_at(Vec3::new(5.0, 0.0, 0.0), 1.0)]));
+        let root = tree.root();
+        let sphere = tree.children(root)[1];
+        let old = tree.replace_element(sphere, Box::new(SdfSphere {
+            radius: 3.0,
+        })).unwrap();
+        assert!(old.get_info() == SdfElementInfo::primitive_info(0));
+        // Transforming the union moves everything below it
+        tree.set_transform(root, Transform::from_xyz(0.0, 10.0, 0.0)).unwrap();
+        tree.refit();
+        check_distances(&tree, |point| sphere_dist(point, &[
+            (Vec3::new(0.0, 10.0, 0.0), 0.5),
+            (Vec3::new(5.0, 10.0, 0.0), 3.0),
+        ]));
+
+        // Removing one of two children leaves a single child union, which must keep its transform
+        tree.remove(tree.children(root)[0]).unwrap();
+        tree.refit();
+        check_distances(&tree, |point| sphere_dist(point, &[(Vec3::new(5.0, 10.0, 0.0), 3.0)]));
+    }
+
+    #[test]
+    fn test_edit_errors() {
+        let mut tree = SdfTree::new(
+            sphere_at(Vec3::ZERO, 1.0)
+                .operation(SdfCaaClone {
+                    displacement: Vec3::splat(4.0),
+                    neg_limit: Vec3::splat(-2.0),
+                    pos_limit: Vec3::splat(2.0),
+                })
+                .operation(SdfUnion::new(0.0))
+                .finalize()
+        );
+        let root = tree.root();
+        let clone = tree.children(root)[0];
+        let sphere = tree.children(clone)[0];
+        let unit = || SdfBuilder::primitive(SdfSphere {
+            radius: 1.0,
+        }).finalize();
+
+        assert!(tree.remove(root).is_err());
+        assert!(tree.remove(sphere).is_err());
+        assert!(tree.insert_child(sphere, unit()).is_err());
+        assert!(tree.insert_child(clone, unit()).is_err());
+        assert!(tree.replace_element(clone, Box::new(SdfSphere {
+            radius: 1.0,
+        })).is_err());
+        assert!(!tree.is_dirty());
+
+        tree.remove(clone).unwrap();
+        assert!(!tree.contains(clone) && !tree.contains(sphere));
+        assert!(tree.set_transform(sphere, Transform::identity()).is_err());
+        assert!(tree.insert_child(clone, unit()).is_err());
+        assert!(tree.remove(clone).is_err());
+        assert_eq!(tree.node_count(), 1);
+    }
+}
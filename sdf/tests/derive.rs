@@ -0,0 +1,6 @@
+// Fields that #[derive(SdfElement)] can't pack have to fail to compile, instead of overwriting each other
+#[test]
+fn test_derive_errors() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
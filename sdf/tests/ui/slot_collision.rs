@@ -0,0 +1,13 @@
+use sdf::{elements::*, math::*};
+
+// The float goes into the z of the Vec3 that's already there
+#[derive(Clone, Debug, SdfElement)]
+#[sdf(op = 100, primitive)]
+struct Collision {
+    #[sdf(vec4s = 0)]
+    center: Vec3,
+    #[sdf(vec4s = 0, component = 2)]
+    radius: f32,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use sdf::{elements::*, math::*};
+
+// There are only three vec4s in a block
+#[derive(Clone, Debug, SdfElement)]
+#[sdf(op = 100, primitive)]
+struct Overflow {
+    #[sdf(vec4s = 3)]
+    center: Vec3,
+}
+
+fn main() {}
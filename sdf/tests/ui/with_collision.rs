@@ -0,0 +1,18 @@
+use sdf::{elements::*, component::*, math::*};
+
+fn write_pair(pair: &[Vec4; 2], block: &mut SdfOpSpecificBlock) {
+    block.vec4s[0] = pair[0];
+    block.vec4s[1] = pair[1];
+}
+
+// The function writes all of vec4s[1], including the w the float wants
+#[derive(Clone, Debug, SdfElement)]
+#[sdf(op = 100, primitive)]
+struct Collision {
+    #[sdf(with = "write_pair", vec4s = 0, count = 2)]
+    pair: [Vec4; 2],
+    #[sdf(vec4s = 1, component = 3)]
+    radius: f32,
+}
+
+fn main() {}
@@ -0,0 +1,16 @@
+use sdf::{elements::*, component::*, math::*};
+
+fn write_pair(pair: &[Vec4; 2], block: &mut SdfOpSpecificBlock) {
+    block.vec4s[0] = pair[0];
+    block.vec4s[1] = pair[1];
+}
+
+// Without the slots it writes, the function could overwrite other fields unnoticed
+#[derive(Clone, Debug, SdfElement)]
+#[sdf(op = 100, primitive)]
+struct Undeclared {
+    #[sdf(with = "write_pair")]
+    pair: [Vec4; 2],
+}
+
+fn main() {}
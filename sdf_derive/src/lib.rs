@@ -0,0 +1,347 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Field, Fields, Lit, Meta,
+    NestedMeta, Path, Type,
+};
+
+/**
+ * Implement `SdfElementCore` for a struct: its op info, cloning it into a box, and packing its
+ * fields into op-specific blocks. The struct has to implement `Clone`, and `SdfElement` is still
+ * written by hand. The op code is also exposed as `OP_CODE`, for the registry.
+ *
+ * The struct takes `#[sdf(op = 3, primitive)]`, `#[sdf(op = 0, union)]` or
+ * `#[sdf(op = 1, strict(acc = 0, drawn = 1))]`, with an optional `blocks = "uptree"` or
+ * `blocks = "both"` for fields that go into the uptree block instead of or as well as the
+ * downtree one.
+ *
+ * Fields are packed with `#[sdf(floats = 0)]`, `#[sdf(vec4s = 1)]` for Vec2, Vec3 and Vec4,
+ * `#[sdf(vec4s = 1, component = 3)]` for a single float, `#[sdf(mat4s = 0)]`, or
+ * `#[sdf(with = "path::to::write", vec4s = 0, count = 2)]` for a function that takes the field and
+ * the block and writes the whole slots it declares. Fields without an attribute aren't packed.
+ */
+#[proc_macro_derive(SdfElement, attributes(sdf))]
+pub fn derive_sdf_element(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+enum Kind {
+    Primitive,
+    Union,
+    Strict(u32, u32),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Blocks {
+    Downtree,
+    Uptree,
+    Both,
+}
+
+struct ElementAttrs {
+    op: u32,
+    kind: Kind,
+    blocks: Blocks,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Array {
+    Floats,
+    Vec4s,
+    Mat4s,
+}
+
+impl Array {
+    fn name(&self) -> &'static str {
+        match self {
+            Array::Floats => "floats",
+            Array::Vec4s => "vec4s",
+            Array::Mat4s => "mat4s",
+        }
+    }
+
+    // Length of the array in SdfOpSpecificBlock, which the generated code checks as well
+    fn len(&self) -> usize {
+        match self {
+            Array::Floats => 2,
+            Array::Vec4s => 3,
+            Array::Mat4s => 2,
+        }
+    }
+
+    // Components a whole slot of the array has
+    fn width(&self) -> usize {
+        match self {
+            Array::Vec4s => 4,
+            _ => 1,
+        }
+    }
+}
+
+enum Packing {
+    Slot { array: Array, index: usize, component: Option<usize> },
+    With { path: Path, array: Array, index: usize, count: usize },
+}
+
+fn int_lit(lit: &Lit) -> syn::Result<u32> {
+    match lit {
+        Lit::Int(int) => int.base10_parse(),
+        other => Err(Error::new(other.span(), "Expected an integer")),
+    }
+}
+
+fn sdf_metas(attrs: &[syn::Attribute]) -> syn::Result<Vec<NestedMeta>> {
+    let mut metas = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("sdf")) {
+        match attr.parse_meta()? {
+            Meta::List(list) => metas.extend(list.nested),
+            other => return Err(Error::new(other.span(), "Expected #[sdf(...)]")),
+        }
+    }
+    Ok(metas)
+}
+
+fn element_attrs(input: &DeriveInput) -> syn::Result<ElementAttrs> {
+    let mut op = None;
+    let mut kind = None;
+    let mut blocks = Blocks::Downtree;
+    for meta in sdf_metas(&input.attrs)? {
+        match meta {
+            NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("op") => {
+                op = Some(int_lit(&pair.lit)?);
+            },
+            NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("blocks") => {
+                blocks = match &pair.lit {
+                    Lit::Str(name) if name.value() == "downtree" => Blocks::Downtree,
+                    Lit::Str(name) if name.value() == "uptree" => Blocks::Uptree,
+                    Lit::Str(name) if name.value() == "both" => Blocks::Both,
+                    other => return Err(Error::new(other.span(), "Expected \"downtree\", \"uptree\" or \"both\"")),
+                };
+            },
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("primitive") => kind = Some(Kind::Primitive),
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("union") => kind = Some(Kind::Union),
+            NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("strict") => {
+                let (mut acc, mut drawn) = (None, None);
+                for nested in list.nested.iter() {
+                    match nested {
+                        NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("acc") => {
+                            acc = Some(int_lit(&pair.lit)?);
+                        },
+                        NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("drawn") => {
+                            drawn = Some(int_lit(&pair.lit)?);
+                        },
+                        other => return Err(Error::new(other.span(), "Expected acc = N or drawn = N")),
+                    }
+                }
+                match (acc, drawn) {
+                    (Some(acc), Some(drawn)) => kind = Some(Kind::Strict(acc, drawn)),
+                    _ => return Err(Error::new(list.span(), "Strict elements need both acc and drawn slot counts")),
+                }
+            },
+            other => return Err(Error::new(other.span(), "Unknown sdf attribute")),
+        }
+    }
+    match (op, kind) {
+        (Some(op), Some(kind)) => Ok(ElementAttrs { op, kind, blocks }),
+        (None, _) => Err(Error::new(Span::call_site(), "Missing #[sdf(op = N)]")),
+        (_, None) => Err(Error::new(Span::call_site(), "Missing element kind: primitive, union or strict(...)")),
+    }
+}
+
+fn field_packing(field: &Field) -> syn::Result<Option<Packing>> {
+    let mut slot = None;
+    let mut component = None;
+    let mut count = None;
+    let mut with = None;
+    for meta in sdf_metas(&field.attrs)? {
+        let pair = match meta {
+            NestedMeta::Meta(Meta::NameValue(pair)) => pair,
+            other => return Err(Error::new(other.span(), "Expected name = value")),
+        };
+        let name = pair.path.get_ident().map(|ident| ident.to_string()).unwrap_or_default();
+        match name.as_str() {
+            "floats" => slot = Some((Array::Floats, int_lit(&pair.lit)? as usize)),
+            "vec4s" => slot = Some((Array::Vec4s, int_lit(&pair.lit)? as usize)),
+            "mat4s" => slot = Some((Array::Mat4s, int_lit(&pair.lit)? as usize)),
+            "component" => {
+                let index = int_lit(&pair.lit)? as usize;
+                if index >= 4 {
+                    return Err(Error::new(pair.lit.span(), "Vec4 components go from 0 to 3"));
+                }
+                component = Some(index);
+            },
+            "count" => {
+                let slots = int_lit(&pair.lit)? as usize;
+                if slots == 0 {
+                    return Err(Error::new(pair.lit.span(), "Functions have to write at least one slot"));
+                }
+                count = Some(slots);
+            },
+            "with" => match &pair.lit {
+                Lit::Str(path) => with = Some(path.parse::<Path>()?),
+                other => return Err(Error::new(other.span(), "Expected a path in a string")),
+            },
+            _ => return Err(Error::new(pair.path.span(), "Unknown sdf field attribute")),
+        }
+    }
+    match (slot, component, count, with) {
+        (None, None, None, None) => Ok(None),
+        (Some((array, index)), None, count, Some(path)) => {
+            Ok(Some(Packing::With { path, array, index, count: count.unwrap_or(1) }))
+        },
+        (None, _, _, Some(_)) => Err(Error::new(
+            field.span(),
+            "Functions have to declare the slots they write, like vec4s = 0, count = 2",
+        )),
+        (_, _, Some(_), None) => Err(Error::new(field.span(), "Only fields packed with a function take a count")),
+        (Some((array, index)), component, None, None) => {
+            if component.is_some() && array != Array::Vec4s {
+                return Err(Error::new(field.span(), "Only vec4s have components"));
+            }
+            Ok(Some(Packing::Slot { array, index, component }))
+        },
+        _ => Err(Error::new(field.span(), "Functions write whole slots, not components")),
+    }
+}
+
+// Number of components a vector field fills, judged by the name of its type
+fn vector_width(ty: &Type) -> usize {
+    match ty {
+        Type::Path(path) => match path.path.segments.last().map(|segment| segment.ident.to_string()).as_deref() {
+            Some("Vec2") => 2,
+            Some("Vec3") => 3,
+            _ => 4,
+        },
+        _ => 4,
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let attrs = element_attrs(input)?;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields.named.iter().collect::<Vec<_>>(),
+            Fields::Unit => Vec::new(),
+            Fields::Unnamed(_) => return Err(Error::new(input.span(), "Elements need named fields")),
+        },
+        _ => return Err(Error::new(input.span(), "Only structs can be elements")),
+    };
+
+    // Whole vectors are written before single components, so a component can fill the w of a Vec3
+    let mut claimed: Vec<(Array, usize, usize)> = Vec::new();
+    let mut whole = Vec::new();
+    let mut parts = Vec::new();
+    let mut checks = Vec::new();
+    for field in fields {
+        let packing = match field_packing(field)? {
+            Some(packing) => packing,
+            None => continue,
+        };
+        let ident = field.ident.as_ref().unwrap();
+        // Claim components of a range of slots, which have to exist and be free
+        let mut claim = |array: Array, slots: std::ops::Range<usize>, components: std::ops::Range<usize>| {
+            let last = slots.end - 1;
+            if last >= array.len() {
+                return Err(Error::new(field.span(), format!(
+                    "Field `{}` doesn't fit into {}, which has {} slots", ident, array.name(), array.len()
+                )));
+            }
+            for index in slots {
+                for component in components.clone() {
+                    if claimed.contains(&(array, index, component)) {
+                        return Err(Error::new(field.span(), format!(
+                            "{}[{}] is already used by another field", array.name(), index
+                        )));
+                    }
+                    claimed.push((array, index, component));
+                }
+            }
+            // Array::len() repeats the block layout, so check against the real one as well
+            let array_ident = syn::Ident::new(array.name(), Span::call_site());
+            let message = format!("Field `{}` doesn't fit into {}", ident, array.name());
+            checks.push(quote! {
+                assert!(#last < ::sdf::component::SdfOpSpecificBlock::ZERO.#array_ident.len(), #message);
+            });
+            Ok(())
+        };
+        match packing {
+            Packing::With { path, array, index, count } => {
+                claim(array, index..index + count, 0..array.width())?;
+                whole.push(quote! { #path(&self.#ident, &mut block); });
+            },
+            Packing::Slot { array, index, component } => {
+                let components = match (array, component) {
+                    (Array::Vec4s, Some(component)) => component..component + 1,
+                    (Array::Vec4s, None) => 0..vector_width(&field.ty),
+                    _ => 0..1,
+                };
+                claim(array, index..index + 1, components)?;
+
+                let array_ident = syn::Ident::new(array.name(), Span::call_site());
+                match (array, component) {
+                    (Array::Vec4s, Some(component)) => parts.push(quote! {
+                        block.vec4s[#index][#component] = self.#ident;
+                    }),
+                    (Array::Vec4s, None) => whole.push(quote! {
+                        block.vec4s[#index] = ::sdf::component::SdfBlockVector::to_vec4(self.#ident);
+                    }),
+                    _ => whole.push(quote! { block.#array_ident[#index] = self.#ident; }),
+                }
+            },
+        }
+    }
+
+    let block = quote! { ::sdf::component::SdfOpSpecificBlock };
+    let packed = quote! {
+        let mut block = #block::ZERO;
+        #(#whole)*
+        #(#parts)*
+        block
+    };
+    let (dt_block, ut_block) = match attrs.blocks {
+        Blocks::Downtree => (packed, quote! { #block::ZERO }),
+        Blocks::Uptree => (quote! { #block::ZERO }, packed),
+        Blocks::Both => (packed.clone(), packed),
+    };
+    let op = attrs.op;
+    let info = match attrs.kind {
+        Kind::Primitive => quote! { primitive_info(Self::OP_CODE) },
+        Kind::Union => quote! { union_info(Self::OP_CODE) },
+        Kind::Strict(acc, drawn) => {
+            let (acc, drawn) = (acc as usize, drawn as usize);
+            quote! { strict_info(Self::OP_CODE, #acc, #drawn) }
+        },
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        const _: () = {
+            #(#checks)*
+        };
+
+        impl #impl_generics #name #ty_generics #where_clause {
+            pub const OP_CODE: u32 = #op;
+        }
+
+        impl #impl_generics ::sdf::elements::SdfElementCore for #name #ty_generics #where_clause {
+            fn get_info(&self) -> ::sdf::elements::SdfElementInfo {
+                ::sdf::elements::SdfElementInfo::#info
+            }
+
+            fn box_clone(&self) -> ::std::boxed::Box<dyn ::sdf::elements::SdfElement> {
+                ::std::boxed::Box::new(::core::clone::Clone::clone(self))
+            }
+
+            fn get_dt_specific_block(&self) -> #block {
+                #dt_block
+            }
+
+            fn get_ut_specific_block(&self) -> #block {
+                #ut_block
+            }
+        }
+    })
+}
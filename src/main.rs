@@ -37,11 +37,7 @@ fn main() {
         }
     )
     .transform(get_rand_transform())
-    .operation(
-        SdfUnion {
-            smooth_radius: 0.0,
-        }
-    );
+    .operation(SdfUnion::new(0.0));
     for _ in 0..2 {
         sdf = sdf.with(
             SdfBuilder::primitive(